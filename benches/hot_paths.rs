@@ -0,0 +1,269 @@
+//! Benchmarks of ingesting and serving NARs, run with `cargo bench`.
+//! The inputs are generated in-process, so no Nix daemon is needed.
+use bytes::Bytes;
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use futures::executor::block_on_stream;
+use futures::{StreamExt, stream};
+use gachix::git_store::GitRepo;
+use gachix::nar::NarGitStream;
+use gachix::nar::decode::NarGitDecoder;
+use gachix::nar::encode_stream::DEFAULT_PREFETCH_DEPTH;
+use gachix::nar::hashing::digest_nar_stream;
+use gachix::nar::pipeline::parse_pipelined;
+use gachix::nix::NixPath;
+use gachix::nix_interface::nar_info::NarInfo;
+use gachix::settings;
+use gachix::store::Store;
+use git2::{FileMode, Repository};
+use std::hint::black_box;
+use std::io::{self, BufReader};
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::io::AsyncRead;
+use tokio_util::io::{StreamReader, SyncIoBridge};
+
+#[path = "../tests/common/fixtures.rs"]
+pub mod fixtures;
+
+fn inputs() -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        (
+            "many-small-files",
+            fixtures::many_small_files_nar(10_000, 200),
+        ),
+        ("single-huge-file", fixtures::single_huge_file_nar(64 << 20)),
+        ("deep-tree", fixtures::deep_tree_nar(200, 5)),
+    ]
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("NarGitDecoder::parse");
+    group.sample_size(10);
+    for (name, nar) in inputs() {
+        group.throughput(Throughput::Bytes(nar.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), &nar, |b, nar| {
+            // every iteration writes into an empty repository, as objects which exist are skipped
+            b.iter_batched(
+                || TempDir::new().unwrap(),
+                |temp_dir| {
+                    let repo = Repository::init(temp_dir.path()).unwrap();
+                    black_box(NarGitDecoder::new(&repo).parse(nar.as_slice()).unwrap());
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// With the objects of a package written as one pack, and written loose one by one
+fn bench_add_nar(c: &mut Criterion) {
+    let mut group = c.benchmark_group("GitRepo::add_nar");
+    group.sample_size(10);
+    for (name, nar) in inputs() {
+        group.throughput(Throughput::Bytes(nar.len() as u64));
+        for (batching, budget) in [
+            ("pack", settings::GitSettings::default().ingest_pack_budget),
+            ("loose", 0),
+        ] {
+            let git_settings = settings::GitSettings {
+                ingest_pack_budget: budget,
+                ..settings::GitSettings::default()
+            };
+            group.bench_with_input(BenchmarkId::new(batching, name), &nar, |b, nar| {
+                b.iter_batched(
+                    || {
+                        let temp_dir = TempDir::new().unwrap();
+                        let repo = GitRepo::new(&temp_dir.path().join("repo"))
+                            .unwrap()
+                            .with_git_settings(git_settings)
+                            .unwrap();
+                        (temp_dir, repo)
+                    },
+                    |(_temp_dir, repo)| black_box(repo.add_nar(nar.as_slice()).unwrap()),
+                    BatchSize::PerIteration,
+                )
+            });
+        }
+    }
+    group.finish();
+}
+
+fn bench_stream(c: &mut Criterion) {
+    let mut group = c.benchmark_group("NarGitStream");
+    group.sample_size(10);
+    for (name, nar) in inputs() {
+        let temp_dir = TempDir::new().unwrap();
+        let repo = Repository::init(temp_dir.path()).unwrap();
+        let (oid, filemode) = NarGitDecoder::new(&repo).parse(nar.as_slice()).unwrap();
+        let filemode = match filemode == i32::from(FileMode::Tree) {
+            true => filemode,
+            // the stream serializes blobs at the root as regular files
+            false => FileMode::Blob.into(),
+        };
+        group.throughput(Throughput::Bytes(nar.len() as u64));
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| {
+                let repo = Repository::open(temp_dir.path()).unwrap();
+                let stream = NarGitStream::new(repo, oid, filemode);
+                let size: usize = block_on_stream(stream)
+                    .map(|chunk| chunk.unwrap().len())
+                    .sum();
+                assert_eq!(size, nar.len());
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Serving a package with many small files, with and without loading the next blob ahead.
+/// The consumer hashes every chunk, standing in for the time spent sending it.
+fn bench_stream_prefetch(c: &mut Criterion) {
+    let nar = fixtures::many_small_files_nar(10_000, 200);
+    let temp_dir = TempDir::new().unwrap();
+    let repo = Repository::init(temp_dir.path()).unwrap();
+    let (oid, filemode) = NarGitDecoder::new(&repo).parse(nar.as_slice()).unwrap();
+    let mut group = c.benchmark_group("NarGitStream prefetch");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(nar.len() as u64));
+    for depth in [0, DEFAULT_PREFETCH_DEPTH] {
+        group.bench_function(BenchmarkId::from_parameter(depth), |b| {
+            b.iter(|| {
+                let repo = Repository::open(temp_dir.path()).unwrap();
+                let stream = NarGitStream::new(repo, oid, filemode).with_prefetch_depth(depth);
+                let digest = digest_nar_stream(stream).unwrap();
+                assert_eq!(digest.size, nar.len() as u64);
+            })
+        });
+    }
+    group.finish();
+}
+
+/// A NAR arriving over a connection which delivers 512 KiB per millisecond
+fn network(nar: &[u8]) -> impl AsyncRead + Unpin + '_ {
+    let chunks = stream::iter(nar.chunks(512 * 1024)).then(|chunk| async move {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        Ok::<_, io::Error>(Bytes::copy_from_slice(chunk))
+    });
+    StreamReader::new(Box::pin(chunks))
+}
+
+/// Receiving a NAR from a daemon, with decoding in the same thread or overlapped with the transfer
+fn bench_daemon_ingest(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let nar = fixtures::single_huge_file_nar(64 << 20);
+    let mut group = c.benchmark_group("daemon ingest");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(nar.len() as u64));
+    let new_repo = || {
+        let temp_dir = TempDir::new().unwrap();
+        let repo = GitRepo::new(&temp_dir.path().join("repo")).unwrap();
+        (temp_dir, repo)
+    };
+    group.bench_function("sequential", |b| {
+        b.iter_batched(
+            new_repo,
+            |(_temp_dir, repo)| {
+                runtime.block_on(async {
+                    tokio::task::block_in_place(|| {
+                        repo.add_nar(BufReader::new(SyncIoBridge::new(network(&nar))))
+                            .unwrap()
+                    })
+                })
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("pipelined", |b| {
+        b.iter_batched(
+            new_repo,
+            |(_temp_dir, repo)| {
+                runtime
+                    .block_on(parse_pipelined(
+                        network(&nar),
+                        move |r| Ok(repo.add_nar(r)?),
+                    ))
+                    .unwrap()
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+fn bench_narinfo(c: &mut Criterion) {
+    let text = fixtures::narinfo_text(50);
+    let narinfo = NarInfo::parse(&text).unwrap();
+    c.bench_function("NarInfo::parse", |b| {
+        b.iter(|| NarInfo::parse(black_box(&text)).unwrap())
+    });
+    c.bench_function("NarInfo::to_string", |b| {
+        b.iter(|| black_box(&narinfo).to_string())
+    });
+}
+
+/// Narinfo GETs of several threads, which check out their own repository handles. The narinfo
+/// cache is disabled, so every request reads the blob.
+fn bench_concurrent_narinfo(c: &mut Criterion) {
+    const PACKAGES: usize = 64;
+    const REQUESTS_PER_THREAD: usize = 500;
+    let temp_dir = TempDir::new().unwrap();
+    let mut settings = settings::Store::new(temp_dir.path().join("repo"));
+    settings.use_local_nix_daemon = false;
+    settings.narinfo_cache.max_entries = 0;
+    let store = Store::new(settings).unwrap();
+    let nar = fixtures::many_small_files_nar(1, 100);
+    let hashes: Vec<String> = (0..PACKAGES)
+        .map(|i| {
+            // 32 characters of the nix base32 alphabet
+            let hash = format!("{i:0>32}");
+            let path = NixPath::new(&format!("/nix/store/{hash}-package-{i}")).unwrap();
+            store
+                .import_nar(nar.as_slice(), &path, vec![], None)
+                .unwrap();
+            hash
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("Store::get_narinfo concurrent");
+    group.sample_size(10);
+    let cores = std::thread::available_parallelism().map_or(4, |n| n.get());
+    let mut threads = vec![1, 2, 4, cores];
+    threads.sort();
+    threads.dedup();
+    for threads in threads {
+        group.throughput(Throughput::Elements((threads * REQUESTS_PER_THREAD) as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(threads),
+            &threads,
+            |b, &threads| {
+                b.iter(|| {
+                    std::thread::scope(|scope| {
+                        for t in 0..threads {
+                            let (store, hashes) = (&store, &hashes);
+                            scope.spawn(move || {
+                                for i in 0..REQUESTS_PER_THREAD {
+                                    let hash = &hashes[(t + i) % hashes.len()];
+                                    black_box(store.get_narinfo(hash).unwrap().unwrap());
+                                }
+                            });
+                        }
+                    })
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_decode,
+    bench_add_nar,
+    bench_stream,
+    bench_stream_prefetch,
+    bench_daemon_ingest,
+    bench_narinfo,
+    bench_concurrent_narinfo
+);
+criterion_main!(benches);
@@ -0,0 +1,252 @@
+use anyhow::{Result, bail};
+use bytes::Bytes;
+use futures::{Stream, TryStreamExt};
+use reqwest::{Method, Response, StatusCode};
+use std::io;
+use std::pin::Pin;
+use std::time::Duration;
+use tracing::warn;
+use url::Url;
+
+use crate::nix_interface::cache_info::CacheInfo;
+use crate::nix_interface::nar_info::NarInfo;
+
+pub type NarByteStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+pub const DEFAULT_RETRIES: u32 = 3;
+// Doubled after every failed attempt
+const RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Timeouts of the requests to a binary cache
+#[derive(Debug, Clone, Copy)]
+pub struct ClientTimeouts {
+    pub connect: Duration,
+    /// Limit for requests of small files such as narinfos
+    pub request: Duration,
+    /// Limit for the time without data while downloading a NAR
+    pub read: Duration,
+}
+
+impl Default for ClientTimeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(10),
+            request: Duration::from_secs(30),
+            read: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Client for the HTTP interface of a Nix binary cache (e.g. https://cache.nixos.org)
+#[derive(Clone)]
+pub struct BinaryCacheClient {
+    base_url: Url,
+    http: reqwest::Client,
+    timeouts: ClientTimeouts,
+    retries: u32,
+}
+
+impl BinaryCacheClient {
+    pub fn new(mut base_url: Url) -> Self {
+        // Url::join replaces the last path segment unless the base ends with a slash
+        if !base_url.path().ends_with('/') {
+            let path = format!("{}/", base_url.path());
+            base_url.set_path(&path);
+        }
+        let timeouts = ClientTimeouts::default();
+        Self {
+            base_url,
+            http: http_client(&timeouts),
+            timeouts,
+            retries: DEFAULT_RETRIES,
+        }
+    }
+
+    pub fn with_timeouts(mut self, timeouts: ClientTimeouts) -> Self {
+        self.http = http_client(&timeouts);
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// How often a request is repeated after a connection error or a 5xx or 429 response
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+
+    pub async fn get_cache_info(&self) -> Result<CacheInfo> {
+        let response = self.get_small("nix-cache-info").await?;
+        let url = response.url().clone();
+        match response.status() {
+            status if status.is_success() => Ok(CacheInfo::parse(&response.text().await?)?),
+            status => bail!("Request for {} failed with status code {}", url, status),
+        }
+    }
+
+    pub async fn get_narinfo(&self, hash: &str) -> Result<Option<NarInfo>> {
+        let response = self.get_small(&format!("{hash}.narinfo")).await?;
+        let url = response.url().clone();
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(Some(NarInfo::parse(&response.text().await?)?)),
+            status => bail!("Request for {} failed with status code {}", url, status),
+        }
+    }
+
+    /// Whether the cache has the package with `hash`, without downloading its narinfo
+    pub async fn head_narinfo(&self, hash: &str) -> Result<bool> {
+        let url = self.base_url.join(&format!("{hash}.narinfo"))?;
+        let response = self
+            .send(Method::HEAD, url.clone(), Some(self.timeouts.request))
+            .await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(false),
+            status if status.is_success() => Ok(true),
+            status => bail!("Request for {} failed with status code {}", url, status),
+        }
+    }
+
+    /// The `.ls` listing of the files of the package with `hash`, if the cache provides one
+    pub async fn get_listing(&self, hash: &str) -> Result<Option<serde_json::Value>> {
+        let response = self.get_small(&format!("{hash}.ls")).await?;
+        let url = response.url().clone();
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => {
+                Ok(Some(serde_json::from_str(&response.text().await?)?))
+            }
+            status => bail!("Request for {} failed with status code {}", url, status),
+        }
+    }
+
+    /// Downloads the (possibly compressed) NAR at `url`, which is relative to the cache root.
+    /// The download is not retried once the first bytes arrived.
+    pub async fn get_nar(&self, url: &str) -> Result<NarByteStream> {
+        let url = self.base_url.join(url)?;
+        // The size of a NAR is unbounded, only a stalled download times out
+        let response = self.send(Method::GET, url.clone(), None).await?;
+        if !response.status().is_success() {
+            bail!(
+                "Request for {} failed with status code {}",
+                url,
+                response.status()
+            );
+        }
+        Ok(Box::pin(response.bytes_stream().map_err(io::Error::other)))
+    }
+
+    async fn get_small(&self, path: &str) -> Result<Response> {
+        let url = self.base_url.join(path)?;
+        self.send(Method::GET, url, Some(self.timeouts.request))
+            .await
+    }
+
+    /// Sends the request, repeating it while it fails in a way which may be temporary
+    async fn send(&self, method: Method, url: Url, timeout: Option<Duration>) -> Result<Response> {
+        let mut attempt = 0;
+        loop {
+            let mut request = self.http.request(method.clone(), url.clone());
+            if let Some(timeout) = timeout {
+                request = request.timeout(timeout);
+            }
+            let reason = match request.send().await {
+                Ok(response) if is_transient(response.status()) && attempt < self.retries => {
+                    format!("status code {}", response.status())
+                }
+                Ok(response) => return Ok(response),
+                Err(e) if (e.is_connect() || e.is_timeout()) && attempt < self.retries => {
+                    e.to_string()
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let delay = RETRY_DELAY * 2u32.pow(attempt);
+            warn!(
+                "Request for {} failed with {}, retrying in {}ms",
+                url,
+                reason,
+                delay.as_millis()
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+/// Responses with a Content-Encoding, which reqwest negotiates for gzip and zstd, are decompressed
+fn http_client(timeouts: &ClientTimeouts) -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(timeouts.connect)
+        .read_timeout(timeouts.read)
+        .build()
+        .expect("the HTTP client is configured correctly")
+}
+
+fn is_transient(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    /// Answers one request per entry of `responses` with a body-less response of that status
+    fn serve(responses: Vec<u16>) -> Result<Url> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = Url::parse(&format!("http://{}/", listener.local_addr()?))?;
+        std::thread::spawn(move || {
+            for status in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} X\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                )
+                .unwrap();
+            }
+        });
+        Ok(url)
+    }
+
+    #[test]
+    fn test_base_url_keeps_path() -> Result<()> {
+        let client = BinaryCacheClient::new(Url::parse("https://example.org/cache")?);
+        let url = client.base_url().join("nar/abc.nar.xz")?;
+        assert_eq!(url.as_str(), "https://example.org/cache/nar/abc.nar.xz");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retries_transient_errors() -> Result<()> {
+        let client = BinaryCacheClient::new(serve(vec![503, 429, 200])?);
+        assert!(
+            client
+                .head_narinfo("0c1z6sxy9wkrg9pcd9ls2bd3qi3ifrx2")
+                .await?
+        );
+
+        let client = BinaryCacheClient::new(serve(vec![503, 503])?).with_retries(1);
+        let error = client
+            .head_narinfo("0c1z6sxy9wkrg9pcd9ls2bd3qi3ifrx2")
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("503"));
+
+        let client = BinaryCacheClient::new(serve(vec![404])?);
+        assert!(
+            !client
+                .head_narinfo("0c1z6sxy9wkrg9pcd9ls2bd3qi3ifrx2")
+                .await?
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,521 @@
+//! Writes settings as a config file in which every setting is explained by a comment
+
+use serde_json::{Map, Value};
+
+use crate::settings::ConfigFormat;
+
+/// The comment above each setting, in the order the settings are written. Unset settings are
+/// written commented out, so all of them can be found in the file.
+const DESCRIPTIONS: &[(&str, &str)] = &[
+    (
+        "store",
+        "The Git repository and how packages are added to it",
+    ),
+    (
+        "store.path",
+        "The path of the Git repository where all packages are stored",
+    ),
+    (
+        "store.builders",
+        "The Nix daemons to contact when adding packages, reached over SSH. Entries are either\n\
+         `[user@]host[:port]` (user defaults to nix-ssh, port to 22) or structured entries with\n\
+         `host`, `port`, `user`, `ssh_key_path`, `systems`, `transport` (libssh2 or openssh)\n\
+         and `host_key_fingerprint` (SHA256:...)",
+    ),
+    (
+        "store.remotes",
+        "The peers to contact when adding packages, tried in the order of their priority (lowest\n\
+         first). Entries are either a URL or structured entries with `name`, `url`, `priority`\n\
+         (50 by default), `kind` (git for Gachix replicas or http for binary caches),\n\
+         `ssh_key_path`, `token_env` (the variable holding an HTTP(S) token), `token_file` (a\n\
+         file holding it, which wins over `token_env`) and `ref_namespace`",
+    ),
+    (
+        "store.use_local_nix_daemon",
+        "Whether to use the Nix daemon on this machine, false on a non Nix system",
+    ),
+    (
+        "store.daemon_socket",
+        "The socket of the local Nix daemon, by default NIX_REMOTE (unix://<path>) and then\n\
+         /nix/var/nix/daemon-socket/socket",
+    ),
+    (
+        "store.daemon_timeouts",
+        "Timeouts in seconds for Nix daemon operations",
+    ),
+    ("store.daemon_timeouts.connect", ""),
+    (
+        "store.daemon_timeouts.query",
+        "Metadata queries, e.g. path info and validity",
+    ),
+    ("store.daemon_timeouts.build", ""),
+    (
+        "store.daemon_timeouts.fetch_idle",
+        "NAR fetches only time out if no data was received for this long",
+    ),
+    (
+        "store.daemon_pool",
+        "Connections kept open to each Nix daemon and shared by all requests. Durations are\n\
+         in seconds",
+    ),
+    ("store.daemon_pool.max_connections", ""),
+    (
+        "store.daemon_pool.idle_timeout",
+        "Connections unused for this long are closed",
+    ),
+    (
+        "store.daemon_pool.acquire_timeout",
+        "Maximum time to wait for a free connection when all are in use",
+    ),
+    (
+        "store.sign_private_key_path",
+        "Deprecated, added to sign_key_paths",
+    ),
+    (
+        "store.sign_key_paths",
+        "The private keys generated by `gachix generate-key`. Each package is signed with\n\
+         all of them, e.g. with the old and the new key while rotating",
+    ),
+    (
+        "store.signer",
+        "Instead of key files, sign with an external program (e.g. a wrapper around an HSM),\n\
+         given as `command` (the program and its arguments), `public_key` (name:base64) and\n\
+         `timeout` (seconds, 30 by default). It reads one fingerprint per line from stdin and\n\
+         writes the base64 signature of each as a line to stdout",
+    ),
+    (
+        "store.trusted_public_keys",
+        "Packages fetched with `fetch-upstream` must be signed by one of these keys\n\
+         (name:base64) or by a key gachix signs with. Empty accepts all packages",
+    ),
+    (
+        "store.store_dir",
+        "The Nix store directory of the cached paths, by default NIX_STORE_DIR or /nix/store",
+    ),
+    (
+        "store.ref_namespace",
+        "The references of a package are <ref_namespace>/<hash>/..., peers must use the same",
+    ),
+    (
+        "store.max_closure_depth",
+        "Packages whose dependency chain is longer are not added",
+    ),
+    (
+        "store.compression",
+        "Compression of the .ls listings stored with each package: zstd or none",
+    ),
+    (
+        "store.ssh_private_key_path",
+        "The private SSH key for authenticating against builders and remotes",
+    ),
+    (
+        "store.known_hosts_file",
+        "Builder host keys are verified against ~/.ssh/known_hosts and this file",
+    ),
+    (
+        "store.accept_new_host_keys",
+        "Trust builders whose host key is unknown and add the key to known_hosts",
+    ),
+    (
+        "store.allow_substitute",
+        "If no Nix daemon has a package, ask them to substitute it, e.g. from cache.nixos.org",
+    ),
+    (
+        "store.all_outputs",
+        "Also add the other outputs of a package's derivation (e.g. dev, man)",
+    ),
+    (
+        "store.sign_local_store",
+        "Also add the signatures of packages taken from the local Nix daemon to its store",
+    ),
+    (
+        "store.chunk_threshold",
+        "Files larger than this many bytes are stored as chunks instead of a single blob",
+    ),
+    (
+        "store.nar_limits",
+        "NARs exceeding these limits are rejected while decoding. Sizes are in bytes",
+    ),
+    ("store.nar_limits.max_file_size", ""),
+    ("store.nar_limits.max_total_size", ""),
+    ("store.nar_limits.max_depth", ""),
+    (
+        "store.nar_limits.max_entries",
+        "Maximum number of directory entries",
+    ),
+    (
+        "store.warn_case_collisions",
+        "Warn about packages with files whose names differ only by case",
+    ),
+    ("store.git", "How git stores objects"),
+    (
+        "store.git.compression_level",
+        "zlib level (0-9) of the objects `gachix maintenance repack` rolls into a pack",
+    ),
+    (
+        "store.git.pack_threshold",
+        "Loose objects are rolled into a pack once there are more than this many",
+    ),
+    (
+        "store.git.ingest_pack_budget",
+        "Bytes of an added package which are written as one pack, the rest is written loose; 0 writes all loose",
+    ),
+    (
+        "store.audit_log",
+        "Record who added, imported, fetched or signed each package on refs/gachix/audit",
+    ),
+    (
+        "store.narinfo_cache",
+        "Served narinfos are kept in memory, the least recently used are dropped first",
+    ),
+    ("store.narinfo_cache.max_entries", "0 disables the cache"),
+    ("store.narinfo_cache.max_bytes", ""),
+    ("server", "The binary cache served by `gachix serve`"),
+    ("server.port", "The port under which Gachix listens"),
+    ("server.host", "The ip address under which Gachix listens"),
+    (
+        "server.priority",
+        "Announced in /nix-cache-info. Nix prefers caches with a lower priority",
+    ),
+    (
+        "server.want_mass_query",
+        "Announced in /nix-cache-info, lets Nix query many narinfos at once",
+    ),
+    (
+        "server.workers",
+        "Number of worker threads, by default one per CPU core",
+    ),
+    (
+        "server.auth",
+        "If set with `user` and `password`, clients must authenticate with HTTP basic auth.\n\
+         `password_file` names a file holding the password instead, which wins over `password`",
+    ),
+    (
+        "server.limits",
+        "Limits of the connections and requests. Durations are in seconds",
+    ),
+    (
+        "server.limits.max_connections",
+        "Open connections per worker",
+    ),
+    (
+        "server.limits.keep_alive",
+        "How long an idle connection is kept open",
+    ),
+    (
+        "server.limits.client_request_timeout",
+        "How long a client may take to send the request headers",
+    ),
+    (
+        "server.limits.max_request_body_size",
+        "Maximum size of a request body in bytes",
+    ),
+    ("server.compression", ""),
+    (
+        "server.compression.enabled",
+        "Compress responses with gzip, brotli or zstd if the client accepts it",
+    ),
+    ("log", ""),
+    (
+        "log.level",
+        "trace, debug, info, warn or error. Overridden by --log-level and RUST_LOG",
+    ),
+    (
+        "log.format",
+        "text or json (one object per line). Overridden by --log-format",
+    ),
+    (
+        "log.timestamps",
+        "Set to false if the time is added anyway, e.g. by journald",
+    ),
+    (
+        "log.file",
+        "`gachix serve` also writes its log to this file",
+    ),
+    ("log.rotate", "When log.file is rolled over"),
+    (
+        "log.rotate.max_size_mb",
+        "The file is renamed to <file>.1 once it would grow beyond this size",
+    ),
+    (
+        "log.rotate.max_files",
+        "Number of renamed files which are kept",
+    ),
+    ("log.console", "Set to false to only log to the file"),
+    ("telemetry", ""),
+    (
+        "telemetry.otlp_endpoint",
+        "Export spans to an OpenTelemetry collector over OTLP/HTTP, e.g.\n\
+         http://localhost:4318/v1/traces. Requires a build with the otlp feature",
+    ),
+    ("log_level", "Deprecated, replaced by log.level"),
+];
+
+/// `settings`, serialized, as a config file of `format`
+pub(crate) fn render(settings: &Map<String, Value>, format: ConfigFormat) -> String {
+    let mut out = String::new();
+    match format {
+        ConfigFormat::Yaml => yaml_table(&mut out, settings, "", 0, false),
+        ConfigFormat::Toml => toml_table(&mut out, settings, "", false),
+    }
+    out
+}
+
+/// The settings of `value` which differ from `default`
+pub(crate) fn changed(value: &Value, default: &Value) -> Option<Value> {
+    match (value, default) {
+        (Value::Object(table), Value::Object(default)) => {
+            let changed: Map<String, Value> = table
+                .iter()
+                .filter_map(|(key, value)| match default.get(key) {
+                    Some(default) => changed(value, default).map(|value| (key.clone(), value)),
+                    None => Some((key.clone(), value.clone())),
+                })
+                .collect();
+            (!changed.is_empty()).then_some(Value::Object(changed))
+        }
+        _ if value == default => None,
+        _ => Some(value.clone()),
+    }
+}
+
+/// The keys of the settings of `value` which differ from `default`, e.g. `server.port`
+pub(crate) fn changed_keys(value: &Value, default: &Value) -> Vec<String> {
+    fn leaf_keys(value: &Value, path: &str, keys: &mut Vec<String>) {
+        match value {
+            Value::Object(table) if !table.is_empty() => {
+                for (key, value) in table {
+                    leaf_keys(value, &join(path, key), keys);
+                }
+            }
+            _ => keys.push(path.to_string()),
+        }
+    }
+    let mut keys = Vec::new();
+    if let Some(changed) = changed(value, default) {
+        leaf_keys(&changed, "", &mut keys);
+    }
+    keys.sort();
+    keys
+}
+
+/// The keys of `table` in the order of `DESCRIPTIONS`, unknown keys last
+fn ordered_keys<'a>(table: &'a Map<String, Value>, path: &str) -> Vec<&'a String> {
+    let position = |key: &str| {
+        DESCRIPTIONS
+            .iter()
+            .position(|(described, _)| *described == join(path, key))
+            .unwrap_or(usize::MAX)
+    };
+    let mut keys: Vec<&String> = table.keys().collect();
+    keys.sort_by_key(|key| position(key));
+    keys
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{path}.{key}")
+    }
+}
+
+fn description(path: &str) -> &'static str {
+    DESCRIPTIONS
+        .iter()
+        .find(|(described, _)| *described == path)
+        .map_or("", |(_, description)| description)
+}
+
+/// Whether a table is written as a section rather than inline
+fn is_section(value: &Value) -> bool {
+    matches!(value, Value::Object(table) if !table.is_empty())
+}
+
+/// Whether any setting in `value` is set. Sections without any are written commented out,
+/// as an empty section would be read as unset
+fn is_set(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Object(table) => table.values().any(is_set),
+        _ => true,
+    }
+}
+
+fn push_comment(out: &mut String, indent: &str, path: &str) {
+    for line in description(path).lines() {
+        out.push_str(&format!("{indent}# {line}\n"));
+    }
+}
+
+fn push_line(out: &mut String, indent: &str, commented: bool, line: &str) {
+    let marker = if commented { "# " } else { "" };
+    out.push_str(&format!("{indent}{marker}{line}\n"));
+}
+
+fn yaml_table(
+    out: &mut String,
+    table: &Map<String, Value>,
+    path: &str,
+    depth: usize,
+    commented: bool,
+) {
+    let indent = "  ".repeat(depth);
+    for key in ordered_keys(table, path) {
+        let value = &table[key];
+        let key_path = join(path, key);
+        if depth == 0 && !out.is_empty() {
+            out.push('\n');
+        }
+        push_comment(out, &indent, &key_path);
+        if is_section(value) {
+            let commented = commented || !is_set(value);
+            push_line(out, &indent, commented, &format!("{key}:"));
+            if let Value::Object(table) = value {
+                yaml_table(out, table, &key_path, depth + 1, commented);
+            }
+        } else if value.is_null() {
+            push_line(out, &indent, true, &format!("{key}:"));
+        } else {
+            let value = inline(value, ConfigFormat::Yaml);
+            push_line(out, &indent, commented, &format!("{key}: {value}"));
+        }
+    }
+}
+
+/// Writes the values of `table` before its subtables, which TOML requires
+fn toml_table(out: &mut String, table: &Map<String, Value>, path: &str, commented: bool) {
+    let (sections, values): (Vec<&String>, Vec<&String>) = ordered_keys(table, path)
+        .into_iter()
+        .partition(|key| is_section(&table[*key]));
+    for key in values {
+        let value = &table[key];
+        push_comment(out, "", &join(path, key));
+        if value.is_null() {
+            push_line(out, "", true, &format!("{key} ="));
+        } else {
+            let value = inline(value, ConfigFormat::Toml);
+            push_line(out, "", commented, &format!("{key} = {value}"));
+        }
+    }
+    for key in sections {
+        let value = &table[key];
+        let key_path = join(path, key);
+        let commented = commented || !is_set(value);
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        push_comment(out, "", &key_path);
+        push_line(out, "", commented, &format!("[{key_path}]"));
+        if let Value::Object(table) = value {
+            toml_table(out, table, &key_path, commented);
+        }
+    }
+}
+
+/// A value on a single line. JSON strings and numbers are valid in YAML and TOML alike
+fn inline(value: &Value, format: ConfigFormat) -> String {
+    match value {
+        Value::Array(elements) => {
+            let elements: Vec<String> = elements
+                .iter()
+                .map(|element| inline(element, format))
+                .collect();
+            format!("[{}]", elements.join(", "))
+        }
+        Value::Object(table) => {
+            let entries: Vec<String> = table
+                .iter()
+                .filter(|(_, value)| !value.is_null())
+                .map(|(key, value)| match format {
+                    ConfigFormat::Yaml => format!("{key}: {}", inline(value, format)),
+                    ConfigFormat::Toml => format!("{key} = {}", inline(value, format)),
+                })
+                .collect();
+            format!("{{ {} }}", entries.join(", "))
+        }
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+    use std::path::PathBuf;
+
+    use crate::settings::{LogLevel, Settings};
+
+    fn paths(table: &Map<String, Value>, path: &str, found: &mut BTreeSet<String>) {
+        for (key, value) in table {
+            let key_path = join(path, key);
+            if let Value::Object(table) = value {
+                paths(table, &key_path, found);
+            }
+            found.insert(key_path);
+        }
+    }
+
+    #[test]
+    fn test_descriptions_cover_settings() -> anyhow::Result<()> {
+        let mut settings = Settings::default();
+        // only written if they are set
+        settings.log_level = Some(LogLevel::Debug);
+        settings.store.sign_private_key_path = Some(PathBuf::from("key"));
+        let Value::Object(table) = serde_json::to_value(&settings)? else {
+            panic!("settings are not a table");
+        };
+        let mut settings_paths = BTreeSet::new();
+        paths(&table, "", &mut settings_paths);
+        let described: BTreeSet<String> = DESCRIPTIONS
+            .iter()
+            .map(|(path, _)| path.to_string())
+            .collect();
+        assert_eq!(settings_paths, described);
+        Ok(())
+    }
+
+    #[test]
+    fn test_render() {
+        let settings = serde_json::json!({
+            "server": { "port": 9000, "workers": null, "limits": { "keep_alive": 10 } },
+            "store": { "remotes": ["https://a.example.org"], "builders": [{ "host": "b" }] },
+            "telemetry": { "otlp_endpoint": null },
+        });
+        let Value::Object(settings) = settings else {
+            unreachable!()
+        };
+        let yaml = render(&settings, ConfigFormat::Yaml);
+        assert!(yaml.starts_with("# The Git repository"), "{yaml}");
+        assert!(yaml.contains("\n  remotes: [\"https://a.example.org\"]\n"));
+        assert!(yaml.contains("\n  builders: [{ host: \"b\" }]\n"));
+        assert!(yaml.contains("\n  port: 9000\n"));
+        assert!(yaml.contains("\n  # workers:\n"));
+        assert!(yaml.contains(
+            "\n  limits:\n    # How long an idle connection is kept open\n    keep_alive: 10\n"
+        ));
+        // a section without any setting would be read as null
+        assert!(yaml.contains("\n# telemetry:\n"));
+
+        let toml = render(&settings, ConfigFormat::Toml);
+        assert!(toml.contains("\n[server]\n"), "{toml}");
+        assert!(toml.contains("\nport = 9000\n"));
+        assert!(toml.contains("\n# workers =\n"));
+        assert!(toml.contains("\n[server.limits]\n"));
+        assert!(toml.contains("\nbuilders = [{ host = \"b\" }]\n"));
+        assert!(toml.contains("\n# [telemetry]\n"));
+    }
+
+    #[test]
+    fn test_changed() {
+        let default = serde_json::json!({ "a": 1, "b": { "c": [], "d": true }, "e": null });
+        assert_eq!(changed(&default, &default), None);
+        let value = serde_json::json!({ "a": 1, "b": { "c": ["x"], "d": true }, "e": { "f": 2 } });
+        assert_eq!(
+            changed(&value, &default),
+            Some(serde_json::json!({ "b": { "c": ["x"] }, "e": { "f": 2 } }))
+        );
+        assert_eq!(changed_keys(&value, &default), ["b.c", "e.f"]);
+        assert!(changed_keys(&default, &default).is_empty());
+    }
+}
@@ -0,0 +1,358 @@
+use serde::{Serialize, Serializer};
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::ops::AddAssign;
+use std::time::Duration;
+use tracing::info;
+
+use crate::nix_interface::path::NixPath;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddOutcome {
+    Added,
+    AlreadyPresent,
+    FetchedFromPeer,
+    Failed,
+}
+
+const OUTCOMES: [AddOutcome; 4] = [
+    AddOutcome::Added,
+    AddOutcome::AlreadyPresent,
+    AddOutcome::FetchedFromPeer,
+    AddOutcome::Failed,
+];
+
+impl AddOutcome {
+    fn label(&self) -> &'static str {
+        match self {
+            AddOutcome::Added => "added",
+            AddOutcome::AlreadyPresent => "already present",
+            AddOutcome::FetchedFromPeer => "fetched from peer",
+            AddOutcome::Failed => "failed",
+        }
+    }
+}
+
+/// How many slow packages the end-of-run summary names
+const SLOWEST_SHOWN: usize = 5;
+
+/// Where the time went while a package was added from a Nix daemon
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub struct IngestTimings {
+    /// Waiting for the NAR to arrive from the daemon
+    #[serde(rename = "fetch_ms", serialize_with = "as_millis")]
+    pub fetch: Duration,
+    /// Decoding the NAR and writing its blobs, without the waiting
+    #[serde(rename = "decode_ms", serialize_with = "as_millis")]
+    pub decode: Duration,
+    /// Wrapping, verifying and committing the package tree
+    #[serde(rename = "tree_ms", serialize_with = "as_millis")]
+    pub tree: Duration,
+    /// Querying the path info and writing the narinfo
+    #[serde(rename = "narinfo_ms", serialize_with = "as_millis")]
+    pub narinfo: Duration,
+    /// The size of the received NAR
+    pub bytes: u64,
+}
+
+impl IngestTimings {
+    pub fn total(&self) -> Duration {
+        self.fetch + self.decode + self.tree + self.narinfo
+    }
+
+    fn phases(&self) -> [(&'static str, Duration); 4] {
+        [
+            ("fetch", self.fetch),
+            ("decode", self.decode),
+            ("tree", self.tree),
+            ("narinfo", self.narinfo),
+        ]
+    }
+
+    /// Emits the one event which describes how the package was added
+    pub fn log(&self, path: &NixPath) {
+        info!(
+            package = path.get_name(),
+            fetch_ms = millis(self.fetch),
+            decode_ms = millis(self.decode),
+            tree_ms = millis(self.tree),
+            narinfo_ms = millis(self.narinfo),
+            total_ms = millis(self.total()),
+            bytes = self.bytes,
+            "Ingested package"
+        );
+    }
+}
+
+impl AddAssign for IngestTimings {
+    fn add_assign(&mut self, other: Self) {
+        self.fetch += other.fetch;
+        self.decode += other.decode;
+        self.tree += other.tree;
+        self.narinfo += other.narinfo;
+        self.bytes += other.bytes;
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn as_millis<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(millis(*duration))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PackageResult {
+    pub path: String,
+    pub outcome: AddOutcome,
+    pub nar_size: u64,
+    /// Only known for packages which were added from a Nix daemon
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timings: Option<IngestTimings>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CategoryTotal {
+    pub outcome: AddOutcome,
+    pub packages: usize,
+    pub bytes: u64,
+}
+
+/// Per-package outcomes collected while walking a closure in `Store::add_closure`
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct AddSummary {
+    pub packages: Vec<PackageResult>,
+    /// The whole closure was found in the cache, without walking it package by package
+    pub already_cached: bool,
+    #[serde(skip)]
+    seen: HashSet<String>,
+}
+
+impl AddSummary {
+    /// Records the outcome of a package. Only the first outcome of a package counts,
+    /// since shared dependencies are visited once per dependent during a closure walk.
+    pub fn record(&mut self, path: &NixPath, outcome: AddOutcome, nar_size: u64) {
+        self.push(path, outcome, nar_size, None);
+    }
+
+    /// Records a package which was added from a Nix daemon, along with the time it took
+    pub fn record_ingest(&mut self, path: &NixPath, nar_size: u64, timings: IngestTimings) {
+        self.push(path, AddOutcome::Added, nar_size, Some(timings));
+    }
+
+    fn push(
+        &mut self,
+        path: &NixPath,
+        outcome: AddOutcome,
+        nar_size: u64,
+        timings: Option<IngestTimings>,
+    ) {
+        if !self.seen.insert(path.get_path().to_string()) {
+            return;
+        }
+        self.packages.push(PackageResult {
+            path: path.get_path().to_string(),
+            outcome,
+            nar_size,
+            timings,
+        });
+    }
+
+    pub fn contains(&self, path: &NixPath) -> bool {
+        self.seen.contains(path.get_path())
+    }
+
+    pub fn count(&self, outcome: AddOutcome) -> usize {
+        self.packages
+            .iter()
+            .filter(|p| p.outcome == outcome)
+            .count()
+    }
+
+    pub fn has_failures(&self) -> bool {
+        self.count(AddOutcome::Failed) > 0
+    }
+
+    pub fn totals(&self) -> Vec<CategoryTotal> {
+        OUTCOMES
+            .iter()
+            .map(|outcome| {
+                let packages = self.packages.iter().filter(|p| p.outcome == *outcome);
+                CategoryTotal {
+                    outcome: *outcome,
+                    packages: packages.clone().count(),
+                    bytes: packages.map(|p| p.nar_size).sum(),
+                }
+            })
+            .collect()
+    }
+
+    /// The time spent in each phase, summed over the packages which were added from a Nix daemon
+    pub fn phase_totals(&self) -> Option<IngestTimings> {
+        self.packages
+            .iter()
+            .filter_map(|p| p.timings)
+            .reduce(|mut total, timings| {
+                total += timings;
+                total
+            })
+    }
+
+    /// The packages which took the longest to add, the slowest first
+    pub fn slowest(&self, n: usize) -> Vec<&PackageResult> {
+        let mut timed: Vec<_> = self
+            .packages
+            .iter()
+            .filter(|p| p.timings.is_some())
+            .collect();
+        timed.sort_by_key(|p| std::cmp::Reverse(p.timings.map(|t| t.total())));
+        timed.truncate(n);
+        timed
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        #[derive(Serialize)]
+        struct JsonSummary<'a> {
+            already_cached: bool,
+            totals: Vec<CategoryTotal>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            phase_totals: Option<IngestTimings>,
+            slowest: Vec<&'a str>,
+            packages: &'a [PackageResult],
+        }
+        serde_json::to_string_pretty(&JsonSummary {
+            already_cached: self.already_cached,
+            totals: self.totals(),
+            phase_totals: self.phase_totals(),
+            slowest: self
+                .slowest(SLOWEST_SHOWN)
+                .into_iter()
+                .map(|p| p.path.as_str())
+                .collect(),
+            packages: &self.packages,
+        })
+    }
+}
+
+impl Display for AddSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.already_cached && self.count(AddOutcome::AlreadyPresent) == self.packages.len() {
+            return writeln!(f, "already cached ({} paths)", self.packages.len());
+        }
+        writeln!(f, "{:<20}{:>10}{:>16}", "Outcome", "Packages", "Bytes")?;
+        for total in self.totals() {
+            writeln!(
+                f,
+                "{:<20}{:>10}{:>16}",
+                total.outcome.label(),
+                total.packages,
+                total.bytes
+            )?;
+        }
+        for failed in self
+            .packages
+            .iter()
+            .filter(|p| p.outcome == AddOutcome::Failed)
+        {
+            writeln!(f, "failed: {}", failed.path)?;
+        }
+        if let Some(phase_totals) = self.phase_totals() {
+            writeln!(f, "{:<20}{:>12}", "Phase", "Time (ms)")?;
+            for (phase, duration) in phase_totals.phases() {
+                writeln!(f, "{:<20}{:>12.1}", phase, millis(duration))?;
+            }
+            for slow in self.slowest(SLOWEST_SHOWN) {
+                let total = slow.timings.map(|t| t.total()).unwrap_or_default();
+                writeln!(f, "slow: {} ({:.1} ms)", slow.path, millis(total))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn test_totals_per_category() -> Result<()> {
+        let hello = NixPath::new("/nix/store/2bcv91i8fahqghn8dmyr791iaycbsjdd-hello-2.12.2")?;
+        let glibc = NixPath::new("/nix/store/xx7cm72qy2c0643cm1ipngd87aqwkcdp-glibc-2.40-66")?;
+        let mut summary = AddSummary::default();
+        summary.record(&hello, AddOutcome::Added, 100);
+        summary.record(&glibc, AddOutcome::AlreadyPresent, 50);
+        summary.record(&glibc, AddOutcome::AlreadyPresent, 50);
+
+        let totals = summary.totals();
+        assert_eq!(totals[0].packages, 1);
+        assert_eq!(totals[0].bytes, 100);
+        assert_eq!(totals[1].bytes, 50);
+        assert_eq!(totals[1].packages, 1);
+        assert_eq!(totals[2].packages, 0);
+        assert!(!summary.has_failures());
+        Ok(())
+    }
+
+    #[test]
+    fn test_already_cached_closure() -> Result<()> {
+        let hello = NixPath::new("/nix/store/2bcv91i8fahqghn8dmyr791iaycbsjdd-hello-2.12.2")?;
+        let glibc = NixPath::new("/nix/store/xx7cm72qy2c0643cm1ipngd87aqwkcdp-glibc-2.40-66")?;
+        let mut summary = AddSummary::default();
+        summary.record(&hello, AddOutcome::AlreadyPresent, 100);
+        summary.record(&glibc, AddOutcome::AlreadyPresent, 50);
+        assert!(summary.to_string().starts_with("Outcome"));
+
+        summary.already_cached = true;
+        assert_eq!(summary.to_string(), "already cached (2 paths)\n");
+        assert!(summary.to_json()?.contains("\"already_cached\": true"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_phase_totals_and_slowest() -> Result<()> {
+        let hello = NixPath::new("/nix/store/2bcv91i8fahqghn8dmyr791iaycbsjdd-hello-2.12.2")?;
+        let glibc = NixPath::new("/nix/store/xx7cm72qy2c0643cm1ipngd87aqwkcdp-glibc-2.40-66")?;
+        let mut summary = AddSummary::default();
+        summary.record(&hello, AddOutcome::AlreadyPresent, 100);
+        assert_eq!(summary.phase_totals(), None);
+        assert!(!summary.to_string().contains("Phase"));
+
+        let mut summary = AddSummary::default();
+        let ms = Duration::from_millis;
+        let hello_timings = IngestTimings {
+            fetch: ms(10),
+            decode: ms(20),
+            tree: ms(5),
+            narinfo: ms(1),
+            bytes: 100,
+        };
+        let glibc_timings = IngestTimings {
+            fetch: ms(200),
+            decode: ms(300),
+            tree: ms(50),
+            narinfo: ms(2),
+            bytes: 5000,
+        };
+        summary.record_ingest(&hello, 100, hello_timings);
+        summary.record_ingest(&glibc, 5000, glibc_timings);
+
+        let totals = summary.phase_totals().unwrap();
+        assert_eq!(totals.fetch, ms(210));
+        assert_eq!(totals.narinfo, ms(3));
+        assert_eq!(totals.bytes, 5100);
+        assert_eq!(totals.total(), ms(588));
+        let slowest: Vec<_> = summary.slowest(1).iter().map(|p| p.path.clone()).collect();
+        assert_eq!(slowest, [glibc.get_path()]);
+
+        let text = summary.to_string();
+        assert!(text.contains("decode"), "{text}");
+        assert!(text.contains(&format!("slow: {} (552.0 ms)", glibc.get_path())));
+        let json = summary.to_json()?;
+        assert!(json.contains("\"fetch_ms\": 210.0"), "{json}");
+        assert!(json.contains("\"tree_ms\": 5.0"), "{json}");
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The kind of operation which changed the cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOperation {
+    /// Added from a Nix daemon
+    Add,
+    /// Imported from a NAR
+    Import,
+    /// Downloaded from an upstream binary cache
+    FetchUpstream,
+    /// Fetched from a git peer
+    FetchPeer,
+    /// Signed with the configured key
+    Sign,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Ok,
+    Failed,
+}
+
+/// One entry of the audit log, stored as the message of a commit on `refs/gachix/audit`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// UTC, formatted as `YYYY-MM-DDTHH:MM:SSZ`
+    pub timestamp: String,
+    pub operation: AuditOperation,
+    /// The hash part of the store path
+    pub package: String,
+    /// The user who ran the command
+    pub actor: String,
+    pub outcome: AuditOutcome,
+}
+
+impl AuditEntry {
+    pub fn new(
+        operation: AuditOperation,
+        package: &str,
+        actor: &str,
+        outcome: AuditOutcome,
+    ) -> Self {
+        Self {
+            timestamp: format_timestamp(SystemTime::now()),
+            operation,
+            package: package.to_string(),
+            actor: actor.to_string(),
+            outcome,
+        }
+    }
+
+    pub fn to_message(&self) -> String {
+        serde_json::to_string(self).expect("an audit entry can always be serialized")
+    }
+
+    pub fn parse(message: &str) -> Result<Self> {
+        Ok(serde_json::from_str(message.trim())?)
+    }
+}
+
+impl Display for AuditEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let operation = serde_json::to_value(self.operation).map_err(|_| std::fmt::Error)?;
+        let outcome = serde_json::to_value(self.outcome).map_err(|_| std::fmt::Error)?;
+        write!(
+            f,
+            "{} {:<15}{:<8}{} {}",
+            self.timestamp,
+            operation.as_str().unwrap_or_default(),
+            outcome.as_str().unwrap_or_default(),
+            self.package,
+            self.actor
+        )
+    }
+}
+
+/// Which entries `Store::audit_log` returns
+#[derive(Debug, Default, Clone)]
+pub struct AuditFilter {
+    /// Only entries at or after this timestamp, compared as `YYYY-MM-DDTHH:MM:SSZ`
+    pub since: Option<String>,
+    /// Only entries about the package with this hash
+    pub package: Option<String>,
+}
+
+impl AuditFilter {
+    pub fn matches(&self, entry: &AuditEntry) -> bool {
+        self.package
+            .as_ref()
+            .is_none_or(|package| *package == entry.package)
+    }
+
+    /// Entries are appended in order, so the walk from the newest entry stops at the first older one
+    pub fn is_before_since(&self, entry: &AuditEntry) -> bool {
+        self.since
+            .as_ref()
+            .is_some_and(|since| entry.timestamp.as_str() < since.as_str())
+    }
+}
+
+/// Parses the argument of `--since`: a UTC date or timestamp like `2025-10-01` or
+/// `2025-10-01T12:00:00Z`, or a period before `now` like `30m`, `12h` or `7d`
+pub fn parse_since(value: &str, now: SystemTime) -> Result<String> {
+    let invalid = || anyhow!("Invalid time '{}', expected e.g. 2025-10-01 or 7d", value);
+    if let Some(unit) = value.chars().last()
+        && let Ok(amount) = value[..value.len() - unit.len_utf8()].parse::<u64>()
+    {
+        let seconds = match unit {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        let start = now
+            .checked_sub(Duration::from_secs(amount * seconds))
+            .ok_or_else(invalid)?;
+        return Ok(format_timestamp(start));
+    }
+    let bytes = value.as_bytes();
+    let is_date = bytes.len() >= 10
+        && bytes[..10].iter().enumerate().all(|(i, b)| {
+            if i == 4 || i == 7 {
+                *b == b'-'
+            } else {
+                b.is_ascii_digit()
+            }
+        });
+    if !is_date {
+        return Err(invalid());
+    }
+    Ok(value.to_string())
+}
+
+/// Formats a time as `YYYY-MM-DDTHH:MM:SSZ`, which sorts like the time itself
+pub fn format_timestamp(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, seconds_of_day) = (seconds / 86400, seconds % 86400);
+    // Converts days since 1970-01-01 to a date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z / 146097;
+    let day_of_era = z - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamps() -> Result<()> {
+        let at = |seconds| UNIX_EPOCH + Duration::from_secs(seconds);
+        assert_eq!(format_timestamp(at(0)), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(at(951782400)), "2000-02-29T00:00:00Z");
+        assert_eq!(format_timestamp(at(1760620245)), "2025-10-16T13:10:45Z");
+
+        let now = at(1760620245);
+        assert_eq!(parse_since("2h", now)?, "2025-10-16T11:10:45Z");
+        assert_eq!(parse_since("7d", now)?, "2025-10-09T13:10:45Z");
+        assert_eq!(parse_since("2025-10-01", now)?, "2025-10-01");
+        assert!(parse_since("yesterday", now).is_err());
+        assert!(parse_since("7w", now).is_err());
+
+        let filter = AuditFilter {
+            since: Some("2025-10-16".to_string()),
+            package: None,
+        };
+        let mut entry = AuditEntry::new(AuditOperation::Add, "abc", "alice", AuditOutcome::Ok);
+        entry.timestamp = format_timestamp(now);
+        assert!(!filter.is_before_since(&entry));
+        entry.timestamp = format_timestamp(at(1760620245 - 86400));
+        assert!(filter.is_before_since(&entry));
+        assert_eq!(AuditEntry::parse(&entry.to_message())?, entry);
+        Ok(())
+    }
+}
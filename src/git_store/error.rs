@@ -0,0 +1,54 @@
+use crate::nix_interface::nar_info::ValidationIssue;
+use crate::{nar, nix_interface};
+use git2::ErrorCode;
+use std::io;
+use thiserror::Error;
+
+/// Errors of the package store and its Git repository
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Package {0} is not cached")]
+    PackageNotFound(String),
+    /// Another process holds a lock in the repository, e.g. on a reference
+    #[error("Repository is locked by another process: {0}")]
+    RepositoryLocked(git2::Error),
+    #[error("Authentication at Git remote {url} failed: {error}")]
+    RemoteAuthFailed { url: String, error: git2::Error },
+    /// A fresh signature does not verify against the narinfo it was written into
+    #[error("Signature of {0} does not verify against the signing key")]
+    SignatureSelfCheck(String),
+    #[error("Narinfo of {path} is invalid: {}", join_issues(.issues))]
+    InvalidNarInfo {
+        path: String,
+        issues: Vec<ValidationIssue>,
+    },
+    #[error(transparent)]
+    Nar(#[from] nar::Error),
+    #[error(transparent)]
+    Nix(#[from] nix_interface::Error),
+    #[error(transparent)]
+    Git(git2::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl From<git2::Error> for Error {
+    fn from(error: git2::Error) -> Self {
+        match error.code() {
+            ErrorCode::Locked => Self::RepositoryLocked(error),
+            _ => Self::Git(error),
+        }
+    }
+}
+
+fn join_issues(issues: &[ValidationIssue]) -> String {
+    issues
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
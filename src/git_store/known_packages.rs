@@ -0,0 +1,183 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::debug;
+
+/// The modification times of the directory holding the package references and of `packed-refs`.
+/// A package added or removed by another process changes one of them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Generation {
+    namespace: Option<SystemTime>,
+    packed_refs: Option<SystemTime>,
+}
+
+/// The ids of the packages with a result reference, so existence checks of known packages don't
+/// touch the repository. The set is loaded on first use rather than when the store is opened,
+/// as listing the references of a large repository takes a while. A miss is not conclusive,
+/// callers look the package up in the repository and `insert` it if it exists.
+///
+/// Packages are never removed by gachix itself, but the set is loaded again once the references
+/// were changed on disk, which is checked at most once per `ttl`.
+pub struct KnownPackages {
+    namespace_dir: PathBuf,
+    packed_refs: PathBuf,
+    ttl: Duration,
+    state: RwLock<State>,
+}
+
+#[derive(Default)]
+struct State {
+    // None until loaded, or if loading failed
+    ids: Option<HashSet<String>>,
+    generation: Option<Generation>,
+    checked_at: Option<Instant>,
+}
+
+impl State {
+    fn is_fresh(&self, ttl: Duration) -> bool {
+        self.checked_at
+            .is_some_and(|checked_at| checked_at.elapsed() < ttl)
+    }
+
+    fn contains(&self, package_id: &str) -> bool {
+        self.ids
+            .as_ref()
+            .is_some_and(|ids| ids.contains(package_id))
+    }
+}
+
+impl KnownPackages {
+    /// For the packages referenced below `ref_namespace` in the repository at `git_dir`
+    pub fn new(git_dir: &Path, ref_namespace: &str, ttl: Duration) -> Self {
+        Self {
+            namespace_dir: git_dir.join(ref_namespace),
+            packed_refs: git_dir.join("packed-refs"),
+            ttl,
+            state: RwLock::default(),
+        }
+    }
+
+    fn generation(&self) -> Generation {
+        let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+        Generation {
+            namespace: modified(&self.namespace_dir),
+            packed_refs: modified(&self.packed_refs),
+        }
+    }
+
+    /// Whether the package is known to exist. `load` lists the ids of all packages, it is called
+    /// on first use and after the references changed on disk. A failed listing is tried again
+    /// with the next check.
+    pub fn contains<E: std::fmt::Display>(
+        &self,
+        package_id: &str,
+        load: impl FnOnce() -> Result<Vec<String>, E>,
+    ) -> bool {
+        {
+            let state = self.state.read().unwrap();
+            if state.is_fresh(self.ttl) {
+                return state.contains(package_id);
+            }
+        }
+        let mut state = self.state.write().unwrap();
+        // Another thread may have checked while this one waited for the lock
+        if !state.is_fresh(self.ttl) {
+            // Read before listing, so a change made meanwhile is noticed with the next check
+            let generation = self.generation();
+            if state.ids.is_none() || state.generation != Some(generation) {
+                state.ids = match load() {
+                    Ok(ids) => Some(ids.into_iter().collect()),
+                    Err(e) => {
+                        debug!("Could not list the packages: {}", e);
+                        None
+                    }
+                };
+                state.generation = Some(generation);
+            }
+            state.checked_at = Some(Instant::now());
+        }
+        state.contains(package_id)
+    }
+
+    /// Remembers a package which was found in the repository
+    pub fn insert(&self, package_id: &str) {
+        if let Some(ids) = &mut self.state.write().unwrap().ids {
+            ids.insert(package_id.to_string());
+        }
+    }
+
+    /// Remembers a package which this process added. Its references may have changed the
+    /// generation, which is not a reason to load the set again.
+    pub fn added(&self, package_id: &str) {
+        let generation = self.generation();
+        let mut state = self.state.write().unwrap();
+        let state = &mut *state;
+        if let Some(ids) = &mut state.ids {
+            ids.insert(package_id.to_string());
+            state.generation = Some(generation);
+        }
+    }
+
+    /// Drops a package whose references were changed, it is looked up in the repository again
+    pub fn forget(&self, package_id: &str) {
+        if let Some(ids) = &mut self.state.write().unwrap().ids {
+            ids.remove(package_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_known_packages() -> anyhow::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let namespace_dir = temp_dir.path().join("refs");
+        fs::create_dir(&namespace_dir)?;
+        let known = KnownPackages::new(temp_dir.path(), "refs", Duration::ZERO);
+        let loaded = Cell::new(0);
+        let load = |ids: &[&str]| {
+            loaded.set(loaded.get() + 1);
+            Ok::<_, String>(ids.iter().map(|id| id.to_string()).collect())
+        };
+
+        // loaded once, until the references change on disk
+        assert!(known.contains("a", || load(&["a"])));
+        assert!(!known.contains("b", || load(&["a"])));
+        assert_eq!(loaded.get(), 1);
+        known.insert("b");
+        assert!(known.contains("b", || load(&[])));
+        assert_eq!(loaded.get(), 1);
+
+        // e.g. another process packed the references
+        let packed_refs = temp_dir.path().join("packed-refs");
+        fs::write(&packed_refs, "")?;
+        assert!(!known.contains("a", || load(&["c"])));
+        assert!(known.contains("c", || load(&[])));
+        assert_eq!(loaded.get(), 2);
+
+        // the references written by this process for a package it added
+        fs::create_dir(namespace_dir.join("d"))?;
+        known.added("d");
+        assert!(known.contains("d", || load(&[])));
+        known.forget("d");
+        assert!(!known.contains("d", || load(&[])));
+        assert_eq!(loaded.get(), 2);
+
+        // a failed listing is tried again with the next check
+        let fails = KnownPackages::new(temp_dir.path(), "refs", Duration::ZERO);
+        assert!(!fails.contains("a", || Err("corrupt packed-refs")));
+        fails.insert("a");
+        assert!(fails.contains("a", || load(&["a"])));
+        assert_eq!(loaded.get(), 3);
+        let later = KnownPackages::new(temp_dir.path(), "refs", Duration::from_secs(60));
+        assert!(!later.contains("a", || Err("corrupt packed-refs")));
+        assert!(!later.contains("a", || load(&["a"])));
+        assert_eq!(loaded.get(), 3);
+        Ok(())
+    }
+}
@@ -1,5 +1,20 @@
+pub mod add_summary;
+pub mod audit;
+pub mod error;
+pub(crate) mod known_packages;
+pub mod name_index;
+pub mod narinfo_cache;
+pub(crate) mod options;
+pub mod package_diff;
+pub(crate) mod package_locks;
+pub(crate) mod ref_snapshot;
+pub mod repo_pool;
 pub mod repository;
+pub mod tar_export;
+pub use error::Error;
 pub use repository::GitRepo;
 pub mod store;
 
 const SINGLE_FILE_PACKAGE_MARKER: &str = "gachix-single-file";
+// Namespace for references which hold bookkeeping data instead of packages
+const METADATA_REF_PREFIX: &str = "refs/gachix";
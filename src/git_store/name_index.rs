@@ -0,0 +1,93 @@
+use anyhow::{Result, anyhow};
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Maps package names (e.g. `openssl-3.5.2`) to the hashes of all cached packages with that name.
+/// Serialized as one `name hash` pair per line, sorted by name and hash.
+#[derive(Debug, Default, PartialEq)]
+pub struct NameIndex {
+    entries: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl NameIndex {
+    pub fn parse(content: &[u8]) -> Result<Self> {
+        let mut index = Self::default();
+        for line in String::from_utf8_lossy(content).lines() {
+            let (name, hash) = line
+                .split_once(' ')
+                .ok_or_else(|| anyhow!("Invalid name index line: '{}'", line))?;
+            index.insert(name, hash);
+        }
+        Ok(index)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut content = String::new();
+        for (name, hashes) in &self.entries {
+            for hash in hashes {
+                content.push_str(&format!("{name} {hash}\n"));
+            }
+        }
+        content.into_bytes()
+    }
+
+    pub fn insert(&mut self, name: &str, hash: &str) {
+        self.entries
+            .entry(name.to_string())
+            .or_default()
+            .insert(hash.to_string());
+    }
+
+    #[allow(dead_code)]
+    pub fn remove(&mut self, name: &str, hash: &str) {
+        if let Some(hashes) = self.entries.get_mut(name) {
+            hashes.remove(hash);
+            if hashes.is_empty() {
+                self.entries.remove(name);
+            }
+        }
+    }
+
+    /// Returns the `(name, hash)` pairs of all packages whose name matches `name_regex`
+    pub fn search(&self, name_regex: &Regex) -> Vec<(String, String)> {
+        self.entries
+            .iter()
+            .filter(|(name, _)| name_regex.is_match(name))
+            .flat_map(|(name, hashes)| hashes.iter().map(|h| (name.clone(), h.clone())))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.values().map(|hashes| hashes.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip_and_search() -> Result<()> {
+        let mut index = NameIndex::default();
+        index.insert("openssl-3.5.2", "hxmkygn2zl0f2w9kbixmm50lsy60zya0");
+        index.insert("openssl-3.0.14", "gf6j3k1flnhayvpnwnhikkg0s5dxrn1i");
+        index.insert("zlib-1.3.1", "5vnba43n1w87cs2i2dd242zy88k4dwf9");
+        index.insert("zlib-1.3.1", "5vnba43n1w87cs2i2dd242zy88k4dwf9");
+
+        let parsed = NameIndex::parse(&index.serialize())?;
+        assert_eq!(parsed, index);
+        assert_eq!(parsed.len(), 3);
+
+        let openssl = parsed.search(&Regex::new("^openssl-")?);
+        assert_eq!(openssl.len(), 2);
+        assert_eq!(openssl[0].0, "openssl-3.0.14");
+
+        index.remove("zlib-1.3.1", "5vnba43n1w87cs2i2dd242zy88k4dwf9");
+        assert!(index.search(&Regex::new("zlib")?).is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,211 @@
+use bytes::Bytes;
+use git2::Oid;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::settings::NarinfoCacheSettings;
+
+/// How the narinfo cache did since the store was opened
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct NarinfoCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+impl NarinfoCacheStats {
+    /// The share of lookups answered from memory, 0 if there were none
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            lookups => self.hits as f64 / lookups as f64,
+        }
+    }
+}
+
+/// The most recently served narinfos, so hot ones are answered without reading the repository.
+/// An entry is only used while the narinfo reference still points to the blob it was read from,
+/// and writers `invalidate` the packages they change.
+pub struct NarinfoCache {
+    lru: Mutex<Lru>,
+    settings: NarinfoCacheSettings,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<String, Entry>,
+    // The package ids by the tick of their last use, the least recently used first
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    bytes: usize,
+}
+
+struct Entry {
+    oid: Oid,
+    narinfo: Bytes,
+    last_used: u64,
+}
+
+impl Lru {
+    fn remove(&mut self, package_id: &str) -> Option<Entry> {
+        let entry = self.entries.remove(package_id)?;
+        self.recency.remove(&entry.last_used);
+        self.bytes -= entry.narinfo.len();
+        Some(entry)
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+impl NarinfoCache {
+    pub fn new(settings: NarinfoCacheSettings) -> Self {
+        Self {
+            lru: Mutex::default(),
+            settings,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.settings.max_entries > 0
+    }
+
+    /// The narinfo of `package_id` if it is cached as the blob `oid`
+    pub fn get(&self, package_id: &str, oid: Oid) -> Option<Bytes> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let mut lru = self.lru.lock().unwrap();
+        let found = match lru.entries.get(package_id).map(|entry| entry.oid == oid) {
+            Some(true) => {
+                let tick = lru.next_tick();
+                let entry = lru.entries.get_mut(package_id).unwrap();
+                let last_used = std::mem::replace(&mut entry.last_used, tick);
+                let narinfo = entry.narinfo.clone();
+                lru.recency.remove(&last_used);
+                lru.recency.insert(tick, package_id.to_string());
+                Some(narinfo)
+            }
+            // Changed by another process, whose writes are not invalidated
+            Some(false) => {
+                lru.remove(package_id);
+                None
+            }
+            None => None,
+        };
+        let counter = match found {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    /// Remembers the narinfo of `package_id` read from the blob `oid`, dropping the least recently
+    /// used ones until the cache is within its limits again
+    pub fn insert(&self, package_id: &str, oid: Oid, narinfo: Bytes) {
+        if !self.is_enabled() || narinfo.len() > self.settings.max_bytes {
+            return;
+        }
+        let mut lru = self.lru.lock().unwrap();
+        lru.remove(package_id);
+        let tick = lru.next_tick();
+        lru.bytes += narinfo.len();
+        lru.recency.insert(tick, package_id.to_string());
+        lru.entries.insert(
+            package_id.to_string(),
+            Entry {
+                oid,
+                narinfo,
+                last_used: tick,
+            },
+        );
+        while lru.entries.len() > self.settings.max_entries || lru.bytes > self.settings.max_bytes {
+            let Some((_, oldest)) = lru.recency.pop_first() else {
+                break;
+            };
+            // Removed from `recency` already, so only the entry is left to drop
+            if let Some(entry) = lru.entries.remove(&oldest) {
+                lru.bytes -= entry.narinfo.len();
+            }
+        }
+    }
+
+    /// Drops the narinfo of a package whose references were changed
+    pub fn invalidate(&self, package_id: &str) {
+        self.lru.lock().unwrap().remove(package_id);
+    }
+
+    pub fn stats(&self) -> NarinfoCacheStats {
+        let lru = self.lru.lock().unwrap();
+        NarinfoCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: lru.entries.len(),
+            bytes: lru.bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oid(byte: u8) -> Oid {
+        Oid::from_bytes(&[byte; 20]).unwrap()
+    }
+
+    #[test]
+    fn test_narinfo_cache() {
+        let cache = NarinfoCache::new(NarinfoCacheSettings {
+            max_entries: 2,
+            max_bytes: 9,
+        });
+        assert_eq!(cache.get("a", oid(1)), None);
+        cache.insert("a", oid(1), Bytes::from_static(b"aaaa"));
+        cache.insert("b", oid(2), Bytes::from_static(b"bbbb"));
+        assert_eq!(cache.get("a", oid(1)), Some(Bytes::from_static(b"aaaa")));
+
+        // "b" is the least recently used
+        cache.insert("c", oid(3), Bytes::from_static(b"cc"));
+        assert_eq!(cache.get("b", oid(2)), None);
+        assert!(cache.get("c", oid(3)).is_some());
+
+        // over the byte budget
+        cache.insert("d", oid(4), Bytes::from_static(b"dddddddd"));
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes), (1, 8));
+        assert_eq!(cache.get("a", oid(1)), None);
+        // larger than the whole budget
+        cache.insert("e", oid(5), Bytes::from_static(b"eeeeeeeeeee"));
+        assert_eq!(cache.get("e", oid(5)), None);
+
+        // a narinfo which was rewritten is read again
+        assert_eq!(cache.get("d", oid(6)), None);
+        assert_eq!(cache.stats().entries, 0);
+        cache.insert("d", oid(6), Bytes::from_static(b"d"));
+        cache.invalidate("d");
+        assert_eq!(cache.get("d", oid(6)), None);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 6));
+        assert_eq!(stats.hit_rate(), 0.25);
+
+        let disabled = NarinfoCache::new(NarinfoCacheSettings {
+            max_entries: 0,
+            ..NarinfoCacheSettings::default()
+        });
+        disabled.insert("a", oid(1), Bytes::from_static(b"a"));
+        assert_eq!(disabled.get("a", oid(1)), None);
+        assert_eq!(disabled.stats(), NarinfoCacheStats::default());
+    }
+}
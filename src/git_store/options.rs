@@ -0,0 +1,144 @@
+use std::fs;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use tracing::info;
+
+use super::error::Result;
+use crate::nar::compression::Compression;
+use crate::nix_interface::path::{StoreConfig, init_store_dir};
+use crate::nix_interface::signature::{PrivateKey, PublicKey};
+use crate::nix_interface::signer::{CommandSigner, NarSigner};
+use crate::settings;
+
+/// The settings which shape the content of a store, checked and converted once when it is opened
+#[derive(Clone)]
+pub(crate) struct StoreOptions {
+    /// Each package is signed by all of them
+    pub signers: Vec<Arc<dyn NarSigner>>,
+    /// Packages of upstream caches need a signature by one of them, unless there are none
+    pub trusted_keys: Vec<PublicKey>,
+    /// The narinfos are validated against it
+    pub store_config: StoreConfig,
+    /// The references of a package are `<ref_namespace>/<hash>/...`
+    pub ref_namespace: String,
+    pub max_closure_depth: usize,
+    /// Of the stored `.ls` listings
+    pub listing_compression: Compression,
+    /// The peers packages are fetched from, in the order they are tried
+    pub remotes: Vec<settings::RemoteConfig>,
+}
+
+impl StoreOptions {
+    pub fn new(settings: &settings::Store) -> Result<Self> {
+        let mut signers: Vec<Arc<dyn NarSigner>> = Vec::new();
+        for key_path in settings.all_sign_key_paths() {
+            let key = PrivateKey::from_file(key_path)?;
+            info!(
+                "Using private key located at: {:?}",
+                fs::canonicalize(key_path)?
+            );
+            signers.push(Arc::new(key));
+        }
+        if let Some(signer) = &settings.signer {
+            if !signers.is_empty() {
+                return Err(anyhow!(
+                    "Only one of store.sign_key_paths and store.signer can be set"
+                )
+                .into());
+            }
+            let public_key = PublicKey::from_str(&signer.public_key)?;
+            info!(
+                "Signing as {} with `{}`",
+                public_key.name,
+                signer.command.join(" ")
+            );
+            signers.push(Arc::new(CommandSigner::new(
+                signer.command.clone(),
+                public_key,
+                signer.timeout(),
+            )?));
+        }
+
+        let mut trusted_keys = settings.trusted_keys().map_err(anyhow::Error::from)?;
+        if !trusted_keys.is_empty() {
+            trusted_keys.extend(signers.iter().map(|signer| signer.public_key()));
+        }
+
+        let store_config = match &settings.store_dir {
+            Some(store_dir) => {
+                init_store_dir(store_dir)?;
+                StoreConfig {
+                    store_dir: store_dir.trim_end_matches('/').to_string(),
+                }
+            }
+            None => StoreConfig::default(),
+        };
+        settings
+            .check_ref_namespace()
+            .map_err(anyhow::Error::from)?;
+        if settings.max_closure_depth == 0 {
+            return Err(anyhow!("store.max_closure_depth must not be 0").into());
+        }
+
+        Ok(Self {
+            signers,
+            trusted_keys,
+            store_config,
+            ref_namespace: settings.ref_namespace.clone(),
+            max_closure_depth: settings.max_closure_depth,
+            listing_compression: settings.compression.clone(),
+            remotes: settings.remotes_by_priority(),
+        })
+    }
+
+    /// Whether packages of upstream caches need a trusted signature
+    pub fn requires_trusted_signature(&self) -> bool {
+        !self.trusted_keys.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_store_options() -> Result<()> {
+        let mut settings = settings::Store::new(PathBuf::from("cache"));
+        let options = StoreOptions::new(&settings)?;
+        assert!(options.signers.is_empty());
+        assert!(!options.requires_trusted_signature());
+        assert_eq!(options.ref_namespace, "refs");
+        assert_eq!(options.listing_compression, Compression::Zstd);
+
+        let temp_dir = tempfile::TempDir::new()?;
+        let key_path = temp_dir.path().join("secret-key");
+        let key = PrivateKey::generate("cache.example.org-1")?;
+        fs::write(&key_path, key.secret_key_string().as_bytes())?;
+        settings.sign_key_paths = vec![key_path.clone(), key_path];
+        settings.trusted_public_keys =
+            vec!["cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=".to_string()];
+        let options = StoreOptions::new(&settings)?;
+        assert_eq!(options.signers.len(), 2);
+        // the keys gachix signs with are trusted as well
+        let names: Vec<&str> = options
+            .trusted_keys
+            .iter()
+            .map(|key| key.name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "cache.nixos.org-1",
+                "cache.example.org-1",
+                "cache.example.org-1"
+            ]
+        );
+
+        settings.ref_namespace = "refs/gachix".to_string();
+        assert!(StoreOptions::new(&settings).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,365 @@
+use crate::nar::chunked::read_chunked_file;
+use crate::nar::entry::EntryKind;
+use anyhow::Result;
+use git2::{Oid, Patch, Repository, Tree};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::Path;
+
+// Content diffs are only shown for text files up to this size
+const PATCH_SIZE_LIMIT: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+impl ChangeKind {
+    fn marker(&self) -> char {
+        match self {
+            ChangeKind::Added => 'A',
+            ChangeKind::Removed => 'D',
+            ChangeKind::Modified => 'M',
+        }
+    }
+}
+
+/// A file or symlink which differs between two packages.
+/// The size of a symlink is the length of its target.
+#[derive(Debug, Clone, Serialize)]
+pub struct FileChange {
+    pub path: String,
+    pub change: ChangeKind,
+    pub old_size: Option<u64>,
+    pub new_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub patch: Option<String>,
+}
+
+impl FileChange {
+    fn size_delta(&self) -> i64 {
+        self.new_size.unwrap_or(0) as i64 - self.old_size.unwrap_or(0) as i64
+    }
+}
+
+/// The file-level differences between two packages, see `Store::diff_packages`
+#[derive(Debug, Clone, Serialize)]
+pub struct PackageDiff {
+    pub old: String,
+    pub new: String,
+    pub changes: Vec<FileChange>,
+}
+
+impl PackageDiff {
+    pub fn count(&self, change: ChangeKind) -> usize {
+        self.changes.iter().filter(|c| c.change == change).count()
+    }
+
+    /// By how many bytes the files of the new package are larger than those of the old one
+    pub fn size_delta(&self) -> i64 {
+        self.changes.iter().map(FileChange::size_delta).sum()
+    }
+
+    pub fn stat(&self) -> String {
+        format!(
+            "{} added, {} removed, {} modified, {:+} bytes",
+            self.count(ChangeKind::Added),
+            self.count(ChangeKind::Removed),
+            self.count(ChangeKind::Modified),
+            self.size_delta()
+        )
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        #[derive(Serialize)]
+        struct JsonDiff<'a> {
+            #[serde(flatten)]
+            diff: &'a PackageDiff,
+            size_delta: i64,
+        }
+        serde_json::to_string_pretty(&JsonDiff {
+            diff: self,
+            size_delta: self.size_delta(),
+        })
+    }
+}
+
+impl Display for PackageDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for change in &self.changes {
+            let sizes = match (change.old_size, change.new_size) {
+                (Some(old), Some(new)) => format!("{old} -> {new} bytes"),
+                (old, new) => format!("{} bytes", old.or(new).unwrap_or(0)),
+            };
+            writeln!(f, "{} {} ({})", change.change.marker(), change.path, sizes)?;
+            if let Some(patch) = &change.patch {
+                write!(f, "{patch}")?;
+            }
+        }
+        writeln!(f, "{}", self.stat())
+    }
+}
+
+/// How an entry is compared, chunked files count as files rather than directories
+enum Node<'r> {
+    Directory(Tree<'r>),
+    File { size: u64, blob: Option<Oid> },
+    Symlink { size: u64 },
+}
+
+impl Node<'_> {
+    fn size(&self) -> u64 {
+        match self {
+            Node::Directory(_) => 0,
+            Node::File { size, .. } | Node::Symlink { size } => *size,
+        }
+    }
+}
+
+/// Lists the files which differ between the trees of two packages.
+/// With `patch`, modified text files up to a small size also get a unified diff.
+pub fn diff_entries(
+    repo: &Repository,
+    old: (Oid, i32),
+    new: (Oid, i32),
+    patch: bool,
+) -> Result<Vec<FileChange>> {
+    let mut differ = Differ {
+        repo,
+        patch,
+        changes: Vec::new(),
+    };
+    differ.diff(Some(old), Some(new), "")?;
+    Ok(differ.changes)
+}
+
+struct Differ<'r> {
+    repo: &'r Repository,
+    patch: bool,
+    changes: Vec<FileChange>,
+}
+
+impl<'r> Differ<'r> {
+    fn node(&self, (oid, filemode): (Oid, i32)) -> Result<Node<'r>> {
+        Ok(match EntryKind::from_filemode(filemode)? {
+            EntryKind::Directory => {
+                let tree = self.repo.find_tree(oid)?;
+                match read_chunked_file(self.repo, &tree)? {
+                    Some((manifest, _)) => Node::File {
+                        size: manifest.size,
+                        blob: None,
+                    },
+                    None => Node::Directory(tree),
+                }
+            }
+            EntryKind::Regular { .. } => Node::File {
+                size: self.repo.odb()?.read_header(oid)?.0 as u64,
+                blob: Some(oid),
+            },
+            EntryKind::Symlink => Node::Symlink {
+                size: self.repo.odb()?.read_header(oid)?.0 as u64,
+            },
+        })
+    }
+
+    fn diff(&mut self, old: Option<(Oid, i32)>, new: Option<(Oid, i32)>, path: &str) -> Result<()> {
+        // Identical subtrees have identical oids, so unchanged parts are not walked
+        if old == new {
+            return Ok(());
+        }
+        let old_node = old.map(|entry| self.node(entry)).transpose()?;
+        let new_node = new.map(|entry| self.node(entry)).transpose()?;
+        match (old_node, new_node) {
+            (Some(Node::Directory(old_tree)), Some(Node::Directory(new_tree))) => {
+                let mut entries: BTreeMap<Vec<u8>, (Option<_>, Option<_>)> = BTreeMap::new();
+                for entry in old_tree.iter() {
+                    entries.entry(entry.name_bytes().to_vec()).or_default().0 =
+                        Some((entry.id(), entry.filemode()));
+                }
+                for entry in new_tree.iter() {
+                    entries.entry(entry.name_bytes().to_vec()).or_default().1 =
+                        Some((entry.id(), entry.filemode()));
+                }
+                for (name, (old, new)) in entries {
+                    let path = format!("{}/{}", path, String::from_utf8_lossy(&name));
+                    self.diff(old, new, &path)?;
+                }
+            }
+            // A directory replaced by a file or the other way around
+            (Some(Node::Directory(_)), new_node @ Some(_)) => {
+                self.diff(old, None, path)?;
+                self.record_leaf(path, None, new_node)?;
+            }
+            (old_node @ Some(_), Some(Node::Directory(_))) => {
+                self.record_leaf(path, old_node, None)?;
+                self.diff(None, new, path)?;
+            }
+            (Some(Node::Directory(tree)), None) => {
+                for entry in tree.iter() {
+                    let path = format!("{}/{}", path, String::from_utf8_lossy(entry.name_bytes()));
+                    self.diff(Some((entry.id(), entry.filemode())), None, &path)?;
+                }
+            }
+            (None, Some(Node::Directory(tree))) => {
+                for entry in tree.iter() {
+                    let path = format!("{}/{}", path, String::from_utf8_lossy(entry.name_bytes()));
+                    self.diff(None, Some((entry.id(), entry.filemode())), &path)?;
+                }
+            }
+            (old_node, new_node) => self.record_leaf(path, old_node, new_node)?,
+        }
+        Ok(())
+    }
+
+    fn record_leaf(&mut self, path: &str, old: Option<Node>, new: Option<Node>) -> Result<()> {
+        let change = match (&old, &new) {
+            (Some(_), Some(_)) => ChangeKind::Modified,
+            (None, Some(_)) => ChangeKind::Added,
+            (Some(_), None) => ChangeKind::Removed,
+            (None, None) => return Ok(()),
+        };
+        let patch = match (&old, &new) {
+            (
+                Some(Node::File {
+                    blob: Some(old), ..
+                }),
+                Some(Node::File {
+                    blob: Some(new), ..
+                }),
+            ) if self.patch => self.text_patch(*old, *new, path)?,
+            _ => None,
+        };
+        self.changes.push(FileChange {
+            path: if path.is_empty() { "/" } else { path }.to_string(),
+            change,
+            old_size: old.map(|node| node.size()),
+            new_size: new.map(|node| node.size()),
+            patch,
+        });
+        Ok(())
+    }
+
+    fn text_patch(&self, old: Oid, new: Oid, path: &str) -> Result<Option<String>> {
+        let old = self.repo.find_blob(old)?;
+        let new = self.repo.find_blob(new)?;
+        let small = |size: usize| size as u64 <= PATCH_SIZE_LIMIT;
+        if !small(old.size()) || !small(new.size()) || old.is_binary() || new.is_binary() {
+            return Ok(None);
+        }
+        let path = path.trim_start_matches('/');
+        let path = Path::new(path);
+        let mut patch = Patch::from_blobs(&old, Some(path), &new, Some(path), None)?;
+        let buf = patch.to_buf()?;
+        Ok(Some(String::from_utf8_lossy(&buf).into_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::FileMode;
+    use tempfile::TempDir;
+
+    fn tree(repo: &Repository, files: &[(&str, &[u8], FileMode)]) -> Result<Oid> {
+        let mut builder = repo.treebuilder(None)?;
+        for (name, content, mode) in files {
+            builder.insert(name, repo.blob(content)?, (*mode).into())?;
+        }
+        Ok(builder.write()?)
+    }
+
+    #[test]
+    fn test_diff_entries() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = Repository::init(temp_dir.path().join("repo"))?;
+        let old_lib = tree(&repo, &[("libfoo.so", b"old library", FileMode::Blob)])?;
+        let new_lib = tree(
+            &repo,
+            &[("libfoo.so", b"new, larger library", FileMode::Blob)],
+        )?;
+        let share = tree(&repo, &[("doc", b"unchanged", FileMode::Blob)])?;
+
+        let mut old = repo.treebuilder(None)?;
+        old.insert("lib", old_lib, FileMode::Tree.into())?;
+        old.insert("share", share, FileMode::Tree.into())?;
+        old.insert(
+            "README",
+            repo.blob(b"line 1\nline 2\n")?,
+            FileMode::Blob.into(),
+        )?;
+        old.insert("removed", repo.blob(b"gone")?, FileMode::Blob.into())?;
+        let old = old.write()?;
+        let mut new = repo.treebuilder(None)?;
+        new.insert("lib", new_lib, FileMode::Tree.into())?;
+        new.insert("share", share, FileMode::Tree.into())?;
+        new.insert(
+            "README",
+            repo.blob(b"line 1\nline two\n")?,
+            FileMode::Blob.into(),
+        )?;
+        new.insert("link", repo.blob(b"README")?, FileMode::Link.into())?;
+        let new = new.write()?;
+
+        let tree_mode = i32::from(FileMode::Tree);
+        let changes = diff_entries(&repo, (old, tree_mode), (new, tree_mode), true)?;
+        let summary: Vec<_> = changes
+            .iter()
+            .map(|c| (c.path.as_str(), c.change, c.old_size, c.new_size))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("/README", ChangeKind::Modified, Some(14), Some(16)),
+                ("/lib/libfoo.so", ChangeKind::Modified, Some(11), Some(19)),
+                ("/link", ChangeKind::Added, None, Some(6)),
+                ("/removed", ChangeKind::Removed, Some(4), None),
+            ]
+        );
+        let patch = changes[0].patch.as_ref().unwrap();
+        assert!(patch.contains("-line 2\n+line two\n"), "{patch}");
+
+        let diff = PackageDiff {
+            old: "old".to_string(),
+            new: "new".to_string(),
+            changes,
+        };
+        assert_eq!(diff.size_delta(), 2 + 8 + 6 - 4);
+        assert_eq!(diff.stat(), "1 added, 1 removed, 2 modified, +12 bytes");
+        assert!(diff_entries(&repo, (old, tree_mode), (old, tree_mode), true)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_directory_replaced_by_file() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = Repository::init(temp_dir.path().join("repo"))?;
+        let dir = tree(
+            &repo,
+            &[("a", b"1", FileMode::Blob), ("b", b"22", FileMode::Blob)],
+        )?;
+        let mut old = repo.treebuilder(None)?;
+        old.insert("bin", dir, FileMode::Tree.into())?;
+        let old = old.write()?;
+        let new = tree(&repo, &[("bin", b"script", FileMode::BlobExecutable)])?;
+
+        let tree_mode = i32::from(FileMode::Tree);
+        let changes = diff_entries(&repo, (old, tree_mode), (new, tree_mode), false)?;
+        let summary: Vec<_> = changes
+            .iter()
+            .map(|c| (c.path.as_str(), c.change))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("/bin/a", ChangeKind::Removed),
+                ("/bin/b", ChangeKind::Removed),
+                ("/bin", ChangeKind::Added),
+            ]
+        );
+        Ok(())
+    }
+}
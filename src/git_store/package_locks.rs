@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::{Mutex as PackageMutex, OwnedMutexGuard};
+use tracing::{Instrument, trace_span};
+
+const NUM_SHARDS: usize = 16;
+
+/// Serializes changes to the same package within a process, while packages with
+/// different hashes are added concurrently. Locks of a package's dependencies may be
+/// taken while holding its own lock, but never the other way around, so closures
+/// being added concurrently can't deadlock.
+pub struct PackageLocks {
+    // Spread over shards so that looking up a lock rarely waits for another lookup
+    shards: Vec<Mutex<HashMap<String, Weak<PackageMutex<()>>>>>,
+}
+
+pub type PackageGuard = OwnedMutexGuard<()>;
+
+impl Default for PackageLocks {
+    fn default() -> Self {
+        Self {
+            shards: (0..NUM_SHARDS).map(|_| Mutex::default()).collect(),
+        }
+    }
+}
+
+impl PackageLocks {
+    fn mutex(&self, package_id: &str) -> Arc<PackageMutex<()>> {
+        let mut hasher = DefaultHasher::new();
+        package_id.hash(&mut hasher);
+        let mut shard = self.shards[hasher.finish() as usize % NUM_SHARDS]
+            .lock()
+            .unwrap();
+        if let Some(mutex) = shard.get(package_id).and_then(Weak::upgrade) {
+            return mutex;
+        }
+        // Only packages which are being changed have a lock
+        shard.retain(|_, mutex| mutex.strong_count() > 0);
+        let mutex = Arc::new(PackageMutex::new(()));
+        shard.insert(package_id.to_string(), Arc::downgrade(&mutex));
+        mutex
+    }
+
+    pub async fn lock(&self, package_id: &str) -> PackageGuard {
+        self.mutex(package_id)
+            .lock_owned()
+            .instrument(trace_span!(
+                "wait_for_package_lock",
+                package_hash = package_id
+            ))
+            .await
+    }
+
+    /// Like `lock`, for callers outside of an async runtime
+    pub fn blocking_lock(&self, package_id: &str) -> PackageGuard {
+        let _span = trace_span!("wait_for_package_lock", package_hash = package_id).entered();
+        self.mutex(package_id).blocking_lock_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_package_locks() {
+        let locks = Arc::new(PackageLocks::default());
+        let guard = locks.lock("a").await;
+        // other packages are not blocked
+        drop(locks.lock("b").await);
+
+        let waiting = tokio::spawn({
+            let locks = locks.clone();
+            async move { drop(locks.lock("a").await) }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+        drop(guard);
+        waiting.await.unwrap();
+
+        // unused locks are dropped
+        drop(locks.lock("c").await);
+        let entries: usize = locks
+            .shards
+            .iter()
+            .map(|shard| {
+                let shard = shard.lock().unwrap();
+                shard.values().filter(|m| m.strong_count() > 0).count()
+            })
+            .sum();
+        assert_eq!(entries, 0);
+    }
+}
@@ -0,0 +1,106 @@
+use git2::Oid;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+// References may also be moved by other processes sharing the repository, e.g. `gachix add`
+// next to a running server, so entries are resolved again after this long
+pub const DEFAULT_TTL: Duration = Duration::from_secs(5);
+
+/// What the references of a package point to
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PackageRefs {
+    pub narinfo: Option<Oid>,
+    pub result: Option<Oid>,
+}
+
+impl PackageRefs {
+    fn is_empty(&self) -> bool {
+        self.narinfo.is_none() && self.result.is_none()
+    }
+}
+
+/// An in-process copy of the package references, so requests resolve them without locking the repository.
+/// Writers `forget` the packages they change. Packages without references are not remembered,
+/// so a package added by another process is visible right away.
+pub struct RefSnapshot {
+    entries: RwLock<HashMap<String, (PackageRefs, Instant)>>,
+    ttl: Duration,
+}
+
+impl RefSnapshot {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entries: RwLock::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    /// Returns the references of a package, calling `resolve` if they are unknown or too old
+    pub fn get(&self, package_id: &str, resolve: impl FnOnce() -> PackageRefs) -> PackageRefs {
+        if let Some((refs, resolved_at)) = self.entries.read().unwrap().get(package_id)
+            && resolved_at.elapsed() < self.ttl
+        {
+            return *refs;
+        }
+        let refs = resolve();
+        let mut entries = self.entries.write().unwrap();
+        if refs.is_empty() {
+            entries.remove(package_id);
+        } else {
+            entries.insert(package_id.to_string(), (refs, Instant::now()));
+        }
+        refs
+    }
+
+    pub fn forget(&self, package_id: &str) {
+        self.entries.write().unwrap().remove(package_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_ref_snapshot() {
+        let snapshot = RefSnapshot::new(Duration::from_secs(60));
+        let resolved = Cell::new(0);
+        let refs = PackageRefs {
+            narinfo: Some(Oid::from_bytes(&[1; 20]).unwrap()),
+            result: Some(Oid::from_bytes(&[2; 20]).unwrap()),
+        };
+        let resolve = |refs| {
+            resolved.set(resolved.get() + 1);
+            refs
+        };
+
+        // missing packages are looked up every time
+        assert!(
+            snapshot
+                .get("a", || resolve(PackageRefs::default()))
+                .is_empty()
+        );
+        assert_eq!(snapshot.get("a", || resolve(refs)), refs);
+        assert_eq!(snapshot.get("a", || resolve(PackageRefs::default())), refs);
+        assert_eq!(resolved.get(), 2);
+
+        snapshot.forget("a");
+        assert!(
+            snapshot
+                .get("a", || resolve(PackageRefs::default()))
+                .is_empty()
+        );
+        assert_eq!(resolved.get(), 3);
+
+        let expired = RefSnapshot::new(Duration::ZERO);
+        expired.get("a", || resolve(refs));
+        assert!(
+            expired
+                .get("a", || resolve(PackageRefs::default()))
+                .is_empty()
+        );
+        assert_eq!(resolved.get(), 5);
+    }
+}
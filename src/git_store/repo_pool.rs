@@ -0,0 +1,153 @@
+use git2::Repository;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tracing::trace_span;
+
+/// Handles on one repository. A `Repository` may be moved between threads but not used from
+/// several at once, so every reader checks out a handle of its own and returns it when done.
+/// The object database and the references live on disk, so all handles see the same data.
+/// Writes of this process go through one handle behind a mutex, so they only contend with other
+/// processes on the reference locks.
+pub struct RepoPool {
+    path: PathBuf,
+    // The lock is only held to take or return a handle, never while one is used
+    idle: Mutex<Vec<Repository>>,
+    max_idle: usize,
+    writer: Mutex<Repository>,
+}
+
+impl RepoPool {
+    /// A pool whose writes go through `repo`. Up to `max_idle` handles are kept for reuse,
+    /// more are opened while there are more readers and closed once they are returned.
+    pub fn new(repo: Repository, max_idle: usize) -> Self {
+        Self {
+            path: repo.path().to_path_buf(),
+            idle: Mutex::new(Vec::new()),
+            max_idle,
+            writer: Mutex::new(repo),
+        }
+    }
+
+    /// Enough idle handles for a reader and a prefetching stream per core
+    pub fn default_max_idle() -> usize {
+        std::thread::available_parallelism().map_or(4, |cores| cores.get()) * 2
+    }
+
+    /// The git directory of the repository
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Checks out a handle for reading or for long running work, like ingesting a NAR
+    pub fn get(self: &Arc<Self>) -> Result<RepoHandle, git2::Error> {
+        let idle = self.idle.lock().unwrap().pop();
+        let repo = match idle {
+            Some(repo) => repo,
+            None => Repository::open(&self.path)?,
+        };
+        Ok(RepoHandle {
+            repo: Some(repo),
+            pool: Some(self.clone()),
+        })
+    }
+
+    /// Waits for the handle shared by the writers, the wait shows up in traces
+    pub fn writer(&self) -> MutexGuard<'_, Repository> {
+        let _span = trace_span!("wait_for_repository").entered();
+        self.writer.lock().unwrap()
+    }
+
+    fn put(&self, repo: Repository) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.max_idle {
+            idle.push(repo);
+        }
+    }
+
+    #[cfg(test)]
+    fn idle_count(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+}
+
+/// A repository handle used by one reader at a time, returned to its pool when dropped
+pub struct RepoHandle {
+    // Only taken when dropped
+    repo: Option<Repository>,
+    pool: Option<Arc<RepoPool>>,
+}
+
+impl RepoHandle {
+    /// Another handle on the same repository, e.g. for a thread working next to this one
+    pub fn sibling(&self) -> Result<RepoHandle, git2::Error> {
+        match &self.pool {
+            Some(pool) => pool.get(),
+            None => Ok(Repository::open(self.path())?.into()),
+        }
+    }
+}
+
+/// A handle outside of any pool, which is closed when dropped
+impl From<Repository> for RepoHandle {
+    fn from(repo: Repository) -> Self {
+        Self {
+            repo: Some(repo),
+            pool: None,
+        }
+    }
+}
+
+impl Deref for RepoHandle {
+    type Target = Repository;
+
+    fn deref(&self) -> &Repository {
+        self.repo
+            .as_ref()
+            .expect("the handle is only taken when dropped")
+    }
+}
+
+impl Drop for RepoHandle {
+    fn drop(&mut self) {
+        if let (Some(repo), Some(pool)) = (self.repo.take(), &self.pool) {
+            pool.put(repo);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repo_pool() -> anyhow::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let pool = Arc::new(RepoPool::new(Repository::init(temp_dir.path())?, 2));
+
+        let handles: Vec<RepoHandle> = (0..3).map(|_| pool.get()).collect::<Result<_, _>>()?;
+        assert_eq!(pool.idle_count(), 0);
+        // a write is seen by handles which were opened before
+        let blob = pool.writer().blob(b"hello")?;
+        for handle in &handles {
+            assert_eq!(handle.find_blob(blob)?.content(), b"hello");
+        }
+        drop(handles);
+        // the third handle is closed
+        assert_eq!(pool.idle_count(), 2);
+
+        let handle = pool.get()?;
+        assert_eq!(pool.idle_count(), 1);
+        let sibling = handle.sibling()?;
+        assert_eq!(pool.idle_count(), 0);
+        assert_eq!(sibling.path(), pool.path());
+        drop((handle, sibling));
+        assert_eq!(pool.idle_count(), 2);
+
+        // handles outside of a pool open their siblings themselves
+        let unpooled = RepoHandle::from(Repository::open(temp_dir.path())?);
+        assert_eq!(unpooled.sibling()?.find_blob(blob)?.content(), b"hello");
+        assert_eq!(pool.idle_count(), 2);
+        Ok(())
+    }
+}
@@ -1,30 +1,53 @@
+use super::METADATA_REF_PREFIX;
+use super::error::{Error, Result};
+use super::package_diff::{FileChange, diff_entries};
+use super::repo_pool::{RepoHandle, RepoPool};
+use super::tar_export::write_tar;
 use crate::nar::NarGitStream;
+use crate::nar::chunked::DEFAULT_CHUNK_SIZE;
 use crate::nar::decode::NarGitDecoder;
-use anyhow::{Context, Result, anyhow, bail};
-use git2::Cred;
+use crate::nar::entry::validate_tree;
+use crate::nar::listing::nar_listing;
+use crate::settings::{GitSettings, NarLimits, RemoteConfig};
+use anyhow::{Context, anyhow};
 use git2::Direction;
 use git2::FetchOptions;
 use git2::RemoteCallbacks;
 use git2::Signature;
 use git2::Time;
+use git2::{Cred, CredentialType};
 use git2::{ErrorCode, FileMode, Oid, Repository};
 use std::env;
 use std::fs;
-use std::io::Read;
+use std::io::{Read, Write};
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
-use std::sync::{Arc, RwLock};
-use tracing::{Level, info, instrument, span, trace};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, MutexGuard};
+use tracing::{Level, Span, field, info, instrument, span, trace, warn};
 
+// Above the loose (1) and pack (2) backends of libgit2, so new objects are written to the mempack
+const MEMPACK_PRIORITY: i32 = 1000;
+// git's gc.autoPackLimit, with more packs every object lookup searches more indexes
+const MAX_PACKS: usize = 50;
+// Numbers the scratch repositories of a process, so concurrent fetches don't share one
+static SCRATCH_REPOS: AtomicUsize = AtomicUsize::new(0);
+
+/// The repository of a store. Reads, and long running operations like ingesting or streaming a
+/// NAR, check a handle out of the pool, while writes share the pool's writer handle.
 pub struct GitRepo {
-    repo: Arc<RwLock<Repository>>,
+    pool: Arc<RepoPool>,
+    path: PathBuf,
+    chunk_threshold: Option<u64>,
+    nar_limits: NarLimits,
+    warn_case_collisions: bool,
+    git_settings: GitSettings,
 }
-unsafe impl Sync for GitRepo {}
-unsafe impl Send for GitRepo {}
 
 impl GitRepo {
-    pub fn new(path_to_repo: &Path) -> Result<Self, git2::Error> {
+    pub fn new(path_to_repo: &Path) -> Result<Self> {
         let repo = if path_to_repo.exists() {
             info!(
                 "Using an existing Git repository at {}",
@@ -41,115 +64,287 @@ impl GitRepo {
         let mut config = repo.config()?;
         config.set_str("protocol.version", "2")?;
         Ok(Self {
-            repo: RwLock::new(repo).into(),
+            pool: Arc::new(RepoPool::new(repo, RepoPool::default_max_idle())),
+            path: path_to_repo.to_path_buf(),
+            chunk_threshold: None,
+            nar_limits: NarLimits::default(),
+            warn_case_collisions: false,
+            git_settings: GitSettings::default(),
         })
     }
 
+    /// A handle of the pool, which no other reader uses until it is dropped
+    fn reader(&self) -> Result<RepoHandle> {
+        Ok(self.pool.get()?)
+    }
+
+    /// The handle shared by all writes of this process
+    fn writer(&self) -> MutexGuard<'_, Repository> {
+        self.pool.writer()
+    }
+
+    /// Files in added NARs larger than `threshold` bytes are stored as chunks
+    pub fn with_chunk_threshold(mut self, threshold: Option<u64>) -> Self {
+        self.chunk_threshold = threshold;
+        self
+    }
+
+    /// Added NARs exceeding these limits are rejected
+    pub fn with_nar_limits(mut self, limits: NarLimits) -> Self {
+        self.nar_limits = limits;
+        self
+    }
+
+    /// Warn about added NARs which can't be unpacked on case-insensitive file systems
+    pub fn with_case_collision_warnings(mut self, enabled: bool) -> Self {
+        self.warn_case_collisions = enabled;
+        self
+    }
+
+    /// Stores the compression level and pack threshold in the repository config, where `git repack`
+    /// and `git gc --auto` pick them up. libgit2 always writes loose objects with the fastest level,
+    /// so the level applies once they are packed. Existing packs are not recompressed.
+    pub fn with_git_settings(mut self, settings: GitSettings) -> Result<Self> {
+        let mut config = self.writer().config()?;
+        match settings.compression_level {
+            Some(level) if level > 9 => {
+                return Err(anyhow!("Invalid git compression level {}, use 0 to 9", level).into());
+            }
+            Some(level) => {
+                config.set_i32("core.compression", level as i32)?;
+                config.set_i32("pack.compression", level as i32)?;
+            }
+            None => {
+                for key in ["core.compression", "pack.compression"] {
+                    match config.remove(key) {
+                        Err(e) if e.code() != ErrorCode::NotFound => return Err(e.into()),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        config.set_i64("gc.auto", settings.pack_threshold as i64)?;
+        self.git_settings = settings;
+        Ok(self)
+    }
+
+    /// The git directory, which holds the objects and references
+    pub fn git_dir(&self) -> &Path {
+        self.pool.path()
+    }
+
+    /// Counts the objects which are not in a pack
+    pub fn loose_object_count(&self) -> Result<usize> {
+        let objects_dir = self.pool.path().join("objects");
+        let mut count = 0;
+        for dir in fs::read_dir(objects_dir)? {
+            let dir = dir?;
+            // loose objects are stored in directories named after the first two hex digits of their id
+            let name = dir.file_name();
+            if name.len() == 2 && name.as_bytes().iter().all(u8::is_ascii_hexdigit) {
+                count += fs::read_dir(dir.path())?.count();
+            }
+        }
+        Ok(count)
+    }
+
+    /// Counts the packs, each added package is written as one
+    pub fn pack_count(&self) -> Result<usize> {
+        let pack_dir = self.pool.path().join("objects").join("pack");
+        let mut count = 0;
+        for entry in fs::read_dir(pack_dir)? {
+            if entry?.path().extension().is_some_and(|ext| ext == "pack") {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Rolls the reachable loose objects into a pack with `git repack` if there are more than the pack threshold,
+    /// and all packs into one if there are more than git's `gc.autoPackLimit`.
+    /// Returns the number of packed loose objects.
+    pub fn repack(&self) -> Result<Option<usize>> {
+        let loose_objects = self.loose_object_count()?;
+        let consolidate = self.pack_count()? > MAX_PACKS;
+        if loose_objects <= self.git_settings.pack_threshold && !consolidate {
+            return Ok(None);
+        }
+        let git_dir = self.pool.path().to_path_buf();
+        // -A instead of -a loosens unreachable packed objects, e.g. of a package whose references
+        // are not written yet, rather than deleting them
+        let all = if consolidate { ["-A"].as_slice() } else { &[] };
+        let output = Command::new("git")
+            .arg("--git-dir")
+            .arg(&git_dir)
+            .args(["repack", "-d", "-q"])
+            .args(all)
+            .output()
+            .context("Failed to run git repack, is git installed?")?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "git repack failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+        // unreachable objects, e.g. of an interrupted ingest, stay loose
+        Ok(Some(
+            loose_objects.saturating_sub(self.loose_object_count()?),
+        ))
+    }
+
+    /// Moves the loose references into `packed-refs` with `git pack-refs`, so a lookup reads one
+    /// file instead of searching a directory per package
+    pub fn pack_refs(&self) -> Result<()> {
+        let output = Command::new("git")
+            .arg("--git-dir")
+            .arg(self.pool.path())
+            .args(["pack-refs", "--all"])
+            .output()
+            .context("Failed to run git pack-refs, is git installed?")?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "git pack-refs failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+        Ok(())
+    }
+
     pub fn add_file_content(&self, content: &[u8]) -> Result<Oid> {
-        let read_repo = self.repo.read().unwrap();
-        let blob_oid = read_repo.blob(content)?;
+        let repo = self.writer();
+        let blob_oid = repo.blob(content)?;
         Ok(blob_oid)
     }
 
     pub fn add_single_entry_tree(&self, entry_oid: Oid, name: &str, filemode: i32) -> Result<Oid> {
-        let repo = self.repo.read().unwrap();
+        let repo = self.writer();
         let mut builder = repo.treebuilder(None)?;
         builder.insert(&name, entry_oid, filemode)?;
         Ok(builder.write()?)
     }
 
-    #[allow(dead_code)]
+    /// Stores a directory of the local file system as a tree, e.g. to build packages in tests
     pub fn add_dir<T: AsRef<Path>>(&self, path: &T) -> Result<Oid> {
         let path = path.as_ref();
         if !path.is_dir() {
-            return Err(anyhow!("No such directory: {}", path.to_str().unwrap()));
+            return Err(anyhow!("No such directory: {}", path.to_str().unwrap()).into());
         }
-        let tree_oid = self.create_tree_from_dir(&path)?;
+        let repo = self.reader()?;
+        let tree_oid = create_tree_from_dir(&repo, path)?;
         Ok(tree_oid)
     }
 
+    /// Decodes the NAR into the repository. Its objects are collected in memory and written as one
+    /// pack once the root is known, which is much faster than writing loose objects for packages
+    /// with many small files. Blobs beyond the ingest pack budget are written loose instead.
+    #[instrument(skip_all, fields(bytes = field::Empty))]
     pub fn add_nar(&self, content: impl Read) -> Result<(Oid, i32)> {
-        let repo = self.repo.read().unwrap();
-        let decoder = NarGitDecoder::new(&repo);
-        let (oid, filemode) = decoder
-            .parse(content)
-            .with_context(|| "Error decoding NAR file")?;
-        Ok((oid, filemode))
+        let loose = self.reader()?;
+        let budget = self.git_settings.ingest_pack_budget;
+        let mut content = CountingReader {
+            inner: content,
+            count: 0,
+        };
+        if budget == 0 {
+            let parsed = self.nar_decoder(&loose).parse(&mut content);
+            Span::current().record("bytes", content.count);
+            return Ok(parsed?);
+        }
+
+        // The mempack stays attached to the handle, so the batch is not written through a pooled one
+        let repo = Repository::open(self.pool.path())?;
+        let odb = repo.odb()?;
+        odb.add_new_mempack_backend(MEMPACK_PRIORITY)?;
+        let decoder = self.nar_decoder(&repo).with_batch_budget(&loose, budget);
+        let parsed = decoder.parse(&mut content);
+        Span::current().record("bytes", content.count);
+        // The objects of an archive which fails to decode are dropped with the handle
+        let root = parsed?;
+
+        // Dumping the mempack would only pack the objects reachable from a commit
+        let batched = decoder.batched_objects();
+        if !batched.is_empty() {
+            let mut builder = repo.packbuilder()?;
+            for oid in batched {
+                builder.insert_object(oid, None)?;
+            }
+            let mut pack = git2::Buf::new();
+            builder.write_buf(&mut pack)?;
+            let mut writer = odb.packwriter()?;
+            writer.write_all(&pack)?;
+            writer.commit()?;
+        }
+        Ok(root)
+    }
+
+    fn nar_decoder<'a>(&self, repo: &'a Repository) -> NarGitDecoder<'a> {
+        NarGitDecoder::new(repo)
+            .with_chunking(self.chunk_threshold, DEFAULT_CHUNK_SIZE)
+            .with_limits(self.nar_limits)
+            .with_case_collision_warnings(self.warn_case_collisions)
     }
 
     pub fn get_blob(&self, oid: Oid) -> Result<Vec<u8>> {
-        let repo = self.repo.read().unwrap();
+        let repo = self.reader()?;
         let blob = repo.find_blob(oid)?;
         Ok(blob.content().to_vec())
     }
 
+    /// Returns the `.ls` listing of the NAR which `get_entry_as_nar` produces for the object
+    pub fn get_entry_listing(&self, oid: Oid) -> Result<String> {
+        let repo = self.reader()?;
+        let filemode = root_filemode(&repo, oid)?;
+        Ok(nar_listing(&repo, oid, filemode)?)
+    }
+
+    /// Lists the files which differ between two NAR roots, given as oid and filemode
+    pub fn diff_entries(
+        &self,
+        old: (Oid, i32),
+        new: (Oid, i32),
+        patch: bool,
+    ) -> Result<Vec<FileChange>> {
+        let repo = self.reader()?;
+        Ok(diff_entries(&repo, old, new, patch)?)
+    }
+
+    /// Writes the object as a tar archive whose top-level entry is called `root_name`
+    pub fn write_entry_as_tar(&self, oid: Oid, root_name: &[u8], writer: impl Write) -> Result<()> {
+        let repo = self.reader()?;
+        let filemode = root_filemode(&repo, oid)?;
+        validate_tree(&repo, oid, filemode)?;
+        Ok(write_tar(&repo, oid, filemode, root_name, writer)?)
+    }
+
     pub fn add_ref(&self, ref_name: &str, oid: Oid) -> Result<()> {
-        let repo = self.repo.read().unwrap();
+        let repo = self.writer();
         repo.reference(&ref_name, oid, false, "")?;
         Ok(())
     }
 
     pub fn get_entry_as_nar(&self, oid: Oid) -> Result<Option<NarGitStream>> {
-        let repo = self.repo.read().unwrap();
-        let object = repo.find_object(oid, None)?;
-        let kind = object
-            .kind()
-            .ok_or_else(|| anyhow!("Object with oid {} does not have a type", oid))?;
-        let filemode = match kind {
-            git2::ObjectType::Blob => FileMode::Blob.into(),
-            git2::ObjectType::Tree => FileMode::Tree.into(),
-            _ => bail!("Object must either be a tree or a blob"),
-        };
+        // the stream outlives this call and is polled from any worker thread
+        let repo = self.reader()?;
+        let filemode = root_filemode(&repo, oid)?;
+        validate_tree(&repo, oid, filemode)?;
 
-        let repo_owned = Arc::clone(&self.repo);
-        let stream = NarGitStream::new(repo_owned, oid, filemode);
+        let stream = NarGitStream::new(repo, oid, filemode);
         Ok(Some(stream))
     }
 
     pub fn get_oid_from_reference(&self, reference: &str) -> Option<Oid> {
-        let repo = self.repo.read().unwrap();
+        let repo = self.reader().ok()?;
         let res = repo.find_reference(reference).ok().and_then(|r| r.target());
         res
     }
 
-    fn create_tree_from_dir(&self, path: &Path) -> Result<Oid> {
-        let repo = self.repo.read().unwrap();
-        let mut builder = repo.treebuilder(None)?;
-        for entry in path.read_dir()? {
-            let entry_path = entry?.path();
-            let entry_file_name = entry_path
-                .file_name()
-                .expect("Failed to get filename")
-                .to_str()
-                .unwrap();
-
-            if entry_path.is_symlink() {
-                let target = fs::read_link(&entry_path)?;
-                let blob_oid = repo.blob(target.as_os_str().as_bytes())?;
-                builder.insert(entry_file_name, blob_oid, FileMode::Link.into())?;
-            } else if entry_path.is_file() {
-                let permissions = entry_path.metadata()?.permissions();
-                let is_executable = permissions.mode() & 0o111 != 0;
-                let filemode = if is_executable {
-                    FileMode::BlobExecutable
-                } else {
-                    FileMode::Blob
-                };
-                let blob_oid = repo.blob_path(&entry_path)?;
-                builder.insert(entry_file_name, blob_oid, filemode.into())?;
-            } else if entry_path.is_dir() {
-                let subtree_oid = self.create_tree_from_dir(&entry_path)?;
-                builder.insert(entry_file_name, subtree_oid, FileMode::Tree.into())?;
-            }
-        }
-        Ok(builder.write()?)
-    }
-
     pub fn commit(&self, tree_oid: Oid, parent_oids: &[Oid], comment: Option<&str>) -> Result<Oid> {
         let span = span!(Level::TRACE, "Commiting", comment);
         let _guard = span.enter();
 
-        let repo = self.repo.write().unwrap();
+        let repo = self.writer();
         let sig = Signature::new("gachix", "gachix@gachix.com", &Time::new(0, 0))?;
 
         trace!("Retrieving main tree object {}", tree_oid);
@@ -176,38 +371,158 @@ impl GitRepo {
         Ok(commit_oid)
     }
 
+    /// Records which commit wraps a tree, so `commit_for_tree` does not have to scan the odb
+    pub fn index_tree(&self, tree_oid: Oid, commit_oid: Oid) -> Result<()> {
+        let repo = self.writer();
+        repo.reference(&tree_index_ref(tree_oid), commit_oid, true, "")?;
+        Ok(())
+    }
+
+    /// The commit which wraps a tree, as recorded by `index_tree`
+    pub fn commit_for_tree(&self, tree_oid: Oid) -> Option<Oid> {
+        self.get_oid_from_reference(&tree_index_ref(tree_oid))
+    }
+
+    pub fn get_commit_tree(&self, commit_oid: Oid) -> Result<Oid> {
+        let repo = self.reader()?;
+        Ok(repo.find_commit(commit_oid)?.tree_id())
+    }
+
+    /// Replaces the blob behind `ref_name` with the result of `update`, which receives the current content.
+    /// The reference is only moved if no other writer moved it in the meantime, otherwise the update is retried.
+    pub fn update_blob_ref<F>(&self, ref_name: &str, update: F) -> Result<()>
+    where
+        F: Fn(Option<&[u8]>) -> Result<Vec<u8>>,
+    {
+        self.update_blob_ref_with_entry(ref_name, None, update)
+    }
+
+    /// Like `update_blob_ref`, but also appends `entry` to its chain in the same reference transaction
+    pub fn update_blob_ref_with_entry<F>(
+        &self,
+        ref_name: &str,
+        entry: Option<&ChainEntry>,
+        update: F,
+    ) -> Result<()>
+    where
+        F: Fn(Option<&[u8]>) -> Result<Vec<u8>>,
+    {
+        loop {
+            let (current_oid, content) = {
+                let repo = self.reader()?;
+                let current_oid = repo.find_reference(ref_name).ok().and_then(|r| r.target());
+                let content = match current_oid {
+                    Some(oid) => Some(repo.find_blob(oid)?.content().to_vec()),
+                    None => None,
+                };
+                (current_oid, content)
+            };
+            // `update` may use the repository itself, so the handle is not held while it runs
+            let new_content = update(content.as_deref())?;
+            let repo = self.writer();
+            let new_oid = repo.blob(&new_content)?;
+            match write_refs(&repo, &[(ref_name, new_oid)], Some(current_oid), entry) {
+                Ok(true) => return Ok(()),
+                Ok(false) => trace!("Reference {} was modified concurrently, retrying", ref_name),
+                Err(e) if is_contended(&e) => {
+                    trace!("Reference {} is locked, retrying", ref_name)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Points all `refs` at their oids and appends `entry` to its chain in one reference transaction,
+    /// so the entry is written if and only if the references are
+    pub fn update_refs(&self, refs: &[(&str, Oid)], entry: Option<&ChainEntry>) -> Result<()> {
+        loop {
+            let repo = self.writer();
+            match write_refs(&repo, refs, None, entry) {
+                Ok(_) => return Ok(()),
+                Err(e) if is_contended(&e) => trace!("References are locked, retrying"),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Calls `f` with the entries of the chain behind `chain_ref`, the newest first, until it returns false
+    pub fn for_each_chain_entry<F>(&self, chain_ref: &str, mut f: F) -> Result<()>
+    where
+        F: FnMut(&str) -> Result<bool>,
+    {
+        let repo = self.reader()?;
+        let mut next = repo.find_reference(chain_ref).ok().and_then(|r| r.target());
+        while let Some(oid) = next {
+            let commit = repo.find_commit(oid)?;
+            if !f(&String::from_utf8_lossy(commit.message_bytes()))? {
+                break;
+            }
+            next = commit.parent_id(0).ok();
+        }
+        Ok(())
+    }
+
     pub fn reference_exists(&self, name: &str) -> Result<bool> {
-        let repo = self.repo.read().unwrap();
+        let repo = self.reader()?;
         match repo.find_reference(name) {
             Ok(_) => Ok(true),
             Err(e) => {
                 if e.code() == ErrorCode::NotFound {
                     Ok(false)
                 } else {
-                    bail!(e)
+                    Err(e.into())
                 }
             }
         }
     }
 
     pub fn list_references(&self, ref_name: &str) -> Result<Vec<String>> {
-        let repo = self.repo.read().unwrap();
-        let refs = repo.references_glob(ref_name)?;
         let mut refs_names = Vec::new();
-        for reference in refs {
+        self.for_each_reference(ref_name, |name| refs_names.push(name.to_string()))?;
+        Ok(refs_names)
+    }
+
+    /// Visits the names of the references matching the glob `ref_name` without collecting them
+    pub fn for_each_reference(&self, ref_name: &str, mut visit: impl FnMut(&str)) -> Result<()> {
+        let repo = self.reader()?;
+        for reference in repo.references_glob(ref_name)? {
             let reference = reference?;
-            refs_names.push(
+            visit(
                 reference
                     .name()
-                    .ok_or_else(|| anyhow!("Could not get name from reference"))?
-                    .to_string(),
+                    .ok_or_else(|| anyhow!("Could not get name from reference"))?,
             );
         }
+        Ok(())
+    }
+
+    /// The names of the references matching the glob `ref_name` whose commit has `parent` as a parent
+    pub fn references_with_parent(&self, ref_name: &str, parent: Oid) -> Result<Vec<String>> {
+        let repo = self.reader()?;
+        let mut refs_names = Vec::new();
+        for reference in repo.references_glob(ref_name)? {
+            let reference = reference?;
+            let Some(commit_oid) = reference.target() else {
+                continue;
+            };
+            if repo
+                .find_commit(commit_oid)?
+                .parent_ids()
+                .any(|oid| oid == parent)
+            {
+                refs_names.push(
+                    reference
+                        .name()
+                        .ok_or_else(|| anyhow!("Could not get name from reference"))?
+                        .to_string(),
+                );
+            }
+        }
         Ok(refs_names)
     }
 
     pub fn match_sole_entry_id(&self, tree_oid: Oid, name: &str) -> Result<Option<Oid>> {
-        let repo = self.repo.read().unwrap();
+        let repo = self.reader()?;
         let tree = repo.find_tree(tree_oid)?;
         if tree.len() != 1 {
             return Ok(None);
@@ -225,100 +540,590 @@ impl GitRepo {
         Ok(entry_oid)
     }
 
-    pub fn check_remote_health(&self, url: &str) -> Result<()> {
-        let repo = self.repo.read().unwrap();
-        let mut remote = repo.remote_anonymous(url)?;
+    pub fn check_remote_health(&self, remote: &RemoteConfig) -> Result<()> {
+        let repo = self.reader()?;
+        let mut connection_remote = repo.remote_anonymous(remote.url.as_str())?;
         let mut callbacks = RemoteCallbacks::new();
-        callbacks.credentials(|_url, _user_from_url, _allowed_types| {
-            let user = env::var("USER").unwrap();
-            if _allowed_types.contains(git2::CredentialType::USERNAME) {
-                return git2::Cred::username(&user);
-            }
-            Cred::ssh_key(
-                &env::var("USER").unwrap(),
-                None,
-                std::path::Path::new(&format!("{}/.ssh/id_ed25519", env::var("HOME").unwrap())),
-                None,
-            )
+        callbacks.credentials(|_url, user_from_url, allowed_types| {
+            remote_credentials(remote, user_from_url, allowed_types)
         });
-        match remote.connect_auth(Direction::Fetch, Some(callbacks), None) {
+        match connection_remote.connect_auth(Direction::Fetch, Some(callbacks), None) {
             Ok(connection) => {
                 connection.list()?;
                 Ok(())
             }
-            Err(e) => {
-                bail!("Connection failed: {}", e);
-            }
+            Err(e) if e.code() == ErrorCode::Auth => Err(remote_error(remote.url.as_str(), e)),
+            Err(e) => Err(anyhow!("Connection failed: {}", e).into()),
         }
     }
 
-    #[instrument(skip(self))]
-    pub fn fetch(&self, url: &str, reference: &str) -> Result<Option<()>> {
-        let repo = self.repo.read().unwrap();
-        let mut remote = match repo.find_remote("peer") {
-            Ok(remote) => remote,
-            _ => repo.remote_with_fetch("peer", url, "")?,
-        };
-        let refspec = format!("{}:{}", reference, reference);
-
-        trace!("Fetching from remote");
-        let mut fetch_options = FetchOptions::new();
+    /// The references of the remote and the objects they point to
+    pub fn list_remote_references(&self, remote: &RemoteConfig) -> Result<Vec<(String, Oid)>> {
+        let repo = self.reader()?;
+        let mut git_remote = repo.remote_anonymous(remote.url.as_str())?;
         let mut callbacks = RemoteCallbacks::new();
-        callbacks.update_tips(|r, _, _| {
-            trace!("Added reference {r}");
-            true
-        });
-        callbacks.credentials(|_url, _user_from_url, _allowed_types| {
-            let user = env::var("USER").unwrap();
-            if _allowed_types.contains(git2::CredentialType::USERNAME) {
-                return git2::Cred::username(&user);
-            }
-            Cred::ssh_key(
-                &env::var("USER").unwrap(),
-                None,
-                std::path::Path::new(&format!("{}/.ssh/id_ed25519", env::var("HOME").unwrap())),
-                None,
-            )
+        callbacks.credentials(|_url, user_from_url, allowed_types| {
+            remote_credentials(remote, user_from_url, allowed_types)
         });
-        fetch_options.remote_callbacks(callbacks);
-        fetch_options.download_tags(git2::AutotagOption::None);
-        fetch_options.update_fetchhead(false);
-        remote.fetch(&vec![refspec], Some(&mut fetch_options), None)?;
+        let connection = git_remote
+            .connect_auth(Direction::Fetch, Some(callbacks), None)
+            .map_err(|e| remote_error(remote.url.as_str(), e))?;
+        Ok(connection
+            .list()?
+            .iter()
+            .map(|head| (head.name().to_string(), head.oid()))
+            .collect())
+    }
 
-        if remote.stats().received_objects() == 0 {
-            trace!("Did not receive anything");
-            return Ok(None);
+    /// Fetches `remote_reference` of the remote into `local_reference`, both may end in a `*`
+    pub fn fetch(
+        &self,
+        remote: &RemoteConfig,
+        remote_reference: &str,
+        local_reference: &str,
+    ) -> Result<Option<()>> {
+        let refspec = format!("{}:{}", remote_reference, local_reference);
+        let received_objects = self.fetch_refspecs(remote, &[refspec])?;
+        Ok((received_objects > 0).then_some(()))
+    }
+
+    /// Fetches all `refspecs` of the remote in one go, returns the number of received objects
+    #[instrument(skip(self, remote, refspecs), fields(remote = %remote))]
+    pub fn fetch_refspecs(&self, remote: &RemoteConfig, refspecs: &[String]) -> Result<usize> {
+        let repo = self.reader()?;
+        fetch_into(&repo, remote, refspecs)
+    }
+
+    /// Fetches `refspecs` into a scratch repository which reads the objects of this one, so the
+    /// store gains neither references nor objects. The commits in `offered` are its only
+    /// references, which the remote leaves out of the pack.
+    ///
+    /// The local transport of libgit2 aborts when a reference of the fetching repository points
+    /// to a blob or tree the remote has, like the narinfo references of a store do.
+    #[instrument(skip(self, remote, refspecs, offered), fields(remote = %remote))]
+    pub fn fetch_into_scratch(
+        &self,
+        remote: &RemoteConfig,
+        refspecs: &[String],
+        offered: &[Oid],
+    ) -> Result<ScratchRepo> {
+        let path = self.pool.path().join(format!(
+            "gachix-scratch-{}-{}",
+            std::process::id(),
+            SCRATCH_REPOS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let objects_dir = fs::canonicalize(self.pool.path().join("objects"))?;
+        Repository::init_bare(&path)?;
+        // Removes the directory again if the fetch fails
+        let mut scratch = ScratchRepo { repo: None, path };
+        fs::write(
+            scratch.path.join("objects/info/alternates"),
+            format!("{}\n", objects_dir.display()),
+        )?;
+        scratch.repo = Some(Repository::open(&scratch.path)?);
+        let repo = scratch.repo();
+        for oid in offered {
+            repo.reference(&format!("refs/offered/{oid}"), *oid, true, "")?;
+        }
+        fetch_into(repo, remote, refspecs)?;
+        Ok(scratch)
+    }
+}
+
+/// A repository holding the references and objects of one fetch, see `GitRepo::fetch_into_scratch`.
+/// Its directory is removed when it is dropped.
+pub struct ScratchRepo {
+    repo: Option<Repository>,
+    path: PathBuf,
+}
+
+impl ScratchRepo {
+    fn repo(&self) -> &Repository {
+        self.repo.as_ref().expect("opened before it is returned")
+    }
+
+    pub fn get_oid_from_reference(&self, reference: &str) -> Option<Oid> {
+        self.repo()
+            .find_reference(reference)
+            .ok()
+            .and_then(|r| r.target())
+    }
+
+    pub fn get_commit_tree(&self, commit_oid: Oid) -> Result<Oid> {
+        Ok(self.repo().find_commit(commit_oid)?.tree_id())
+    }
+
+    pub fn get_blob(&self, oid: Oid) -> Result<Vec<u8>> {
+        Ok(self.repo().find_blob(oid)?.content().to_vec())
+    }
+}
+
+impl Drop for ScratchRepo {
+    fn drop(&mut self) {
+        self.repo = None;
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            warn!("Could not remove {}: {}", self.path.display(), e);
         }
-        trace!("Received {} objects", remote.stats().received_objects());
+    }
+}
+
+/// Fetches all `refspecs` of the remote into `repo`, returns the number of received objects
+fn fetch_into(repo: &Repository, remote: &RemoteConfig, refspecs: &[String]) -> Result<usize> {
+    // anonymous, as a persisted remote would keep the URL of whichever peer came first
+    let mut git_remote = repo.remote_anonymous(remote.url.as_str())?;
+
+    trace!("Fetching from remote");
+    let mut fetch_options = FetchOptions::new();
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.update_tips(|r, _, _| {
+        trace!("Added reference {r}");
+        true
+    });
+    callbacks.credentials(|_url, user_from_url, allowed_types| {
+        remote_credentials(remote, user_from_url, allowed_types)
+    });
+    fetch_options.remote_callbacks(callbacks);
+    fetch_options.download_tags(git2::AutotagOption::None);
+    fetch_options.update_fetchhead(false);
+    git_remote
+        .fetch(refspecs, Some(&mut fetch_options), None)
+        .map_err(|e| remote_error(remote.url.as_str(), e))?;
+
+    let received_objects = git_remote.stats().received_objects();
+    if received_objects == 0 {
+        trace!("Did not receive anything");
+    } else {
+        trace!("Received {} objects", received_objects);
+    }
+    Ok(received_objects)
+}
+
+fn create_tree_from_dir(repo: &Repository, path: &Path) -> Result<Oid> {
+    let mut builder = repo.treebuilder(None)?;
+    for entry in path.read_dir()? {
+        let entry_path = entry?.path();
+        let entry_file_name = entry_path
+            .file_name()
+            .expect("Failed to get filename")
+            .to_str()
+            .unwrap();
+
+        if entry_path.is_symlink() {
+            let target = fs::read_link(&entry_path)?;
+            let blob_oid = repo.blob(target.as_os_str().as_bytes())?;
+            builder.insert(entry_file_name, blob_oid, FileMode::Link.into())?;
+        } else if entry_path.is_file() {
+            let permissions = entry_path.metadata()?.permissions();
+            let is_executable = permissions.mode() & 0o111 != 0;
+            let filemode = if is_executable {
+                FileMode::BlobExecutable
+            } else {
+                FileMode::Blob
+            };
+            let blob_oid = repo.blob_path(&entry_path)?;
+            builder.insert(entry_file_name, blob_oid, filemode.into())?;
+        } else if entry_path.is_dir() {
+            let subtree_oid = create_tree_from_dir(repo, &entry_path)?;
+            builder.insert(entry_file_name, subtree_oid, FileMode::Tree.into())?;
+        }
+    }
+    Ok(builder.write()?)
+}
+
+/// The filemode with which an object is serialized as the root of a NAR
+fn root_filemode(repo: &Repository, oid: Oid) -> Result<i32> {
+    let object = repo.find_object(oid, None)?;
+    let kind = object
+        .kind()
+        .ok_or_else(|| anyhow!("Object with oid {} does not have a type", oid))?;
+    match kind {
+        git2::ObjectType::Blob => Ok(FileMode::Blob.into()),
+        git2::ObjectType::Tree => Ok(FileMode::Tree.into()),
+        _ => Err(anyhow!("Object must either be a tree or a blob").into()),
+    }
+}
+
+/// A commit appended to the chain of commits behind `chain_ref`, e.g. the audit log.
+/// The commit has an empty tree, its message is the entry.
+pub struct ChainEntry {
+    pub chain_ref: String,
+    pub message: String,
+}
+
+/// Writes `refs` and appends `entry` to its chain, after locking all of them.
+/// With `expected`, nothing is written and false is returned if the first of `refs`
+/// does not point to the expected oid anymore.
+fn write_refs(
+    repo: &Repository,
+    refs: &[(&str, Oid)],
+    expected: Option<Option<Oid>>,
+    entry: Option<&ChainEntry>,
+) -> Result<bool, git2::Error> {
+    let mut transaction = repo.transaction()?;
+    for (name, _) in refs {
+        transaction.lock_ref(name)?;
+    }
+    if let (Some(expected), Some((name, _))) = (expected, refs.first()) {
+        let current = repo.find_reference(name).ok().and_then(|r| r.target());
+        if current != expected {
+            return Ok(false);
+        }
+    }
+    if let Some(entry) = entry {
+        transaction.lock_ref(&entry.chain_ref)?;
+        let parent = match repo.find_reference(&entry.chain_ref) {
+            Ok(reference) => Some(reference.peel_to_commit()?),
+            Err(e) if e.code() == ErrorCode::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let empty_tree = repo.find_tree(repo.treebuilder(None)?.write()?)?;
+        let sig = Signature::now("gachix", "gachix@gachix.com")?;
+        let commit_oid = repo.commit(
+            None,
+            &sig,
+            &sig,
+            &entry.message,
+            &empty_tree,
+            parent.iter().collect::<Vec<_>>().as_slice(),
+        )?;
+        transaction.set_target(&entry.chain_ref, commit_oid, None, "")?;
+    }
+    for (name, oid) in refs {
+        transaction.set_target(name, *oid, None, "")?;
+    }
+    transaction.commit()?;
+    Ok(true)
+}
+
+/// The token of the remote for HTTP(S), otherwise its SSH key or `~/.ssh/id_ed25519`
+fn remote_credentials(
+    remote: &RemoteConfig,
+    user_from_url: Option<&str>,
+    allowed_types: CredentialType,
+) -> std::result::Result<Cred, git2::Error> {
+    let user = match user_from_url {
+        Some(user) => user.to_string(),
+        None => env::var("USER").unwrap_or_else(|_| "git".to_string()),
+    };
+    if allowed_types.contains(CredentialType::USER_PASS_PLAINTEXT)
+        && let Some(token) = remote.token()
+    {
+        return Cred::userpass_plaintext(user_from_url.unwrap_or("git"), token.expose());
+    }
+    if allowed_types.contains(CredentialType::USERNAME) {
+        return Cred::username(&user);
+    }
+    let key_path = match &remote.ssh_key_path {
+        Some(path) => path.clone(),
+        None => PathBuf::from(env::var("HOME").unwrap_or_default()).join(".ssh/id_ed25519"),
+    };
+    Cred::ssh_key(&user, None, &key_path, None)
+}
+
+/// Tells failed authentication at a remote apart from other errors of talking to it
+fn remote_error(url: &str, error: git2::Error) -> Error {
+    match error.code() {
+        ErrorCode::Auth => Error::RemoteAuthFailed {
+            url: url.to_string(),
+            error,
+        },
+        _ => error.into(),
+    }
+}
+
+/// Another writer, possibly another process, holds the lock of a reference
+fn is_contended(e: &git2::Error) -> bool {
+    matches!(
+        e.code(),
+        ErrorCode::Modified | ErrorCode::Exists | ErrorCode::Locked
+    )
+}
 
-        Ok(Some(()))
+/// Counts the bytes of a NAR for the `bytes` field of the `add_nar` span
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
     }
 }
 
+fn tree_index_ref(tree_oid: Oid) -> String {
+    format!("{METADATA_REF_PREFIX}/trees/{tree_oid}")
+}
+
 impl Clone for GitRepo {
     fn clone(&self) -> Self {
         Self {
-            repo: self.repo.clone(),
+            pool: self.pool.clone(),
+            path: self.path.clone(),
+            chunk_threshold: self.chunk_threshold,
+            nar_limits: self.nar_limits,
+            warn_case_collisions: self.warn_case_collisions,
+            git_settings: self.git_settings,
         }
     }
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
-//     use anyhow::Result;
-//     use rand::distributions::{Alphanumeric, DistString};
-//     use rand::{self};
-//     use std::fs;
-//     use std::path::PathBuf;
-//     use tempfile::TempDir;
-//
-//     fn create_random_package(dir: &PathBuf) -> Result<PathBuf> {
-//         let mut rng = rand::thread_rng();
-//         let random_string = Alphanumeric.sample_string(&mut rng, 5);
-//         let package_path = dir.join(&random_string);
-//         fs::create_dir(&package_path)?;
-//         fs::write(package_path.join("some_file"), random_string)?;
-//         Ok(package_path.to_path_buf())
-//     }
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use rand::distributions::{Alphanumeric, DistString};
+    use rand::{self};
+    use std::fs;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    fn create_random_package(dir: &Path) -> Result<PathBuf> {
+        let mut rng = rand::thread_rng();
+        let random_string = Alphanumeric.sample_string(&mut rng, 5);
+        let package_path = dir.join(&random_string);
+        fs::create_dir(&package_path)?;
+        fs::write(package_path.join("some_file"), random_string)?;
+        Ok(package_path.to_path_buf())
+    }
+
+    #[test]
+    fn test_commit_for_tree() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = GitRepo::new(&temp_dir.path().join("repo"))?;
+        let package_path = create_random_package(temp_dir.path())?;
+
+        let tree_oid = repo.add_dir(&package_path)?;
+        assert_eq!(repo.commit_for_tree(tree_oid), None);
+
+        let commit_oid = repo.commit(tree_oid, &[], None)?;
+        repo.index_tree(tree_oid, commit_oid)?;
+        assert_eq!(repo.commit_for_tree(tree_oid), Some(commit_oid));
+        assert_eq!(repo.get_commit_tree(commit_oid)?, tree_oid);
+        Ok(())
+    }
+
+    #[test]
+    fn test_git_settings() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("repo");
+        let settings = GitSettings {
+            compression_level: Some(9),
+            pack_threshold: 2,
+            ..GitSettings::default()
+        };
+        let repo = GitRepo::new(&path)?.with_git_settings(settings)?;
+        let config = Repository::open(&path)?.config()?.snapshot()?;
+        assert_eq!(config.get_i32("core.compression")?, 9);
+        assert_eq!(config.get_i32("pack.compression")?, 9);
+        assert_eq!(config.get_i64("gc.auto")?, 2);
+
+        // objects written before are kept as they are
+        let blob = repo.add_file_content(b"before")?;
+        repo.add_ref("refs/test/before", blob)?;
+        let loose_path = path
+            .join(".git/objects")
+            .join(&blob.to_string()[..2])
+            .join(&blob.to_string()[2..]);
+        let loose_object = fs::read(&loose_path)?;
+        let repo = GitRepo::new(&path)?.with_git_settings(GitSettings {
+            compression_level: Some(0),
+            ..settings
+        })?;
+        assert_eq!(fs::read(&loose_path)?, loose_object);
+        let config = Repository::open(&path)?.config()?.snapshot()?;
+        assert_eq!(config.get_i32("core.compression")?, 0);
+
+        assert_eq!(repo.repack()?, None);
+        repo.add_ref("refs/test/after", repo.add_file_content(b"after")?)?;
+        repo.add_file_content(b"unreachable")?;
+        assert_eq!(repo.repack()?, Some(2));
+        assert_eq!(repo.loose_object_count()?, 1);
+        assert_eq!(repo.get_blob(blob)?, b"before");
+
+        let repo = GitRepo::new(&path)?.with_git_settings(GitSettings::default())?;
+        let config = Repository::open(&path)?.config()?.snapshot()?;
+        assert!(config.get_i32("core.compression").is_err());
+        assert!(
+            GitRepo::new(&path)?
+                .with_git_settings(GitSettings {
+                    compression_level: Some(10),
+                    ..settings
+                })
+                .is_err()
+        );
+        drop(repo);
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_blob_ref() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = GitRepo::new(&temp_dir.path().join("repo"))?;
+        let ref_name = "refs/gachix/counter";
+
+        for _ in 0..3 {
+            repo.update_blob_ref(ref_name, |content| {
+                let count = content.map(|c| c.len()).unwrap_or(0);
+                Ok(vec![b'x'; count + 1])
+            })?;
+        }
+        let oid = repo.get_oid_from_reference(ref_name).unwrap();
+        assert_eq!(repo.get_blob(oid)?, b"xxx");
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_refs_with_chain_entries() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = GitRepo::new(&temp_dir.path().join("repo"))?;
+        let chain_ref = "refs/gachix/audit";
+        let blob = repo.add_file_content(b"narinfo")?;
+
+        for i in 0..3 {
+            let entry = ChainEntry {
+                chain_ref: chain_ref.to_string(),
+                message: format!("entry {i}"),
+            };
+            repo.update_refs(&[(&format!("refs/{i}/narinfo"), blob)], Some(&entry))?;
+        }
+        repo.update_blob_ref_with_entry(
+            "refs/gachix/counter",
+            Some(&ChainEntry {
+                chain_ref: chain_ref.to_string(),
+                message: "entry 3".to_string(),
+            }),
+            |_| Ok(b"1".to_vec()),
+        )?;
+        repo.update_refs(&[("refs/3/narinfo", blob)], None)?;
+
+        assert_eq!(repo.get_oid_from_reference("refs/2/narinfo"), Some(blob));
+        assert!(repo.reference_exists("refs/3/narinfo")?);
+        let mut messages = Vec::new();
+        repo.for_each_chain_entry(chain_ref, |message| {
+            messages.push(message.to_string());
+            Ok(messages.len() < 3)
+        })?;
+        assert_eq!(messages, ["entry 3", "entry 2", "entry 1"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_locked_reference() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("repo");
+        let repo = GitRepo::new(&path)?;
+        let blob = repo.add_file_content(b"narinfo")?;
+        // left behind by another process which is writing the reference
+        fs::create_dir_all(path.join(".git/refs/test"))?;
+        fs::write(path.join(".git/refs/test/locked.lock"), "")?;
+        assert!(matches!(
+            repo.add_ref("refs/test/locked", blob),
+            Err(Error::RepositoryLocked(_))
+        ));
+        Ok(())
+    }
+
+    fn collect_nar(stream: NarGitStream) -> Result<Vec<u8>> {
+        let chunks = futures::executor::block_on_stream(stream).collect::<Result<Vec<_>>>()?;
+        Ok(chunks.concat())
+    }
+
+    #[test]
+    fn test_add_nar_as_pack() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let package_path = create_random_package(temp_dir.path())?;
+        for i in 0..20 {
+            fs::write(package_path.join(format!("file{i}")), vec![i; 1000])?;
+        }
+        let source = GitRepo::new(&temp_dir.path().join("source"))?;
+        let tree_oid = source.add_dir(&package_path)?;
+        let nar = collect_nar(source.get_entry_as_nar(tree_oid)?.unwrap())?;
+
+        // 21 blobs and a tree, of which the tree and the first 10 files fit into the mixed budget
+        for (budget, loose, packs) in [(0, 22, 0), (1 << 20, 0, 1), (10_000, 11, 1)] {
+            let repo = GitRepo::new(&temp_dir.path().join(budget.to_string()))?.with_git_settings(
+                GitSettings {
+                    ingest_pack_budget: budget,
+                    ..GitSettings::default()
+                },
+            )?;
+            // the same objects as without batching
+            assert_eq!(
+                repo.add_nar(nar.as_slice())?,
+                (tree_oid, FileMode::Tree.into())
+            );
+            assert_eq!(
+                (repo.loose_object_count()?, repo.pack_count()?),
+                (loose, packs)
+            );
+            assert_eq!(collect_nar(repo.get_entry_as_nar(tree_oid)?.unwrap())?, nar);
+            // objects which exist are not written again
+            repo.add_nar(nar.as_slice())?;
+            assert_eq!(
+                (repo.loose_object_count()?, repo.pack_count()?),
+                (loose, packs)
+            );
+        }
+
+        // a broken archive leaves no pack behind
+        let repo = GitRepo::new(&temp_dir.path().join("broken"))?;
+        assert!(repo.add_nar(&nar[..nar.len() - 8]).is_err());
+        assert_eq!(repo.pack_count()?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_streaming_and_ingestion() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = GitRepo::new(&temp_dir.path().join("repo"))?;
+        let package_path = create_random_package(temp_dir.path())?;
+        fs::create_dir(package_path.join("lib"))?;
+        for i in 0..20 {
+            fs::write(
+                package_path.join("lib").join(i.to_string()),
+                vec![i; 10_000],
+            )?;
+        }
+        let tree_oid = repo.add_dir(&package_path)?;
+        let nar = collect_nar(repo.get_entry_as_nar(tree_oid)?.unwrap())?;
+
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..8)
+                .map(|worker| {
+                    let repo = repo.clone();
+                    let nar = &nar;
+                    scope.spawn(move || -> Result<()> {
+                        for round in 0..20 {
+                            if worker % 2 == 0 {
+                                let stream = repo.get_entry_as_nar(tree_oid)?.unwrap();
+                                assert_eq!(&collect_nar(stream)?, nar);
+                            } else {
+                                let added = repo.add_nar(nar.as_slice())?;
+                                assert_eq!(added, (tree_oid, FileMode::Tree.into()));
+                                let content = format!("{worker} {round}");
+                                let blob = repo.add_file_content(content.as_bytes())?;
+                                repo.add_ref(&format!("refs/stress/{worker}/{round}"), blob)?;
+                            }
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .try_for_each(|worker| worker.join().unwrap())
+        })?;
+
+        assert_eq!(repo.list_references("refs/stress/*")?.len(), 80);
+        // streams can be created on one thread and consumed on another
+        let stream = repo.get_entry_as_nar(tree_oid)?.unwrap();
+        let streamed = std::thread::spawn(move || collect_nar(stream))
+            .join()
+            .unwrap()?;
+        assert_eq!(streamed, nar);
+        Ok(())
+    }
+}
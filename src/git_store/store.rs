@@ -1,112 +1,457 @@
-use super::SINGLE_FILE_PACKAGE_MARKER;
+use super::add_summary::{AddOutcome, AddSummary, IngestTimings};
+use super::audit::{AuditEntry, AuditFilter, AuditOperation, AuditOutcome};
+use super::error::{Error, Result};
+use super::known_packages::KnownPackages;
+use super::name_index::NameIndex;
+use super::narinfo_cache::{NarinfoCache, NarinfoCacheStats};
+use super::options::StoreOptions;
+use super::package_diff::PackageDiff;
+use super::package_locks::PackageLocks;
+use super::ref_snapshot::{self, PackageRefs, RefSnapshot};
+use super::{METADATA_REF_PREFIX, SINGLE_FILE_PACKAGE_MARKER};
+use std::cell::RefCell;
+use std::collections::BinaryHeap;
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::collections::VecDeque;
-use std::fs;
-use std::str::FromStr;
+use std::env;
+use std::io::{BufReader, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
+use crate::client::BinaryCacheClient;
 use crate::git_store::GitRepo;
+use crate::git_store::repository::ChainEntry;
 use crate::nar::NarGitStream;
+use crate::nar::compression::{Compression, decompress};
+use crate::nar::hashing::{HashingReader, NarDigest, digest_nar_stream};
+use crate::nix_interface::backend::NixBackend;
 use crate::nix_interface::daemon::DynNixDaemon;
 use crate::nix_interface::daemon::NixDaemon;
+use crate::nix_interface::daemon::{HostKeyPolicy, SshOptions};
+use crate::nix_interface::daemon_pool::{DaemonPool, PooledDaemon};
+use crate::nix_interface::derivation::Derivation;
 use crate::nix_interface::nar_info::NarInfo;
 use crate::nix_interface::path::NixPath;
-use crate::nix_interface::signature::PrivateKey;
 use crate::nix_interface::signature::fingerprint_store_object;
-use crate::settings;
-use anyhow::{anyhow, bail};
+use crate::nix_interface::signature::{PublicKey, SigStatus};
+use crate::nix_interface::signer::NarSigner;
+use crate::settings::{self, RemoteConfig, RemoteKind};
+use anyhow::{Context, anyhow};
 use async_recursion::async_recursion;
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
+use bytes::Bytes;
+use futures::{StreamExt, stream};
 use git2::FileMode;
 use git2::Oid;
-use tracing::{debug, info, warn};
+use nix_daemon::BuildResultStatus;
+use regex::Regex;
+use tokio_util::io::{StreamReader, SyncIoBridge};
+use tracing::{Instrument, debug, debug_span, info, instrument, warn};
 
-use anyhow::Result;
+// Narinfos signed with one call of the signer by `sign_all`
+const SIGN_BATCH_SIZE: usize = 256;
 
-#[derive(Clone)]
-pub struct Store {
+/// A `.ls` listing as stored in the repository or freshly generated
+pub enum Listing {
+    Zstd(Vec<u8>),
+    Json(String),
+}
+
+/// Which entries `Store::list_entries` returns
+#[derive(Debug, Default, Clone)]
+pub struct ListOptions {
+    /// Number of entries skipped at the start
+    pub offset: usize,
+    pub limit: Option<usize>,
+    /// Only list packages whose name matches this regular expression
+    pub name_filter: Option<String>,
+}
+
+/// How a package compares to the same store path on a git remote, see `Store::verify_remote`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RemoteMatch {
+    /// The remote doesn't have the package
+    Missing,
+    /// Both have the same result commit
+    Identical,
+    /// The commits differ, e.g. by their builder, but the trees are the same
+    SameTree,
+    /// The commits differ, the trees are only compared by a deep check
+    DifferentCommit,
+    /// The same NAR is stored as different trees, so the peers can't share its objects.
+    /// The ingestion was not deterministic, or the peers chunk large files differently.
+    Divergent,
+    /// The remote has a different NAR under the same store path, e.g. of a build which is not reproducible
+    DifferentNar,
+}
+
+impl std::fmt::Display for RemoteMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            RemoteMatch::Missing => "missing on the remote",
+            RemoteMatch::Identical => "identical",
+            RemoteMatch::SameTree => "same tree",
+            RemoteMatch::DifferentCommit => "different commit",
+            RemoteMatch::Divergent => "same NAR stored as a different tree",
+            RemoteMatch::DifferentNar => "different NAR",
+        };
+        write!(f, "{description}")
+    }
+}
+
+/// Where a cached package came from and what happened to it since, see `Store::package_history`
+#[derive(Debug, Clone)]
+pub struct PackageHistory {
+    pub narinfo: NarInfo,
+    pub commit: Oid,
+    /// The entries of the audit log about the package, the oldest first
+    pub entries: Vec<AuditEntry>,
+    /// The cached packages which list the package as a reference
+    pub reverse_dependencies: Vec<String>,
+}
+
+impl PackageHistory {
+    /// How often the package was added again after the first time, e.g. by another process
+    /// which raced this one, and how often it was signed
+    pub fn changes(&self) -> (usize, usize) {
+        let (signed, ingested): (Vec<_>, Vec<_>) = self
+            .entries
+            .iter()
+            .filter(|entry| entry.outcome == AuditOutcome::Ok)
+            .partition(|entry| entry.operation == AuditOperation::Sign);
+        (ingested.len().saturating_sub(1), signed.len())
+    }
+}
+
+/// A package taken from a Nix daemon, which is not committed yet
+pub struct DaemonPackage {
+    pub narinfo: NarInfo,
+    pub narinfo_blob_oid: Oid,
+    pub package_oid: Oid,
+    /// The address of the remote daemon the package came from
+    pub builder: Option<String>,
+    pub timings: IngestTimings,
+}
+
+/// The packages in the Git repository, added from the Nix daemons `B`
+pub struct Store<B = DynNixDaemon> {
     settings: settings::Store,
     repo: GitRepo,
-    private_key: Option<PrivateKey>,
+    refs: Arc<RefSnapshot>,
+    known_packages: Arc<KnownPackages>,
+    narinfos: Arc<NarinfoCache>,
+    package_locks: Arc<PackageLocks>,
+    // Replaced as a whole when the settings are reloaded
+    options: Arc<RwLock<Arc<StoreOptions>>>,
+    // Index of the builder which is tried first for the next remote build
+    next_builder: Arc<AtomicUsize>,
+    local_pool: Option<DaemonPool<B>>,
+    // One pool per entry of `settings.builders`, in the same order
+    builder_pools: Vec<DaemonPool<B>>,
+    // Who is recorded in the audit log
+    actor: String,
+}
+
+// Not derived, as that would require the daemon to be `Clone`
+impl<B> Clone for Store<B> {
+    fn clone(&self) -> Self {
+        Self {
+            settings: self.settings.clone(),
+            repo: self.repo.clone(),
+            refs: self.refs.clone(),
+            known_packages: self.known_packages.clone(),
+            narinfos: self.narinfos.clone(),
+            package_locks: self.package_locks.clone(),
+            options: self.options.clone(),
+            next_builder: self.next_builder.clone(),
+            local_pool: self.local_pool.clone(),
+            builder_pools: self.builder_pools.clone(),
+            actor: self.actor.clone(),
+        }
+    }
 }
 
 impl Store {
     pub fn new(settings: settings::Store) -> Result<Self> {
-        let repo = GitRepo::new(&settings.path)?;
+        let local_pool = settings.use_local_nix_daemon.then(|| {
+            let daemon_settings = settings.clone();
+            DaemonPool::new(
+                local_daemon(&settings).get_address(),
+                settings.daemon_pool,
+                move || Ok(local_daemon(&daemon_settings)),
+            )
+        });
+        let builder_pools = settings
+            .builders
+            .iter()
+            .map(|builder| {
+                let daemon_settings = settings.clone();
+                let builder = builder.clone();
+                DaemonPool::new(builder.to_string(), settings.daemon_pool, move || {
+                    remote_daemon(&daemon_settings, &builder)
+                })
+            })
+            .collect();
+        Self::with_pools(settings, local_pool, builder_pools)
+    }
 
-        let private_key = if let Some(key_path) = &settings.sign_private_key_path {
-            let key = PrivateKey::from_str(&fs::read_to_string(key_path)?)?;
-            info!(
-                "Using private key located at: {:?}",
-                fs::canonicalize(key_path)?
-            );
-            Some(key)
-        } else {
-            None
+    /// Imports a cached package into the Nix store of this machine, e.g. after the Nix store
+    /// collected it as garbage. Its references must be valid in the Nix store already.
+    pub async fn import_into_nix_store(&self, package_id: &str) -> Result<()> {
+        let narinfo = self.get_parsed_narinfo(package_id)?;
+        let nar = self
+            .package_nar_stream(self.package_oid(package_id)?)?
+            .ok_or_else(|| anyhow!("Could not find the NAR of {}", package_id))?;
+        let nar = StreamReader::new(nar.map(|chunk| chunk.map_err(std::io::Error::other)));
+        let mut nix = local_daemon(&self.settings);
+        nix.connect().await?;
+        nix.add_to_store_nar(&narinfo, nar, false, true).await?;
+        Ok(())
+    }
+}
+
+impl<B: NixBackend> Store<B> {
+    /// A store whose local daemon and builders are all served by clones of `backend`
+    pub fn with_backend(settings: settings::Store, backend: B) -> Result<Self>
+    where
+        B: Clone + Sync,
+    {
+        let pool = |address: String| {
+            let backend = backend.clone();
+            DaemonPool::new(address, settings.daemon_pool, move || Ok(backend.clone()))
         };
+        let local_pool = settings
+            .use_local_nix_daemon
+            .then(|| pool(backend.get_address()));
+        let builder_pools = settings
+            .builders
+            .iter()
+            .map(|builder| pool(builder.to_string()))
+            .collect();
+        Self::with_pools(settings, local_pool, builder_pools)
+    }
+
+    fn with_pools(
+        settings: settings::Store,
+        local_pool: Option<DaemonPool<B>>,
+        builder_pools: Vec<DaemonPool<B>>,
+    ) -> Result<Self> {
+        let repo = GitRepo::new(&settings.path)?
+            .with_chunk_threshold(settings.chunk_threshold)
+            .with_nar_limits(settings.nar_limits)
+            .with_case_collision_warnings(settings.warn_case_collisions)
+            .with_git_settings(settings.git)?;
+
+        let options = StoreOptions::new(&settings)?;
+        let narinfos = NarinfoCache::new(settings.narinfo_cache);
+        let known_packages = KnownPackages::new(
+            repo.git_dir(),
+            &options.ref_namespace,
+            ref_snapshot::DEFAULT_TTL,
+        );
 
         let store = Self {
             settings,
             repo,
-            private_key,
+            refs: Arc::new(RefSnapshot::new(ref_snapshot::DEFAULT_TTL)),
+            known_packages: Arc::new(known_packages),
+            narinfos: Arc::new(narinfos),
+            package_locks: Arc::default(),
+            options: Arc::new(RwLock::new(Arc::new(options))),
+            next_builder: Arc::new(AtomicUsize::new(0)),
+            local_pool,
+            builder_pools,
+            actor: env::var("USER").unwrap_or_else(|_| "unknown".to_string()),
         };
-        info!(
-            "Repository contains {} packages",
-            store.num_available_packages()?
-        );
+        // Enumerating all package refs is too slow for large repositories, so only the cached count is used
+        match store.cached_package_count()? {
+            Some(num_packages) => info!("Repository contains {} packages", num_packages),
+            None => debug!("Repository has no cached package count"),
+        }
         Ok(store)
     }
 
-    pub fn available_daemons(&self) -> Result<Vec<DynNixDaemon>> {
-        let mut daemons = Vec::new();
-        if self.settings.use_local_nix_daemon {
-            daemons.push(DynNixDaemon::Local(NixDaemon::local()));
+    /// Sets who is recorded in the audit log, the user running gachix by default
+    pub fn with_actor(mut self, actor: impl Into<String>) -> Self {
+        self.actor = actor.into();
+        self
+    }
+
+    /// The options currently in effect, a later `reload` does not change them
+    fn options(&self) -> Arc<StoreOptions> {
+        self.options.read().unwrap().clone()
+    }
+
+    /// Applies the signers, trusted keys, remotes, closure depth and listing compression of
+    /// `settings` to this store and all its clones. The other settings only take effect when
+    /// the store is opened again. If `settings` are invalid, the current options stay in effect.
+    pub fn reload(&self, settings: &settings::Store) -> Result<()> {
+        let current = self.options();
+        let mut options = StoreOptions::new(settings)?;
+        options.store_config = current.store_config.clone();
+        options.ref_namespace = current.ref_namespace.clone();
+        *self.options.write().unwrap() = Arc::new(options);
+        Ok(())
+    }
+
+    /// Pools of the local Nix daemon, if enabled, followed by those of the builders
+    fn daemon_pools(&self) -> impl Iterator<Item = &DaemonPool<B>> {
+        self.local_pool.iter().chain(self.builder_pools.iter())
+    }
+
+    /// Resolves a store path or the bare hash part of one, using the cached narinfos and then the Nix daemons
+    pub async fn resolve_store_path(&self, path_or_hash: &str) -> Result<NixPath> {
+        if !NixPath::is_hash_part(path_or_hash) {
+            return Ok(NixPath::new(path_or_hash)?);
         }
-        if self.settings.builders.is_empty() {
-            return Ok(daemons);
+        if self.get_narinfo(path_or_hash)?.is_some() {
+            return Ok(self.get_parsed_narinfo(path_or_hash)?.store_path);
+        }
+        for pool in self.daemon_pools() {
+            let mut daemon = match pool.get().await {
+                Ok(daemon) => daemon,
+                Err(e) => {
+                    warn!("Skipping Nix daemon at {}: {}", pool.get_address(), e);
+                    continue;
+                }
+            };
+            if let Some(path) = daemon.query_path_from_hash_part(path_or_hash).await? {
+                return Ok(path);
+            }
+        }
+        Err(anyhow!("No store path with hash {} is known", path_or_hash).into())
+    }
+
+    /// Builds the derivation on a remote builder supporting its platform and returns its outputs,
+    /// only the selected one for paths like `<drv path>!dev`.
+    /// Builders are tried round-robin, the local Nix daemon is used once all of them failed.
+    pub async fn build_missing(&self, drv_path: &NixPath) -> Result<Vec<NixPath>> {
+        let derivation = Derivation::from_store(drv_path)?;
+        if let Some(output) = drv_path.output_name()
+            && !derivation.outputs.contains_key(output)
+        {
+            return Err(anyhow!("{} has no output {}", drv_path.without_output(), output).into());
+        }
+        let outputs = derivation
+            .outputs
+            .iter()
+            .filter(|(name, _)| drv_path.output_name().is_none_or(|output| output == *name))
+            .map(|(_, path)| -> Result<NixPath> {
+                if path.is_empty() {
+                    return Err(
+                        anyhow!("Building content-addressed derivations is not supported").into(),
+                    );
+                }
+                Ok(NixPath::new(path)?)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if outputs
+            .iter()
+            .all(|output| self.has_package(output.get_base_32_hash()))
+        {
+            debug!("All outputs of {} already exist", drv_path.get_name());
+            return Ok(outputs);
+        }
+
+        let builders = &self.settings.builders;
+        let start = self.next_builder.fetch_add(1, Ordering::Relaxed);
+        let candidates = (0..builders.len())
+            .map(|i| (start + i) % builders.len())
+            .filter(|&i| builders[i].supports_system(&derivation.system));
+        for i in candidates {
+            let builder = &builders[i];
+            match self.build_on(&self.builder_pools[i], drv_path).await {
+                Ok(()) => {
+                    info!("Built {} on {}", drv_path.get_name(), builder);
+                    return Ok(outputs);
+                }
+                Err(e) => warn!(
+                    "Failed to build {} on {}: {}",
+                    drv_path.get_name(),
+                    builder,
+                    e
+                ),
+            }
         }
-        let key_file = self.settings.ssh_private_key_path.as_ref().ok_or_else(|| {
-            anyhow!("Path to private ssh key must be specified when using remote Nix daemons")
-        })?;
 
-        for url in &self.settings.builders {
-            daemons.push(DynNixDaemon::Remote(NixDaemon::remote(
-                &url.host_str().unwrap(),
-                key_file.clone(),
-            )));
+        let no_builder = || -> Error {
+            anyhow!(
+                "No builder for {} could build {}",
+                derivation.system,
+                drv_path
+            )
+            .into()
+        };
+        if !self.settings.use_local_nix_daemon {
+            return Err(no_builder());
+        }
+        let Some(local_pool) = &self.local_pool else {
+            return Err(no_builder());
+        };
+        info!("Building {} with the local Nix daemon", drv_path.get_name());
+        self.build_on(local_pool, drv_path).await?;
+        Ok(outputs)
+    }
+
+    async fn build_on(&self, pool: &DaemonPool<B>, drv_path: &NixPath) -> Result<()> {
+        let mut daemon = pool.get().await?;
+        let results = daemon.build(&[drv_path]).await?;
+        for result in results.values() {
+            if !matches!(
+                result.status,
+                BuildResultStatus::Built
+                    | BuildResultStatus::Substituted
+                    | BuildResultStatus::AlreadyValid
+                    | BuildResultStatus::ResolvesToAlreadyValid
+            ) {
+                return Err(anyhow!(
+                    "Build failed with {:?}: {}",
+                    result.status,
+                    result.error_msg
+                )
+                .into());
+            }
         }
-        Ok(daemons)
+        Ok(())
     }
 
     pub async fn peer_health_check(&self) -> bool {
         let mut success = true;
 
-        for mut daemon in self.available_daemons().unwrap() {
-            match daemon.connect().await {
+        for pool in self.daemon_pools() {
+            match pool.get().await {
                 Ok(_) => info!(
                     "Succesfully connected to Nix daemon at {}",
-                    daemon.get_address()
+                    pool.get_address()
                 ),
                 Err(e) => {
                     success = false;
                     warn!(
-                        "Failed to connect to remote Nix daemon at {} : {}",
-                        daemon.get_address(),
+                        "Failed to connect to Nix daemon at {}: {}",
+                        pool.get_address(),
                         e
                     )
                 }
             };
-            daemon.disconnect();
         }
 
-        for url in &self.settings.remotes {
-            let url_str = url.as_str();
-            let host = url.host().unwrap();
-            match self.repo.check_remote_health(&url_str) {
-                Ok(_) => info!("Succesfully connected to Git repository at {}", host),
+        for remote in &self.options().remotes {
+            let result = match remote.kind {
+                RemoteKind::Git => self.repo.check_remote_health(remote),
+                RemoteKind::Http => BinaryCacheClient::new(remote.url.clone())
+                    .get_cache_info()
+                    .await
+                    .map(|_| ())
+                    .map_err(Error::from),
+            };
+            match result {
+                Ok(_) => info!("Succesfully connected to remote {}", remote),
                 Err(e) => {
                     success = false;
-                    warn!("Failed to connect to Git repository {}: {}", host, e)
+                    warn!("Failed to connect to remote {}: {}", remote, e)
                 }
             }
         }
@@ -114,387 +459,3015 @@ impl Store {
         success
     }
 
-    pub async fn add_single(&self, package_path: &NixPath) -> Result<()> {
+    #[instrument(skip_all, fields(package_hash = package_path.get_base_32_hash()))]
+    pub async fn add_single(&self, package_path: &NixPath) -> Result<AddSummary> {
         info!("Adding single package {}", package_path.get_name());
         let package_id = package_path.get_base_32_hash();
+        let _lock = self.package_locks.lock(package_id).await;
+        let mut summary = AddSummary::default();
 
         let narinfo_ref = self.get_narinfo_ref(package_id);
 
         if self.repo.reference_exists(&narinfo_ref)? {
             debug!("Package already exists");
-            return Ok(());
+            summary.record(
+                package_path,
+                AddOutcome::AlreadyPresent,
+                self.get_nar_size(package_id)?,
+            );
+            return Ok(summary);
         }
 
-        let Ok(Some((_, narinfo_blob_oid, _))) =
-            self.get_package_from_nix_daemons(package_path).await
-        else {
-            bail!(
+        let Ok(Some(package)) = self.get_package_from_nix_daemons(package_path).await else {
+            return Err(anyhow!(
                 "There doesn't exist a Nix daemon which has {}",
                 package_path
-            );
+            )
+            .into());
         };
-        self.repo.add_ref(&narinfo_ref, narinfo_blob_oid)?;
-        Ok(())
+        // Without a commit the tree is only reachable through the NAR key
+        self.repo.update_refs(
+            &[
+                (&narinfo_ref, package.narinfo_blob_oid),
+                (&self.get_nar_ref(&package.narinfo.key), package.package_oid),
+            ],
+            self.audit_entry(AuditOperation::Add, package_id, AuditOutcome::Ok)
+                .as_ref(),
+        )?;
+        self.forget_package(package_id);
+        self.add_listing(package_id, package.package_oid);
+        package.timings.log(package_path);
+        summary.record_ingest(package_path, package.narinfo.nar_size, package.timings);
+        self.update_name_index(&summary)?;
+        Ok(summary)
+    }
+
+    /// Adds a package from a NAR which is not accompanied by a narinfo, e.g. one piped from
+    /// `nix nar dump-path`. The NAR may be compressed. Its references must already be cached.
+    #[instrument(skip_all, fields(package_hash = package_path.get_base_32_hash()))]
+    pub fn import_nar(
+        &self,
+        nar: impl Read + Send,
+        package_path: &NixPath,
+        references: Vec<NixPath>,
+        deriver: Option<NixPath>,
+    ) -> Result<AddSummary> {
+        info!("Importing package {}", package_path.get_name());
+        let package_id = package_path.get_base_32_hash();
+        let _lock = self.package_locks.blocking_lock(package_id);
+        let mut summary = AddSummary::default();
+
+        if self
+            .repo
+            .reference_exists(&self.get_narinfo_ref(package_id))?
+        {
+            debug!("Package already exists");
+            summary.record(
+                package_path,
+                AddOutcome::AlreadyPresent,
+                self.get_nar_size(package_id)?,
+            );
+            return Ok(summary);
+        }
+
+        // In the order of `NarInfo::get_dependencies`, whatever the order they were given in
+        let mut dependencies: Vec<&NixPath> =
+            references.iter().filter(|r| *r != package_path).collect();
+        dependencies.sort_unstable_by(|a, b| a.get_path().cmp(b.get_path()));
+        dependencies.dedup();
+        let mut parent_commits = Vec::new();
+        for reference in dependencies {
+            let commit_oid = self
+                .get_commit(reference.get_base_32_hash())
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Reference {} of {} is not cached, it has to be added first",
+                        reference,
+                        package_path
+                    )
+                })?;
+            parent_commits.push(commit_oid);
+        }
+
+        let (nar, compression) = decompress(nar)?;
+        debug!("Importing {} NAR", compression.name());
+        let mut reader = HashingReader::new(nar);
+        let (package_oid, filemode) = self.repo.add_nar(&mut reader)?;
+        let digest = reader.digest();
+        let package_oid = self.package_tree(package_oid, filemode)?;
+
+        let nar_hash = digest.nix_hash();
+        let signatures = self.sign(package_path, &nar_hash, digest.size, &references)?;
+        // Served uncompressed, so the file is the NAR
+        let narinfo = NarInfo::builder()
+            .store_path(package_path.clone())
+            .key(nar_key(&nar_hash))
+            .file(nar_hash.clone(), digest.size)
+            .nar(nar_hash, digest.size)
+            .references(references)
+            .deriver(deriver)
+            .signatures(signatures)
+            .build()?;
+        let narinfo_blob_oid = self
+            .repo
+            .add_file_content(self.serialize_narinfo(&narinfo)?.as_bytes())?;
+
+        let commit_oid =
+            self.commit_package(package_oid, &parent_commits, package_path.get_name())?;
+        self.add_package_refs(
+            package_id,
+            commit_oid,
+            narinfo_blob_oid,
+            (&narinfo.key, package_oid),
+            AuditOperation::Import,
+        )?;
+        self.add_listing(package_id, package_oid);
+        summary.record(package_path, AddOutcome::Added, digest.size);
+        self.update_name_index(&summary)?;
+        Ok(summary)
     }
 
-    pub async fn add_closure(&self, package_path: &NixPath) -> Result<()> {
+    #[instrument(skip_all, fields(package_hash = package_path.get_base_32_hash()))]
+    pub async fn add_closure(&self, package_path: &NixPath) -> Result<AddSummary> {
         info!("Adding closure for {}", package_path.get_name());
-        let entries_before = self.num_available_packages()?;
-        match self._add_closure(package_path).await? {
-            Some(_) => {
-                let entries_after = self.num_available_packages()?;
-                let num_packages_added = entries_after - entries_before;
-                info!("Added {num_packages_added} packages")
-            }
-            None => bail!(
-                "Could not add closure of package {}",
-                package_path.get_name()
-            ),
+        let mut summary = AddSummary::default();
+        let cached_closure = self.cached_closure(package_path).unwrap_or_else(|e| {
+            debug!("Could not walk the cached closure: {}", e);
+            None
+        });
+        if let Some(closure) = cached_closure {
+            info!(
+                "{} is already cached ({} paths)",
+                package_path.get_name(),
+                closure.len()
+            );
+            for (path, nar_size) in &closure {
+                summary.record(path, AddOutcome::AlreadyPresent, *nar_size);
+            }
+            summary.already_cached = true;
+        } else if let Err(e) = self._add_closure(package_path, &mut summary, 0).await {
+            warn!("Failed to add {}: {}", package_path.get_name(), e);
+            summary.record(package_path, AddOutcome::Failed, 0);
+        }
+        if self.settings.all_outputs && self.has_package(package_path.get_base_32_hash()) {
+            for output in self.other_outputs(package_path).await? {
+                if let Err(e) = self._add_closure(&output, &mut summary, 0).await {
+                    warn!("Failed to add {}: {}", output.get_name(), e);
+                    summary.record(&output, AddOutcome::Failed, 0);
+                }
+            }
+        }
+        info!(
+            "Added {} packages",
+            summary.count(AddOutcome::Added) + summary.count(AddOutcome::FetchedFromPeer)
+        );
+        self.update_name_index(&summary)?;
+        self.audit_failures(AuditOperation::Add, &summary)?;
+        Ok(summary)
+    }
+
+    /// Rejects packages whose dependency chain is longer than `store.max_closure_depth`
+    fn check_closure_depth(&self, package_path: &NixPath, depth: usize) -> Result<()> {
+        if depth > self.options().max_closure_depth {
+            return Err(anyhow!(
+                "The dependency chain leading to {} is longer than store.max_closure_depth ({})",
+                package_path.get_name(),
+                self.options().max_closure_depth
+            )
+            .into());
         }
         Ok(())
     }
 
+    /// Walks the closure of `package_path` through the cached narinfos and returns its packages
+    /// with their NAR sizes, or `None` as soon as a package is missing
+    #[instrument(level = "debug", skip_all)]
+    fn cached_closure(&self, package_path: &NixPath) -> Result<Option<Vec<(NixPath, u64)>>> {
+        let mut narinfos = ClosureNarinfos::default();
+        let mut closure = Vec::new();
+        let mut seen = HashSet::from([package_path.get_base_32_hash().to_string()]);
+        let mut queue = VecDeque::from([package_path.clone()]);
+        while let Some(path) = queue.pop_front() {
+            let package_id = path.get_base_32_hash();
+            let refs = self.package_refs(package_id);
+            if refs.narinfo.is_none() || refs.result.is_none() {
+                debug!("{} is not cached yet", path.get_name());
+                return Ok(None);
+            }
+            let narinfo = narinfos.get(self, package_id)?;
+            for dependency in narinfo.get_dependencies() {
+                if seen.insert(dependency.get_base_32_hash().to_string()) {
+                    queue.push_back(dependency.clone());
+                }
+            }
+            closure.push((path, narinfo.nar_size));
+        }
+        Ok(Some(closure))
+    }
+
+    /// The outputs of the derivation which produced `package_path`, except `package_path` itself.
+    /// Outputs which are not valid in the store of the Nix daemon are skipped.
+    async fn other_outputs(&self, package_path: &NixPath) -> Result<Vec<NixPath>> {
+        let narinfo = self.get_parsed_narinfo(package_path.get_base_32_hash())?;
+        let Some(deriver) = &narinfo.deriver else {
+            debug!("{} has no known deriver", package_path.get_name());
+            return Ok(Vec::new());
+        };
+        for pool in self.daemon_pools() {
+            let mut daemon = match pool.get().await {
+                Ok(daemon) => daemon,
+                Err(e) => {
+                    warn!("Skipping Nix daemon at {}: {}", pool.get_address(), e);
+                    continue;
+                }
+            };
+            let outputs = match daemon.derivation_outputs(deriver).await {
+                Ok(outputs) if !outputs.is_empty() => outputs,
+                Ok(_) => continue,
+                Err(e) => {
+                    debug!(
+                        "Nix daemon at {} does not know {}: {}",
+                        daemon.get_address(),
+                        deriver,
+                        e
+                    );
+                    continue;
+                }
+            };
+            let others: Vec<NixPath> = outputs
+                .into_values()
+                .filter(|output| output != package_path)
+                .collect();
+            let valid = daemon.query_valid_paths(&others).await?;
+            for output in others.iter().filter(|output| !valid.contains(output)) {
+                info!(
+                    "Skipping output {} of {}, it was never built",
+                    output.get_name(),
+                    deriver.get_name()
+                );
+            }
+            return Ok(valid);
+        }
+        info!(
+            "No Nix daemon knows the outputs of {}, only adding {}",
+            deriver.get_name(),
+            package_path.get_name()
+        );
+        Ok(Vec::new())
+    }
+
+    /// Adds the package after its dependencies, `depth` is the length of the dependency chain
+    /// which led to it
     #[async_recursion]
-    pub async fn _add_closure(&self, package_path: &NixPath) -> Result<Option<Oid>> {
+    pub async fn _add_closure(
+        &self,
+        package_path: &NixPath,
+        summary: &mut AddSummary,
+        depth: usize,
+    ) -> Result<Option<Oid>> {
         let package_id = package_path.get_base_32_hash();
+        // Held while the dependencies are added, so a concurrent add of the same package
+        // waits and then finds it
+        let _lock = self.package_locks.lock(package_id).await;
 
         // Check if commit already exists locally
         if let Some(commit_oid) = self.get_commit(package_id) {
             debug!("Package already exists: {}", package_path.get_name());
+            if !summary.contains(package_path) {
+                summary.record(
+                    package_path,
+                    AddOutcome::AlreadyPresent,
+                    self.get_nar_size(package_id)?,
+                );
+            }
             return Ok(Some(commit_oid));
         }
+        self.check_closure_depth(package_path, depth)?;
 
         // Ask Git peers if they have replicated the package
-        if let Some(commit_oid) = self.get_package_commit_from_git_remotes(package_path)? {
+        if let Some(commit_oid) = self.get_package_commit_from_git_remotes(package_path, summary)? {
             return Ok(Some(commit_oid));
         }
 
         // Ask known Nix daemons if they can build the package
-        let Ok(Some((narinfo, narinfo_blob_oid, package_oid))) =
-            self.get_package_from_nix_daemons(package_path).await
-        else {
+        let Ok(Some(package)) = self.get_package_from_nix_daemons(package_path).await else {
+            summary.record(package_path, AddOutcome::Failed, 0);
             return Ok(None);
         };
 
         // Recurse into package dependecies and collect their commit oids
-        let deps = narinfo.get_dependencies();
+        // A failed dependency does not stop its siblings from being added,
+        // but the package itself can only be committed once all of them exist
+        let deps = package.narinfo.get_dependencies();
         let mut parent_commits = Vec::new();
+        let mut missing_dependency = false;
         for dependency in &deps {
-            let Some(dep_coid) = self._add_closure(&dependency).await? else {
-                return Ok(None);
-            };
-            parent_commits.push(dep_coid);
+            match self._add_closure(dependency, summary, depth + 1).await {
+                Ok(Some(dep_coid)) => parent_commits.push(dep_coid),
+                Ok(None) => missing_dependency = true,
+                Err(e) => {
+                    warn!("Failed to add {}: {}", dependency.get_name(), e);
+                    summary.record(dependency, AddOutcome::Failed, 0);
+                    missing_dependency = true;
+                }
+            }
+        }
+        if missing_dependency {
+            summary.record(package_path, AddOutcome::Failed, 0);
+            return Ok(None);
         }
 
         // Commit the package tree and specify dependency commits as parents
-        let commit_oid =
-            self.repo
-                .commit(package_oid, &parent_commits, Some(package_path.get_name()))?;
+        // and record which remote builder produced the package
+        let message = match &package.builder {
+            Some(builder) => format!("{}\n\nBuilt-by: {}", package_path.get_name(), builder),
+            None => package_path.get_name().to_string(),
+        };
+        let start = Instant::now();
+        let commit_oid = self.commit_package(package.package_oid, &parent_commits, &message)?;
+        self.add_package_refs(
+            package_id,
+            commit_oid,
+            package.narinfo_blob_oid,
+            (&package.narinfo.key, package.package_oid),
+            AuditOperation::Add,
+        )?;
+        let mut timings = package.timings;
+        timings.tree += start.elapsed();
 
-        // Add references: nix-hash -> package-commit-oid, nix-hash -> narinfo-blob-oid
-        self.repo
-            .add_ref(&self.get_result_ref(package_id), commit_oid)?;
-        self.repo
-            .add_ref(&self.get_narinfo_ref(package_id), narinfo_blob_oid)?;
+        self.add_listing(package_id, package.package_oid);
+        timings.log(package_path);
+        summary.record_ingest(package_path, package.narinfo.nar_size, timings);
         Ok(Some(commit_oid))
     }
 
+    #[instrument(skip_all, fields(package_hash = package_path.get_base_32_hash()))]
     pub async fn get_package_from_nix_daemons(
         &self,
         package_path: &NixPath,
-    ) -> Result<Option<(NarInfo, Oid, Oid)>> {
-        for mut daemon in self.available_daemons()? {
-            daemon.connect().await?;
-            // Ask if daemon has the package
-            // TODO: ask it to build the package if it does not have it
-            if !daemon.path_exists(package_path).await? {
-                continue;
+    ) -> Result<Option<DaemonPackage>> {
+        let mut missing_on = Vec::new();
+        for pool in self.daemon_pools() {
+            // An unreachable daemon should not prevent the others from providing the package
+            let mut daemon = match pool.get().await {
+                Ok(daemon) => daemon,
+                Err(e) => {
+                    warn!("Skipping Nix daemon at {}: {}", pool.get_address(), e);
+                    continue;
+                }
             };
-            // Add the package contents to the Git database
-            let clone = self.repo.clone();
-            let (mut package_oid, filemode) = daemon
-                .fetch(package_path, move |r| Ok(clone.add_nar(r)?))
-                .await?;
-
-            // Handle single file packages
-            // Commits can only point to trees therefore we need to wrap the blob in a special tree
-            if filemode != i32::from(FileMode::Tree) {
-                package_oid = self.repo.add_single_entry_tree(
-                    package_oid,
-                    SINGLE_FILE_PACKAGE_MARKER,
-                    filemode,
-                )?;
-            }
-
-            // Get metadata info about the package and add it to the Git database
-            let narinfo = self
-                .build_narinfo(&mut daemon, package_oid.to_string().as_str(), package_path)
-                .await?;
-            let narinfo_blob_oid = self.repo.add_file_content(narinfo.to_string().as_bytes())?;
-
-            match &daemon {
-                DynNixDaemon::Local(_) => {
-                    debug!("Using local daemon, fetched {} ", package_path.get_name())
+            // Ask if daemon has the package
+            match daemon.path_exists(package_path).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    missing_on.push(daemon);
+                    continue;
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to query Nix daemon at {}: {}",
+                        daemon.get_address(),
+                        e
+                    );
+                    continue;
                 }
-                DynNixDaemon::Remote(daemon) => debug!(
-                    "Using daemon at {}, fetched package {}",
-                    daemon.get_address(),
-                    package_path.get_name()
-                ),
             }
-            daemon.disconnect();
-            return Ok(Some((narinfo, narinfo_blob_oid, package_oid)));
+            return self
+                .ingest_from_daemon(daemon, package_path)
+                .await
+                .map(Some);
         }
-        Ok(None)
-    }
 
-    fn get_package_commit_from_git_remotes(&self, store_path: &NixPath) -> Result<Option<Oid>> {
-        let package_id = store_path.get_base_32_hash();
-        let mut commit_oid = None;
-        let mut success_remote = "";
-        for remote_url in &self.settings.remotes {
-            let url = remote_url.as_str();
-            if let Some(oid) = self.fetch_from_remote(package_id, url)? {
-                debug!(
-                    "Using git peer at {}, fetched package {}",
-                    remote_url,
-                    store_path.get_name()
+        // Only substitute once no daemon has the package, since it fills up the daemon's store
+        if self.settings.allow_substitute {
+            for mut daemon in missing_on {
+                info!(
+                    "Asking Nix daemon at {} to substitute {}",
+                    daemon.get_address(),
+                    package_path.get_name()
                 );
-                commit_oid = Some(oid);
-                success_remote = url;
-                break;
-            }
-        }
-        if commit_oid == None {
-            return Ok(None);
-        }
-
-        let mut open = VecDeque::new();
-        let mut visited = HashSet::new();
-        open.push_back(package_id.to_string());
-        visited.insert(package_id.to_string());
-        while let Some(id) = open.pop_front() {
-            for dep in self.get_dep_ids(&id)? {
-                let dep_hash = dep.get_base_32_hash();
-                if !visited.contains(dep_hash) {
-                    if !(self.repo.reference_exists(&self.get_result_ref(dep_hash))?
-                        && self
-                            .repo
-                            .reference_exists(&self.get_narinfo_ref(dep_hash))?)
-                    {
-                        self.fetch_from_remote(dep_hash, success_remote)?;
-                        debug!(
-                            "Using git peer at {}, fetched package {}",
-                            success_remote,
-                            dep.get_name()
-                        );
+                match daemon.substitute(package_path).await {
+                    Ok(()) => {
+                        return self
+                            .ingest_from_daemon(daemon, package_path)
+                            .await
+                            .map(Some);
                     }
-                    // TODO: do I need to add to open queue if references already exist?
-                    open.push_back(dep_hash.to_string());
-                    visited.insert(dep_hash.to_string());
+                    Err(e) => warn!(
+                        "Nix daemon at {} could not substitute {}: {:#}",
+                        daemon.get_address(),
+                        package_path.get_name(),
+                        e
+                    ),
                 }
             }
         }
-
-        Ok(commit_oid)
-    }
-
-    fn fetch_from_remote(&self, package_id: &str, remote: &str) -> Result<Option<Oid>> {
-        if let Some(()) = self
-            .repo
-            .fetch(&remote, &format!("{}/*", self.get_package_ref(package_id)))?
-        {
-            let oid = self
-                .get_commit(package_id)
-                .ok_or_else(|| anyhow!("Could not get commit id for {}", package_id))?;
-            return Ok(Some(oid));
-        }
         Ok(None)
     }
 
-    fn get_dep_ids(&self, package_id: &str) -> Result<Vec<NixPath>> {
-        let narinfo_blob = self
-            .get_narinfo(package_id)?
-            .ok_or_else(|| anyhow!("Could not find narinfo for {}", package_id))?;
-        let narinfo = NarInfo::parse(&String::from_utf8_lossy(&narinfo_blob).to_string())?;
-        let dependencies = narinfo.get_dependencies();
-        Ok(dependencies.into_iter().cloned().collect())
-    }
-
-    async fn build_narinfo(
+    /// Adds the package, which must be valid in the store of the connected `daemon`, to the Git database
+    async fn ingest_from_daemon(
         &self,
-        nix_daemon: &mut DynNixDaemon,
-        key: &str,
-        store_path: &NixPath,
-    ) -> Result<NarInfo> {
-        let Some(path_info) = nix_daemon.get_pathinfo(&store_path).await? else {
-            return Err(anyhow!(
-                "Could not find narinfo for {}",
-                store_path.get_path()
-            ));
-        };
-        let references: Vec<NixPath> = path_info
-            .references
-            .iter()
-            .map(|p| NixPath::new(p))
-            .collect::<Result<Vec<_>, _>>()?;
+        mut daemon: PooledDaemon<B>,
+        package_path: &NixPath,
+    ) -> Result<DaemonPackage> {
+        let mut timings = IngestTimings::default();
 
-        let nar_size = path_info.nar_size;
-        let nar_hash = hex::decode(path_info.nar_hash)?;
+        // Add the package contents to the Git database
+        let clone = self.repo.clone();
+        let (package_oid, filemode, received, decoded, waited) = daemon
+            .fetch(package_path, move |r| {
+                let start = Instant::now();
+                // The decoder reads a few bytes at a time, the clock is only read per chunk
+                let mut timed = BufReader::new(WaitTimingReader {
+                    inner: r,
+                    waited: Duration::ZERO,
+                });
+                let mut reader = HashingReader::new(&mut timed);
+                let (oid, filemode) = clone.add_nar(&mut reader)?;
+                let digest = reader.digest();
+                Ok((
+                    oid,
+                    filemode,
+                    digest,
+                    start.elapsed(),
+                    timed.get_ref().waited,
+                ))
+            })
+            .instrument(debug_span!("fetch_nar"))
+            .await?;
+        timings.fetch = waited;
+        timings.decode = decoded.saturating_sub(waited);
+        timings.bytes = received.size;
 
-        // TODO: compute hash instead of copying it and verify it against the received hash
-        let mut nar_hash_32_base = nix_base32::to_nix_base32(&nar_hash);
-        // TODO: formatting should be handled by the NarInfo struct
-        nar_hash_32_base = format!("sha256:{}", nar_hash_32_base);
+        let start = Instant::now();
+        let package_oid =
+            debug_span!("package_tree").in_scope(|| self.package_tree(package_oid, filemode))?;
+        timings.tree = start.elapsed();
 
-        let signature = self.private_key.as_ref().map(|private_key| {
-            let fingerprint =
-                fingerprint_store_object(store_path, &nar_hash_32_base, nar_size, &references);
-            let signature_bytes = private_key.sign(fingerprint.as_bytes());
+        // Get metadata info about the package and add it to the Git database
+        let start = Instant::now();
+        let narinfo = self
+            .build_narinfo(&mut daemon, package_path)
+            .instrument(debug_span!("build_narinfo"))
+            .await?;
+        timings.narinfo = start.elapsed();
+
+        // A truncated or corrupted transfer must not end up in a package, no refs exist yet
+        check_nar_digest(&narinfo, &received).with_context(|| {
             format!(
-                "{}:{}",
-                private_key.name,
-                BASE64_STANDARD.encode(signature_bytes)
+                "NAR received from Nix daemon at {} is corrupt",
+                daemon.get_address()
             )
-        });
+        })?;
 
-        let deriver = path_info.deriver.map(|d| NixPath::new(&d)).transpose()?;
-        let narinfo = NarInfo::new(
-            store_path.clone(),
-            key.to_string(),
-            nar_hash_32_base.clone(),
-            path_info.nar_size,
-            None,
-            nar_hash_32_base,
-            path_info.nar_size,
-            deriver,
-            references,
-            signature,
-        );
-        Ok(narinfo)
-    }
+        // The stored tree must serialize to exactly the NAR the daemon knows
+        let start = Instant::now();
+        let store = self.clone();
+        let span = debug_span!("verify_nar");
+        let digest =
+            tokio::task::spawn_blocking(move || span.in_scope(|| store.nar_digest(package_oid)))
+                .await
+                .map_err(anyhow::Error::from)??;
+        check_nar_digest(&narinfo, &digest)?;
+        timings.tree += start.elapsed();
 
-    pub fn get_narinfo(&self, base32_hash: &str) -> Result<Option<Vec<u8>>> {
-        let result = self
+        let start = Instant::now();
+        let narinfo_blob_oid = self
             .repo
-            .get_oid_from_reference(&self.get_narinfo_ref(base32_hash));
-        match result {
-            Some(oid) => Ok(Some(self.repo.get_blob(oid)?)),
-            None => Ok(None),
-        }
+            .add_file_content(self.serialize_narinfo(&narinfo)?.as_bytes())?;
+        timings.narinfo += start.elapsed();
+
+        let builder = match daemon.is_local() {
+            true => {
+                debug!("Using local daemon, fetched {} ", package_path.get_name());
+                None
+            }
+            false => {
+                debug!(
+                    "Using daemon at {}, fetched package {}",
+                    daemon.get_address(),
+                    package_path.get_name()
+                );
+                Some(daemon.get_address())
+            }
+        };
+        Ok(DaemonPackage {
+            narinfo,
+            narinfo_blob_oid,
+            package_oid,
+            builder,
+            timings,
+        })
     }
 
-    pub fn entry_exists(&self, base32_hash: &str) -> Result<bool> {
+    /// Handle single file packages
+    /// Commits can only point to trees therefore we need to wrap the blob in a special tree
+    fn package_tree(&self, package_oid: Oid, filemode: i32) -> Result<Oid> {
+        if filemode == i32::from(FileMode::Tree) {
+            return Ok(package_oid);
+        }
         self.repo
-            .reference_exists(&self.get_result_ref(base32_hash))
+            .add_single_entry_tree(package_oid, SINGLE_FILE_PACKAGE_MARKER, filemode)
     }
 
-    pub fn get_as_nar_stream(&self, key: &str) -> Result<Option<NarGitStream>> {
-        let tree_oid = Oid::from_str(key)?;
-        // get the blob oid if the package consists of a single file
-        // else use the package tree oid
-        let oid = self
-            .repo
-            .match_sole_entry_id(tree_oid, SINGLE_FILE_PACKAGE_MARKER)?
-            .unwrap_or(tree_oid);
-        self.repo.get_entry_as_nar(oid)
+    pub async fn add_closure_from_upstream(
+        &self,
+        package_path: &NixPath,
+        upstream: &BinaryCacheClient,
+        jobs: usize,
+    ) -> Result<AddSummary> {
+        info!(
+            "Adding closure for {} from {}",
+            package_path.get_name(),
+            upstream.base_url()
+        );
+        match upstream.get_cache_info().await {
+            Ok(cache_info) => cache_info
+                .check_store_dir(&self.options().store_config.store_dir)
+                .map_err(|e| anyhow!("Cannot add from {}: {}", upstream.base_url(), e))?,
+            Err(e) => warn!(
+                "Could not get the cache info of {}: {:#}",
+                upstream.base_url(),
+                e
+            ),
+        }
+        let mut summary = AddSummary::default();
+
+        // Resolve the closure through the upstream narinfos, skipping packages we already have
+        let mut missing: HashMap<String, NarInfo> = HashMap::new();
+        let mut open = VecDeque::from([package_path.clone()]);
+        let mut visited = HashSet::new();
+        while let Some(path) = open.pop_front() {
+            let package_id = path.get_base_32_hash();
+            if !visited.insert(package_id.to_string()) {
+                continue;
+            }
+            if self.has_package(package_id) {
+                summary.record(
+                    &path,
+                    AddOutcome::AlreadyPresent,
+                    self.get_nar_size(package_id)?,
+                );
+                continue;
+            }
+            let Some(narinfo) = upstream.get_narinfo(package_id).await? else {
+                return Err(anyhow!("{} is not available at {}", path, upstream.base_url()).into());
+            };
+            // Otherwise another package would be committed under the requested id
+            if narinfo.store_path.get_base_32_hash() != package_id {
+                return Err(anyhow!(
+                    "The narinfo of {} at {} is for {}",
+                    path,
+                    upstream.base_url(),
+                    narinfo.store_path
+                )
+                .into());
+            }
+            if self.options().requires_trusted_signature()
+                && !narinfo.is_trusted(&self.options().trusted_keys)
+            {
+                return Err(anyhow!(
+                    "{} at {} has no signature by a key of store.trusted_public_keys",
+                    path,
+                    upstream.base_url()
+                )
+                .into());
+            }
+            open.extend(narinfo.get_dependencies().into_iter().cloned());
+            missing.insert(package_id.to_string(), narinfo);
+        }
+        debug!("{} packages are missing locally", missing.len());
+
+        // Download and decode the missing packages concurrently
+        let downloads: Vec<(&String, Result<Oid>)> = stream::iter(missing.iter())
+            .map(|(package_id, narinfo)| async move {
+                (package_id, self.add_upstream_nar(upstream, narinfo).await)
+            })
+            .buffer_unordered(jobs.max(1))
+            .collect()
+            .await;
+        let mut package_oids = HashMap::new();
+        for (package_id, result) in downloads {
+            match result {
+                Ok(package_oid) => {
+                    package_oids.insert(package_id.clone(), package_oid);
+                }
+                Err(e) => warn!("Failed to download {}: {}", package_id, e),
+            }
+        }
+
+        // Commits need the commits of their dependencies, so commit in dependency order
+        let mut commits = HashMap::new();
+        self.commit_upstream_package(
+            package_path.get_base_32_hash(),
+            &missing,
+            &package_oids,
+            &mut commits,
+            &mut summary,
+            0,
+        )
+        .await?;
+        // Packages whose download failed may not have been reached through a commit chain
+        for (package_id, narinfo) in &missing {
+            if !package_oids.contains_key(package_id) {
+                summary.record(&narinfo.store_path, AddOutcome::Failed, 0);
+            }
+        }
+        self.update_name_index(&summary)?;
+        self.audit_failures(AuditOperation::FetchUpstream, &summary)?;
+        Ok(summary)
+    }
+
+    async fn add_upstream_nar(
+        &self,
+        upstream: &BinaryCacheClient,
+        narinfo: &NarInfo,
+    ) -> Result<Oid> {
+        let url = narinfo
+            .url
+            .clone()
+            .ok_or_else(|| anyhow!("Narinfo of {} has no URL", narinfo.store_path))?;
+        let nar_stream = upstream.get_nar(&url).await?;
+        let declared = narinfo.compression.clone();
+        let store_path = narinfo.store_path.to_string();
+        let repo = self.repo.clone();
+        let (package_oid, filemode, digest) = tokio::task::spawn_blocking(move || {
+            let reader = BufReader::new(SyncIoBridge::new(StreamReader::new(nar_stream)));
+            // The format is detected from the data, since caches are not always
+            // truthful about the compression they declare
+            let (reader, detected) = decompress(reader)?;
+            if declared != detected {
+                if detected == Compression::None {
+                    return Err(anyhow!("Unsupported NAR compression: {}", declared).into());
+                }
+                warn!(
+                    "NAR of {} is declared as {} but is {} compressed",
+                    store_path, declared, detected
+                );
+            }
+            let mut reader = HashingReader::new(reader);
+            let (oid, filemode) = repo.add_nar(&mut reader)?;
+            Ok::<_, Error>((oid, filemode, reader.digest()))
+        })
+        .await
+        .map_err(anyhow::Error::from)??;
+        // The signatures of the narinfo must not end up on a corrupt or tampered download
+        check_nar_digest(narinfo, &digest)
+            .with_context(|| format!("NAR downloaded from {} is corrupt", upstream.base_url()))?;
+        debug!(
+            "Using binary cache at {}, fetched package {}",
+            upstream.base_url(),
+            narinfo.store_path.get_name()
+        );
+        self.package_tree(package_oid, filemode)
+    }
+
+    #[async_recursion]
+    async fn commit_upstream_package(
+        &self,
+        package_id: &str,
+        missing: &HashMap<String, NarInfo>,
+        package_oids: &HashMap<String, Oid>,
+        commits: &mut HashMap<String, Option<Oid>>,
+        summary: &mut AddSummary,
+        depth: usize,
+    ) -> Result<Option<Oid>> {
+        if let Some(commit_oid) = commits.get(package_id) {
+            return Ok(*commit_oid);
+        }
+        let _lock = self.package_locks.lock(package_id).await;
+        if let Some(commit_oid) = self.get_commit(package_id) {
+            return Ok(Some(commit_oid));
+        }
+        let (Some(narinfo), Some(package_oid)) =
+            (missing.get(package_id), package_oids.get(package_id))
+        else {
+            commits.insert(package_id.to_string(), None);
+            return Ok(None);
+        };
+        self.check_closure_depth(&narinfo.store_path, depth)?;
+
+        let mut parent_commits = Vec::new();
+        let mut missing_dependency = false;
+        for dependency in narinfo.get_dependencies() {
+            match self
+                .commit_upstream_package(
+                    dependency.get_base_32_hash(),
+                    missing,
+                    package_oids,
+                    commits,
+                    summary,
+                    depth + 1,
+                )
+                .await?
+            {
+                Some(dep_coid) => parent_commits.push(dep_coid),
+                None => missing_dependency = true,
+            }
+        }
+        if missing_dependency {
+            summary.record(&narinfo.store_path, AddOutcome::Failed, 0);
+            commits.insert(package_id.to_string(), None);
+            return Ok(None);
+        }
+
+        // The NAR is served uncompressed from the git tree,
+        // the upstream signatures stay valid since they only cover the NAR hash
+        let mut narinfo = narinfo.clone();
+        narinfo.key = nar_key(&narinfo.nar_hash);
+        narinfo.url = None;
+        narinfo.compression = Compression::None;
+        narinfo.file_hash = Some(narinfo.nar_hash.clone());
+        narinfo.file_size = Some(narinfo.nar_size);
+        let narinfo_blob_oid = self
+            .repo
+            .add_file_content(self.serialize_narinfo(&narinfo)?.as_bytes())?;
+
+        let commit_oid =
+            self.commit_package(*package_oid, &parent_commits, narinfo.store_path.get_name())?;
+        self.add_package_refs(
+            package_id,
+            commit_oid,
+            narinfo_blob_oid,
+            (&narinfo.key, *package_oid),
+            AuditOperation::FetchUpstream,
+        )?;
+        self.add_listing(package_id, *package_oid);
+        summary.record(&narinfo.store_path, AddOutcome::Added, narinfo.nar_size);
+        commits.insert(package_id.to_string(), Some(commit_oid));
+        Ok(Some(commit_oid))
+    }
+
+    fn get_package_commit_from_git_remotes(
+        &self,
+        store_path: &NixPath,
+        summary: &mut AddSummary,
+    ) -> Result<Option<Oid>> {
+        let package_id = store_path.get_base_32_hash();
+        let options = self.options();
+        let git_remotes = options
+            .remotes
+            .iter()
+            .filter(|remote| remote.kind == RemoteKind::Git);
+        let mut fetched = None;
+        for remote in git_remotes {
+            if let Some(oid) = self.fetch_from_remote(package_id, remote)? {
+                debug!(
+                    "Using git peer {}, fetched package {}",
+                    remote,
+                    store_path.get_name()
+                );
+                fetched = Some((oid, remote));
+                break;
+            }
+        }
+        let Some((commit_oid, success_remote)) = fetched else {
+            return Ok(None);
+        };
+        self.audit(AuditOperation::FetchPeer, package_id)?;
+        let mut narinfos = ClosureNarinfos::default();
+        summary.record(
+            store_path,
+            AddOutcome::FetchedFromPeer,
+            narinfos.get(self, package_id)?.nar_size,
+        );
+        self.complete_closure_from_remote(package_id, success_remote, summary, &mut narinfos)?;
+        Ok(Some(commit_oid))
+    }
+
+    /// Fetches the references of the dependencies of a package fetched from `remote`.
+    /// Dependencies which are already cached locally have their whole closure cached,
+    /// so their subtrees are not walked.
+    fn complete_closure_from_remote(
+        &self,
+        package_id: &str,
+        remote: &RemoteConfig,
+        summary: &mut AddSummary,
+        narinfos: &mut ClosureNarinfos,
+    ) -> Result<()> {
+        let mut open = VecDeque::new();
+        let mut visited = HashSet::new();
+        open.push_back(package_id.to_string());
+        visited.insert(package_id.to_string());
+        while let Some(id) = open.pop_front() {
+            let deps: Vec<NixPath> = narinfos
+                .get(self, &id)?
+                .get_dependencies()
+                .into_iter()
+                .cloned()
+                .collect();
+            for dep in deps {
+                let dep_hash = dep.get_base_32_hash();
+                if !visited.insert(dep_hash.to_string()) {
+                    continue;
+                }
+                if self.has_package(dep_hash) {
+                    continue;
+                }
+                self.fetch_from_remote(dep_hash, remote)?;
+                self.audit(AuditOperation::FetchPeer, dep_hash)?;
+                debug!(
+                    "Using git peer {}, fetched package {}",
+                    remote,
+                    dep.get_name()
+                );
+                summary.record(
+                    &dep,
+                    AddOutcome::FetchedFromPeer,
+                    narinfos.get(self, dep_hash)?.nar_size,
+                );
+                open.push_back(dep_hash.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the references nix-hash -> package-commit-oid, nix-hash -> narinfo-blob-oid
+    /// and NAR key -> package-tree-oid and records the operation in the audit log,
+    /// all in one reference transaction
+    fn add_package_refs(
+        &self,
+        package_id: &str,
+        commit_oid: Oid,
+        narinfo_blob_oid: Oid,
+        (key, package_oid): (&str, Oid),
+        operation: AuditOperation,
+    ) -> Result<()> {
+        self.repo.update_refs(
+            &[
+                (&self.get_result_ref(package_id), commit_oid),
+                (&self.get_narinfo_ref(package_id), narinfo_blob_oid),
+                (&self.get_nar_ref(key), package_oid),
+            ],
+            self.audit_entry(operation, package_id, AuditOutcome::Ok)
+                .as_ref(),
+        )?;
+        self.forget_package(package_id);
+        self.known_packages.added(package_id);
+        Ok(())
+    }
+
+    /// The audit log entry of an operation, unless the audit log is disabled
+    fn audit_entry(
+        &self,
+        operation: AuditOperation,
+        package_id: &str,
+        outcome: AuditOutcome,
+    ) -> Option<ChainEntry> {
+        self.settings.audit_log.then(|| ChainEntry {
+            chain_ref: self.get_audit_ref(),
+            message: AuditEntry::new(operation, package_id, &self.actor, outcome).to_message(),
+        })
     }
 
-    pub fn list_entries(&self) -> Result<Vec<String>> {
-        let entries = self.repo.list_references("refs/*")?;
+    /// Records an operation whose references were written by git itself, e.g. by a fetch
+    fn audit(&self, operation: AuditOperation, package_id: &str) -> Result<()> {
+        match self.audit_entry(operation, package_id, AuditOutcome::Ok) {
+            Some(entry) => self.repo.update_refs(&[], Some(&entry)),
+            None => Ok(()),
+        }
+    }
+
+    /// Records the packages which could not be added during an operation
+    fn audit_failures(&self, operation: AuditOperation, summary: &AddSummary) -> Result<()> {
+        for failed in summary
+            .packages
+            .iter()
+            .filter(|p| p.outcome == AddOutcome::Failed)
+        {
+            let package = NixPath::new(&failed.path)?;
+            let entry =
+                self.audit_entry(operation, package.get_base_32_hash(), AuditOutcome::Failed);
+            if let Some(entry) = entry {
+                self.repo.update_refs(&[], Some(&entry))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the entries of the audit log which match `filter`, the newest first
+    pub fn audit_log(&self, filter: &AuditFilter) -> Result<Vec<AuditEntry>> {
+        let mut entries = Vec::new();
+        self.repo
+            .for_each_chain_entry(&self.get_audit_ref(), |message| {
+                let entry = AuditEntry::parse(message)?;
+                if filter.is_before_since(&entry) {
+                    return Ok(false);
+                }
+                if filter.matches(&entry) {
+                    entries.push(entry);
+                }
+                Ok(true)
+            })?;
         Ok(entries)
     }
 
+    fn commit_package(&self, package_oid: Oid, parent_commits: &[Oid], name: &str) -> Result<Oid> {
+        let commit_oid = self.repo.commit(package_oid, parent_commits, Some(name))?;
+        self.repo.index_tree(package_oid, commit_oid)?;
+        Ok(commit_oid)
+    }
+
+    /// Packs the loose objects once there are more than configured, see `GitRepo::repack`,
+    /// and the references, so looking up a package does not search the file system
+    pub fn repack(&self) -> Result<Option<usize>> {
+        let packed = self.repo.repack()?;
+        self.repo.pack_refs()?;
+        Ok(packed)
+    }
+
+    /// Recreates the tree to commit index from the result references of all packages
+    pub fn rebuild_tree_index(&self) -> Result<usize> {
+        let result_refs = self
+            .repo
+            .list_references(&self.package_ref_glob("result"))?;
+        for result_ref in &result_refs {
+            let commit_oid = self
+                .repo
+                .get_oid_from_reference(result_ref)
+                .ok_or_else(|| anyhow!("Could not resolve reference {}", result_ref))?;
+            let tree_oid = self.repo.get_commit_tree(commit_oid)?;
+            self.repo.index_tree(tree_oid, commit_oid)?;
+        }
+        Ok(result_refs.len())
+    }
+
+    /// Adds the packages which were added or fetched during an operation to the name index
+    fn update_name_index(&self, summary: &AddSummary) -> Result<()> {
+        let new_packages = summary
+            .packages
+            .iter()
+            .filter(|p| matches!(p.outcome, AddOutcome::Added | AddOutcome::FetchedFromPeer))
+            .map(|p| NixPath::new(&p.path))
+            .collect::<Result<Vec<_>, _>>()?;
+        if new_packages.is_empty() {
+            return Ok(());
+        }
+        self.repo
+            .update_blob_ref(&self.get_name_index_ref(), |content| {
+                let mut index = content
+                    .map(NameIndex::parse)
+                    .transpose()?
+                    .unwrap_or_default();
+                for package in &new_packages {
+                    index.insert(package.get_name(), package.get_base_32_hash());
+                }
+                Ok(index.serialize())
+            })?;
+        self.increase_package_count(new_packages.len())
+    }
+
+    /// Returns the number of packages as maintained in the metadata, without enumerating refs
+    pub fn cached_package_count(&self) -> Result<Option<usize>> {
+        let Some(count_oid) = self
+            .repo
+            .get_oid_from_reference(&self.get_package_count_ref())
+        else {
+            return Ok(None);
+        };
+        Ok(Some(parse_package_count(&self.repo.get_blob(count_oid)?)?))
+    }
+
+    fn increase_package_count(&self, num_added: usize) -> Result<()> {
+        self.repo
+            .update_blob_ref(&self.get_package_count_ref(), |content| {
+                let count = match content {
+                    Some(content) => parse_package_count(content)? + num_added,
+                    // Repositories without a cached count are enumerated once
+                    None => self.num_available_packages()?,
+                };
+                Ok(count.to_string().into_bytes())
+            })
+    }
+
+    /// Recreates the name index from the narinfos of all packages
+    pub fn rebuild_name_index(&self) -> Result<usize> {
+        let mut index = NameIndex::default();
+        for narinfo_ref in self
+            .repo
+            .list_references(&self.package_ref_glob("narinfo"))?
+        {
+            let Some(package_id) = self.package_id_of(&narinfo_ref) else {
+                continue;
+            };
+            let narinfo = self.get_parsed_narinfo(package_id)?;
+            index.insert(
+                narinfo.store_path.get_name(),
+                narinfo.store_path.get_base_32_hash(),
+            );
+        }
+        let num_packages = index.len();
+        self.repo
+            .update_blob_ref(&self.get_name_index_ref(), |_| Ok(index.serialize()))?;
+        self.repo
+            .update_blob_ref(&self.get_package_count_ref(), |_| {
+                Ok(num_packages.to_string().into_bytes())
+            })?;
+        Ok(num_packages)
+    }
+
+    /// Returns the `(name, hash)` pairs of all cached packages whose name matches `name_regex`
+    pub fn search(&self, name_regex: &str) -> Result<Vec<(String, String)>> {
+        let name_regex = Regex::new(name_regex).map_err(anyhow::Error::from)?;
+        let Some(index_oid) = self.repo.get_oid_from_reference(&self.get_name_index_ref()) else {
+            return Ok(Vec::new());
+        };
+        let index = NameIndex::parse(&self.repo.get_blob(index_oid)?)?;
+        Ok(index.search(&name_regex))
+    }
+
+    #[instrument(skip(self, package_id), fields(package_hash = package_id))]
+    fn fetch_from_remote(&self, package_id: &str, remote: &RemoteConfig) -> Result<Option<Oid>> {
+        let remote_namespace = remote
+            .ref_namespace
+            .clone()
+            .unwrap_or_else(|| self.options().ref_namespace.clone());
+        let fetched = self.repo.fetch(
+            remote,
+            &format!("{remote_namespace}/{package_id}/*"),
+            &format!("{}/*", self.get_package_ref(package_id)),
+        )?;
+        // references may have been updated even if no objects were received
+        self.forget_package(package_id);
+        if let Some(()) = fetched {
+            let oid = self
+                .get_commit(package_id)
+                .ok_or_else(|| anyhow!("Could not get commit id for {}", package_id))?;
+            self.known_packages.added(package_id);
+            let package_oid = self.repo.get_commit_tree(oid)?;
+            self.repo.index_tree(package_oid, oid)?;
+            // Peers don't share their NAR index, it is derived from the narinfo
+            let key = self.get_parsed_narinfo(package_id)?.key;
+            if !is_legacy_nar_key(&key) {
+                self.repo.add_ref(&self.get_nar_ref(&key), package_oid)?;
+            }
+            return Ok(Some(oid));
+        }
+        Ok(None)
+    }
+
+    fn get_parsed_narinfo(&self, package_id: &str) -> Result<NarInfo> {
+        let narinfo_blob = self
+            .get_narinfo(package_id)?
+            .ok_or_else(|| Error::PackageNotFound(package_id.to_string()))?;
+        Ok(NarInfo::parse(&String::from_utf8_lossy(&narinfo_blob))?)
+    }
+
+    fn get_nar_size(&self, package_id: &str) -> Result<u64> {
+        Ok(self.get_parsed_narinfo(package_id)?.nar_size)
+    }
+
+    async fn build_narinfo(&self, nix_daemon: &mut B, store_path: &NixPath) -> Result<NarInfo> {
+        let Some(path_info) = nix_daemon.get_pathinfo(&store_path).await? else {
+            return Err(anyhow!("Could not find narinfo for {}", store_path.get_path()).into());
+        };
+        let references: Vec<NixPath> = path_info
+            .references
+            .iter()
+            .map(|p| NixPath::new(p))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let nar_size = path_info.nar_size;
+        let nar_hash = hex::decode(path_info.nar_hash).map_err(anyhow::Error::from)?;
+
+        // The hash is checked against the stored package after ingestion
+        let mut nar_hash_32_base = nix_base32::to_nix_base32(&nar_hash);
+        // TODO: formatting should be handled by the NarInfo struct
+        nar_hash_32_base = format!("sha256:{}", nar_hash_32_base);
+
+        let signatures = self.sign(store_path, &nar_hash_32_base, nar_size, &references)?;
+        if !signatures.is_empty() && nix_daemon.is_local() && self.settings.sign_local_store {
+            add_local_signatures(nix_daemon, store_path, &signatures).await;
+        }
+
+        let deriver = path_info.deriver.map(|d| NixPath::new(&d)).transpose()?;
+        let narinfo = NarInfo::builder()
+            .store_path(store_path.clone())
+            .key(nar_key(&nar_hash_32_base))
+            .file(nar_hash_32_base.clone(), nar_size)
+            .nar(nar_hash_32_base, nar_size)
+            .references(references)
+            .deriver(deriver)
+            .signatures(signatures)
+            .ca(path_info.ca.filter(|ca| !ca.is_empty()))
+            .build()?;
+        Ok(narinfo)
+    }
+
+    /// Signs the store object with each configured signer
+    fn sign(
+        &self,
+        store_path: &NixPath,
+        nar_hash: &str,
+        nar_size: u64,
+        references: &[NixPath],
+    ) -> Result<Vec<String>> {
+        let fingerprint = fingerprint_store_object(store_path, nar_hash, nar_size, references);
+        self.options()
+            .signers
+            .iter()
+            .map(|signer| {
+                let signature_bytes = signer.sign(fingerprint.as_bytes())?;
+                Ok(format_signature(signer.as_ref(), &signature_bytes))
+            })
+            .collect()
+    }
+
+    /// Signs the narinfos with one call of each signer, keyed by their fingerprint
+    fn sign_batch(&self, narinfo_refs: &[String]) -> Result<HashMap<String, Vec<String>>> {
+        let mut fingerprints = Vec::new();
+        for narinfo_ref in narinfo_refs {
+            if let Some(oid) = self.repo.get_oid_from_reference(narinfo_ref) {
+                let content = self.repo.get_blob(oid)?;
+                fingerprints
+                    .push(NarInfo::parse(&String::from_utf8_lossy(&content))?.fingerprint());
+            }
+        }
+        let mut signatures = vec![Vec::new(); fingerprints.len()];
+        for signer in &self.options().signers {
+            let batch = signer.sign_batch(
+                &fingerprints
+                    .iter()
+                    .map(|f| f.as_bytes())
+                    .collect::<Vec<_>>(),
+            )?;
+            for (signatures, signature) in signatures.iter_mut().zip(batch) {
+                signatures.push(format_signature(signer.as_ref(), &signature));
+            }
+        }
+        Ok(fingerprints.into_iter().zip(signatures).collect())
+    }
+
+    fn validate_narinfo(&self, narinfo: &NarInfo) -> Result<()> {
+        narinfo
+            .validate(&self.options().store_config)
+            .map_err(|issues| Error::InvalidNarInfo {
+                path: narinfo.store_path.to_string(),
+                issues,
+            })
+    }
+
+    /// Serializes the narinfo after validating it and checking that the signatures by the
+    /// configured keys verify against the fingerprint a client reconstructs from the serialized narinfo
+    fn serialize_narinfo(&self, narinfo: &NarInfo) -> Result<String> {
+        self.validate_narinfo(narinfo)?;
+        let content = narinfo.to_string();
+        if self.options().signers.is_empty() {
+            return Ok(content);
+        }
+        let public_keys: Vec<PublicKey> = self
+            .options()
+            .signers
+            .iter()
+            .map(|signer| signer.public_key())
+            .collect();
+        // Signatures by other keys, e.g. of upstream caches, are unknown here
+        let statuses = NarInfo::parse(&content)?.verify_signatures(&public_keys);
+        if statuses.contains(&SigStatus::Invalid) {
+            return Err(Error::SignatureSelfCheck(narinfo.store_path.to_string()));
+        }
+        Ok(content)
+    }
+
+    /// Replaces the signatures of all cached narinfos with those by the configured signers.
+    /// With `also_local`, the signatures are also added to the paths in the local Nix store.
+    /// Returns the number of signed packages.
+    pub async fn sign_all(&self, also_local: bool) -> Result<usize> {
+        if self.options().signers.is_empty() {
+            return Err(
+                anyhow!("Signing requires store.sign_key_paths or store.signer to be set").into(),
+            );
+        }
+        let mut local_daemon = match (also_local, &self.local_pool) {
+            (false, _) => None,
+            (true, Some(pool)) => Some(pool.get().await?),
+            (true, None) => {
+                return Err(
+                    anyhow!("Signing the local store requires store.use_local_nix_daemon").into(),
+                );
+            }
+        };
+
+        let narinfo_refs = self
+            .repo
+            .list_references(&self.package_ref_glob("narinfo"))?;
+        let mut presigned = HashMap::new();
+        for (i, narinfo_ref) in narinfo_refs.iter().enumerate() {
+            // An external signer is run once per batch instead of once per package
+            if i % SIGN_BATCH_SIZE == 0 {
+                let end = (i + SIGN_BATCH_SIZE).min(narinfo_refs.len());
+                presigned = self.sign_batch(&narinfo_refs[i..end])?;
+            }
+            // the update may be retried, so only its last result counts
+            let signed = RefCell::new(None);
+            let package_id = self.package_id_of(narinfo_ref).unwrap_or_default();
+            let entry = self.audit_entry(AuditOperation::Sign, package_id, AuditOutcome::Ok);
+            self.repo
+                .update_blob_ref_with_entry(narinfo_ref, entry.as_ref(), |content| {
+                    let content = content.ok_or_else(|| anyhow!("{} disappeared", narinfo_ref))?;
+                    let mut narinfo = NarInfo::parse(&String::from_utf8_lossy(content))?;
+                    // the narinfo may have changed since the batch was signed
+                    let signatures = match presigned.get(&narinfo.fingerprint()) {
+                        Some(signatures) => signatures.clone(),
+                        None => self.sign(
+                            &narinfo.store_path,
+                            &narinfo.nar_hash,
+                            narinfo.nar_size,
+                            &narinfo.references,
+                        )?,
+                    };
+                    narinfo.signatures = signatures.clone();
+                    *signed.borrow_mut() = Some((narinfo.store_path.clone(), signatures));
+                    Ok(self.serialize_narinfo(&narinfo)?.into_bytes())
+                })?;
+            self.forget_package(package_id);
+            if let (Some(daemon), Some((store_path, signatures))) =
+                (local_daemon.as_deref_mut(), signed.into_inner())
+                && daemon.path_exists(&store_path).await?
+            {
+                add_local_signatures(daemon, &store_path, &signatures).await;
+            }
+        }
+        Ok(narinfo_refs.len())
+    }
+
+    pub fn get_narinfo(&self, base32_hash: &str) -> Result<Option<Bytes>> {
+        let Some(oid) = self.package_refs(base32_hash).narinfo else {
+            return Ok(None);
+        };
+        if let Some(narinfo) = self.narinfos.get(base32_hash, oid) {
+            return Ok(Some(narinfo));
+        }
+        let narinfo = Bytes::from(self.repo.get_blob(oid)?);
+        self.narinfos.insert(base32_hash, oid, narinfo.clone());
+        Ok(Some(narinfo))
+    }
+
+    /// How often narinfos were served from memory
+    pub fn narinfo_cache_stats(&self) -> NarinfoCacheStats {
+        self.narinfos.stats()
+    }
+
+    pub fn entry_exists(&self, base32_hash: &str) -> Result<bool> {
+        Ok(self.has_package(base32_hash))
+    }
+
+    /// Whether the package has a result reference, answered from memory for known packages.
+    /// The narinfo reference is written in the same transaction.
+    fn has_package(&self, package_id: &str) -> bool {
+        if self
+            .known_packages
+            .contains(package_id, || self.list_result_package_ids())
+        {
+            return true;
+        }
+        let exists = self.package_refs(package_id).result.is_some();
+        if exists {
+            self.known_packages.insert(package_id);
+        }
+        exists
+    }
+
+    /// The ids of all packages with a result reference
+    fn list_result_package_ids(&self) -> Result<Vec<String>> {
+        let mut ids = Vec::new();
+        self.repo
+            .for_each_reference(&self.package_ref_glob("result"), |name| {
+                ids.extend(self.package_id_of(name).map(String::from));
+            })?;
+        Ok(ids)
+    }
+
+    /// Drops what is remembered about a package whose references were changed
+    fn forget_package(&self, package_id: &str) {
+        self.refs.forget(package_id);
+        self.known_packages.forget(package_id);
+        self.narinfos.invalidate(package_id);
+    }
+
+    /// Resolves the references of a package, usually from the snapshot without locking the repository
+    fn package_refs(&self, hash: &str) -> PackageRefs {
+        self.refs.get(hash, || PackageRefs {
+            narinfo: self
+                .repo
+                .get_oid_from_reference(&self.get_narinfo_ref(hash)),
+            result: self.repo.get_oid_from_reference(&self.get_result_ref(hash)),
+        })
+    }
+
+    /// The NAR behind the URL `nar/<key>.nar` of a narinfo
+    pub fn get_as_nar_stream(&self, key: &str) -> Result<Option<NarGitStream>> {
+        let package_oid = match self.repo.get_oid_from_reference(&self.get_nar_ref(key)) {
+            Some(package_oid) => package_oid,
+            // Packages added before the URLs were content-addressed have the tree as key
+            None if is_legacy_nar_key(key) => Oid::from_str(key)?,
+            None => return Ok(None),
+        };
+        self.package_nar_stream(package_oid)
+    }
+
+    fn package_nar_stream(&self, package_oid: Oid) -> Result<Option<NarGitStream>> {
+        let oid = self.nar_root(package_oid)?;
+        self.repo.get_entry_as_nar(oid)
+    }
+
+    /// The tree of a cached package, found through the key of its NAR URL like the served NAR,
+    /// since packages added without their closure have no commit
+    fn package_oid(&self, package_id: &str) -> Result<Oid> {
+        let key = self.get_parsed_narinfo(package_id)?.key;
+        match self.repo.get_oid_from_reference(&self.get_nar_ref(&key)) {
+            Some(package_oid) => Ok(package_oid),
+            None if is_legacy_nar_key(&key) => Ok(Oid::from_str(&key)?),
+            None => Err(anyhow!("Could not find the NAR of {}", package_id).into()),
+        }
+    }
+
+    /// Writes the NAR of the package, as it is served
+    pub fn export_nar(&self, package_id: &str, mut writer: impl Write) -> Result<()> {
+        let stream = self
+            .package_nar_stream(self.package_oid(package_id)?)?
+            .ok_or_else(|| anyhow!("Could not find the NAR of {}", package_id))?;
+        std::io::copy(&mut stream.into_sync_reader(), &mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes the package as a tar archive holding a directory or file named like its store path
+    pub fn export_tar(&self, package_id: &str, writer: impl Write) -> Result<()> {
+        let narinfo = self.get_parsed_narinfo(package_id)?;
+        let root = self.nar_root(self.package_oid(package_id)?)?;
+        let root_name = format!(
+            "{}-{}",
+            narinfo.store_path.get_base_32_hash(),
+            narinfo.store_path.get_name()
+        );
+        self.repo
+            .write_entry_as_tar(root, root_name.as_bytes(), writer)
+    }
+
+    /// Lists the files which differ between two cached packages, given by their hash parts
+    pub fn diff_packages(&self, old_id: &str, new_id: &str, patch: bool) -> Result<PackageDiff> {
+        let old = self.diff_root(old_id)?;
+        let new = self.diff_root(new_id)?;
+        Ok(PackageDiff {
+            old: old_id.to_string(),
+            new: new_id.to_string(),
+            changes: self.repo.diff_entries(old, new, patch)?,
+        })
+    }
+
+    /// The root of the package's NAR with the filemode it is served with
+    fn diff_root(&self, package_id: &str) -> Result<(Oid, i32)> {
+        let package_oid = self.package_oid(package_id)?;
+        let root = self.nar_root(package_oid)?;
+        let filemode = if root == package_oid {
+            FileMode::Tree
+        } else {
+            FileMode::Blob
+        };
+        Ok((root, filemode.into()))
+    }
+
+    /// Returns the blob oid if the package consists of a single file, else the package tree oid
+    fn nar_root(&self, package_oid: Oid) -> Result<Oid> {
+        Ok(self
+            .repo
+            .match_sole_entry_id(package_oid, SINGLE_FILE_PACKAGE_MARKER)?
+            .unwrap_or(package_oid))
+    }
+
+    /// Stores the `.ls` listing of the package compressed as configured, so it isn't generated
+    /// on every request. The listing is only an optimization, so failures are logged rather than returned.
+    fn add_listing(&self, package_id: &str, package_oid: Oid) {
+        let result = (|| {
+            let listing = self.repo.get_entry_listing(self.nar_root(package_oid)?)?;
+            let content = match self.options().listing_compression {
+                Compression::Zstd => zstd::encode_all(listing.as_bytes(), 0)?,
+                _ => listing.into_bytes(),
+            };
+            let blob_oid = self.repo.add_file_content(&content)?;
+            self.repo
+                .add_ref(&self.get_listing_ref(package_id), blob_oid)
+        })();
+        if let Err(e) = result {
+            warn!("Could not store the listing of {}: {:#}", package_id, e);
+        }
+    }
+
+    /// Returns the `.ls` listing of the package, generating it if it was not stored at ingest time
+    pub fn get_listing(&self, package_id: &str) -> Result<Option<Listing>> {
+        if let Some(oid) = self
+            .repo
+            .get_oid_from_reference(&self.get_listing_ref(package_id))
+        {
+            // Stored with the compression configured at the time
+            let content = self.repo.get_blob(oid)?;
+            return Ok(Some(match Compression::sniff(&content) {
+                Compression::Zstd => Listing::Zstd(content),
+                _ => Listing::Json(String::from_utf8_lossy(&content).into_owned()),
+            }));
+        }
+        if self.get_narinfo(package_id)?.is_none() {
+            return Ok(None);
+        }
+        let oid = self.nar_root(self.package_oid(package_id)?)?;
+        Ok(Some(Listing::Json(self.repo.get_entry_listing(oid)?)))
+    }
+
+    /// Stores the listings of packages which were added before listings were stored.
+    /// Returns the number of generated listings.
+    pub fn generate_listings(&self) -> Result<usize> {
+        let mut num_generated = 0;
+        for package_id in self.list_package_ids()? {
+            if self
+                .repo
+                .reference_exists(&self.get_listing_ref(&package_id))?
+            {
+                continue;
+            }
+            self.add_listing(&package_id, self.package_oid(&package_id)?);
+            num_generated += 1;
+        }
+        Ok(num_generated)
+    }
+
+    /// Hashes the NAR serialization of the package tree
+    pub fn nar_digest(&self, package_oid: Oid) -> Result<NarDigest> {
+        let stream = self
+            .package_nar_stream(package_oid)?
+            .ok_or_else(|| anyhow!("Could not find package tree {}", package_oid))?;
+        Ok(digest_nar_stream(stream)?)
+    }
+
+    /// Checks that the narinfo is valid and that the stored package serializes to the NAR
+    /// recorded in it
+    pub fn verify(&self, package_id: &str) -> Result<()> {
+        let narinfo = self.get_parsed_narinfo(package_id)?;
+        self.validate_narinfo(&narinfo)?;
+        check_nar_digest(&narinfo, &self.nar_digest(self.package_oid(package_id)?)?)
+    }
+
+    /// Compares the packages with the same store paths on the git remote with the name or URL
+    /// `remote_name`. A deep check fetches the result commits which differ to compare their trees,
+    /// the fetched references are deleted afterwards.
+    pub fn verify_remote(
+        &self,
+        remote_name: &str,
+        package_ids: &[String],
+        deep: bool,
+    ) -> Result<Vec<(String, RemoteMatch)>> {
+        let options = self.options();
+        let remote = options
+            .remotes
+            .iter()
+            .find(|r| {
+                r.kind == RemoteKind::Git
+                    && (r.name == remote_name || r.url.as_str() == remote_name)
+            })
+            .ok_or_else(|| anyhow!("There is no git remote {} in store.remotes", remote_name))?;
+        let remote_namespace = remote
+            .ref_namespace
+            .as_deref()
+            .unwrap_or(&options.ref_namespace);
+        let remote_refs: HashMap<String, Oid> = self
+            .repo
+            .list_remote_references(remote)?
+            .into_iter()
+            .collect();
+
+        let mut matches = Vec::new();
+        let mut differing = Vec::new();
+        for package_id in package_ids {
+            let local_commit = self
+                .get_commit(package_id)
+                .ok_or_else(|| Error::PackageNotFound(package_id.to_string()))?;
+            let result_ref = format!("{remote_namespace}/{package_id}/result");
+            let package_match = match remote_refs.get(&result_ref) {
+                None => RemoteMatch::Missing,
+                Some(remote_commit) if *remote_commit == local_commit => RemoteMatch::Identical,
+                Some(remote_commit) if deep => {
+                    differing.push((package_id.as_str(), local_commit, *remote_commit));
+                    continue;
+                }
+                Some(_) => RemoteMatch::DifferentCommit,
+            };
+            matches.push((package_id.clone(), package_match));
+        }
+        if differing.is_empty() {
+            return Ok(matches);
+        }
+
+        let refspecs: Vec<String> = differing
+            .iter()
+            .map(|(package_id, _, _)| {
+                format!("+{remote_namespace}/{package_id}/*:refs/verify/{package_id}/*")
+            })
+            .collect();
+        // Only the objects which differ are received, as the local commits are offered to the remote
+        let offered: Vec<Oid> = differing.iter().map(|(_, local, _)| *local).collect();
+        let fetched = self.repo.fetch_into_scratch(remote, &refspecs, &offered)?;
+        for (package_id, local_commit, remote_commit) in differing {
+            let local_tree = self.repo.get_commit_tree(local_commit)?;
+            let package_match = if fetched.get_commit_tree(remote_commit)? == local_tree {
+                RemoteMatch::SameTree
+            } else {
+                let remote_narinfo = fetched
+                    .get_oid_from_reference(&format!("refs/verify/{package_id}/narinfo"))
+                    .ok_or_else(|| anyhow!("The remote has no narinfo for {}", package_id))?;
+                let remote_narinfo =
+                    NarInfo::parse(&String::from_utf8_lossy(&fetched.get_blob(remote_narinfo)?))?;
+                if remote_narinfo.nar_hash == self.get_parsed_narinfo(package_id)?.nar_hash {
+                    RemoteMatch::Divergent
+                } else {
+                    RemoteMatch::DifferentNar
+                }
+            };
+            matches.push((package_id.to_string(), package_match));
+        }
+        Ok(matches)
+    }
+
+    /// Returns the hashes of the cached packages which list the package as a reference, sorted.
+    /// The commits of the references are the parents of a result commit, so every result commit
+    /// is read, but neither narinfos nor trees.
+    pub fn reverse_dependencies(&self, package_id: &str) -> Result<Vec<String>> {
+        let commit_oid = self
+            .get_commit(package_id)
+            .ok_or_else(|| Error::PackageNotFound(package_id.to_string()))?;
+        let mut package_ids: Vec<String> = self
+            .repo
+            .references_with_parent(&self.package_ref_glob("result"), commit_oid)?
+            .iter()
+            .filter_map(|r| self.package_id_of(r))
+            .map(String::from)
+            .collect();
+        package_ids.sort_unstable();
+        Ok(package_ids)
+    }
+
+    /// Collects the narinfo, the commit, the audit log entries and the reverse dependencies of a package
+    pub fn package_history(&self, package_id: &str) -> Result<PackageHistory> {
+        let narinfo = self.get_parsed_narinfo(package_id)?;
+        let commit = self
+            .get_commit(package_id)
+            .ok_or_else(|| Error::PackageNotFound(package_id.to_string()))?;
+        let mut entries = self.audit_log(&AuditFilter {
+            package: Some(package_id.to_string()),
+            ..AuditFilter::default()
+        })?;
+        entries.reverse();
+        Ok(PackageHistory {
+            narinfo,
+            commit,
+            entries,
+            reverse_dependencies: self.reverse_dependencies(package_id)?,
+        })
+    }
+
+    /// Returns the hashes of all packages which have a narinfo
+    pub fn list_package_ids(&self) -> Result<Vec<String>> {
+        let package_ids = self
+            .repo
+            .list_references(&self.package_ref_glob("narinfo"))?
+            .iter()
+            .filter_map(|r| self.package_id_of(r))
+            .map(String::from)
+            .collect();
+        Ok(package_ids)
+    }
+
+    /// Returns the references of the cached packages, sorted by package hash so that pages
+    /// are consistent across calls. With a limit only the entries up to the page are kept in memory.
+    pub fn list_entries(
+        &self,
+        options: &ListOptions,
+    ) -> Result<std::iter::Skip<std::vec::IntoIter<String>>> {
+        let package_ids = match &options.name_filter {
+            Some(filter) => Some(
+                self.search(filter)?
+                    .into_iter()
+                    .map(|(_, hash)| hash)
+                    .collect::<HashSet<_>>(),
+            ),
+            None => None,
+        };
+        let keep = options
+            .limit
+            .map(|limit| options.offset.saturating_add(limit));
+        // The largest of the kept entries is on top, so it is replaced by smaller ones
+        let mut entries = BinaryHeap::<String>::new();
+        let glob = format!("{}/*", self.options().ref_namespace);
+        self.repo.for_each_reference(&glob, |name| {
+            if name.starts_with(METADATA_REF_PREFIX) {
+                return;
+            }
+            if let Some(package_ids) = &package_ids {
+                let package_id = self.package_id_of(name);
+                if !package_id.is_some_and(|id| package_ids.contains(id)) {
+                    return;
+                }
+            }
+            if let Some(keep) = keep
+                && entries.len() >= keep
+            {
+                match entries.peek() {
+                    Some(largest) if name < largest.as_str() => {
+                        entries.pop();
+                    }
+                    _ => return,
+                }
+            }
+            entries.push(name.to_string());
+        })?;
+        Ok(entries.into_sorted_vec().into_iter().skip(options.offset))
+    }
+
     fn num_available_packages(&self) -> Result<usize> {
-        Ok(self.repo.list_references("refs/*/narinfo")?.len())
+        Ok(self
+            .repo
+            .list_references(&self.package_ref_glob("narinfo"))?
+            .len())
     }
 
     pub fn get_commit(&self, hash: &str) -> Option<Oid> {
-        self.repo.get_oid_from_reference(&self.get_result_ref(hash))
+        self.package_refs(hash).result
     }
 
     fn get_package_ref(&self, hash: &str) -> String {
-        format!("refs/{hash}")
+        format!("{}/{hash}", self.options().ref_namespace)
+    }
+
+    /// Matches the reference of kind `kind`, e.g. `narinfo`, of all packages
+    fn package_ref_glob(&self, kind: &str) -> String {
+        format!("{}/*/{kind}", self.options().ref_namespace)
+    }
+
+    /// The hash of the package whose reference is `reference`
+    fn package_id_of<'a>(&self, reference: &'a str) -> Option<&'a str> {
+        reference
+            .strip_prefix(self.options().ref_namespace.as_str())?
+            .strip_prefix('/')?
+            .split('/')
+            .next()
     }
 
     fn get_result_ref(&self, hash: &str) -> String {
         format!("{}/result", self.get_package_ref(hash))
     }
 
-    fn get_narinfo_ref(&self, hash: &str) -> String {
-        format!("{}/narinfo", self.get_package_ref(hash))
+    fn get_narinfo_ref(&self, hash: &str) -> String {
+        format!("{}/narinfo", self.get_package_ref(hash))
+    }
+
+    fn get_listing_ref(&self, hash: &str) -> String {
+        format!("{}/listing", self.get_package_ref(hash))
+    }
+
+    fn get_nar_ref(&self, key: &str) -> String {
+        format!("{METADATA_REF_PREFIX}/nars/{key}")
+    }
+
+    fn get_name_index_ref(&self) -> String {
+        format!("{METADATA_REF_PREFIX}/name-index")
+    }
+
+    fn get_audit_ref(&self) -> String {
+        format!("{METADATA_REF_PREFIX}/audit")
+    }
+
+    fn get_package_count_ref(&self) -> String {
+        format!("{METADATA_REF_PREFIX}/package-count")
+    }
+}
+
+/// Parsed narinfos of the closure a single operation walks, so that every narinfo blob is read once.
+/// Unlike the `NarinfoCache` of served narinfos, it is dropped with the operation.
+#[derive(Default)]
+struct ClosureNarinfos {
+    narinfos: HashMap<String, NarInfo>,
+    blob_reads: usize,
+}
+
+impl ClosureNarinfos {
+    fn get<B: NixBackend>(&mut self, store: &Store<B>, package_id: &str) -> Result<&NarInfo> {
+        if !self.narinfos.contains_key(package_id) {
+            let narinfo = store.get_parsed_narinfo(package_id)?;
+            self.blob_reads += 1;
+            self.narinfos.insert(package_id.to_string(), narinfo);
+        }
+        Ok(&self.narinfos[package_id])
+    }
+}
+
+fn remote_daemon(
+    settings: &settings::Store,
+    builder: &settings::Builder,
+) -> anyhow::Result<DynNixDaemon> {
+    if builder.transport == settings::SshTransport::Openssh {
+        let mut ssh_args = Vec::new();
+        if let Some(port) = builder.port {
+            ssh_args.extend(["-p".to_string(), port.to_string()]);
+        }
+        if let Some(user) = &builder.user {
+            ssh_args.extend(["-l".to_string(), user.clone()]);
+        }
+        if let Some(key_file) = &builder.ssh_key_path {
+            ssh_args.extend(["-i".to_string(), key_file.to_string_lossy().to_string()]);
+        }
+        if let Some(known_hosts) = &settings.known_hosts_file {
+            ssh_args.extend([
+                "-o".to_string(),
+                format!(
+                    "UserKnownHostsFile={} ~/.ssh/known_hosts",
+                    known_hosts.display()
+                ),
+            ]);
+        }
+        if settings.accept_new_host_keys {
+            ssh_args.extend([
+                "-o".to_string(),
+                "StrictHostKeyChecking=accept-new".to_string(),
+            ]);
+        }
+        return Ok(DynNixDaemon::OpenSsh(
+            NixDaemon::ssh_command(&builder.host, ssh_args).with_timeouts(settings.daemon_timeouts),
+        ));
+    }
+    let key_file = builder
+        .ssh_key_path
+        .as_ref()
+        .or(settings.ssh_private_key_path.as_ref())
+        .ok_or_else(|| {
+            anyhow!(
+                "Path to private ssh key must be specified for remote Nix daemon {}",
+                builder
+            )
+        })?;
+    let ssh = SshOptions {
+        port: builder.port(),
+        user: builder.user().to_string(),
+        private_key_path: key_file.clone(),
+        host_key: HostKeyPolicy {
+            known_hosts_files: settings
+                .known_hosts_file
+                .iter()
+                .cloned()
+                .chain(dirs::home_dir().map(|home| home.join(".ssh/known_hosts")))
+                .collect(),
+            accept_new: settings.accept_new_host_keys,
+            pinned_fingerprint: builder.host_key_fingerprint.clone(),
+        },
+    };
+    Ok(DynNixDaemon::Remote(
+        NixDaemon::remote(&builder.host, ssh).with_timeouts(settings.daemon_timeouts),
+    ))
+}
+
+fn local_daemon(settings: &settings::Store) -> DynNixDaemon {
+    DynNixDaemon::Local(
+        NixDaemon::local(settings.daemon_socket.as_deref()).with_timeouts(settings.daemon_timeouts),
+    )
+}
+
+/// The key in the NAR URL, the nix-base32 SHA-256 of the served NAR. As NARs are served
+/// uncompressed, this is the hash part of the NarHash and FileHash.
+fn nar_key(nar_hash: &str) -> String {
+    nar_hash
+        .strip_prefix("sha256:")
+        .unwrap_or(nar_hash)
+        .to_string()
+}
+
+/// Whether the NAR URL has the package tree as key instead of the NAR hash
+fn is_legacy_nar_key(key: &str) -> bool {
+    key.len() == 40 && key.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// The signature in the `name:base64` form of narinfos
+fn format_signature(signer: &dyn NarSigner, signature: &[u8]) -> String {
+    format!(
+        "{}:{}",
+        signer.key_name(),
+        BASE64_STANDARD.encode(signature)
+    )
+}
+
+/// Adds our signatures to the path in the local Nix store, so it agrees with the cache.
+/// This requires the daemon to trust us, failing to do so is not fatal.
+async fn add_local_signatures(
+    daemon: &mut impl NixBackend,
+    store_path: &NixPath,
+    signatures: &[String],
+) {
+    if let Err(e) = daemon.add_signatures(store_path, signatures).await {
+        warn!(
+            "Could not add signatures to {} in the local Nix store: {:#}",
+            store_path, e
+        );
+    }
+}
+
+fn check_nar_digest(narinfo: &NarInfo, digest: &NarDigest) -> Result<()> {
+    let nar_hash = digest.nix_hash();
+    if nar_hash != narinfo.nar_hash || digest.size != narinfo.nar_size {
+        return Err(anyhow!(
+            "NAR of {} has hash {} and size {}, but {} and {} were expected",
+            narinfo.store_path,
+            nar_hash,
+            digest.size,
+            narinfo.nar_hash,
+            narinfo.nar_size
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Measures how long reads block, i.e. how long the decoder waits for a Nix daemon
+struct WaitTimingReader<R> {
+    inner: R,
+    waited: Duration,
+}
+
+impl<R: Read> Read for WaitTimingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let start = Instant::now();
+        let result = self.inner.read(buf);
+        self.waited += start.elapsed();
+        result
     }
 }
 
+fn parse_package_count(content: &[u8]) -> anyhow::Result<usize> {
+    let count = std::str::from_utf8(content)?.trim();
+    count
+        .parse()
+        .with_context(|| format!("Invalid cached package count '{count}'"))
+}
+
 #[cfg(test)]
 mod tests {
+    use crate::client::BinaryCacheClient;
+    use crate::fixtures;
+    use crate::git_store::audit::{AuditFilter, AuditOperation, AuditOutcome};
+    use crate::git_store::error::Error;
+    use crate::git_store::name_index::NameIndex;
+    use crate::nar;
     use crate::{
-        git_store::store::Store,
+        git_store::store::{
+            AddOutcome, AddSummary, ClosureNarinfos, ListOptions, Listing, RemoteMatch, Store,
+        },
         nix_interface::{
+            backend::NixBackend,
             daemon::{DynNixDaemon, NixDaemon},
+            mock::MockNixBackend,
+            nar_info::NarInfo,
             path::NixPath,
+            signature::{PrivateKey, PublicKey},
         },
-        settings,
+        settings::{self, RemoteConfig},
     };
     use anyhow::Result;
+    use regex::Regex;
+    use sha2::{Digest, Sha256};
+    use std::collections::HashMap;
+    use std::io::Read;
     use std::path::PathBuf;
     use std::process::Command;
     use tempfile::TempDir;
 
-    fn build_nix_package(package_name: &str) -> Result<NixPath> {
-        let output = Command::new("nix")
-            .arg("build")
-            .arg(format!("nixpkgs#{}", package_name))
-            .arg("--print-out-paths")
-            .output()?;
+    pub fn set_repo_path(path: &PathBuf) -> settings::Store {
+        settings::Store::new(path.clone())
+    }
 
-        let path = NixPath::new(&String::from_utf8_lossy(&output.stdout).to_string())?;
-        Ok(path)
+    /// The NAR of a regular file with `content`
+    fn regular_file_nar(content: &[u8]) -> Vec<u8> {
+        let mut nar = Vec::new();
+        for token in [
+            b"nix-archive-1".as_slice(),
+            b"(",
+            b"type",
+            b"regular",
+            b"contents",
+            content,
+            b")",
+        ] {
+            nar.extend((token.len() as u64).to_le_bytes());
+            nar.extend(token);
+            nar.resize(nar.len().next_multiple_of(8), 0);
+        }
+        nar
     }
 
-    pub fn set_repo_path(path: &PathBuf) -> settings::Store {
-        settings::Store {
-            path: path.clone(),
-            builders: vec![],
-            remotes: vec![],
-            use_local_nix_daemon: true,
-            sign_private_key_path: None,
-            ssh_private_key_path: None,
+    #[test]
+    fn test_import_nar() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let mut settings = set_repo_path(&temp_dir.path().join("gachix"));
+        settings.use_local_nix_daemon = false;
+        let store = Store::new(settings)?;
+        let dependency = NixPath::new("/nix/store/0c1z6sxy9wkrg9pcd9ls2bd3qi3ifrx2-dependency")?;
+        let package = NixPath::new("/nix/store/1c1z6sxy9wkrg9pcd9ls2bd3qi3ifrx2-package")?;
+
+        // references have to be imported first
+        let nar = regular_file_nar(b"package");
+        let error = store
+            .import_nar(nar.as_slice(), &package, vec![dependency.clone()], None)
+            .unwrap_err();
+        assert!(error.to_string().contains("has to be added first"));
+        let error = store
+            .import_nar(&b"not a NAR at all"[..], &dependency, vec![], None)
+            .unwrap_err();
+        assert!(matches!(error, Error::Nar(nar::Error::InvalidNar(_))));
+
+        let dependency_nar = regular_file_nar(b"dependency");
+        let compressed = zstd::encode_all(dependency_nar.as_slice(), 0)?;
+        store.import_nar(compressed.as_slice(), &dependency, vec![], None)?;
+        let summary = store.import_nar(
+            nar.as_slice(),
+            &package,
+            vec![dependency.clone(), package.clone()],
+            None,
+        )?;
+        assert!(!summary.has_failures());
+
+        for (path, nar) in [(&dependency, &dependency_nar), (&package, &nar)] {
+            let narinfo = store.get_narinfo(path.get_base_32_hash())?.unwrap();
+            let narinfo = NarInfo::parse(std::str::from_utf8(&narinfo)?)?;
+            assert_eq!(narinfo.nar_size, nar.len() as u64);
+            assert_eq!(
+                narinfo.key,
+                nix_base32::to_nix_base32(&Sha256::digest(nar.as_slice()))
+            );
+            // URLs of packages added before they were content-addressed keep working
+            let package_oid = store.package_oid(path.get_base_32_hash())?;
+            for key in [narinfo.key.clone(), package_oid.to_string()] {
+                let stream = store.get_as_nar_stream(&key)?.unwrap();
+                let mut served = Vec::new();
+                stream.into_sync_reader().read_to_end(&mut served)?;
+                assert_eq!(&served, nar);
+            }
+            store.verify(path.get_base_32_hash())?;
+        }
+        assert!(store.get_as_nar_stream(&"0".repeat(52))?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_audit_log() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let mut settings = set_repo_path(&temp_dir.path().join("gachix"));
+        settings.use_local_nix_daemon = false;
+        let store = Store::new(settings.clone())?.with_actor("alice");
+        let dependency = NixPath::new("/nix/store/0c1z6sxy9wkrg9pcd9ls2bd3qi3ifrx2-dependency")?;
+        let package = NixPath::new("/nix/store/1c1z6sxy9wkrg9pcd9ls2bd3qi3ifrx2-package")?;
+        store.import_nar(
+            regular_file_nar(b"dependency").as_slice(),
+            &dependency,
+            vec![],
+            None,
+        )?;
+        store.import_nar(
+            regular_file_nar(b"package").as_slice(),
+            &package,
+            vec![dependency.clone()],
+            None,
+        )?;
+
+        let entries = store.audit_log(&AuditFilter::default())?;
+        let packages: Vec<_> = entries.iter().map(|e| e.package.as_str()).collect();
+        assert_eq!(
+            packages,
+            [package.get_base_32_hash(), dependency.get_base_32_hash()]
+        );
+        assert!(entries.iter().all(|e| e.actor == "alice"
+            && e.operation == AuditOperation::Import
+            && e.outcome == AuditOutcome::Ok));
+
+        let filter = AuditFilter {
+            since: None,
+            package: Some(dependency.get_base_32_hash().to_string()),
+        };
+        assert_eq!(store.audit_log(&filter)?.len(), 1);
+        let filter = AuditFilter {
+            since: Some("9999-01-01".to_string()),
+            package: None,
+        };
+        assert!(store.audit_log(&filter)?.is_empty());
+
+        // nothing is recorded once the audit log is disabled
+        settings.audit_log = false;
+        let store = Store::new(settings)?;
+        let other = NixPath::new("/nix/store/2c1z6sxy9wkrg9pcd9ls2bd3qi3ifrx2-other")?;
+        store.import_nar(regular_file_nar(b"other").as_slice(), &other, vec![], None)?;
+        assert_eq!(store.audit_log(&AuditFilter::default())?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_dependencies() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let mut settings = set_repo_path(&temp_dir.path().join("gachix"));
+        settings.use_local_nix_daemon = false;
+        let store = Store::new(settings)?.with_actor("alice");
+        let package =
+            |id: usize, name: &str| NixPath::new(&format!("/nix/store/{id:032}-{name}")).unwrap();
+        let (lib, app, tool, unrelated) = (
+            package(0, "lib"),
+            package(1, "app"),
+            package(2, "tool"),
+            package(3, "unrelated"),
+        );
+        for (path, references) in [
+            (&lib, vec![lib.clone()]),
+            (&app, vec![lib.clone()]),
+            (&tool, vec![app.clone(), lib.clone(), tool.clone()]),
+            (&unrelated, vec![]),
+        ] {
+            let nar = regular_file_nar(path.get_name().as_bytes());
+            store.import_nar(nar.as_slice(), path, references, None)?;
+        }
+
+        let hash = |path: &NixPath| path.get_base_32_hash().to_string();
+        assert_eq!(
+            store.reverse_dependencies(lib.get_base_32_hash())?,
+            [hash(&app), hash(&tool)]
+        );
+        assert_eq!(
+            store.reverse_dependencies(app.get_base_32_hash())?,
+            [hash(&tool)]
+        );
+        // a package referencing itself is not its own reverse dependency
+        assert!(
+            store
+                .reverse_dependencies(tool.get_base_32_hash())?
+                .is_empty()
+        );
+        assert!(
+            store
+                .reverse_dependencies(unrelated.get_base_32_hash())?
+                .is_empty()
+        );
+        assert!(store.reverse_dependencies(&"z".repeat(32)).is_err());
+
+        let history = store.package_history(lib.get_base_32_hash())?;
+        assert_eq!(history.narinfo.store_path, lib);
+        assert_eq!(
+            Some(history.commit),
+            store.get_commit(lib.get_base_32_hash())
+        );
+        assert_eq!(history.entries.len(), 1);
+        assert_eq!(history.entries[0].operation, AuditOperation::Import);
+        assert_eq!(history.entries[0].actor, "alice");
+        assert_eq!(history.changes(), (0, 0));
+        assert_eq!(history.reverse_dependencies, [hash(&app), hash(&tool)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_added_packages_are_visible_immediately() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let mut settings = set_repo_path(&temp_dir.path().join("gachix"));
+        settings.use_local_nix_daemon = false;
+        let server = Store::new(settings.clone())?;
+        // e.g. `gachix add` running next to the server
+        let other_process = Store::new(settings)?;
+        let package = NixPath::new("/nix/store/0c1z6sxy9wkrg9pcd9ls2bd3qi3ifrx2-hello")?;
+        let id = package.get_base_32_hash();
+
+        assert_eq!(server.get_narinfo(id)?, None);
+        assert!(!server.entry_exists(id)?);
+        other_process.import_nar(
+            regular_file_nar(b"hello").as_slice(),
+            &package,
+            vec![],
+            None,
+        )?;
+        assert!(server.entry_exists(id)?);
+        assert!(server.get_narinfo(id)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_packages_are_found_in_packed_refs() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo_path = temp_dir.path().join("gachix");
+        let mut settings = set_repo_path(&repo_path);
+        settings.use_local_nix_daemon = false;
+        let store = Store::new(settings.clone())?;
+        let package = NixPath::new("/nix/store/0c1z6sxy9wkrg9pcd9ls2bd3qi3ifrx2-hello")?;
+        let id = package.get_base_32_hash();
+        store.import_nar(
+            regular_file_nar(b"hello").as_slice(),
+            &package,
+            vec![],
+            None,
+        )?;
+        assert!(store.entry_exists(id)?);
+
+        store.repack()?;
+        assert!(!repo_path.join(".git/refs").join(id).join("result").exists());
+        assert!(store.entry_exists(id)?);
+        // loaded from the packed references
+        assert!(Store::new(settings)?.entry_exists(id)?);
+        assert!(!store.entry_exists(&"1".repeat(32))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_imports_dont_block_reads() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let mut settings = set_repo_path(&temp_dir.path().join("gachix"));
+        settings.use_local_nix_daemon = false;
+        let store = Store::new(settings)?;
+        let served = NixPath::new("/nix/store/0c1z6sxy9wkrg9pcd9ls2bd3qi3ifrx2-served")?;
+        store.import_nar(
+            regular_file_nar(b"served").as_slice(),
+            &served,
+            vec![],
+            None,
+        )?;
+
+        // the last two import the same package
+        let packages: Vec<_> = (1..=5)
+            .map(|i| {
+                NixPath::new(&format!(
+                    "/nix/store/{i}c1z6sxy9wkrg9pcd9ls2bd3qi3ifrx2-big"
+                ))
+            })
+            .chain([NixPath::new(
+                "/nix/store/5c1z6sxy9wkrg9pcd9ls2bd3qi3ifrx2-big",
+            )])
+            .collect::<std::result::Result<_, _>>()?;
+        let done = std::sync::atomic::AtomicBool::new(false);
+        let (summaries, latencies) = std::thread::scope(|scope| {
+            let readers: Vec<_> = (0..2)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut slowest = std::time::Duration::ZERO;
+                        while !done.load(std::sync::atomic::Ordering::SeqCst) {
+                            let start = std::time::Instant::now();
+                            assert!(
+                                store
+                                    .get_narinfo(served.get_base_32_hash())
+                                    .unwrap()
+                                    .is_some()
+                            );
+                            slowest = slowest.max(start.elapsed());
+                        }
+                        slowest
+                    })
+                })
+                .collect();
+            let importers: Vec<_> = packages
+                .iter()
+                .map(|package| {
+                    let store = &store;
+                    scope.spawn(move || {
+                        let content = vec![package.get_base_32_hash().as_bytes()[0]; 8 << 20];
+                        store.import_nar(
+                            regular_file_nar(&content).as_slice(),
+                            package,
+                            vec![],
+                            None,
+                        )
+                    })
+                })
+                .collect();
+            let summaries: Vec<_> = importers.into_iter().map(|i| i.join().unwrap()).collect();
+            done.store(true, std::sync::atomic::Ordering::SeqCst);
+            let latencies: Vec<_> = readers.into_iter().map(|r| r.join().unwrap()).collect();
+            (summaries, latencies)
+        });
+
+        let summaries = summaries
+            .into_iter()
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let added: usize = summaries.iter().map(|s| s.count(AddOutcome::Added)).sum();
+        let present: usize = summaries
+            .iter()
+            .map(|s| s.count(AddOutcome::AlreadyPresent))
+            .sum();
+        assert_eq!((added, present), (5, 1));
+        for slowest in latencies {
+            assert!(
+                slowest < std::time::Duration::from_millis(500),
+                "{slowest:?}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_packages() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let mut settings = set_repo_path(&temp_dir.path().join("gachix"));
+        settings.use_local_nix_daemon = false;
+        let store = Store::new(settings)?;
+        let old = NixPath::new("/nix/store/0c1z6sxy9wkrg9pcd9ls2bd3qi3ifrx2-hello-1.0")?;
+        let new = NixPath::new("/nix/store/1c1z6sxy9wkrg9pcd9ls2bd3qi3ifrx2-hello-1.1")?;
+        store.import_nar(regular_file_nar(b"v1").as_slice(), &old, vec![], None)?;
+        store.import_nar(regular_file_nar(b"v1.1").as_slice(), &new, vec![], None)?;
+
+        let diff = store.diff_packages(old.get_base_32_hash(), new.get_base_32_hash(), false)?;
+        assert_eq!(diff.changes.len(), 1);
+        assert_eq!(diff.changes[0].path, "/");
+        assert_eq!(diff.size_delta(), 2);
+
+        let missing = "2c1z6sxy9wkrg9pcd9ls2bd3qi3ifrx2";
+        let error = store
+            .diff_packages(old.get_base_32_hash(), missing, false)
+            .unwrap_err();
+        assert!(matches!(&error, Error::PackageNotFound(id) if id == missing));
+        assert!(error.to_string().contains("is not cached"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_package() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let mut settings = set_repo_path(&temp_dir.path().join("gachix"));
+        settings.use_local_nix_daemon = false;
+        let store = Store::new(settings)?;
+        let package = NixPath::new("/nix/store/0c1z6sxy9wkrg9pcd9ls2bd3qi3ifrx2-hello")?;
+        let nar = regular_file_nar(b"hello");
+        store.import_nar(nar.as_slice(), &package, vec![], None)?;
+
+        let mut exported = Vec::new();
+        store.export_nar(package.get_base_32_hash(), &mut exported)?;
+        assert_eq!(exported, nar);
+
+        let mut archive = Vec::new();
+        store.export_tar(package.get_base_32_hash(), &mut archive)?;
+        assert!(archive.starts_with(b"0c1z6sxy9wkrg9pcd9ls2bd3qi3ifrx2-hello\0"));
+        assert_eq!(&archive[512..517], b"hello");
+        Ok(())
+    }
+
+    #[test]
+    fn test_closure_from_remote_reads_each_narinfo_once() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let mut settings = set_repo_path(&temp_dir.path().join("peer"));
+        settings.use_local_nix_daemon = false;
+        let peer = Store::new(settings)?;
+        let mut settings = set_repo_path(&temp_dir.path().join("gachix"));
+        settings.use_local_nix_daemon = false;
+        let store = Store::new(settings)?;
+
+        // Layers of packages which reference every package of the layer below,
+        // so there are many more edges than packages
+        let package =
+            |id: usize, name: &str| NixPath::new(&format!("/nix/store/{id:032}-{name}")).unwrap();
+        let (layers, width) = (6, 4);
+        let mut below: Vec<NixPath> = Vec::new();
+        for layer in 0..layers {
+            let mut current = Vec::new();
+            for i in 0..width {
+                let path = package(layer * width + i, &format!("layer-{layer}-{i}"));
+                let nar = regular_file_nar(path.get_name().as_bytes());
+                peer.import_nar(nar.as_slice(), &path, below.clone(), None)?;
+                current.push(path);
+            }
+            below = current;
+        }
+        let root = package(layers * width, "root");
+        peer.import_nar(regular_file_nar(b"root").as_slice(), &root, below, None)?;
+
+        // the bottom layer was fetched from the peer before
+        let remote: RemoteConfig =
+            format!("file://{}", temp_dir.path().join("peer").display()).parse()?;
+        for i in 0..width {
+            store.fetch_from_remote(&format!("{i:032}"), &remote)?;
+        }
+        store.fetch_from_remote(root.get_base_32_hash(), &remote)?;
+        assert!(store.get_commit(root.get_base_32_hash()).is_some());
+        let mut summary = AddSummary::default();
+        let mut narinfos = ClosureNarinfos::default();
+        store.complete_closure_from_remote(
+            root.get_base_32_hash(),
+            &remote,
+            &mut summary,
+            &mut narinfos,
+        )?;
+
+        let fetched = (layers - 1) * width;
+        assert_eq!(summary.count(AddOutcome::FetchedFromPeer), fetched);
+        assert_eq!(narinfos.blob_reads, fetched + 1);
+        for id in 0..layers * width {
+            assert!(store.entry_exists(&format!("{id:032}"))?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_remote() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let mut settings = set_repo_path(&temp_dir.path().join("peer"));
+        settings.use_local_nix_daemon = false;
+        let peer = Store::new(settings)?;
+        let peer_url = format!("file://{}", temp_dir.path().join("peer").display());
+        let mut settings = set_repo_path(&temp_dir.path().join("gachix"));
+        settings.use_local_nix_daemon = false;
+        settings.remotes = vec![peer_url.parse::<RemoteConfig>()?];
+        let store = Store::new(settings.clone())?;
+        // Large files are chunked by this store but not by the peer
+        settings.chunk_threshold = Some(4);
+        let chunking = Store::new(settings)?;
+
+        let package =
+            |id: usize, name: &str| NixPath::new(&format!("/nix/store/{id:032}-{name}")).unwrap();
+        let identical = package(0, "identical");
+        let other_closure = package(1, "other-closure");
+        let divergent = package(2, "divergent");
+        let different = package(3, "different");
+        let missing = package(4, "missing");
+        for target in [&peer, &store] {
+            target.import_nar(
+                regular_file_nar(b"same").as_slice(),
+                &identical,
+                vec![],
+                None,
+            )?;
         }
+        peer.import_nar(
+            regular_file_nar(b"same").as_slice(),
+            &other_closure,
+            vec![identical.clone()],
+            None,
+        )?;
+        store.import_nar(
+            regular_file_nar(b"same").as_slice(),
+            &other_closure,
+            vec![],
+            None,
+        )?;
+        let large = regular_file_nar(b"larger than a chunk");
+        peer.import_nar(large.as_slice(), &divergent, vec![], None)?;
+        chunking.import_nar(large.as_slice(), &divergent, vec![], None)?;
+        peer.import_nar(regular_file_nar(b"a").as_slice(), &different, vec![], None)?;
+        store.import_nar(regular_file_nar(b"b").as_slice(), &different, vec![], None)?;
+        store.import_nar(regular_file_nar(b"c").as_slice(), &missing, vec![], None)?;
+
+        let package_ids: Vec<String> =
+            [&identical, &other_closure, &divergent, &different, &missing]
+                .iter()
+                .map(|path| path.get_base_32_hash().to_string())
+                .collect();
+        let compared = |deep| -> Result<Vec<RemoteMatch>> {
+            let mut matches = store.verify_remote(&peer_url, &package_ids, deep)?;
+            matches.sort_by(|a, b| a.0.cmp(&b.0));
+            Ok(matches.into_iter().map(|(_, m)| m).collect())
+        };
+        use RemoteMatch::*;
+        assert_eq!(
+            compared(false)?,
+            [
+                Identical,
+                DifferentCommit,
+                DifferentCommit,
+                DifferentCommit,
+                Missing
+            ]
+        );
+        assert_eq!(
+            compared(true)?,
+            [Identical, SameTree, Divergent, DifferentNar, Missing]
+        );
+        // neither the fetched references nor objects are kept
+        let remote_commit = peer.get_commit(different.get_base_32_hash()).unwrap();
+        assert!(store.repo.get_commit_tree(remote_commit).is_err());
+        assert!(store.repo.list_references("refs/verify/*")?.is_empty());
+        assert!(std::fs::read_dir(store.repo.git_dir())?.all(|entry| {
+            !entry
+                .unwrap()
+                .file_name()
+                .to_string_lossy()
+                .starts_with("gachix-scratch")
+        }));
+        assert!(store.verify_remote("unknown", &package_ids, false).is_err());
+        Ok(())
+    }
+
+    /// Serves `files` by their path over HTTP, other paths are not found
+    fn serve_files(files: HashMap<String, Vec<u8>>) -> Result<url::Url> {
+        use std::io::{BufRead, BufReader, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let url = url::Url::parse(&format!("http://{}/", listener.local_addr()?))?;
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                let path = request_line.split(' ').nth(1).unwrap_or_default();
+                let (status, body) = match files.get(path) {
+                    Some(body) => (200, body.as_slice()),
+                    None => (404, [].as_slice()),
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} X\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                    body.len()
+                )
+                .unwrap();
+                stream.write_all(body).unwrap();
+            }
+        });
+        Ok(url)
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn test_add_package() -> Result<()> {
+    async fn test_add_closure_from_upstream_checks_narinfos() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let mut settings = set_repo_path(&temp_dir.path().join("gachix"));
+        settings.use_local_nix_daemon = false;
+        let store = Store::new(settings)?;
+        let package =
+            |id: usize, name: &str| NixPath::new(&format!("/nix/store/{id:032}-{name}")).unwrap();
+        let (valid, tampered, other) = (
+            package(0, "valid"),
+            package(1, "tampered"),
+            package(2, "other"),
+        );
+        let narinfo = |path: &NixPath, nar: &[u8]| -> Result<Vec<u8>> {
+            let nar_hash = format!("sha256:{}", nix_base32::to_nix_base32(&Sha256::digest(nar)));
+            let narinfo = NarInfo::builder()
+                .store_path(path.clone())
+                .key(path.get_base_32_hash())
+                .url(format!("nar/{}.nar", path.get_base_32_hash()))
+                .compression(nar::compression::Compression::None)
+                .nar(nar_hash, nar.len() as u64)
+                .build()?;
+            Ok(narinfo.to_string().into_bytes())
+        };
+        let files = HashMap::from([
+            (
+                format!("/{}.narinfo", valid.get_base_32_hash()),
+                narinfo(&valid, &regular_file_nar(b"valid"))?,
+            ),
+            (
+                format!("/nar/{}.nar", valid.get_base_32_hash()),
+                regular_file_nar(b"valid"),
+            ),
+            // the NAR is not the one the narinfo describes
+            (
+                format!("/{}.narinfo", tampered.get_base_32_hash()),
+                narinfo(&tampered, &regular_file_nar(b"tampered"))?,
+            ),
+            (
+                format!("/nar/{}.nar", tampered.get_base_32_hash()),
+                regular_file_nar(b"evil"),
+            ),
+            // served under the hash of another package
+            (
+                format!("/{}.narinfo", other.get_base_32_hash()),
+                narinfo(&valid, &regular_file_nar(b"valid"))?,
+            ),
+        ]);
+        let upstream = BinaryCacheClient::new(serve_files(files)?);
+
+        let summary = store
+            .add_closure_from_upstream(&valid, &upstream, 1)
+            .await?;
+        assert_eq!(summary.count(AddOutcome::Added), 1);
+        store.verify(valid.get_base_32_hash())?;
+
+        let summary = store
+            .add_closure_from_upstream(&tampered, &upstream, 1)
+            .await?;
+        assert_eq!(summary.count(AddOutcome::Failed), 1);
+        assert!(store.get_commit(tampered.get_base_32_hash()).is_none());
+
+        assert!(
+            store
+                .add_closure_from_upstream(&other, &upstream, 1)
+                .await
+                .is_err()
+        );
+        assert!(store.get_commit(other.get_base_32_hash()).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_cached_closure_short_circuits() -> Result<()> {
+        let rt = tokio::runtime::Runtime::new()?;
         let temp_dir = TempDir::new()?;
         let repo_path = temp_dir.path().join("gachix");
-        let store = Store::new(set_repo_path(&repo_path))?;
+        let mut settings = set_repo_path(&repo_path);
+        settings.use_local_nix_daemon = false;
+        let store = Store::new(settings)?;
+        let mut closure: Vec<NixPath> = Vec::new();
+        for (i, name) in ["glibc", "zlib", "hello"].iter().enumerate() {
+            let path = NixPath::new(&format!("/nix/store/{i:032}-{name}"))?;
+            let nar = regular_file_nar(name.as_bytes());
+            store.import_nar(nar.as_slice(), &path, closure.clone(), None)?;
+            closure.push(path);
+        }
+        let root = closure.last().unwrap();
+
+        let summary = rt.block_on(store.add_closure(root))?;
+        assert!(summary.already_cached);
+        assert_eq!(summary.count(AddOutcome::AlreadyPresent), 3);
+        assert_eq!(summary.to_string(), "already cached (3 paths)\n");
+
+        // a gap in the closure falls back to the walk, which stops at the cached root
+        let zlib = closure[1].get_base_32_hash();
+        git2::Repository::open(&repo_path)?
+            .find_reference(&store.get_result_ref(zlib))?
+            .delete()?;
+        store.forget_package(zlib);
+        let summary = rt.block_on(store.add_closure(root))?;
+        assert!(!summary.already_cached);
+        assert_eq!(summary.packages.len(), 1);
+        assert_eq!(summary.count(AddOutcome::AlreadyPresent), 1);
+        Ok(())
+    }
+
+    /// A Nix store holding `package`, a directory tree which depends on the single file `dependency`
+    fn mock_nix_store() -> Result<(MockNixBackend, NixPath, NixPath)> {
+        let backend = MockNixBackend::new();
+        let dependency = NixPath::new("/nix/store/0c1z6sxy9wkrg9pcd9ls2bd3qi3ifrx2-dependency")?;
+        let package = NixPath::new("/nix/store/1c1z6sxy9wkrg9pcd9ls2bd3qi3ifrx2-package")?;
+        backend.add_path(
+            &dependency,
+            fixtures::single_huge_file_nar(1 << 16),
+            &[],
+            None,
+        );
+        backend.add_path(
+            &package,
+            fixtures::deep_tree_nar(3, 4),
+            &[dependency.clone(), package.clone()],
+            None,
+        );
+        Ok((backend, package, dependency))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_add_package() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let (backend, package, _) = mock_nix_store()?;
+        let store = Store::with_backend(set_repo_path(&temp_dir.path().join("gachix")), backend)?;
 
-        let path = build_nix_package("hello")?;
-        store.get_package_from_nix_daemons(&path).await?;
+        let daemon_package = store.get_package_from_nix_daemons(&package).await?.unwrap();
+        assert_eq!(daemon_package.narinfo.store_path, package);
+        assert_eq!(daemon_package.builder, None);
         Ok(())
     }
 
     #[tokio::test(flavor = "multi_thread")]
     async fn test_add_closure() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let (backend, package, dependency) = mock_nix_store()?;
+        let store = Store::with_backend(set_repo_path(&temp_dir.path().join("gachix")), backend)?;
+
+        let summary = store.add_closure(&package).await?;
+        assert_eq!(summary.count(AddOutcome::Added), 2);
+        assert!(store.get_commit(dependency.get_base_32_hash()).is_some());
+        assert!(store.add_closure(&package).await?.already_cached);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_add_closure_with_missing_dependency() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let (_, package, dependency) = mock_nix_store()?;
+        let backend = MockNixBackend::new();
+        backend.add_path(
+            &package,
+            fixtures::deep_tree_nar(3, 4),
+            &[dependency.clone()],
+            None,
+        );
+        let store = Store::with_backend(set_repo_path(&temp_dir.path().join("gachix")), backend)?;
+
+        let summary = store.add_closure(&package).await?;
+        assert_eq!(summary.count(AddOutcome::Failed), 2);
+        assert!(store.get_commit(package.get_base_32_hash()).is_none());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_max_closure_depth() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let (backend, package, dependency) = mock_nix_store()?;
+        let top = NixPath::new("/nix/store/2c1z6sxy9wkrg9pcd9ls2bd3qi3ifrx2-top")?;
+        backend.add_path(&top, regular_file_nar(b"top"), &[package.clone()], None);
+        let mut settings = set_repo_path(&temp_dir.path().join("gachix"));
+        settings.max_closure_depth = 1;
+        let store = Store::with_backend(settings, backend)?;
+
+        // the dependency is two steps away from top
+        let summary = store.add_closure(&top).await?;
+        assert_eq!(summary.count(AddOutcome::Failed), 3);
+        assert!(store.get_commit(top.get_base_32_hash()).is_none());
+
+        let summary = store.add_closure(&package).await?;
+        assert_eq!(summary.count(AddOutcome::Added), 2);
+        assert!(store.get_commit(dependency.get_base_32_hash()).is_some());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_store_options() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let (backend, package, dependency) = mock_nix_store()?;
+        let key_path = temp_dir.path().join("secret-key");
+        std::fs::write(&key_path, TEST_SECRET_KEY)?;
+        let other_key = PrivateKey::generate("other.example.org-1")?;
+        let other_key_path = temp_dir.path().join("other-secret-key");
+        std::fs::write(&other_key_path, other_key.secret_key_string().as_bytes())?;
+        let mut settings = set_repo_path(&temp_dir.path().join("gachix"));
+        settings.sign_key_paths = vec![key_path, other_key_path];
+        settings.ref_namespace = "refs/packages".to_string();
+        settings.compression = nar::compression::Compression::None;
+        let store = Store::with_backend(settings, backend)?;
+        store.add_closure(&package).await?;
+
+        let mut package_ids = store.list_package_ids()?;
+        package_ids.sort();
+        assert_eq!(
+            package_ids,
+            [dependency.get_base_32_hash(), package.get_base_32_hash()]
+        );
+        let entries: Vec<_> = store.list_entries(&ListOptions::default())?.collect();
+        assert!(
+            entries
+                .iter()
+                .all(|entry| entry.starts_with("refs/packages/")),
+            "{entries:?}"
+        );
+
+        // signed by both keys
+        let narinfo = store.get_parsed_narinfo(package.get_base_32_hash())?;
+        let public_keys = [TEST_PUBLIC_KEY.parse()?, other_key.public_key()];
+        assert_eq!(narinfo.signatures.len(), 2);
+        for public_key in public_keys {
+            assert!(narinfo.has_valid_signature(&[public_key]));
+        }
+
+        // listings are stored uncompressed
+        match store.get_listing(package.get_base_32_hash())? {
+            Some(Listing::Json(listing)) => assert!(listing.starts_with('{')),
+            _ => panic!("expected an uncompressed listing"),
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_reload() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let (backend, package, _) = mock_nix_store()?;
+        let mut settings = set_repo_path(&temp_dir.path().join("gachix"));
+        let store = Store::with_backend(settings.clone(), backend)?;
+        store.add_single(&package).await?;
+        assert!(store.sign_all(false).await.is_err());
+
+        // clones share the reloaded options, e.g. those of the server workers
+        let clone = store.clone();
+        let key_path = temp_dir.path().join("secret-key");
+        std::fs::write(&key_path, TEST_SECRET_KEY)?;
+        settings.sign_key_paths = vec![key_path];
+        store.reload(&settings)?;
+        assert_eq!(clone.sign_all(false).await?, 1);
+        let narinfo = clone.get_parsed_narinfo(package.get_base_32_hash())?;
+        assert!(narinfo.has_valid_signature(&[TEST_PUBLIC_KEY.parse()?]));
+
+        // invalid settings keep the current ones
+        settings.sign_key_paths = vec![temp_dir.path().join("missing-key")];
+        assert!(store.reload(&settings).is_err());
+        assert_eq!(store.sign_all(false).await?, 1);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_verify_package() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let (backend, package, _) = mock_nix_store()?;
+        let store = Store::with_backend(set_repo_path(&temp_dir.path().join("gachix")), backend)?;
+
+        store.add_single(&package).await?;
+        // the narinfo hash comes from the Nix daemon, i.e. it is what `nix hash path` reports
+        store.verify(package.get_base_32_hash())?;
+        assert_eq!(store.list_package_ids()?, vec![package.get_base_32_hash()]);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_add_fixed_output_path() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let key_path = temp_dir.path().join("secret-key");
+        std::fs::write(&key_path, TEST_SECRET_KEY)?;
+        let mut settings = set_repo_path(&temp_dir.path().join("gachix"));
+        settings.sign_private_key_path = Some(key_path);
+
+        // like the output of fetchurl, a single file whose path is computed from its hash
+        let tarball = b"not really a tarball".as_slice();
+        let ca = format!(
+            "fixed:sha256:{}",
+            nix_base32::to_nix_base32(&Sha256::digest(tarball))
+        );
+        let path = NixPath::new("/nix/store/2c1z6sxy9wkrg9pcd9ls2bd3qi3ifrx2-source.tar.gz")?;
+        let backend = MockNixBackend::new();
+        backend.add_path(&path, regular_file_nar(tarball), &[], None);
+        backend.set_ca(&path, &ca);
+        let store = Store::with_backend(settings, backend)?;
+        store.add_single(&path).await?;
+
+        let narinfo = store.get_parsed_narinfo(path.get_base_32_hash())?;
+        assert_eq!(narinfo.ca.as_deref(), Some(ca.as_str()));
+        assert!(narinfo.is_trusted(&[]));
+        // CA paths are signed all the same
+        let public_key: PublicKey = TEST_PUBLIC_KEY.parse()?;
+        assert!(narinfo.has_valid_signature(&[public_key]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reject_invalid_narinfo() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+        let package = NixPath::new("/nix/store/1c1z6sxy9wkrg9pcd9ls2bd3qi3ifrx2-package")?;
+        // the deriver of a package has to be a derivation
+        let deriver = NixPath::new("/nix/store/0c1z6sxy9wkrg9pcd9ls2bd3qi3ifrx2-dependency")?;
+        assert!(matches!(
+            store.import_nar(
+                regular_file_nar(b"package").as_slice(),
+                &package,
+                vec![],
+                Some(deriver),
+            ),
+            Err(Error::InvalidNarInfo { issues, .. }) if issues.len() == 1
+        ));
+        assert!(store.get_commit(package.get_base_32_hash()).is_none());
+
+        // narinfos which are already stored are reported by `verify`
+        let narinfo = fixtures::narinfo_text(0).replace("NarSize: 4096", "NarSize: 0");
+        let hash = NarInfo::parse(&narinfo)?
+            .store_path
+            .get_base_32_hash()
+            .to_string();
+        let blob = store.repo.add_file_content(narinfo.as_bytes())?;
+        store.repo.add_ref(&store.get_narinfo_ref(&hash), blob)?;
+        let error = store.verify(&hash).unwrap_err();
+        assert!(error.to_string().contains("NarSize is zero"), "{error}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_add_narinfo() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let (mut backend, package, dependency) = mock_nix_store()?;
+        let store = Store::with_backend(
+            set_repo_path(&temp_dir.path().join("gachix")),
+            backend.clone(),
+        )?;
+
+        let narinfo = store.build_narinfo(&mut backend, &package).await?;
+        let nar = fixtures::deep_tree_nar(3, 4);
+        let nar_hash = nix_base32::to_nix_base32(&Sha256::digest(&nar));
+        assert_eq!(narinfo.key, nar_hash);
+        assert!(
+            narinfo
+                .to_string()
+                .contains(&format!("URL: nar/{nar_hash}.nar\n"))
+        );
+        assert_eq!(narinfo.references, vec![dependency, package]);
+        assert_eq!(narinfo.nar_size, nar.len() as u64);
+        assert_eq!(
+            narinfo.nar_hash,
+            format!(
+                "sha256:{}",
+                nix_base32::to_nix_base32(&Sha256::digest(&nar))
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_startup_does_not_enumerate_refs() -> Result<()> {
         let temp_dir = TempDir::new()?;
         let repo_path = temp_dir.path().join("gachix");
         let store = Store::new(set_repo_path(&repo_path))?;
+        assert_eq!(store.cached_package_count()?, None);
+        store.increase_package_count(2)?;
+        store.increase_package_count(3)?;
+        assert_eq!(store.cached_package_count()?, Some(3));
+
+        // A corrupt packed-refs file makes every enumeration fail, so startup must not touch it
+        std::fs::write(repo_path.join(".git/packed-refs"), "not a packed ref\n")?;
+        let store = Store::new(set_repo_path(&repo_path))?;
+        assert!(store.list_entries(&ListOptions::default()).is_err());
+        assert_eq!(store.cached_package_count()?, Some(3));
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_entries_pages() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+        let blob = store.repo.add_file_content(b"synthetic")?;
+        let mut package_ids: Vec<_> = (0..2000).map(|i| format!("{i:0>32}")).collect();
+        // references are not created in order
+        package_ids.reverse();
+        let mut index = NameIndex::default();
+        for (i, package_id) in package_ids.iter().enumerate() {
+            store
+                .repo
+                .add_ref(&store.get_narinfo_ref(package_id), blob)?;
+            store
+                .repo
+                .add_ref(&store.get_result_ref(package_id), blob)?;
+            if i % 10 == 0 {
+                index.insert("hello-2.12", package_id);
+            }
+        }
+        store
+            .repo
+            .update_blob_ref(&store.get_name_index_ref(), |_| Ok(index.serialize()))?;
+        package_ids.sort();
+
+        let all: Vec<_> = store.list_entries(&ListOptions::default())?.collect();
+        assert_eq!(all.len(), 4000);
+        assert_eq!(all[0], format!("refs/{}/narinfo", package_ids[0]));
+        assert_eq!(all[1], format!("refs/{}/result", package_ids[0]));
+        assert!(all.is_sorted());
+
+        // consecutive pages cover all entries exactly once
+        let mut paged = Vec::new();
+        for offset in (0..4000).step_by(300) {
+            let options = ListOptions {
+                offset,
+                limit: Some(300),
+                ..Default::default()
+            };
+            let page: Vec<_> = store.list_entries(&options)?.collect();
+            assert_eq!(page.len(), 300.min(4000 - offset));
+            paged.extend(page);
+        }
+        assert_eq!(paged, all);
+
+        let past_end = ListOptions {
+            offset: 5000,
+            ..Default::default()
+        };
+        assert_eq!(store.list_entries(&past_end)?.count(), 0);
+        let empty_page = ListOptions {
+            limit: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(store.list_entries(&empty_page)?.count(), 0);
+
+        let filtered = ListOptions {
+            offset: 10,
+            limit: Some(100),
+            name_filter: Some("^hello".to_string()),
+        };
+        let page: Vec<_> = store.list_entries(&filtered)?.collect();
+        let hello_ids: Vec<_> = index
+            .search(&Regex::new("^hello")?)
+            .into_iter()
+            .map(|(_, hash)| hash)
+            .collect();
+        assert_eq!(hello_ids.len(), 200);
+        let expected: Vec<_> = all
+            .iter()
+            .filter(|r| hello_ids.iter().any(|id| r.contains(id.as_str())))
+            .skip(10)
+            .take(100)
+            .cloned()
+            .collect();
+        assert_eq!(page, expected);
+        Ok(())
+    }
+
+    // The signature of the narinfo created by `sign_unsigned_narinfo` by TEST_SECRET_KEY
+    const TEST_SIGNATURE: &str = "cache.example.org-1:jFog+zme0bn4iIErH7hDJwl7gYVQTf4e/nGWldg+bh3CpBbThOpE+Y5pXf6JG8ZAd/eB/id8DxISBTlRgk6QCg==";
+    const TEST_SECRET_KEY: &str = "cache.example.org-1:ZJui+kG6vPCSRD4+p1P4DyUVlASmp/zsaeN84PTFW28tj2/PtQWvFWK6Mw+ay8kGif8AZkR5KosHLvuwlzDlgg==";
+    const TEST_PUBLIC_KEY: &str =
+        "cache.example.org-1:LY9vz7UFrxViujMPmsvJBon/AGZEeSqLBy77sJcw5YI=";
+
+    /// Adds an unsigned narinfo, signs all and returns its signatures
+    async fn sign_unsigned_narinfo(settings: settings::Store) -> Result<Vec<String>> {
+        let store = Store::new(settings)?;
+        let store_path = NixPath::new("/nix/store/5vnba43n1w87cs2i2dd242zy88k4dwf9-zlib-1.3.1")?;
+        let hash = store_path.get_base_32_hash().to_string();
+        let nar_hash = "sha256:1l29f8r5q2739wnq4i7m2v545qx77b3wrdsw9xz2ajiy3hv1al8b";
+        let narinfo = NarInfo::builder()
+            .store_path(store_path.clone())
+            .key("somekey")
+            .file(nar_hash, 42)
+            .nar(nar_hash, 42)
+            .references(vec![store_path])
+            .build()?;
+        let blob = store
+            .repo
+            .add_file_content(narinfo.to_string().as_bytes())?;
+        store.repo.add_ref(&store.get_narinfo_ref(&hash), blob)?;
+
+        assert_eq!(store.sign_all(false).await?, 1);
+        Ok(store.get_parsed_narinfo(&hash)?.signatures)
+    }
+
+    #[test]
+    fn test_resigned_narinfo_is_not_served_from_cache() -> Result<()> {
+        let rt = tokio::runtime::Runtime::new()?;
+        let temp_dir = TempDir::new()?;
+        let mut settings = set_repo_path(&temp_dir.path().join("gachix"));
+        let store = Store::new(settings.clone())?;
+        let package = NixPath::new("/nix/store/0c1z6sxy9wkrg9pcd9ls2bd3qi3ifrx2-hello")?;
+        let id = package.get_base_32_hash();
+        store.import_nar(
+            regular_file_nar(b"hello").as_slice(),
+            &package,
+            vec![],
+            None,
+        )?;
 
-        let path = build_nix_package("sl")?;
-        store.add_closure(&path).await?;
+        let unsigned = store.get_narinfo(id)?.unwrap();
+        assert_eq!(store.get_narinfo(id)?, Some(unsigned.clone()));
+        let stats = store.narinfo_cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+        assert!(!String::from_utf8(unsigned.to_vec())?.contains("Sig:"));
+
+        let key_path = temp_dir.path().join("secret-key");
+        std::fs::write(&key_path, TEST_SECRET_KEY)?;
+        settings.sign_key_paths = vec![key_path];
+        store.reload(&settings)?;
+        assert_eq!(rt.block_on(store.sign_all(false))?, 1);
+        let signed = String::from_utf8(store.get_narinfo(id)?.unwrap().to_vec())?;
+        assert!(signed.contains("Sig: cache.example.org-1:"), "{signed}");
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_add_narinfo() -> Result<()> {
+    async fn test_sign_all() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let key_path = temp_dir.path().join("secret-key");
+        std::fs::write(&key_path, TEST_SECRET_KEY)?;
+        let mut settings = set_repo_path(&temp_dir.path().join("gachix"));
+        settings.sign_private_key_path = Some(key_path);
+        assert_eq!(sign_unsigned_narinfo(settings).await?, vec![TEST_SIGNATURE]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sign_all_with_command_signer() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let signature = TEST_SIGNATURE.split_once(':').unwrap().1;
+        let mut settings = set_repo_path(&temp_dir.path().join("gachix"));
+        settings.signer = Some(settings::Signer {
+            command: vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                format!("while read fingerprint; do echo {signature}; done"),
+            ],
+            public_key: TEST_PUBLIC_KEY.to_string(),
+            timeout: 10,
+        });
+        assert_eq!(
+            sign_unsigned_narinfo(settings.clone()).await?,
+            vec![TEST_SIGNATURE]
+        );
+
+        settings.sign_private_key_path = Some(temp_dir.path().join("secret-key"));
+        assert!(Store::new(settings).is_err());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_reimport_into_nix_store() -> Result<()> {
         let temp_dir = TempDir::new()?;
         let repo_path = temp_dir.path().join("gachix");
         let store = Store::new(set_repo_path(&repo_path))?;
 
-        let path = build_nix_package("kitty")?;
-        let mut nix = DynNixDaemon::Local(NixDaemon::local());
+        let file_path = temp_dir.path().join("gachix-reimport-test");
+        std::fs::write(&file_path, temp_dir.path().to_string_lossy().as_bytes())?;
+        let output = Command::new("nix-store")
+            .arg("--add")
+            .arg(&file_path)
+            .output()?;
+        let path = NixPath::new(String::from_utf8_lossy(&output.stdout).trim())?;
+        store.add_single(&path).await?;
+
+        Command::new("nix-store")
+            .arg("--delete")
+            .arg(path.get_path())
+            .status()?;
+        let mut nix = DynNixDaemon::Local(NixDaemon::local(None));
         nix.connect().await?;
-        store.build_narinfo(&mut nix, "somekey", &path).await?;
+        assert!(!nix.path_exists(&path).await?);
+
+        let narinfo = store.get_parsed_narinfo(path.get_base_32_hash())?;
+        // paths added with `nix-store --add` are content-addressed, so no signature is needed
+        // for the daemon to accept it
+        assert!(narinfo.ca.as_ref().unwrap().starts_with("fixed:r:sha256:"));
+        store.import_into_nix_store(path.get_base_32_hash()).await?;
+        assert!(nix.path_exists(&path).await?);
         Ok(())
     }
 }
@@ -0,0 +1,392 @@
+use crate::nar::chunked::read_chunked_file;
+use crate::nar::entry::EntryKind;
+use anyhow::Result;
+use git2::{Oid, Repository};
+use std::io::{self, Write};
+
+const BLOCK_LEN: usize = 512;
+// Like in the Nix store, every entry has the same modification time so the archive is reproducible
+const MTIME: u64 = 1;
+
+/// Writes the package below `oid` as a tar archive whose single top-level entry is `root_name`.
+/// Entries appear in the byte order of their names, each directory before its contents.
+pub fn write_tar<W: Write>(
+    repo: &Repository,
+    oid: Oid,
+    filemode: i32,
+    root_name: &[u8],
+    writer: W,
+) -> Result<()> {
+    let mut tar = TarWriter { writer };
+    tar.entry(repo, oid, filemode, root_name)?;
+    tar.finish()?;
+    Ok(())
+}
+
+struct TarWriter<W> {
+    writer: W,
+}
+
+#[derive(Clone, Copy)]
+enum EntryType {
+    File,
+    Symlink,
+    Directory,
+}
+
+impl EntryType {
+    fn flag(&self) -> u8 {
+        match self {
+            EntryType::File => b'0',
+            EntryType::Symlink => b'2',
+            EntryType::Directory => b'5',
+        }
+    }
+}
+
+impl<W: Write> TarWriter<W> {
+    fn entry(&mut self, repo: &Repository, oid: Oid, filemode: i32, path: &[u8]) -> Result<()> {
+        match EntryKind::from_filemode(filemode)? {
+            EntryKind::Directory => {
+                let tree = repo.find_tree(oid)?;
+                if let Some((manifest, chunks)) = read_chunked_file(repo, &tree)? {
+                    let mode = if manifest.executable { 0o755 } else { 0o644 };
+                    self.header(path, EntryType::File, mode, manifest.size, b"")?;
+                    for chunk in chunks {
+                        self.writer.write_all(repo.find_blob(chunk)?.content())?;
+                    }
+                    self.padding(manifest.size)?;
+                    return Ok(());
+                }
+                self.header(&[path, b"/"].concat(), EntryType::Directory, 0o755, 0, b"")?;
+                let mut entries: Vec<_> = tree.iter().collect();
+                entries.sort_by(|x, y| x.name_bytes().cmp(y.name_bytes()));
+                for entry in entries {
+                    let entry_path = [path, b"/", entry.name_bytes()].concat();
+                    self.entry(repo, entry.id(), entry.filemode(), &entry_path)?;
+                }
+            }
+            EntryKind::Regular { executable } => {
+                let blob = repo.find_blob(oid)?;
+                let size = blob.size() as u64;
+                let mode = if executable { 0o755 } else { 0o644 };
+                self.header(path, EntryType::File, mode, size, b"")?;
+                self.writer.write_all(blob.content())?;
+                self.padding(size)?;
+            }
+            EntryKind::Symlink => {
+                let blob = repo.find_blob(oid)?;
+                self.header(path, EntryType::Symlink, 0o777, 0, blob.content())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Names and link targets which don't fit into the header are preceded by GNU long name entries
+    fn header(
+        &mut self,
+        path: &[u8],
+        entry_type: EntryType,
+        mode: u32,
+        size: u64,
+        link: &[u8],
+    ) -> io::Result<()> {
+        if link.len() > 100 {
+            self.long_name(b'K', link)?;
+        }
+        if path.len() > 100 {
+            self.long_name(b'L', path)?;
+        }
+        let header = header_block(
+            &path[..path.len().min(100)],
+            entry_type.flag(),
+            mode,
+            size,
+            &link[..link.len().min(100)],
+        );
+        self.writer.write_all(&header)
+    }
+
+    fn long_name(&mut self, flag: u8, name: &[u8]) -> io::Result<()> {
+        // the name is NUL terminated
+        let size = name.len() as u64 + 1;
+        let header = header_block(b"././@LongLink", flag, 0o644, size, b"");
+        self.writer.write_all(&header)?;
+        self.writer.write_all(name)?;
+        self.writer.write_all(&[0])?;
+        self.padding(size)
+    }
+
+    fn padding(&mut self, size: u64) -> io::Result<()> {
+        let remainder = (size % BLOCK_LEN as u64) as usize;
+        if remainder > 0 {
+            self.writer
+                .write_all(&[0; BLOCK_LEN][..BLOCK_LEN - remainder])?;
+        }
+        Ok(())
+    }
+
+    /// An archive ends with two empty blocks
+    fn finish(mut self) -> io::Result<()> {
+        self.writer.write_all(&[0; 2 * BLOCK_LEN])?;
+        self.writer.flush()
+    }
+}
+
+fn header_block(name: &[u8], flag: u8, mode: u32, size: u64, link: &[u8]) -> [u8; BLOCK_LEN] {
+    let mut header = [0; BLOCK_LEN];
+    header[..name.len()].copy_from_slice(name);
+    write_octal(&mut header[100..108], mode as u64);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_number(&mut header[124..136], size);
+    write_octal(&mut header[136..148], MTIME);
+    header[156] = flag;
+    header[157..157 + link.len()].copy_from_slice(link);
+    // GNU format, which allows long names and base-256 sizes
+    header[257..265].copy_from_slice(b"ustar  \0");
+    header[265..269].copy_from_slice(b"root");
+    header[297..301].copy_from_slice(b"root");
+
+    // the checksum is computed with the checksum field filled with spaces
+    header[148..156].fill(b' ');
+    let checksum: u64 = header.iter().map(|b| *b as u64).sum();
+    write_octal(&mut header[148..155], checksum);
+    header
+}
+
+/// Writes `value` as zero padded octal digits followed by a NUL
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+/// Sizes of 8 GiB and more don't fit into octal digits and are stored in base-256
+fn write_number(field: &mut [u8], value: u64) {
+    if value < 1 << (3 * (field.len() - 1)) {
+        write_octal(field, value);
+    } else {
+        field.fill(0);
+        field[0] = 0x80;
+        let len = field.len();
+        field[len - 8..].copy_from_slice(&value.to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::FileMode;
+    use std::collections::BTreeMap;
+    use tempfile::TempDir;
+
+    /// Type flag, mode, contents and link target of an archive entry
+    type TarEntry = (u8, u32, Vec<u8>, Vec<u8>);
+
+    fn read_tar(archive: &[u8]) -> BTreeMap<Vec<u8>, TarEntry> {
+        fn field(header: &[u8], range: std::ops::Range<usize>) -> Vec<u8> {
+            let field = &header[range];
+            let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+            field[..end].to_vec()
+        }
+        fn octal(header: &[u8], range: std::ops::Range<usize>) -> u64 {
+            u64::from_str_radix(std::str::from_utf8(&field(header, range)).unwrap(), 8).unwrap()
+        }
+
+        let mut entries = BTreeMap::new();
+        let mut offset = 0;
+        let (mut long_name, mut long_link) = (None, None);
+        loop {
+            let header = &archive[offset..offset + BLOCK_LEN];
+            if header.iter().all(|b| *b == 0) {
+                assert!(archive[offset..].iter().all(|b| *b == 0));
+                break;
+            }
+            let mut check = header.to_vec();
+            check[148..156].fill(b' ');
+            assert_eq!(
+                check.iter().map(|b| *b as u64).sum::<u64>(),
+                octal(header, 148..156)
+            );
+            assert_eq!(octal(header, 136..148), MTIME);
+
+            let size = octal(header, 124..136) as usize;
+            let data_start = offset + BLOCK_LEN;
+            let data = archive[data_start..data_start + size].to_vec();
+            offset = data_start + size.next_multiple_of(BLOCK_LEN);
+            match header[156] {
+                b'L' => long_name = Some(data[..data.len() - 1].to_vec()),
+                b'K' => long_link = Some(data[..data.len() - 1].to_vec()),
+                flag => {
+                    let name = long_name.take().unwrap_or_else(|| field(header, 0..100));
+                    let link = long_link.take().unwrap_or_else(|| field(header, 157..257));
+                    let mode = octal(header, 100..108) as u32;
+                    entries.insert(name, (flag, mode, data, link));
+                }
+            }
+        }
+        entries
+    }
+
+    #[test]
+    fn test_write_tar() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = Repository::init(temp_dir.path().join("repo"))?;
+        let long_name = "n".repeat(150);
+        let long_target = "t".repeat(120);
+        let mut lib = repo.treebuilder(None)?;
+        lib.insert(&long_name, repo.blob(b"long")?, FileMode::Blob.into())?;
+        let lib = lib.write()?;
+        let mut root = repo.treebuilder(None)?;
+        root.insert("lib", lib, FileMode::Tree.into())?;
+        root.insert(
+            "hello",
+            repo.blob(&[7; 1000])?,
+            FileMode::BlobExecutable.into(),
+        )?;
+        root.insert("link", repo.blob(b"hello")?, FileMode::Link.into())?;
+        let far = repo.blob(long_target.as_bytes())?;
+        root.insert("far", far, FileMode::Link.into())?;
+        let root = root.write()?;
+
+        let mut archive = Vec::new();
+        write_tar(&repo, root, FileMode::Tree.into(), b"pkg", &mut archive)?;
+        assert_eq!(archive.len() % BLOCK_LEN, 0);
+        let entries = read_tar(&archive);
+        let names: Vec<_> = entries.keys().map(|k| String::from_utf8_lossy(k)).collect();
+        assert_eq!(
+            names,
+            [
+                "pkg/".to_string(),
+                "pkg/far".to_string(),
+                "pkg/hello".to_string(),
+                "pkg/lib/".to_string(),
+                format!("pkg/lib/{long_name}"),
+                "pkg/link".to_string(),
+            ]
+        );
+        assert_eq!(
+            entries[b"pkg/hello".as_slice()],
+            (b'0', 0o755, vec![7; 1000], vec![])
+        );
+        assert_eq!(entries[b"pkg/link".as_slice()].3, b"hello");
+        assert_eq!(entries[b"pkg/far".as_slice()].3, long_target.as_bytes());
+        assert_eq!(entries[b"pkg/lib/".as_slice()].0, b'5');
+
+        // the archive only depends on the contents
+        let mut again = Vec::new();
+        write_tar(&repo, root, FileMode::Tree.into(), b"pkg", &mut again)?;
+        assert_eq!(archive, again);
+        Ok(())
+    }
+
+    /// The files below `dir` in the form of the entries of a NAR listing
+    fn unpacked_files(dir: &std::path::Path, prefix: &str, files: &mut Vec<(String, String)>) {
+        use std::os::unix::fs::PermissionsExt;
+        let mut entries: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap())
+            .collect();
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let path = format!("{prefix}/{}", entry.file_name().to_string_lossy());
+            let metadata = std::fs::symlink_metadata(entry.path()).unwrap();
+            if metadata.is_dir() {
+                files.push((path.clone(), "directory".to_string()));
+                unpacked_files(&entry.path(), &path, files);
+            } else if metadata.is_symlink() {
+                let target = std::fs::read_link(entry.path()).unwrap();
+                files.push((path, format!("symlink {}", target.display())));
+            } else {
+                let executable = metadata.permissions().mode() & 0o111 != 0;
+                files.push((path, format!("regular {} {executable}", metadata.len())));
+            }
+        }
+    }
+
+    fn listed_files(node: &serde_json::Value, prefix: &str, files: &mut Vec<(String, String)>) {
+        for (name, entry) in node["entries"].as_object().unwrap() {
+            let path = format!("{prefix}/{name}");
+            match entry["type"].as_str().unwrap() {
+                "directory" => {
+                    files.push((path.clone(), "directory".to_string()));
+                    listed_files(entry, &path, files);
+                }
+                "symlink" => files.push((
+                    path,
+                    format!("symlink {}", entry["target"].as_str().unwrap()),
+                )),
+                _ => {
+                    let executable = entry["executable"].as_bool().unwrap_or(false);
+                    files.push((path, format!("regular {} {executable}", entry["size"])));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_extracted_tar_matches_nar() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = Repository::init(temp_dir.path().join("repo"))?;
+        let mut bin = repo.treebuilder(None)?;
+        bin.insert(
+            "hello",
+            repo.blob(b"#!/bin/sh\n")?,
+            FileMode::BlobExecutable.into(),
+        )?;
+        let bin = bin.write()?;
+        let mut deep = repo.treebuilder(None)?;
+        deep.insert(
+            "d".repeat(120),
+            repo.blob(&[1; 700])?,
+            FileMode::Blob.into(),
+        )?;
+        deep.insert(
+            "empty",
+            repo.treebuilder(None)?.write()?,
+            FileMode::Tree.into(),
+        )?;
+        let deep = deep.write()?;
+        let mut root = repo.treebuilder(None)?;
+        root.insert("bin", bin, FileMode::Tree.into())?;
+        root.insert("s".repeat(101), deep, FileMode::Tree.into())?;
+        root.insert("link", repo.blob(b"bin/hello")?, FileMode::Link.into())?;
+        let root = root.write()?;
+
+        let mut archive = Vec::new();
+        write_tar(&repo, root, FileMode::Tree.into(), b"pkg", &mut archive)?;
+        let out = temp_dir.path().join("out");
+        std::fs::create_dir(&out)?;
+        let mut tar = std::process::Command::new("tar")
+            .arg("-xf")
+            .arg("-")
+            .arg("-C")
+            .arg(&out)
+            .stdin(std::process::Stdio::piped())
+            .spawn()?;
+        tar.stdin.take().unwrap().write_all(&archive)?;
+        assert!(tar.wait()?.success());
+
+        let mut unpacked = Vec::new();
+        unpacked_files(&out.join("pkg"), "", &mut unpacked);
+        let listing = crate::nar::listing::nar_listing(&repo, root, FileMode::Tree.into())?;
+        let listing: serde_json::Value = serde_json::from_str(&listing)?;
+        let mut listed = Vec::new();
+        listed_files(&listing["root"], "", &mut listed);
+        unpacked.sort();
+        listed.sort();
+        assert_eq!(unpacked, listed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_base_256_size() {
+        let mut field = [0; 12];
+        write_number(&mut field, 1 << 40);
+        assert_eq!(field[0], 0x80);
+        assert_eq!(u64::from_be_bytes(field[4..].try_into().unwrap()), 1 << 40);
+        write_number(&mut field, 0o777);
+        assert_eq!(&field, b"00000000777\0");
+    }
+}
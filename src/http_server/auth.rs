@@ -0,0 +1,108 @@
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::web::Data;
+use actix_web::{Error, HttpResponse};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use tracing::warn;
+
+use crate::settings::Auth;
+
+/// Rejects requests without the credentials of `server.auth` if it is set, which is the case
+/// if the app has the `Auth` as data
+pub async fn require_auth(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    if let Some(auth) = req.app_data::<Data<Auth>>()
+        && !is_authorized(req.headers().get(header::AUTHORIZATION), auth)
+    {
+        warn!("Rejected a request without valid credentials");
+        let response = HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Basic realm=\"gachix\""))
+            .finish();
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+fn is_authorized(header: Option<&HeaderValue>, auth: &Auth) -> bool {
+    let Some(credentials) = header
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|encoded| BASE64_STANDARD.decode(encoded.trim()).ok())
+    else {
+        return false;
+    };
+    let expected = format!("{}:{}", auth.user, auth.password().expose());
+    constant_time_eq(&credentials, expected.as_bytes())
+}
+
+/// Compares without returning early, so the time taken does not tell how much of a guess is right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::{App, http::StatusCode, test, web};
+
+    fn basic(credentials: &str) -> (header::HeaderName, String) {
+        (
+            header::AUTHORIZATION,
+            format!("Basic {}", BASE64_STANDARD.encode(credentials)),
+        )
+    }
+
+    #[actix_web::test]
+    async fn test_require_auth() {
+        let auth = Auth::new("nix", "secret");
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(auth))
+                .wrap(from_fn(require_auth))
+                .route("/nix-cache-info", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for (request, expected) in [
+            (
+                test::TestRequest::get().insert_header(basic("nix:secret")),
+                StatusCode::OK,
+            ),
+            (test::TestRequest::get(), StatusCode::UNAUTHORIZED),
+            (
+                test::TestRequest::get().insert_header(basic("nix:wrong")),
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                test::TestRequest::get().insert_header(basic("nix:secret2")),
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                test::TestRequest::get().insert_header((header::AUTHORIZATION, "Bearer secret")),
+                StatusCode::UNAUTHORIZED,
+            ),
+        ] {
+            let response =
+                test::call_service(&app, request.uri("/nix-cache-info").to_request()).await;
+            assert_eq!(response.status(), expected);
+        }
+
+        // without `Auth` everyone is served
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(require_auth))
+                .route("/nix-cache-info", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let request = test::TestRequest::get().uri("/nix-cache-info").to_request();
+        assert_eq!(
+            test::call_service(&app, request).await.status(),
+            StatusCode::OK
+        );
+    }
+}
@@ -1,2 +1,5 @@
+pub mod auth;
+pub mod reload;
+pub mod request_id;
 pub mod server;
-pub use server::start_server;
+pub use server::{ServerConfig, start_server};
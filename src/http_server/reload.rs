@@ -0,0 +1,78 @@
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::web::Data;
+use serde::Serialize;
+use tokio::signal::unix::{SignalKind, signal};
+use tracing::{error, info, warn};
+
+/// Loads the settings again and applies those which can change while the server runs
+pub type Reload = Box<dyn FnMut() -> anyhow::Result<()> + Send>;
+
+/// The settings the server runs with, reported by `/health` so a reload can be confirmed
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ConfigGeneration {
+    /// 0 for the settings the server started with, incremented by each successful reload
+    pub generation: u64,
+    /// Unix time in seconds
+    pub loaded_at: u64,
+}
+
+impl ConfigGeneration {
+    fn now(generation: u64) -> Self {
+        Self {
+            generation,
+            loaded_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+        }
+    }
+}
+
+/// The generation of the settings in effect, shared by the workers
+#[derive(Debug)]
+pub struct CurrentConfig(Mutex<ConfigGeneration>);
+
+impl Default for CurrentConfig {
+    fn default() -> Self {
+        Self(Mutex::new(ConfigGeneration::now(0)))
+    }
+}
+
+impl CurrentConfig {
+    pub fn get(&self) -> ConfigGeneration {
+        *self.0.lock().unwrap()
+    }
+
+    pub(super) fn advance(&self) -> ConfigGeneration {
+        let mut current = self.0.lock().unwrap();
+        *current = ConfigGeneration::now(current.generation + 1);
+        *current
+    }
+}
+
+/// Runs `reload` whenever the process receives SIGHUP. If it fails, the error is logged and
+/// the previous settings stay in effect.
+pub async fn reload_on_hangup(mut reload: Reload, current: Data<CurrentConfig>) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!("The settings can't be reloaded on SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        info!("Received SIGHUP, reloading the settings");
+        // Runs on the thread of the main arbiter, so the workers keep serving meanwhile
+        match reload() {
+            Ok(()) => {
+                let generation = current.advance();
+                info!(
+                    "Reloaded the settings, generation {}",
+                    generation.generation
+                );
+            }
+            Err(e) => error!("Keeping the previous settings, the reload failed: {:#}", e),
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::fmt::Display;
+use std::future::{Ready, ready};
+use std::time::Instant;
+use tracing::{Instrument, field, info, info_span, warn};
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+// Longer ids sent by clients are replaced, so they can't flood the logs
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Correlates the log events of a request with the response the client got
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestId(String);
+
+impl RequestId {
+    fn generate() -> Self {
+        let mut bytes = [0u8; 16];
+        SystemRandom::new()
+            .fill(&mut bytes)
+            .expect("the system random number generator failed");
+        Self(hex::encode(bytes))
+    }
+
+    /// Accepts the id of a proxy or client if it is safe to log
+    fn from_header(value: &HeaderValue) -> Option<Self> {
+        let id = value.to_str().ok()?;
+        let valid = !id.is_empty()
+            && id.len() <= MAX_REQUEST_ID_LEN
+            && id
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b));
+        valid.then(|| Self(id.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromRequest for RequestId {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        // Requests which did not pass the middleware still get an id for their error responses
+        let id = req.extensions().get::<RequestId>().cloned();
+        ready(Ok(id.unwrap_or_else(RequestId::generate)))
+    }
+}
+
+/// Runs a request in a span carrying its id, which is taken from the `X-Request-Id` header
+/// or generated, and echoed in the response. Logs one event per request once the response
+/// headers are ready, so the latency of a streamed NAR does not include sending its body.
+pub async fn trace_request(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(RequestId::from_header)
+        .unwrap_or_else(RequestId::generate);
+    req.extensions_mut().insert(request_id.clone());
+    let method = req.method().clone();
+    let span = info_span!(
+        "request",
+        request_id = %request_id,
+        %method,
+        route = field::Empty,
+        status = field::Empty,
+    );
+    let start = Instant::now();
+
+    let result = next.call(req).instrument(span.clone()).await;
+    let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+    let _guard = span.enter();
+    match result {
+        Ok(mut response) => {
+            // The pattern instead of the path, so requests for different packages are grouped
+            let route = response
+                .request()
+                .match_pattern()
+                .unwrap_or_else(|| "unmatched".to_string());
+            let status = response.status().as_u16();
+            span.record("route", &route).record("status", status);
+            if let Ok(value) = HeaderValue::from_str(request_id.as_str()) {
+                response.headers_mut().insert(REQUEST_ID_HEADER, value);
+            }
+            info!(%method, %route, status, latency_ms, "Request completed");
+            Ok(response)
+        }
+        Err(e) => {
+            warn!(%method, latency_ms, "Request failed: {}", e);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::{App, HttpResponse, test, web};
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use tracing::error;
+
+    /// Collects the formatted log output
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    async fn failing_handler(request_id: RequestId) -> HttpResponse {
+        error!("Something went wrong");
+        HttpResponse::InternalServerError().body(format!("Server error (request id {request_id})"))
+    }
+
+    #[actix_web::test]
+    async fn test_request_ids() {
+        let logs = CapturedLogs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(trace_request))
+                .route("/{hash}.narinfo", web::get().to(failing_handler)),
+        )
+        .await;
+
+        // the id of the client is passed through
+        let request = test::TestRequest::get()
+            .uri("/abc.narinfo")
+            .insert_header((REQUEST_ID_HEADER, "client-id-1"))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(
+            response.headers().get(REQUEST_ID_HEADER).unwrap(),
+            "client-id-1"
+        );
+        let body = test::read_body(response).await;
+        assert_eq!(body, "Server error (request id client-id-1)");
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = logs.lines().collect();
+        assert_eq!(lines.len(), 2, "{logs}");
+        assert!(
+            lines
+                .iter()
+                .all(|line| line.contains("request_id=client-id-1"))
+        );
+        assert!(lines[0].contains("Something went wrong"));
+        assert!(lines[1].contains("route=/{hash}.narinfo"));
+        assert!(lines[1].contains("status=500"));
+        assert!(lines[1].contains("latency_ms="));
+
+        // unsafe ids are replaced
+        for header in [None, Some("not a valid id"), Some(&*"x".repeat(200))] {
+            let mut request = test::TestRequest::get().uri("/abc.narinfo");
+            if let Some(header) = header
+                && let Ok(value) = HeaderValue::from_str(header)
+            {
+                request = request.insert_header((REQUEST_ID_HEADER, value));
+            }
+            let response = test::call_service(&app, request.to_request()).await;
+            let id = response
+                .headers()
+                .get(REQUEST_ID_HEADER)
+                .unwrap()
+                .to_str()
+                .unwrap();
+            assert_eq!(id.len(), 32);
+            assert_ne!(Some(id), header);
+        }
+    }
+}
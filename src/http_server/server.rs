@@ -1,50 +1,174 @@
-use crate::git_store::store::Store;
-use crate::nix_interface::cache_info;
+use super::auth::require_auth;
+use super::reload::{CurrentConfig, Reload, reload_on_hangup};
+use super::request_id::{RequestId, trace_request};
+use crate::git_store::Error;
+use crate::git_store::narinfo_cache::NarinfoCacheStats;
+use crate::git_store::store::{Listing, Store};
+use crate::nar;
+use crate::nix_interface::cache_info::CacheInfo;
+use crate::nix_interface::path::NixPath;
+use crate::settings;
 use actix_web::{
-    App, HttpResponse, HttpServer, Responder, get, head,
-    web::{Data, Path},
+    App, HttpRequest, HttpResponse, HttpServer, Responder, dev, get, head,
+    http::header,
+    middleware::{Compress, Condition, from_fn},
+    web::{Data, Path, PayloadConfig},
 };
 use tracing::error;
-use tracing_actix_web::TracingLogger;
+
+/// A 400 response if `hash` can't be the hash part of a store path
+fn check_hash(hash: &str) -> Result<(), HttpResponse> {
+    NixPath::validate_hash(hash).map_err(|e| HttpResponse::BadRequest().body(e.to_string()))
+}
+
+/// A 500 response carrying the request id, so a failed substitution can be found in the logs
+fn internal_error(request_id: &RequestId, message: &str) -> HttpResponse {
+    HttpResponse::InternalServerError().body(format!("{message} (request id {request_id})"))
+}
 
 #[get("/nix-cache-info")]
-async fn nix_cache_info() -> impl Responder {
-    let default_cache_info = cache_info::CacheInfo::default();
-    HttpResponse::Ok().body(default_cache_info.to_string())
+async fn nix_cache_info(cache_info: Data<CacheInfo>) -> impl Responder {
+    HttpResponse::Ok().body(cache_info.to_string())
+}
+
+/// Whether the server is up and which generation of the settings it runs with
+#[get("/health")]
+async fn health(current: Data<CurrentConfig>) -> impl Responder {
+    let current = current.get();
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "ok",
+        "config_generation": current.generation,
+        "config_loaded_at": current.loaded_at,
+    }))
+}
+
+/// Counters in the Prometheus text format
+#[get("/metrics")]
+async fn metrics(cache: Data<Store>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(render_metrics(&cache.narinfo_cache_stats()))
+}
+
+fn render_metrics(narinfo_cache: &NarinfoCacheStats) -> String {
+    let rows = [
+        (
+            "gachix_narinfo_cache_hits_total",
+            "counter",
+            "Narinfo requests answered from memory",
+            narinfo_cache.hits as f64,
+        ),
+        (
+            "gachix_narinfo_cache_misses_total",
+            "counter",
+            "Narinfo requests which read the repository",
+            narinfo_cache.misses as f64,
+        ),
+        (
+            "gachix_narinfo_cache_hit_ratio",
+            "gauge",
+            "Share of narinfo requests answered from memory",
+            narinfo_cache.hit_rate(),
+        ),
+        (
+            "gachix_narinfo_cache_entries",
+            "gauge",
+            "Narinfos held in memory",
+            narinfo_cache.entries as f64,
+        ),
+        (
+            "gachix_narinfo_cache_bytes",
+            "gauge",
+            "Size of the narinfos held in memory",
+            narinfo_cache.bytes as f64,
+        ),
+    ];
+    rows.iter()
+        .map(|(name, kind, help, value)| {
+            format!("# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n")
+        })
+        .collect()
 }
 
 #[get("/{nix_hash}.narinfo")]
-async fn get_narinfo(cache: Data<Store>, path: Path<String>) -> impl Responder {
+async fn get_narinfo(
+    cache: Data<Store>,
+    path: Path<String>,
+    request_id: RequestId,
+) -> impl Responder {
     let cache = cache.into_inner();
     let hash = path.into_inner();
+    if let Err(response) = check_hash(&hash) {
+        return response;
+    }
     let res = cache.get_narinfo(&hash);
     match res {
         Ok(Some(nar_info)) => HttpResponse::Ok().body(nar_info),
         Ok(None) => HttpResponse::NotFound().body("Entry is not in the Cache"),
         Err(e) => {
             error!("Error while fetching NarInfo: {e}");
-            HttpResponse::InternalServerError().body("Server error while fetching narinfo entry")
+            internal_error(&request_id, "Server error while fetching narinfo entry")
         }
     }
 }
 
-#[get("/nar/{nix_hash}.ls")]
-async fn get_listing(path: Path<String>) -> impl Responder {
+#[get("/{nix_hash}.ls")]
+async fn get_listing(
+    cache: Data<Store>,
+    path: Path<String>,
+    request: HttpRequest,
+    request_id: RequestId,
+) -> impl Responder {
+    let cache = cache.into_inner();
     let hash = path.into_inner();
-    HttpResponse::Ok().body(hash)
+    if let Err(response) = check_hash(&hash) {
+        return response;
+    }
+    let accepts_zstd = request
+        .headers()
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("zstd"));
+
+    let listing = match cache.get_listing(&hash) {
+        Ok(Some(Listing::Zstd(compressed))) if accepts_zstd => {
+            return HttpResponse::Ok()
+                .content_type("application/json")
+                .insert_header((header::CONTENT_ENCODING, "zstd"))
+                .body(compressed);
+        }
+        Ok(Some(Listing::Zstd(compressed))) => zstd::decode_all(compressed.as_slice()),
+        Ok(Some(Listing::Json(listing))) => Ok(listing.into_bytes()),
+        Ok(None) => return HttpResponse::NotFound().body("Entry is not in the Cache"),
+        Err(e) => Err(std::io::Error::other(e)),
+    };
+    match listing {
+        Ok(listing) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(listing),
+        Err(e) => {
+            error!("Error while fetching listing: {e}");
+            internal_error(&request_id, "Server error while fetching listing")
+        }
+    }
 }
 
 #[get("/nar/{file_hash}.nar")]
-async fn get_nar(cache: Data<Store>, path: Path<String>) -> impl Responder {
+async fn get_nar(cache: Data<Store>, path: Path<String>, request_id: RequestId) -> impl Responder {
     let cache = cache.into_inner();
     let hash = path.into_inner();
 
     match cache.get_as_nar_stream(&hash) {
         Ok(Some(nar_stream)) => HttpResponse::Ok().streaming(nar_stream),
         Ok(None) => HttpResponse::NotFound().body("Entry is not in the Cache"),
+        // Detected before streaming, so the client gets an error instead of a truncated NAR
+        Err(e @ Error::Nar(nar::Error::UnsupportedEntry(_))) => {
+            error!("Entry {hash} can't be served as a NAR: {e}");
+            HttpResponse::Conflict().body(format!("{e} (request id {request_id})"))
+        }
         Err(e) => {
             error!("Error while fetching Nar: {e}");
-            HttpResponse::InternalServerError().body("Server error while fetching entry")
+            internal_error(&request_id, "Server error while fetching entry")
         }
     }
 }
@@ -53,26 +177,126 @@ async fn get_nar(cache: Data<Store>, path: Path<String>) -> impl Responder {
 async fn nar_exists(cache: Data<Store>, path: Path<String>) -> impl Responder {
     let cache = cache.into_inner();
     let hash = path.into_inner();
+    if let Err(response) = check_hash(&hash) {
+        return response;
+    }
 
     match cache.entry_exists(&hash) {
-        Ok(true) => HttpResponse::Ok(),
-        _ => HttpResponse::NotFound(),
+        Ok(true) => HttpResponse::Ok().finish(),
+        _ => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// The settings of the HTTP server. All of `server` is applied to actix in `into_actix`
+pub struct ServerConfig {
+    settings: settings::Server,
+    reload: Option<Reload>,
+    current: Data<CurrentConfig>,
+}
+
+impl ServerConfig {
+    pub fn new(settings: settings::Server) -> Self {
+        Self {
+            settings,
+            reload: None,
+            current: Data::new(CurrentConfig::default()),
+        }
+    }
+
+    /// Runs `reload` on SIGHUP, which is expected to apply the settings which can change
+    /// at runtime. The server itself keeps the address and limits it was started with.
+    pub fn with_reload(
+        mut self,
+        reload: impl FnMut() -> anyhow::Result<()> + Send + 'static,
+    ) -> Self {
+        self.reload = Some(Box::new(reload));
+        self
+    }
+
+    /// Announced in `/nix-cache-info`
+    pub fn cache_info(&self) -> CacheInfo {
+        CacheInfo::new(self.settings.want_mass_query, self.settings.priority)
+    }
+
+    /// Binds the server serving `store` to the configured address, it runs once awaited
+    pub fn into_actix(self, store: Store) -> std::io::Result<dev::Server> {
+        let cache_info = self.cache_info();
+        let current = self.current;
+        if let Some(reload) = self.reload {
+            actix_web::rt::spawn(reload_on_hangup(reload, current.clone()));
+        }
+        let settings = self.settings;
+        let limits = settings.limits;
+        let compress = settings.compression.enabled;
+        let auth = settings.auth.clone();
+        let mut server = HttpServer::new(move || {
+            let mut app = App::new()
+                .app_data(Data::new(store.clone()))
+                .app_data(Data::new(cache_info.clone()))
+                .app_data(current.clone())
+                .app_data(PayloadConfig::new(limits.max_request_body_size));
+            if let Some(auth) = &auth {
+                app = app.app_data(Data::new(auth.clone()));
+            }
+            app.wrap(Condition::new(compress, Compress::default()))
+                // Also rejected requests are traced, the middleware added last runs first
+                .wrap(from_fn(require_auth))
+                .wrap(from_fn(trace_request))
+                .service(get_narinfo)
+                .service(nix_cache_info)
+                .service(nar_exists)
+                .service(get_nar)
+                .service(get_listing)
+                .service(health)
+                .service(metrics)
+        })
+        .max_connections(limits.max_connections)
+        .keep_alive(limits.keep_alive())
+        .client_request_timeout(limits.client_request_timeout());
+        if let Some(workers) = settings.workers {
+            server = server.workers(workers);
+        }
+        Ok(server.bind((settings.host.as_str(), settings.port))?.run())
     }
 }
 
 #[actix_web::main]
-pub async fn start_server(host: &str, port: u16, store: Store) -> std::io::Result<()> {
-    HttpServer::new(move || {
-        App::new()
-            .wrap(TracingLogger::default())
-            .app_data(Data::new(store.clone()))
-            .service(get_narinfo)
-            .service(nix_cache_info)
-            .service(nar_exists)
-            .service(get_nar)
-            .service(get_listing)
-    })
-    .bind((host, port))?
-    .run()
-    .await
+pub async fn start_server(config: ServerConfig, store: Store) -> std::io::Result<()> {
+    config.into_actix(store)?.await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+
+    #[actix_web::test]
+    async fn test_health() {
+        let current = Data::new(CurrentConfig::default());
+        let app = test::init_service(App::new().app_data(current.clone()).service(health)).await;
+        let get_health = || test::TestRequest::get().uri("/health").to_request();
+
+        let body: serde_json::Value = test::call_and_read_body_json(&app, get_health()).await;
+        assert_eq!(body["status"], "ok");
+        assert_eq!(body["config_generation"], 0);
+        assert!(body["config_loaded_at"].as_u64().unwrap() > 0);
+
+        current.advance();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, get_health()).await;
+        assert_eq!(body["config_generation"], 1);
+    }
+
+    #[actix_web::test]
+    async fn test_render_metrics() {
+        let rendered = render_metrics(&NarinfoCacheStats {
+            hits: 3,
+            misses: 1,
+            entries: 1,
+            bytes: 512,
+        });
+        assert!(rendered.contains("\ngachix_narinfo_cache_hits_total 3\n"));
+        assert!(rendered.contains("# TYPE gachix_narinfo_cache_hit_ratio gauge\n"));
+        assert!(rendered.contains("\ngachix_narinfo_cache_hit_ratio 0.75\n"));
+        assert!(rendered.contains("\ngachix_narinfo_cache_bytes 512\n"));
+    }
 }
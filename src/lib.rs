@@ -0,0 +1,57 @@
+//! Gachix stores Nix packages in a Git repository and serves them as a Nix binary cache.
+//!
+//! The `gachix` binary is a thin command line interface over this library. The entry points are
+//! [`store::Store`], which adds packages to the repository and reads them back, the NAR codecs
+//! [`nar::NarGitDecoder`] and [`nar::NarGitStream`], and the Nix types in [`nix`].
+//!
+//! Packages can be added without a Nix daemon by importing their NAR:
+//!
+//! ```
+//! use gachix::nix::NixPath;
+//! use gachix::settings;
+//! use gachix::store::{ListOptions, Store};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let dir = tempfile::tempdir()?;
+//! let mut settings = settings::Store::new(dir.path().join("cache"));
+//! settings.use_local_nix_daemon = false;
+//! let store = Store::new(settings)?;
+//!
+//! // The NAR of a regular file containing "hello"
+//! let mut nar = Vec::new();
+//! for token in [&b"nix-archive-1"[..], b"(", b"type", b"regular", b"contents", b"hello", b")"] {
+//!     nar.extend((token.len() as u64).to_le_bytes());
+//!     nar.extend(token);
+//!     nar.resize(nar.len().next_multiple_of(8), 0);
+//! }
+//! let path = NixPath::new("/nix/store/0c0mxnlmvk0wgr8j1ydlfljgwjfgy2va-hello")?;
+//! store.import_nar(&nar[..], &path, vec![], None)?;
+//!
+//! let entries: Vec<String> = store.list_entries(&ListOptions::default())?.collect();
+//! assert!(entries.contains(&"refs/0c0mxnlmvk0wgr8j1ydlfljgwjfgy2va/narinfo".to_string()));
+//!
+//! let narinfo = store.get_narinfo(path.get_base_32_hash())?.expect("package is cached");
+//! assert!(String::from_utf8(narinfo.to_vec())?.contains(&format!("StorePath: {path}")));
+//! let mut exported = Vec::new();
+//! store.export_nar(path.get_base_32_hash(), &mut exported)?;
+//! assert_eq!(exported, nar);
+//! # Ok(())
+//! # }
+//! ```
+pub mod client;
+mod config_template;
+pub mod git_store;
+pub mod http_server;
+mod log_file;
+pub mod nar;
+pub mod nix_interface;
+pub mod settings;
+pub mod telemetry;
+
+#[cfg(test)]
+#[allow(dead_code)]
+#[path = "../tests/common/fixtures.rs"]
+mod fixtures;
+
+pub use git_store::store;
+pub use nix_interface as nix;
@@ -0,0 +1,133 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Appends to a log file which is rolled over once it would grow beyond `max_size` bytes.
+/// Rollovers are numbered, `<file>.1` being the newest, and only `max_files` of them are kept.
+/// Writes from several threads have to be serialized, e.g. by `tracing_appender::non_blocking`.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn new(path: impl Into<PathBuf>, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let path = path.into();
+        if let Some(parent) = path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            fs::create_dir_all(parent)?;
+        }
+        let (file, size) = open(&path)?;
+        Ok(Self {
+            path,
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rollover_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            // The oldest rollover is overwritten by the one before it
+            for n in (1..self.max_files).rev() {
+                let from = self.rollover_path(n);
+                if from.exists() {
+                    fs::rename(from, self.rollover_path(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rollover_path(1))?;
+        }
+        self.reopen()
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        (self.file, self.size) = open(&self.path)?;
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> io::Result<(File, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok((file, size))
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Writing on would go to a file nobody can read anymore
+        if !self.path.exists() {
+            self.reopen()?;
+        }
+        // A single line larger than the limit still ends up in a file of its own
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_rotation() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("logs/gachix.log");
+        let mut file = RotatingFile::new(&path, 20, 2)?;
+        let read = |n: Option<usize>| {
+            let path = match n {
+                Some(n) => format!("{}.{n}", path.display()),
+                None => path.display().to_string(),
+            };
+            fs::read_to_string(path).unwrap_or_default()
+        };
+
+        file.write_all(b"first line\n")?;
+        file.write_all(b"second line\n")?;
+        assert_eq!(read(None), "second line\n");
+        assert_eq!(read(Some(1)), "first line\n");
+
+        file.write_all(b"third line\n")?;
+        file.write_all(b"fourth line\n")?;
+        assert_eq!(read(None), "fourth line\n");
+        assert_eq!(read(Some(1)), "third line\n");
+        assert_eq!(read(Some(2)), "second line\n");
+        // only two rollovers are kept
+        assert!(!temp_dir.path().join("logs/gachix.log.3").exists());
+
+        // the file is recreated after it was removed
+        fs::remove_file(&path)?;
+        file.write_all(b"fifth\n")?;
+        assert_eq!(read(None), "fifth\n");
+
+        // writing to an existing file continues it
+        drop(file);
+        let mut file = RotatingFile::new(&path, 20, 2)?;
+        file.write_all(b"sixth\n")?;
+        assert_eq!(read(None), "fifth\nsixth\n");
+        Ok(())
+    }
+}
@@ -1,68 +1,202 @@
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
-mod git_store;
-mod http_server;
-mod nar;
-mod nix_interface;
-
-use crate::http_server::start_server;
-use crate::nix_interface::path::NixPath;
-use anyhow::Result;
-use git_store::store::Store;
+use clap::{Parser, Subcommand, ValueEnum};
+use flate2::write::GzEncoder;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, IsTerminal, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use gachix::client::BinaryCacheClient;
+use gachix::git_store::add_summary::{AddOutcome, AddSummary};
+use gachix::git_store::audit::{AuditFilter, parse_since};
+use gachix::http_server::{ServerConfig, start_server};
+use gachix::nix::NixPath;
+use gachix::nix::PrivateKey;
+use gachix::nix::path::store_dir;
+use gachix::settings;
+use gachix::store::{ListOptions, RemoteMatch, Store};
+use gachix::telemetry::{self, LogLevelHandle};
 use tokio::runtime::Runtime;
-use tracing_subscriber::EnvFilter;
-mod settings;
+use tracing::warn;
+use url::Url;
 
 fn main() -> Result<()> {
     let args = Args::parse();
 
-    let settings = settings::load_config(&args.config.unwrap_or("".to_string()))?;
+    let settings = load_settings(&args)?;
 
-    let filter =
-        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&settings.log_level));
+    // Need neither the store nor logging
+    if let Command::GenerateKey(x) = &args.cmd {
+        return x.run();
+    }
+    if let Command::Config(x) = &args.cmd {
+        return x.run(&settings);
+    }
 
-    tracing_subscriber::fmt().with_env_filter(filter).init();
+    check_settings(&settings)?;
 
-    let args = Args::parse();
-    let cache = Store::new(settings.store)?;
+    // Flushes the trace and the exported spans once the command is done, also if it failed
+    let telemetry = telemetry::init(&settings, args.trace_out.as_deref())?;
+
+    // Connections to Nix daemons and remotes are only opened once a command needs them
+    let cache = Store::new(settings.store.clone())?;
 
     match args.cmd {
         Command::Add(x) => x.run(&cache)?,
+        Command::Audit(x) => x.run(&cache)?,
+        Command::Config(_) => unreachable!("handled before the store is opened"),
+        Command::DiffPaths(x) => x.run(&cache)?,
+        Command::Doctor(x) => x.run(&cache)?,
+        Command::Export(x) => x.run(&cache)?,
+        Command::FetchUpstream(x) => x.run(&cache)?,
+        Command::GenerateKey(_) => unreachable!("handled before the store is opened"),
+        Command::Import(x) => x.run(&cache)?,
         Command::List(x) => x.run(&cache)?,
-        Command::Serve(x) => x.run(cache, settings.server)?,
+        Command::LogHistory(x) => x.run(&cache)?,
+        Command::Maintenance(x) => x.run(&cache)?,
+        Command::Sign(x) => x.run(&cache)?,
+        Command::Verify(x) => x.run(&cache)?,
+        Command::VerifyRemote(x) => x.run(&cache)?,
+        Command::Serve(x) => x.run(cache, settings, telemetry.log_level())?,
     };
     Ok(())
 }
 
+/// The settings of the config files and the environment, overridden by the command line
+fn load_settings(args: &Args) -> Result<settings::Settings> {
+    let mut settings = settings::load_config(args.config.as_deref(), &args.overrides())?;
+    // Only the long-running server writes the log file, so other commands can't race its rotation
+    if !matches!(args.cmd, Command::Serve(_)) {
+        settings.log.file = None;
+        settings.log.console = true;
+    }
+    Ok(settings)
+}
+
+fn check_settings(settings: &settings::Settings) -> Result<()> {
+    if let Err(issues) = settings.validate() {
+        let issues: Vec<String> = issues.iter().map(|issue| format!("  {issue}")).collect();
+        bail!("Invalid settings:\n{}", issues.join("\n"));
+    }
+    Ok(())
+}
+
 #[derive(Parser)]
 struct Args {
-    #[clap(short, long)]
-    config: Option<String>,
+    /// Config file, by default `/etc/gachix/config.{yaml,toml}` and `$XDG_CONFIG_HOME/gachix/config.{yaml,toml}` are read
+    /// if they exist. A file given here must exist
+    #[clap(short, long, env = "GACHIX_CONFIG")]
+    config: Option<PathBuf>,
+    /// Trust builders whose SSH host key is not known yet and add it to known_hosts
+    #[clap(long, global = true)]
+    accept_new_host_keys: bool,
+    /// Overrides `log.level` of the config, but not `RUST_LOG`
+    #[clap(long, global = true, value_enum)]
+    log_level: Option<settings::LogLevel>,
+    /// Overrides `log.format` of the config
+    #[clap(long, global = true, value_enum)]
+    log_format: Option<settings::LogFormat>,
+    /// Write a trace of the run to this file, which can be opened in Perfetto or chrome://tracing
+    #[clap(long, global = true)]
+    trace_out: Option<PathBuf>,
+    /// Overrides a setting of the config and the environment, e.g. `--set store.compression=zstd`.
+    /// Lists take comma separated elements
+    #[clap(long = "set", global = true, value_name = "KEY=VALUE")]
+    set: Vec<settings::Override>,
     #[command(subcommand)]
     cmd: Command,
 }
 
+impl Args {
+    /// The settings given by flags, followed by those of `--set`, which win if both set a key
+    fn overrides(&self) -> Vec<settings::Override> {
+        let mut overrides = Vec::new();
+        if self.accept_new_host_keys {
+            overrides.push(settings::Override::new(
+                "store.accept_new_host_keys",
+                "true",
+            ));
+        }
+        if let Command::Add(add) = &self.cmd
+            && add.all_outputs
+        {
+            overrides.push(settings::Override::new("store.all_outputs", "true"));
+        }
+        if let Some(level) = self.log_level {
+            overrides.push(settings::Override::new("log.level", level.as_str()));
+        }
+        if let Some(format) = self.log_format
+            && let Some(value) = format.to_possible_value()
+        {
+            overrides.push(settings::Override::new("log.format", value.get_name()));
+        }
+        overrides.extend(self.set.iter().cloned());
+        overrides
+    }
+}
+
 #[derive(Subcommand)]
 enum Command {
     Add(Add),
+    #[command(subcommand)]
+    Audit(Audit),
+    #[command(subcommand)]
+    Config(Config),
+    DiffPaths(DiffPaths),
+    Doctor(Doctor),
+    Export(Export),
+    FetchUpstream(FetchUpstream),
+    GenerateKey(GenerateKey),
+    Import(Import),
     List(List),
+    LogHistory(LogHistory),
+    #[command(subcommand)]
+    Maintenance(Maintenance),
+    Sign(Sign),
+    Verify(Verify),
+    VerifyRemote(VerifyRemote),
     Serve(Serve),
 }
 
 #[derive(Parser)]
 struct Add {
+    /// Store path, a file inside one or a link to it such as `$(which hello)`, or its 32 character hash part
     file_path: PathBuf,
     #[arg(short, long, action)]
     single: bool,
+    /// Print the summary of added packages as JSON
+    #[arg(long, action)]
+    json: bool,
+    /// If the path is a derivation, build it on the configured builders and add its outputs
+    #[arg(long, action)]
+    build_missing: bool,
+    /// Also add the other outputs of the package's derivation, e.g. dev and man
+    #[arg(long, action)]
+    all_outputs: bool,
+    /// Check that all Nix daemons and remotes are reachable before adding
+    #[arg(long, action)]
+    check_peers: bool,
 }
 impl Add {
     async fn run_async(&self, cache: &Store) -> Result<()> {
-        let path = NixPath::new(&self.file_path)?;
-        cache.peer_health_check().await;
-        if self.single {
-            cache.add_single(&path).await?;
+        let path = cache
+            .resolve_store_path(&follow_into_store(&self.file_path).to_string_lossy())
+            .await?;
+        if self.check_peers {
+            cache.peer_health_check().await;
+        }
+        let paths = if self.build_missing && path.is_derivation() {
+            cache.build_missing(&path).await?
         } else {
-            cache.add_closure(&path).await?;
+            vec![path]
+        };
+        for path in paths {
+            let summary = if self.single {
+                cache.add_single(&path).await?
+            } else {
+                cache.add_closure(&path).await?
+            };
+            report_summary(&summary, &path, self.json)?;
         }
         Ok(())
     }
@@ -73,12 +207,595 @@ impl Add {
     }
 }
 
+/// Show which files differ between two cached packages
+#[derive(Parser)]
+struct DiffPaths {
+    /// Store path or 32 character hash part of the old package
+    old: String,
+    /// Store path or 32 character hash part of the new package
+    new: String,
+    /// Show content diffs of small text files
+    #[arg(long, action)]
+    patch: bool,
+    /// Only print the number of changed files and the size difference
+    #[arg(long, action, conflicts_with = "patch")]
+    stat: bool,
+    /// Print the differences as JSON
+    #[arg(long, action)]
+    json: bool,
+}
+impl DiffPaths {
+    fn run(&self, cache: &Store) -> Result<()> {
+        let diff =
+            cache.diff_packages(&package_id(&self.old)?, &package_id(&self.new)?, self.patch)?;
+        if self.json {
+            println!("{}", diff.to_json()?);
+        } else if self.stat {
+            println!("{}", diff.stat());
+        } else {
+            print!("{diff}");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Subcommand)]
+enum Audit {
+    /// Show who added, fetched or signed which package, the newest entries first
+    Log {
+        /// Only entries since a UTC date like 2025-10-01, or within a period like 12h or 7d
+        #[arg(long)]
+        since: Option<String>,
+        /// Only entries about this store path or 32 character hash part
+        #[arg(long)]
+        package: Option<String>,
+        /// Print one JSON object per entry
+        #[arg(long, action)]
+        json: bool,
+    },
+}
+impl Audit {
+    fn run(&self, cache: &Store) -> Result<()> {
+        match self {
+            Audit::Log {
+                since,
+                package,
+                json,
+            } => {
+                let filter = AuditFilter {
+                    since: since
+                        .as_deref()
+                        .map(|since| parse_since(since, std::time::SystemTime::now()))
+                        .transpose()?,
+                    package: package.as_deref().map(package_id).transpose()?,
+                };
+                let mut stdout = std::io::stdout().lock();
+                for entry in cache.audit_log(&filter)? {
+                    if *json {
+                        writeln!(stdout, "{}", entry.to_message())?;
+                    } else {
+                        writeln!(stdout, "{entry}")?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Show when and how a package entered the cache and which cached packages depend on it
+#[derive(Parser)]
+struct LogHistory {
+    /// Store path or its 32 character hash part
+    path: String,
+}
+impl LogHistory {
+    fn run(&self, cache: &Store) -> Result<()> {
+        let history = cache.package_history(&package_id(&self.path)?)?;
+        let mut stdout = std::io::stdout().lock();
+        writeln!(stdout, "{}", history.narinfo.store_path)?;
+        writeln!(stdout, "commit     {}", history.commit)?;
+        if let Some(deriver) = &history.narinfo.deriver {
+            writeln!(stdout, "deriver    {deriver}")?;
+        }
+        writeln!(stdout, "signatures {}", history.narinfo.signatures.len())?;
+        writeln!(stdout)?;
+        if history.entries.is_empty() {
+            writeln!(
+                stdout,
+                "No audit log entries, store.audit_log may have been disabled"
+            )?;
+        }
+        for entry in &history.entries {
+            writeln!(stdout, "{entry}")?;
+        }
+        let (reingested, resigned) = history.changes();
+        if reingested + resigned > 0 {
+            writeln!(
+                stdout,
+                "Added again {reingested} times and signed {resigned} times since"
+            )?;
+        }
+        writeln!(stdout)?;
+        writeln!(
+            stdout,
+            "Referenced by {} cached packages",
+            history.reverse_dependencies.len()
+        )?;
+        for package_id in &history.reverse_dependencies {
+            writeln!(stdout, "  {package_id}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Check that the configured Nix daemons, builders and remotes are reachable
+#[derive(Parser)]
+struct Doctor {}
+impl Doctor {
+    fn run(&self, cache: &Store) -> Result<()> {
+        let rt = Runtime::new()?;
+        if !rt.block_on(cache.peer_health_check()) {
+            bail!("Some peers are not reachable");
+        }
+        println!("All peers are reachable");
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    Nar,
+    Tar,
+    #[value(name = "tar.gz")]
+    TarGz,
+    #[value(name = "tar.zst")]
+    TarZst,
+}
+
+/// Write a cached package to a file or stdout
+#[derive(Parser)]
+struct Export {
+    /// Store path or its 32 character hash part
+    path: String,
+    #[arg(long, value_enum, default_value_t = ExportFormat::Nar)]
+    format: ExportFormat,
+    /// File to write to instead of stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+impl Export {
+    fn run(&self, cache: &Store) -> Result<()> {
+        let package_id = package_id(&self.path)?;
+        let writer: Box<dyn Write> = match &self.output {
+            Some(path) => {
+                Box::new(BufWriter::new(File::create(path).with_context(|| {
+                    format!("Could not create {}", path.display())
+                })?))
+            }
+            None if std::io::stdout().is_terminal() => {
+                bail!("Refusing to write an archive to a terminal, use --output or a pipe")
+            }
+            None => Box::new(BufWriter::new(std::io::stdout())),
+        };
+        match self.format {
+            ExportFormat::Nar => Ok(cache.export_nar(&package_id, writer)?),
+            ExportFormat::Tar => Ok(cache.export_tar(&package_id, writer)?),
+            ExportFormat::TarGz => {
+                let mut encoder = GzEncoder::new(writer, flate2::Compression::default());
+                cache.export_tar(&package_id, &mut encoder)?;
+                encoder.finish()?.flush()?;
+                Ok(())
+            }
+            ExportFormat::TarZst => {
+                let mut encoder = zstd::Encoder::new(writer, 0)?;
+                cache.export_tar(&package_id, &mut encoder)?;
+                encoder.finish()?.flush()?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Resolves links such as `~/.nix-profile/bin/hello` to the file in the Nix store they point to.
+/// Hash parts, paths in the store and paths which don't exist are returned unchanged.
+fn follow_into_store(path: &Path) -> PathBuf {
+    if path.starts_with(store_dir()) || NixPath::is_hash_part(&path.to_string_lossy()) {
+        return path.to_path_buf();
+    }
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// The hash part of a store path, hash parts are checked and passed through
+fn package_id(path_or_hash: &str) -> Result<String> {
+    match path_or_hash.starts_with('/') {
+        true => Ok(NixPath::new(path_or_hash)?.get_base_32_hash().to_string()),
+        false => {
+            NixPath::validate_hash(path_or_hash)?;
+            Ok(path_or_hash.to_string())
+        }
+    }
+}
+
+/// Import a store path and its closure from an upstream binary cache
 #[derive(Parser)]
-struct List {}
+struct FetchUpstream {
+    store_path: PathBuf,
+    /// URL of the binary cache, e.g. https://cache.nixos.org
+    #[arg(long)]
+    from: Url,
+    /// Maximum number of concurrent NAR downloads
+    #[arg(short, long, default_value_t = 4)]
+    jobs: usize,
+    /// Print the summary of added packages as JSON
+    #[arg(long, action)]
+    json: bool,
+}
+impl FetchUpstream {
+    async fn run_async(&self, cache: &Store) -> Result<()> {
+        let path = NixPath::new(&self.store_path)?;
+        let upstream = BinaryCacheClient::new(self.from.clone());
+        let summary = cache
+            .add_closure_from_upstream(&path, &upstream, self.jobs)
+            .await?;
+        report_summary(&summary, &path, self.json)
+    }
+
+    fn run(&self, cache: &Store) -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(self.run_async(cache))
+    }
+}
+
+/// Import a package from a NAR, e.g. `nix nar dump-path <path> | gachix import - --store-path <path>`
+#[derive(Parser)]
+struct Import {
+    /// NAR file, or `-` to read it from stdin. xz, zstd and gzip compressed NARs are accepted
+    file: PathBuf,
+    /// Store path of the package in the NAR
+    #[arg(long)]
+    store_path: PathBuf,
+    /// Store path which the package references, these must already be cached
+    #[arg(long = "reference")]
+    references: Vec<PathBuf>,
+    /// Store path of the derivation which built the package
+    #[arg(long)]
+    deriver: Option<PathBuf>,
+    /// Print the summary of added packages as JSON
+    #[arg(long, action)]
+    json: bool,
+}
+impl Import {
+    fn run(&self, cache: &Store) -> Result<()> {
+        let path = NixPath::new(&self.store_path)?;
+        let references = self
+            .references
+            .iter()
+            .map(NixPath::new)
+            .collect::<Result<Vec<_>, _>>()?;
+        let deriver = self.deriver.as_ref().map(NixPath::new).transpose()?;
+        let summary = if self.file.as_os_str() == "-" {
+            let stdin = std::io::stdin();
+            if stdin.is_terminal() {
+                bail!("Refusing to read a NAR from a terminal, pipe it into `gachix import -`");
+            }
+            cache.import_nar(BufReader::new(stdin), &path, references, deriver)?
+        } else {
+            let file = File::open(&self.file)
+                .with_context(|| format!("Could not open {}", self.file.display()))?;
+            cache.import_nar(BufReader::new(file), &path, references, deriver)?
+        };
+        report_summary(&summary, &path, self.json)
+    }
+}
+
+fn report_summary(summary: &AddSummary, path: &NixPath, json: bool) -> Result<()> {
+    if json {
+        println!("{}", summary.to_json()?);
+    } else {
+        print!("{summary}");
+    }
+    if summary.has_failures() {
+        bail!(
+            "Could not add {} of {} packages in the closure of {}",
+            summary.count(AddOutcome::Failed),
+            summary.packages.len(),
+            path.get_name()
+        );
+    }
+    Ok(())
+}
+
+/// List the references of cached packages, sorted by package hash
+#[derive(Parser)]
+struct List {
+    /// Only list packages whose name matches this regular expression
+    #[arg(short, long)]
+    filter: Option<String>,
+    /// Number of entries to skip
+    #[arg(long, default_value_t = 0)]
+    offset: usize,
+    /// Maximum number of entries to print
+    #[arg(long)]
+    limit: Option<usize>,
+}
 impl List {
     fn run(&self, cache: &Store) -> Result<()> {
-        let result = cache.list_entries()?;
-        result.iter().for_each(|e| println!("{e}"));
+        let options = ListOptions {
+            offset: self.offset,
+            limit: self.limit,
+            name_filter: self.filter.clone(),
+        };
+        let mut stdout = std::io::stdout().lock();
+        for entry in cache.list_entries(&options)? {
+            writeln!(stdout, "{entry}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Subcommand)]
+enum Maintenance {
+    /// Recreate the index which maps package names to their hashes
+    Reindex,
+    /// Recreate the index which maps package trees to their commits
+    RebuildTreeIndex,
+    /// Store the .ls listings of packages which were added without one
+    GenerateListings,
+    /// Roll loose git objects into a pack once there are more than store.git.pack_threshold,
+    /// and pack the references
+    Repack,
+}
+impl Maintenance {
+    fn run(&self, cache: &Store) -> Result<()> {
+        match self {
+            Maintenance::Reindex => {
+                let num_packages = cache.rebuild_name_index()?;
+                println!("Indexed {num_packages} packages");
+            }
+            Maintenance::RebuildTreeIndex => {
+                let num_packages = cache.rebuild_tree_index()?;
+                println!("Indexed {num_packages} packages");
+            }
+            Maintenance::GenerateListings => {
+                let num_packages = cache.generate_listings()?;
+                println!("Generated listings for {num_packages} packages");
+            }
+            Maintenance::Repack => match cache.repack()? {
+                Some(num_objects) => println!("Packed {num_objects} loose objects"),
+                None => println!("Not enough loose objects or packs to repack"),
+            },
+        }
+        Ok(())
+    }
+}
+
+#[derive(Subcommand)]
+enum Config {
+    /// Show which config files were loaded and the resulting settings
+    Show,
+    /// Write a config file with every setting at its default value, explained by comments
+    Init {
+        /// By default `$XDG_CONFIG_HOME/gachix/config.<format>`
+        #[clap(long)]
+        path: Option<PathBuf>,
+        /// By default the one of the extension of `--path`, otherwise yaml
+        #[clap(long, value_enum)]
+        format: Option<settings::ConfigFormat>,
+        /// Replace an existing file
+        #[clap(long)]
+        force: bool,
+        /// Only write the settings of the current configuration which differ from the defaults
+        #[clap(long)]
+        minimal: bool,
+    },
+}
+impl Config {
+    fn run(&self, settings: &settings::Settings) -> Result<()> {
+        match self {
+            Config::Init {
+                path,
+                format,
+                force,
+                minimal,
+            } => {
+                let format = format
+                    .or_else(|| path.as_deref().and_then(settings::ConfigFormat::from_path))
+                    .unwrap_or(settings::ConfigFormat::Yaml);
+                let path = match path {
+                    Some(path) => path.clone(),
+                    None => settings::user_config_file(format)
+                        .context("No config directory found, pass --path")?,
+                };
+                let contents = if *minimal {
+                    settings.minimal_config_file(format)?
+                } else {
+                    settings::Settings::default_config_file(format)?
+                };
+                if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                    std::fs::create_dir_all(dir)?;
+                }
+                // An existing config is only replaced on request
+                let mut file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .create_new(!force)
+                    .truncate(true)
+                    .open(&path)
+                    .with_context(|| {
+                        if path.exists() && !force {
+                            format!(
+                                "{} already exists, pass --force to replace it",
+                                path.display()
+                            )
+                        } else {
+                            format!("Could not create {}", path.display())
+                        }
+                    })?;
+                file.write_all(contents.as_bytes())?;
+                println!("Wrote {}", path.display());
+            }
+            Config::Show => {
+                if settings.config_files.is_empty() {
+                    println!("No config file found, using the defaults");
+                } else {
+                    println!("Loaded, later files override earlier ones:");
+                    for config_file in &settings.config_files {
+                        println!("  {}", config_file.display());
+                    }
+                }
+                if !settings.overrides.is_empty() {
+                    println!("Overridden, taking precedence over the files and the environment:");
+                    for key in &settings.overrides {
+                        println!("  {key} (source: cli)");
+                    }
+                }
+                println!("{settings:#?}");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Create a key pair for signing narinfos, compatible with `nix-store --generate-binary-cache-key`
+#[derive(Parser)]
+struct GenerateKey {
+    /// Name of the key, conventionally the host name of the cache and a number, e.g. `cache.example.org-1`
+    name: String,
+    /// File for the secret key, to be added to `store.sign_key_paths`
+    secret_key_file: PathBuf,
+    /// File for the public key, which clients add to `trusted-public-keys`
+    public_key_file: PathBuf,
+}
+impl GenerateKey {
+    fn run(&self) -> Result<()> {
+        let key = PrivateKey::generate(&self.name)?;
+        // Only the owner may read the secret key, an existing one is never replaced
+        let mut secret_key_file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&self.secret_key_file)
+            .with_context(|| format!("Could not create {}", self.secret_key_file.display()))?;
+        secret_key_file.write_all(key.secret_key_string().as_bytes())?;
+        std::fs::write(&self.public_key_file, key.public_key_string())?;
+        println!("{}", key.public_key_string());
+        Ok(())
+    }
+}
+
+/// Sign the narinfos of all packages with the configured private key
+#[derive(Parser)]
+struct Sign {
+    /// Also add the signatures to the paths in the local Nix store
+    #[arg(long, action)]
+    also_local: bool,
+}
+impl Sign {
+    fn run(&self, cache: &Store) -> Result<()> {
+        let rt = Runtime::new()?;
+        let num_packages = rt.block_on(cache.sign_all(self.also_local))?;
+        println!("Signed {num_packages} packages");
+        Ok(())
+    }
+}
+
+/// Check that stored packages serialize to the NAR hash recorded in their narinfo
+#[derive(Parser)]
+struct Verify {
+    /// Store paths or their 32 character hash parts, all packages are checked if none are given
+    paths: Vec<String>,
+}
+impl Verify {
+    fn run(&self, cache: &Store) -> Result<()> {
+        let package_ids = if self.paths.is_empty() {
+            cache.list_package_ids()?
+        } else {
+            self.paths
+                .iter()
+                .map(|p| package_id(p))
+                .collect::<Result<Vec<_>>>()?
+        };
+        let mut num_failed = 0;
+        for package_id in &package_ids {
+            if let Err(e) = cache.verify(package_id) {
+                println!("{package_id}: {e:#}");
+                num_failed += 1;
+            }
+        }
+        if num_failed > 0 {
+            bail!(
+                "{num_failed} of {} packages failed verification",
+                package_ids.len()
+            );
+        }
+        println!("Verified {} packages", package_ids.len());
+        Ok(())
+    }
+}
+
+/// Check that the packages a git remote has as well are stored as the same trees, so the peers share their objects
+#[derive(Parser)]
+struct VerifyRemote {
+    /// Name or URL of a git remote in store.remotes
+    remote: String,
+    /// Store paths or their 32 character hash parts, all packages are compared if none are given
+    paths: Vec<String>,
+    /// Fetch the packages whose commits differ to compare their trees and NAR hashes
+    #[arg(long)]
+    deep: bool,
+}
+impl VerifyRemote {
+    fn run(&self, cache: &Store) -> Result<()> {
+        let package_ids = if self.paths.is_empty() {
+            cache.list_package_ids()?
+        } else {
+            self.paths
+                .iter()
+                .map(|p| package_id(p))
+                .collect::<Result<Vec<_>>>()?
+        };
+        let matches = cache.verify_remote(&self.remote, &package_ids, self.deep)?;
+        let mut counts: Vec<(RemoteMatch, usize)> = Vec::new();
+        for (package_id, package_match) in &matches {
+            if matches!(
+                package_match,
+                RemoteMatch::Divergent | RemoteMatch::DifferentNar
+            ) {
+                println!("{package_id}: {package_match}");
+            }
+            match counts.iter_mut().find(|(m, _)| m == package_match) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((*package_match, 1)),
+            }
+        }
+        let summary: Vec<String> = counts
+            .iter()
+            .map(|(package_match, count)| format!("{count} {package_match}"))
+            .collect();
+        println!(
+            "Compared {} packages with {}: {}",
+            matches.len(),
+            self.remote,
+            summary.join(", ")
+        );
+        if !self.deep
+            && counts
+                .iter()
+                .any(|(m, _)| *m == RemoteMatch::DifferentCommit)
+        {
+            println!("Run with --deep to compare the trees of the packages whose commits differ");
+        }
+        let num_divergent = matches
+            .iter()
+            .filter(|(_, m)| matches!(m, RemoteMatch::Divergent | RemoteMatch::DifferentNar))
+            .count();
+        if num_divergent > 0 {
+            bail!(
+                "{num_divergent} of {} packages differ from the remote",
+                matches.len()
+            );
+        }
         Ok(())
     }
 }
@@ -86,8 +803,32 @@ impl List {
 #[derive(Parser)]
 struct Serve {}
 impl Serve {
-    fn run(&self, cache: Store, server_settings: settings::Server) -> Result<()> {
-        start_server(&server_settings.host, server_settings.port, cache)?;
+    fn run(
+        &self,
+        cache: Store,
+        settings: settings::Settings,
+        log_level: LogLevelHandle,
+    ) -> Result<()> {
+        let server_settings = settings.server.clone();
+        let store = cache.clone();
+        let mut current = settings;
+        let reload = move || -> Result<()> {
+            // The command line is the same, only the config files may have changed
+            let new = load_settings(&Args::parse())?;
+            check_settings(&new)?;
+            let (reloaded, ignored) = current.reloadable(&new)?;
+            for key in ignored {
+                warn!("The change of {key} only takes effect when gachix is restarted");
+            }
+            store.reload(&reloaded.store)?;
+            log_level.set(reloaded.log.level)?;
+            current = reloaded;
+            Ok(())
+        };
+        start_server(
+            ServerConfig::new(server_settings).with_reload(reload),
+            cache,
+        )?;
         Ok(())
     }
 }
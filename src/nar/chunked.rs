@@ -0,0 +1,187 @@
+use anyhow::{Result, anyhow, bail};
+use git2::{ObjectType, Oid, Repository, Tree};
+
+// Large regular files are stored as a tree holding this manifest and the file contents split into chunks.
+// The decoder rejects entries with the marker name, so a tree holding one is always a chunked file.
+pub const CHUNKED_FILE_MARKER: &str = "gachix-chunked-file";
+pub const CHUNK_FORMAT_VERSION: u32 = 1;
+// The chunk size must be the same on every machine, otherwise peers would produce different trees
+pub const DEFAULT_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkManifest {
+    pub size: u64,
+    pub chunk_size: u64,
+    pub executable: bool,
+}
+
+impl ChunkManifest {
+    pub fn serialize(&self) -> String {
+        format!(
+            "{CHUNKED_FILE_MARKER} {CHUNK_FORMAT_VERSION}\nsize {}\nchunk-size {}\nexecutable {}\n",
+            self.size, self.chunk_size, self.executable
+        )
+    }
+
+    pub fn parse(content: &[u8]) -> Result<Self> {
+        let content = std::str::from_utf8(content)?;
+        let mut lines = content.lines();
+        let header = lines.next().unwrap_or_default();
+        let version = header
+            .strip_prefix(CHUNKED_FILE_MARKER)
+            .ok_or_else(|| anyhow!("Chunk manifest has no header"))?
+            .trim();
+        if version != CHUNK_FORMAT_VERSION.to_string() {
+            bail!("Unsupported chunked file format version '{}'", version);
+        }
+
+        let mut size = None;
+        let mut chunk_size = None;
+        let mut executable = None;
+        for line in lines {
+            match line.split_once(' ') {
+                Some(("size", v)) => size = Some(v.parse()?),
+                Some(("chunk-size", v)) => chunk_size = Some(v.parse()?),
+                Some(("executable", v)) => executable = Some(v.parse()?),
+                _ => bail!("Invalid chunk manifest line: '{}'", line),
+            }
+        }
+        let chunk_size =
+            chunk_size.ok_or_else(|| anyhow!("Chunk manifest is missing the chunk size"))?;
+        // Trees fetched from peers are not checked otherwise
+        if chunk_size == 0 {
+            bail!("Chunk manifest has a chunk size of 0");
+        }
+        Ok(Self {
+            size: size.ok_or_else(|| anyhow!("Chunk manifest is missing the size"))?,
+            chunk_size,
+            executable: executable
+                .ok_or_else(|| anyhow!("Chunk manifest is missing the executable flag"))?,
+        })
+    }
+}
+
+/// Chunks are named by their zero-padded index, so git's tree order is the file order
+pub fn chunk_name(index: u64) -> String {
+    format!("{index:010}")
+}
+
+/// Returns the manifest and the ordered chunk oids if `tree` represents a chunked file
+pub fn read_chunked_file(
+    repo: &Repository,
+    tree: &Tree,
+) -> Result<Option<(ChunkManifest, Vec<Oid>)>> {
+    let Some(marker) = tree.get_name(CHUNKED_FILE_MARKER) else {
+        return Ok(None);
+    };
+    if marker.kind() != Some(ObjectType::Blob) {
+        return Ok(None);
+    }
+    let manifest = ChunkManifest::parse(repo.find_blob(marker.id())?.content())?;
+    let chunks: Vec<Oid> = tree
+        .iter()
+        .filter(|entry| entry.name_bytes() != CHUNKED_FILE_MARKER.as_bytes())
+        .map(|entry| entry.id())
+        .collect();
+    let expected_chunks = manifest.size.div_ceil(manifest.chunk_size);
+    if chunks.len() as u64 != expected_chunks {
+        bail!(
+            "Chunked file should consist of {} chunks, found {}",
+            expected_chunks,
+            chunks.len()
+        );
+    }
+    Ok(Some((manifest, chunks)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nar::NarGitStream;
+    use crate::nar::decode::NarGitDecoder;
+    use crate::nar::encode::NarGitEncoder;
+    use crate::nar::{NIX_VERSION_MAGIC, PAD_LEN};
+    use futures::{StreamExt, executor::block_on};
+    use git2::FileMode;
+    use std::io::Cursor;
+    use tempfile::TempDir;
+
+    fn padded(bytes: &[u8]) -> Vec<u8> {
+        let mut buf = (bytes.len() as u64).to_le_bytes().to_vec();
+        buf.extend_from_slice(bytes);
+        buf.resize(buf.len() + (PAD_LEN - bytes.len() % PAD_LEN) % PAD_LEN, 0);
+        buf
+    }
+
+    fn nar_of_directory(entries: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let mut nar = padded(NIX_VERSION_MAGIC);
+        for token in [b"(".as_slice(), b"type", b"directory"] {
+            nar.extend(padded(token));
+        }
+        for (name, content, executable) in entries {
+            for token in [b"entry".as_slice(), b"(", b"name", name.as_bytes(), b"node"] {
+                nar.extend(padded(token));
+            }
+            for token in [b"(".as_slice(), b"type", b"regular"] {
+                nar.extend(padded(token));
+            }
+            if *executable {
+                nar.extend(padded(b"executable"));
+                nar.extend(padded(b""));
+            }
+            nar.extend(padded(b"contents"));
+            nar.extend(padded(content));
+            nar.extend(padded(b")"));
+            nar.extend(padded(b")"));
+        }
+        nar.extend(padded(b")"));
+        nar
+    }
+
+    #[test]
+    fn test_chunked_file_roundtrip() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = Repository::init(temp_dir.path().join("repo"))?;
+        let nar = nar_of_directory(&[
+            ("big", b"this file is larger than the threshold", true),
+            ("small", b"tiny", false),
+        ]);
+
+        let decoder = NarGitDecoder::new(&repo).with_chunking(Some(16), 5);
+        let (oid, filemode) = decoder.parse(Cursor::new(&nar))?;
+        assert_eq!(filemode, i32::from(FileMode::Tree));
+
+        {
+            let tree = repo.find_tree(oid)?;
+            let big = repo.find_tree(tree.get_name("big").unwrap().id())?;
+            let (manifest, chunks) = read_chunked_file(&repo, &big)?.unwrap();
+            assert_eq!(manifest.size, 38);
+            assert!(manifest.executable);
+            assert_eq!(chunks.len(), 8);
+
+            let object = repo.find_object(oid, None)?;
+            let encoded = NarGitEncoder::new(&repo, &object, filemode).encode()?;
+            assert_eq!(encoded, nar);
+        }
+
+        let stream = NarGitStream::new(repo, oid, filemode);
+        let streamed: Vec<u8> = block_on(stream.collect::<Vec<_>>())
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?
+            .concat();
+        assert_eq!(streamed, nar);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reject_unknown_manifest_version() {
+        let manifest = "gachix-chunked-file 2\nsize 1\nchunk-size 1\nexecutable false\n";
+        assert!(ChunkManifest::parse(manifest.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_reject_zero_chunk_size() {
+        let manifest = "gachix-chunked-file 1\nsize 1\nchunk-size 0\nexecutable false\n";
+        assert!(ChunkManifest::parse(manifest.as_bytes()).is_err());
+    }
+}
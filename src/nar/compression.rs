@@ -0,0 +1,197 @@
+use anyhow::{Result, bail};
+use flate2::read::GzDecoder;
+use liblzma::read::XzDecoder;
+use std::fmt::Display;
+use std::io::{self, Cursor, Read};
+
+/// Compression formats of NARs, as named in narinfo files
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Xz,
+    Zstd,
+    Gzip,
+    Bzip2,
+    /// A format gachix can't decompress, e.g. `br`
+    Unknown(String),
+}
+
+const MAGIC_LEN: usize = 6;
+
+impl Compression {
+    /// Detects the format from the first bytes of a file
+    pub fn sniff(header: &[u8]) -> Self {
+        if header.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Self::Xz
+        } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Self::Zstd
+        } else if header.starts_with(&[0x1f, 0x8b]) {
+            Self::Gzip
+        } else if header.starts_with(b"BZh") {
+            Self::Bzip2
+        } else {
+            Self::None
+        }
+    }
+
+    /// Parses the `Compression` of a narinfo. An empty value means `none`, as written by
+    /// older versions of gachix.
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "" | "none" => Self::None,
+            "xz" => Self::Xz,
+            "zstd" => Self::Zstd,
+            "gzip" => Self::Gzip,
+            "bzip2" => Self::Bzip2,
+            other => Self::Unknown(other.to_string()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Self::None => "none",
+            Self::Xz => "xz",
+            Self::Zstd => "zstd",
+            Self::Gzip => "gzip",
+            Self::Bzip2 => "bzip2",
+            Self::Unknown(name) => name,
+        }
+    }
+
+    /// What Nix appends to `.nar` in the URL of a NAR in this format, if there is a convention
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            Self::None => Some(""),
+            Self::Xz => Some(".xz"),
+            Self::Zstd => Some(".zst"),
+            Self::Gzip => Some(".gz"),
+            Self::Bzip2 => Some(".bz2"),
+            Self::Unknown(_) => None,
+        }
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Wraps `reader` in a decompressor for the format its first bytes indicate.
+/// Uncompressed input is passed through, nothing is buffered beyond the magic bytes.
+pub fn decompress<'a>(
+    mut reader: impl Read + Send + 'a,
+) -> Result<(Box<dyn Read + Send + 'a>, Compression)> {
+    let mut header = Vec::with_capacity(MAGIC_LEN);
+    (&mut reader)
+        .take(MAGIC_LEN as u64)
+        .read_to_end(&mut header)?;
+    let compression = Compression::sniff(&header);
+    let reader = Cursor::new(header).chain(reader);
+    let reader: Box<dyn Read + Send> = match compression {
+        Compression::None => return Ok((Box::new(reader), compression)),
+        Compression::Xz => Box::new(XzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        Compression::Gzip => Box::new(GzDecoder::new(reader)),
+        Compression::Bzip2 => bail!("bzip2 compressed NARs are not supported"),
+        Compression::Unknown(_) => unreachable!("formats are only sniffed"),
+    };
+    let reader = NamedDecompressor {
+        inner: reader,
+        compression: compression.clone(),
+    };
+    Ok((Box::new(reader), compression))
+}
+
+/// Names the compression format in errors of the decompressor
+struct NamedDecompressor<'a> {
+    inner: Box<dyn Read + Send + 'a>,
+    compression: Compression,
+}
+
+impl Read for NamedDecompressor<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Corrupt {} compressed NAR: {}", self.compression.name(), e),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use liblzma::write::XzEncoder;
+    use std::io::Write;
+
+    const CONTENT: &[u8] = b"\x0d\0\0\0\0\0\0\0nix-archive-1\0\0\0 not really a NAR";
+
+    fn decompressed(input: Vec<u8>) -> Result<(Vec<u8>, Compression)> {
+        let (mut reader, compression) = decompress(Cursor::new(input))?;
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output)?;
+        Ok((output, compression))
+    }
+
+    #[test]
+    fn test_detect_and_decompress() -> Result<()> {
+        let mut xz = XzEncoder::new(Vec::new(), 6);
+        xz.write_all(CONTENT)?;
+        let mut gzip = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(CONTENT)?;
+
+        for (input, expected) in [
+            (CONTENT.to_vec(), Compression::None),
+            (xz.finish()?, Compression::Xz),
+            (zstd::encode_all(CONTENT, 0)?, Compression::Zstd),
+            (gzip.finish()?, Compression::Gzip),
+        ] {
+            let (output, compression) = decompressed(input)?;
+            assert_eq!(compression, expected);
+            assert_eq!(output, CONTENT);
+        }
+        // shorter than the magic bytes
+        assert_eq!(decompressed(b"abc".to_vec())?.0, b"abc");
+        Ok(())
+    }
+
+    #[test]
+    fn test_names() {
+        for compression in [
+            Compression::None,
+            Compression::Xz,
+            Compression::Zstd,
+            Compression::Gzip,
+            Compression::Bzip2,
+            Compression::Unknown("br".to_string()),
+        ] {
+            assert_eq!(Compression::from_name(compression.name()), compression);
+        }
+        assert_eq!(Compression::from_name(""), Compression::None);
+        assert_eq!(Compression::Zstd.extension(), Some(".zst"));
+        assert_eq!(Compression::from_name("br").extension(), None);
+    }
+
+    #[test]
+    fn test_corrupt_stream_names_format() -> Result<()> {
+        let mut xz = XzEncoder::new(Vec::new(), 6);
+        xz.write_all(CONTENT)?;
+        let mut corrupt = xz.finish()?;
+        let middle = corrupt.len() / 2;
+        corrupt[middle..].fill(0xaa);
+
+        let error = decompressed(corrupt).unwrap_err();
+        assert!(
+            error.to_string().contains("Corrupt xz compressed NAR"),
+            "{error}"
+        );
+
+        let error = decompressed(b"BZh91AY&SY".to_vec()).unwrap_err();
+        assert!(error.to_string().contains("bzip2"));
+        Ok(())
+    }
+}
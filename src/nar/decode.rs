@@ -1,24 +1,188 @@
+use super::chunked::{CHUNKED_FILE_MARKER, ChunkManifest, DEFAULT_CHUNK_SIZE, chunk_name};
+use super::error::{Error, Result};
 use super::{NIX_VERSION_MAGIC, PAD_LEN};
-use anyhow::Result;
-use anyhow::anyhow;
-use git2::{FileMode, Oid, Repository};
-use std::io::Read;
+use crate::settings::NarLimits;
+use git2::{FileMode, ObjectType, Oid, Repository, TreeBuilder};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read};
+use tracing::warn;
+
+// Tags, entry names and symlink targets are never longer than this in NARs produced by Nix
+const MAX_STRING_LEN: u64 = 4096;
+// Nix on macOS appends this and a number to names which collide case-insensitively when unpacking
+pub const CASE_HACK_SUFFIX: &str = "~nix~case~hack~";
+
+/// A limit of `NarLimits` which an archive exceeded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NarLimit {
+    FileSize,
+    TotalSize,
+    Depth,
+    Entries,
+}
+
+/// Returned as `Error::LimitExceeded` when an archive exceeds one of the `NarLimits`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LimitExceeded {
+    pub limit: NarLimit,
+    pub max: u64,
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (description, setting) = match self.limit {
+            NarLimit::FileSize => ("size of a single file", "max_file_size"),
+            NarLimit::TotalSize => ("total size of all files", "max_total_size"),
+            NarLimit::Depth => ("directory depth", "max_depth"),
+            NarLimit::Entries => ("number of directory entries", "max_entries"),
+        };
+        write!(
+            f,
+            "NAR exceeds the maximum {} of {} (store.nar_limits.{})",
+            description, self.max, setting
+        )
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
 
 pub struct NarGitDecoder<'a> {
     repo: &'a Repository,
+    chunk_threshold: Option<u64>,
+    chunk_size: u64,
+    limits: NarLimits,
+    warn_case_collisions: bool,
+    batch: Option<Batch<'a>>,
+}
+
+/// Bounds the blob bytes written to the decoder's repository, which holds them in memory
+struct Batch<'a> {
+    loose: &'a Repository,
+    budget: u64,
+    used: Cell<u64>,
+    // Objects written to the decoder's repository, which the caller packs
+    written: RefCell<Vec<Oid>>,
+}
+
+/// What was decoded so far, to enforce the limits which span the whole archive
+#[derive(Default)]
+struct DecodeTotals {
+    size: u64,
+    entries: u64,
 }
 
 impl<'a> NarGitDecoder<'a> {
     pub fn new(repo: &'a Repository) -> Self {
-        Self { repo }
+        Self {
+            repo,
+            chunk_threshold: None,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            limits: NarLimits::default(),
+            warn_case_collisions: false,
+            batch: None,
+        }
+    }
+
+    /// For a repository whose object database batches writes in memory: once `budget` bytes of
+    /// blobs were written to it, further blobs are written loose through `loose` instead.
+    /// Trees are always written to the batching repository, which sees the objects of both.
+    pub fn with_batch_budget(mut self, loose: &'a Repository, budget: u64) -> Self {
+        self.batch = Some(Batch {
+            loose,
+            budget,
+            used: Cell::new(0),
+            written: RefCell::new(Vec::new()),
+        });
+        self
+    }
+
+    /// The objects written to the decoder's repository rather than loose, empty without a batch
+    pub fn batched_objects(&self) -> Vec<Oid> {
+        self.batch
+            .as_ref()
+            .map(|batch| batch.written.borrow().clone())
+            .unwrap_or_default()
+    }
+
+    /// Notes an object which was written through `repo`, if that is the batching repository
+    fn record(&self, repo: &Repository, oid: Oid) -> Oid {
+        if let Some(batch) = &self.batch
+            && std::ptr::eq(repo, self.repo)
+        {
+            batch.written.borrow_mut().push(oid);
+        }
+        oid
+    }
+
+    fn write_small_blob(&self, content: &[u8]) -> Result<Oid> {
+        let repo = self.blob_repo(content.len() as u64);
+        Ok(self.record(repo, repo.blob(content)?))
+    }
+
+    fn write_tree(&self, tree_builder: TreeBuilder) -> Result<Oid> {
+        Ok(self.record(self.repo, tree_builder.write()?))
+    }
+
+    /// The repository a blob of `len` bytes is written to
+    fn blob_repo(&self, len: u64) -> &'a Repository {
+        match &self.batch {
+            Some(batch) if batch.used.get() + len > batch.budget => batch.loose,
+            Some(batch) => {
+                batch.used.set(batch.used.get() + len);
+                self.repo
+            }
+            None => self.repo,
+        }
+    }
+
+    /// Log a warning for directories whose entries can't coexist on a case-insensitive file system
+    pub fn with_case_collision_warnings(mut self, enabled: bool) -> Self {
+        self.warn_case_collisions = enabled;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: NarLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Regular files larger than `threshold` bytes are split into blobs of `chunk_size` bytes
+    pub fn with_chunking(mut self, threshold: Option<u64>, chunk_size: u64) -> Self {
+        self.chunk_threshold = threshold;
+        self.chunk_size = chunk_size;
+        self
     }
 
     pub fn parse(&self, mut reader: impl Read) -> Result<(Oid, i32)> {
         self.read_expect(NIX_VERSION_MAGIC, &mut reader)?;
-        self.recursive_parse(&mut reader)
+        let root = self.recursive_parse(&mut reader, b"", 0, &mut DecodeTotals::default())?;
+        // Otherwise the hash and size recorded for the input would not match the stored archive
+        let mut byte = [0u8; 1];
+        loop {
+            match reader.read(&mut byte) {
+                Ok(0) => return Ok(root),
+                Ok(_) => return Err(invalid("Trailing data after NAR end")),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
     }
 
-    fn recursive_parse(&self, reader: &mut impl Read) -> Result<(Oid, i32)> {
+    /// `path` is the location of the node inside the archive, empty for the root.
+    /// Names are handled as raw bytes since they need not be valid UTF-8.
+    /// The recursion is bounded by the depth limit, so malicious archives can't exhaust the stack.
+    fn recursive_parse(
+        &self,
+        reader: &mut impl Read,
+        path: &[u8],
+        depth: usize,
+        totals: &mut DecodeTotals,
+    ) -> Result<(Oid, i32)> {
+        if depth > self.limits.max_depth {
+            return Err(self.exceeded(NarLimit::Depth).into());
+        }
         self.read_expect(b"(", reader)?;
         self.read_expect(b"type", reader)?;
 
@@ -29,56 +193,108 @@ impl<'a> NarGitDecoder<'a> {
         match file_type.as_str() {
             "regular" => {
                 let tag = self.read_utf8_padded(reader)?;
-                match tag.as_str() {
+                let executable = match tag.as_str() {
                     "executable" => {
-                        filemode = FileMode::BlobExecutable.into();
                         self.read_expect(b"", reader)?;
                         self.read_expect(b"contents", reader)?;
+                        true
                     }
-                    "contents" => filemode = FileMode::Blob,
+                    "contents" => false,
                     _ => {
-                        return Err(anyhow!(
+                        return Err(invalid(format!(
                             "Expected 'executable' or 'contents', instead found '{}'",
                             tag
-                        ));
+                        )));
+                    }
+                };
+                let len = self.read_len(reader)?;
+                self.add_file_size(totals, len)?;
+                match self.chunk_threshold {
+                    Some(threshold) if len > threshold => {
+                        oid = self.write_chunked_file(reader, len, executable)?;
+                        filemode = FileMode::Tree;
+                    }
+                    _ => {
+                        oid = self.write_blob(reader, len)?;
+                        filemode = if executable {
+                            FileMode::BlobExecutable
+                        } else {
+                            FileMode::Blob
+                        };
                     }
                 }
-                let data = self.read_bytes_padded(reader)?;
-                oid = self.repo.blob(&data)?;
                 self.read_expect(b")", reader)?;
             }
             "symlink" => {
                 self.read_expect(b"target", reader)?;
                 let target = self.read_bytes_padded(reader)?;
-                oid = self.repo.blob(&target)?;
+                self.add_file_size(totals, target.len() as u64)?;
+                oid = self.write_small_blob(&target)?;
                 filemode = FileMode::Link;
                 self.read_expect(b")", reader)?;
             }
             "directory" => {
-                let mut directory_entries = Vec::new();
+                let mut directory_entries: Vec<(Oid, i32, Vec<u8>)> = Vec::new();
                 loop {
                     match self.read_utf8_padded(reader)?.as_str() {
                         "entry" => {
+                            totals.entries += 1;
+                            if totals.entries > self.limits.max_entries {
+                                return Err(self.exceeded(NarLimit::Entries).into());
+                            }
                             self.read_expect(b"(", reader)?;
                             self.read_expect(b"name", reader)?;
-                            let name = self.read_utf8_padded(reader)?;
+                            let name = self.read_bytes_padded(reader)?;
+                            validate_entry_name(&name, path)?;
+                            // A later duplicate would silently replace the earlier entry in the tree
+                            if let Some((_, _, previous)) = directory_entries.last() {
+                                if name == *previous {
+                                    return Err(invalid(format!(
+                                        "Duplicate entry '{}' in directory '{}'",
+                                        name.escape_ascii(),
+                                        display_path(path)
+                                    )));
+                                }
+                                if name < *previous {
+                                    return Err(invalid(format!(
+                                        "Entry '{}' in directory '{}' is not sorted after '{}'",
+                                        name.escape_ascii(),
+                                        display_path(path),
+                                        previous.escape_ascii()
+                                    )));
+                                }
+                            }
                             self.read_expect(b"node", reader)?;
-                            let (oid, filemode) = self.recursive_parse(reader)?;
+                            let child_path = [path, b"/", &name].concat();
+                            let (oid, filemode) =
+                                self.recursive_parse(reader, &child_path, depth + 1, totals)?;
                             directory_entries.push((oid, filemode, name));
                             self.read_expect(b")", reader)?;
                         }
                         ")" => break,
-                        _ => return Err(anyhow!("Incorrect directory field")),
+                        _ => return Err(invalid("Incorrect directory field")),
                     };
                 }
+                if self.warn_case_collisions {
+                    let names = directory_entries.iter().map(|(_, _, name)| name.as_slice());
+                    for (first, second) in case_collisions(names) {
+                        warn!(
+                            "Entries '{}' and '{}' in directory '{}' collide on case-insensitive file systems",
+                            first.escape_ascii(),
+                            second.escape_ascii(),
+                            display_path(path)
+                        );
+                    }
+                }
+                // Names are stored exactly as in the NAR, including case hack suffixes
                 let mut tree_builder = self.repo.treebuilder(None)?;
                 for (oid, filemode, name) in directory_entries {
                     tree_builder.insert(name, oid, filemode)?;
                 }
-                oid = tree_builder.write()?;
+                oid = self.write_tree(tree_builder)?;
                 filemode = FileMode::Tree;
             }
-            _ => return Err(anyhow!("Unrecognized file type")),
+            _ => return Err(invalid("Unrecognized file type")),
         }
         Ok((oid, filemode.into()))
     }
@@ -90,13 +306,13 @@ impl<'a> NarGitDecoder<'a> {
         if expected.len() != actual_len {
             // let mut data_buffer = vec![0u8; actual_len];
             // reader.read_exact(&mut data_buffer)?;
-            return Err(anyhow!(
+            return Err(invalid(format!(
                 "Expected '{}' with length {}, instead found something with length {}",
                 String::from_utf8(expected.to_vec()).unwrap(),
                 expected.len(),
                 // String::from_utf8(data_buffer)?,
                 actual_len
-            ));
+            )));
         }
 
         let mut data_buffer = vec![0u8; actual_len];
@@ -104,16 +320,16 @@ impl<'a> NarGitDecoder<'a> {
 
         if expected != data_buffer {
             if let Ok(content_str) = String::from_utf8(data_buffer) {
-                return Err(anyhow!(
+                return Err(invalid(format!(
                     "Expected '{}' tag, instead found: '{}'",
                     String::from_utf8(expected.to_vec()).unwrap(),
                     content_str
-                ));
+                )));
             } else {
-                return Err(anyhow!(
+                return Err(invalid(format!(
                     "Expected '{}' tag",
                     String::from_utf8(expected.to_vec()).unwrap(),
-                ));
+                )));
             }
         }
 
@@ -123,7 +339,7 @@ impl<'a> NarGitDecoder<'a> {
             let padding = &mut buffer[0..PAD_LEN - remainder];
             reader.read_exact(padding)?;
             if !buffer.iter().all(|b| *b == 0) {
-                return Err(anyhow!("Bad archive padding"));
+                return Err(invalid("Bad archive padding"));
             }
         }
         Ok(())
@@ -131,34 +347,186 @@ impl<'a> NarGitDecoder<'a> {
 
     fn read_utf8_padded(&self, reader: &mut impl Read) -> Result<String> {
         let bytes = self.read_bytes_padded(reader)?;
-        Ok(String::from_utf8(bytes)?)
+        String::from_utf8(bytes)
+            .map_err(|e| invalid(format!("NAR string is not valid UTF-8: {}", e)))
     }
 
     fn read_bytes_padded(&self, reader: &mut impl Read) -> Result<Vec<u8>> {
+        let len = self.read_len(reader)?;
+        // the length is checked before allocating a buffer for it
+        if len > MAX_STRING_LEN {
+            return Err(invalid(format!(
+                "NAR string of length {} exceeds the maximum of {}",
+                len, MAX_STRING_LEN
+            )));
+        }
+        self.read_content_padded(reader, len)
+    }
+
+    fn add_file_size(&self, totals: &mut DecodeTotals, len: u64) -> Result<()> {
+        if len > self.limits.max_file_size {
+            return Err(self.exceeded(NarLimit::FileSize).into());
+        }
+        totals.size = totals.size.saturating_add(len);
+        if totals.size > self.limits.max_total_size {
+            return Err(self.exceeded(NarLimit::TotalSize).into());
+        }
+        Ok(())
+    }
+
+    fn exceeded(&self, limit: NarLimit) -> LimitExceeded {
+        let max = match limit {
+            NarLimit::FileSize => self.limits.max_file_size,
+            NarLimit::TotalSize => self.limits.max_total_size,
+            NarLimit::Depth => self.limits.max_depth as u64,
+            NarLimit::Entries => self.limits.max_entries,
+        };
+        LimitExceeded { limit, max }
+    }
+
+    fn read_len(&self, reader: &mut impl Read) -> Result<u64> {
         let mut len_buffer = [0u8; PAD_LEN];
         reader.read_exact(&mut len_buffer[..])?;
-        let len = u64::from_le_bytes(len_buffer);
+        Ok(u64::from_le_bytes(len_buffer))
+    }
 
+    fn read_content_padded(&self, reader: &mut impl Read, len: u64) -> Result<Vec<u8>> {
         let mut data_buffer = vec![0u8; len as usize];
         reader.read_exact(&mut data_buffer)?;
+        self.read_padding(reader, len)?;
+        Ok(data_buffer)
+    }
 
-        let remainder = data_buffer.len() % PAD_LEN;
+    fn read_padding(&self, reader: &mut impl Read, len: u64) -> Result<()> {
+        let remainder = (len % PAD_LEN as u64) as usize;
         if remainder > 0 {
             let mut buffer = [0u8; PAD_LEN];
             let padding = &mut buffer[0..PAD_LEN - remainder];
             reader.read_exact(padding)?;
             if !buffer.iter().all(|b| *b == 0) {
-                return Err(anyhow!("Bad archive padding"));
+                return Err(invalid("Bad archive padding"));
             }
         }
+        Ok(())
+    }
 
-        Ok(data_buffer)
+    /// Streams the file contents into the object database, so the file is never held in memory
+    fn write_blob(&self, reader: &mut impl Read, len: u64) -> Result<Oid> {
+        let repo = self.blob_repo(len);
+        let odb = repo.odb()?;
+        let mut writer = odb.writer(len as usize, ObjectType::Blob)?;
+        let copied = io::copy(&mut reader.take(len), &mut writer)?;
+        if copied != len {
+            return Err(invalid(format!(
+                "NAR ended after {} of {} bytes of file contents",
+                copied, len
+            )));
+        }
+        let oid = self.record(repo, writer.finalize()?);
+        self.read_padding(reader, len)?;
+        Ok(oid)
+    }
+
+    /// Writes the file contents as chunk blobs while reading, so only one chunk is held in memory
+    fn write_chunked_file(
+        &self,
+        reader: &mut impl Read,
+        len: u64,
+        executable: bool,
+    ) -> Result<Oid> {
+        let manifest = ChunkManifest {
+            size: len,
+            chunk_size: self.chunk_size,
+            executable,
+        };
+        let mut tree_builder = self.repo.treebuilder(None)?;
+        let manifest = manifest.serialize();
+        let manifest_oid = self.write_small_blob(manifest.as_bytes())?;
+        tree_builder.insert(CHUNKED_FILE_MARKER, manifest_oid, FileMode::Blob.into())?;
+
+        let mut buffer = vec![0u8; self.chunk_size.min(len) as usize];
+        let mut remaining = len;
+        let mut index = 0;
+        while remaining > 0 {
+            let chunk = &mut buffer[..self.chunk_size.min(remaining) as usize];
+            reader.read_exact(chunk)?;
+            let chunk_oid = self.write_small_blob(chunk)?;
+            tree_builder.insert(chunk_name(index), chunk_oid, FileMode::Blob.into())?;
+            remaining -= chunk.len() as u64;
+            index += 1;
+        }
+        self.read_padding(reader, len)?;
+        self.write_tree(tree_builder)
+    }
+}
+
+/// Rejects names which Nix would not produce and which could not be unpacked safely
+fn validate_entry_name(name: &[u8], parent: &[u8]) -> Result<()> {
+    if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') || name.contains(&0)
+    {
+        return Err(invalid(format!(
+            "Invalid entry name '{}' in directory '{}'",
+            name.escape_ascii(),
+            display_path(parent)
+        )));
+    }
+    // Readers take a tree holding an entry with this name for a chunked file
+    if name == CHUNKED_FILE_MARKER.as_bytes() {
+        return Err(invalid(format!(
+            "Entry name '{}' in directory '{}' is reserved for chunked files",
+            name.escape_ascii(),
+            display_path(parent)
+        )));
+    }
+    Ok(())
+}
+
+/// Returns the pairs of names which refer to the same file on a case-insensitive file system.
+/// A name with a case hack suffix is compared without it, since Nix strips the suffix when dumping.
+fn case_collisions<'n>(names: impl Iterator<Item = &'n [u8]>) -> Vec<(&'n [u8], &'n [u8])> {
+    let mut seen: HashMap<String, &[u8]> = HashMap::new();
+    let mut collisions = Vec::new();
+    for name in names {
+        let folded = String::from_utf8_lossy(strip_case_hack(name)).to_lowercase();
+        match seen.get(&folded) {
+            Some(first) => collisions.push((*first, name)),
+            None => {
+                seen.insert(folded, name);
+            }
+        }
+    }
+    collisions
+}
+
+/// Strips a trailing `~nix~case~hack~<number>` from `name`
+fn strip_case_hack(name: &[u8]) -> &[u8] {
+    let suffix = CASE_HACK_SUFFIX.as_bytes();
+    let digits = name.iter().rev().take_while(|b| b.is_ascii_digit()).count();
+    let without_digits = &name[..name.len() - digits];
+    match without_digits.strip_suffix(suffix) {
+        Some(stripped) if digits > 0 => stripped,
+        _ => name,
+    }
+}
+
+/// An `Error::InvalidNar` with the message
+fn invalid(message: impl Into<String>) -> Error {
+    Error::InvalidNar(message.into())
+}
+
+/// Non-UTF-8 bytes are escaped, e.g. `\xff`
+fn display_path(path: &[u8]) -> String {
+    if path.is_empty() {
+        "/".to_string()
+    } else {
+        path.escape_ascii().to_string()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::nar::encode::NarGitEncoder;
     use anyhow::Result;
     use nix_nar::Encoder;
     use std::fs::{self, File};
@@ -205,6 +573,399 @@ mod tests {
         Ok(())
     }
 
+    /// The NAR of a regular file up to the file contents, and the part after them
+    fn regular_file_nar_parts(size: u64) -> (Vec<u8>, Vec<u8>) {
+        let mut header = Vec::new();
+        for token in [NIX_VERSION_MAGIC, b"(", b"type", b"regular", b"contents"] {
+            header.extend((token.len() as u64).to_le_bytes());
+            header.extend(token);
+            header.resize(header.len().next_multiple_of(PAD_LEN), 0);
+        }
+        header.extend(size.to_le_bytes());
+        let mut trailer = vec![0u8; (PAD_LEN - size as usize % PAD_LEN) % PAD_LEN];
+        trailer.extend(1u64.to_le_bytes());
+        trailer.extend(b")\0\0\0\0\0\0\0");
+        (header, trailer)
+    }
+
+    #[test]
+    fn test_streamed_blobs_match_in_memory_blobs() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let repo = Repository::init(temp_dir.path().join("repo"))?;
+        let decoder = NarGitDecoder::new(&repo);
+
+        for size in [0, 1, 8, 13, 100_000] {
+            let content: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            let (header, trailer) = regular_file_nar_parts(size);
+            let nar = [header, content.clone(), trailer].concat();
+
+            let (oid, _) = decoder.parse(Cursor::new(nar))?;
+            assert_eq!(oid, Oid::hash_object(ObjectType::Blob, &content)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_large_file_from_stream() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let repo = Repository::init(temp_dir.path().join("repo"))?;
+        let size: u64 = 64 * 1024 * 1024 + 3;
+
+        // The contents are generated while decoding, so the NAR is never held in memory
+        let (header, trailer) = regular_file_nar_parts(size);
+        let nar = Cursor::new(header)
+            .chain(io::repeat(7).take(size))
+            .chain(Cursor::new(trailer));
+
+        let (oid, _) = NarGitDecoder::new(&repo).parse(nar)?;
+        let expected = vec![7u8; size as usize];
+        assert_eq!(oid, Oid::hash_object(ObjectType::Blob, &expected)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reject_trailing_data() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = Repository::init(temp_dir.path().join("repo"))?;
+        let decoder = NarGitDecoder::new(&repo);
+        let (header, trailer) = regular_file_nar_parts(5);
+        let nar = [header, b"hello".to_vec(), trailer].concat();
+        decoder.parse(Cursor::new(&nar))?;
+
+        let zeros = [nar.clone(), vec![0u8; PAD_LEN]].concat();
+        let second_archive = [nar.clone(), nar.clone()].concat();
+        for input in [zeros, second_archive] {
+            let error = decoder.parse(Cursor::new(input)).unwrap_err();
+            assert!(matches!(error, Error::InvalidNar(_)));
+            assert_eq!(error.to_string(), "Trailing data after NAR end");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncated_file_contents() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let repo = Repository::init(temp_dir.path().join("repo"))?;
+        let (header, _) = regular_file_nar_parts(100);
+        let nar = [header, vec![1u8; 50]].concat();
+        assert!(NarGitDecoder::new(&repo).parse(Cursor::new(nar)).is_err());
+        Ok(())
+    }
+
+    fn padded(token: &[u8]) -> Vec<u8> {
+        let mut buf = (token.len() as u64).to_le_bytes().to_vec();
+        buf.extend(token);
+        buf.resize(buf.len().next_multiple_of(PAD_LEN), 0);
+        buf
+    }
+
+    /// A NAR of a directory with empty regular files named `names`, in the given order
+    fn directory_nar(names: &[&[u8]]) -> Vec<u8> {
+        let mut nar = padded(NIX_VERSION_MAGIC);
+        for token in [b"(".as_slice(), b"type", b"directory"] {
+            nar.extend(padded(token));
+        }
+        for name in names {
+            for token in [b"entry".as_slice(), b"(", b"name", name, b"node"] {
+                nar.extend(padded(token));
+            }
+            for token in [
+                b"(".as_slice(),
+                b"type",
+                b"regular",
+                b"contents",
+                b"",
+                b")",
+                b")",
+            ] {
+                nar.extend(padded(token));
+            }
+        }
+        nar.extend(padded(b")"));
+        nar
+    }
+
+    fn decode_error(nar: Vec<u8>) -> String {
+        let temp_dir = TempDir::new().unwrap();
+        let repo = Repository::init(temp_dir.path().join("repo")).unwrap();
+        let result = NarGitDecoder::new(&repo).parse(Cursor::new(nar));
+        format!("{:#}", result.expect_err("decoding should fail"))
+    }
+
+    #[test]
+    fn test_accept_sorted_entries() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = Repository::init(temp_dir.path().join("repo"))?;
+        let nar = directory_nar(&[b"A", b"a", b"a.txt", b"b"]);
+        let (oid, _) = NarGitDecoder::new(&repo).parse(Cursor::new(nar))?;
+        assert_eq!(repo.find_tree(oid)?.len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reject_unsorted_entries() {
+        let error = decode_error(directory_nar(&[b"b", b"a"]));
+        assert!(error.contains("'a' in directory '/' is not sorted after 'b'"));
+    }
+
+    #[test]
+    fn test_reject_duplicate_entries() {
+        let error = decode_error(directory_nar(&[b"a", b"a"]));
+        assert!(error.contains("Duplicate entry 'a'"));
+    }
+
+    #[test]
+    fn test_reject_invalid_entry_names() {
+        for name in [b"".as_slice(), b".", b"..", b"a/b", b"/", b"a\0b"] {
+            let error = decode_error(directory_nar(&[name]));
+            assert!(error.contains("Invalid entry name"), "{error}");
+        }
+    }
+
+    #[test]
+    fn test_reject_chunked_file_marker() {
+        let error = decode_error(directory_nar(&[CHUNKED_FILE_MARKER.as_bytes()]));
+        assert!(error.contains("reserved for chunked files"), "{error}");
+    }
+
+    #[test]
+    fn test_error_names_parent_path() {
+        // a directory `sub` holding a badly ordered directory
+        let mut nar = padded(NIX_VERSION_MAGIC);
+        for token in [b"(".as_slice(), b"type", b"directory"] {
+            nar.extend(padded(token));
+        }
+        for token in [b"entry".as_slice(), b"(", b"name", b"sub", b"node"] {
+            nar.extend(padded(token));
+        }
+        let inner = directory_nar(&[b"y", b"x"]);
+        nar.extend(&inner[padded(NIX_VERSION_MAGIC).len()..]);
+        for token in [b")".as_slice(), b")"] {
+            nar.extend(padded(token));
+        }
+        let error = decode_error(nar);
+        assert!(error.contains("'x' in directory '/sub' is not sorted after 'y'"));
+    }
+
+    /// A NAR of `depth` nested directories named `d`
+    fn nested_directories_nar(depth: usize) -> Vec<u8> {
+        let mut nar = padded(NIX_VERSION_MAGIC);
+        for _ in 0..depth {
+            for token in [
+                b"(".as_slice(),
+                b"type",
+                b"directory",
+                b"entry",
+                b"(",
+                b"name",
+            ] {
+                nar.extend(padded(token));
+            }
+            nar.extend(padded(b"d"));
+            nar.extend(padded(b"node"));
+        }
+        for token in [b"(".as_slice(), b"type", b"directory", b")"] {
+            nar.extend(padded(token));
+        }
+        for _ in 0..depth {
+            nar.extend(padded(b")"));
+            nar.extend(padded(b")"));
+        }
+        nar
+    }
+
+    fn exceeded_limit(nar: Vec<u8>, limits: NarLimits) -> Option<NarLimit> {
+        let temp_dir = TempDir::new().unwrap();
+        let repo = Repository::init(temp_dir.path().join("repo")).unwrap();
+        let result = NarGitDecoder::new(&repo)
+            .with_limits(limits)
+            .parse(Cursor::new(nar));
+        match result.expect_err("decoding should fail") {
+            Error::LimitExceeded(exceeded) => Some(exceeded.limit),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_reject_huge_file_length() {
+        let (header, _) = regular_file_nar_parts(u64::MAX);
+        let limit = exceeded_limit(header, NarLimits::default());
+        assert_eq!(limit, Some(NarLimit::FileSize));
+    }
+
+    #[test]
+    fn test_reject_huge_string_length() {
+        let mut nar = padded(NIX_VERSION_MAGIC);
+        nar.extend(padded(b"("));
+        nar.extend(padded(b"type"));
+        nar.extend(u64::MAX.to_le_bytes());
+        assert!(decode_error(nar).contains("exceeds the maximum"));
+    }
+
+    #[test]
+    fn test_total_size_limit() {
+        let (header, trailer) = regular_file_nar_parts(100);
+        let nar = [header, vec![0u8; 100], trailer].concat();
+        let limits = NarLimits {
+            max_total_size: 50,
+            ..NarLimits::default()
+        };
+        assert_eq!(exceeded_limit(nar, limits), Some(NarLimit::TotalSize));
+    }
+
+    #[test]
+    fn test_depth_limit() -> Result<()> {
+        let limits = NarLimits {
+            max_depth: 3,
+            ..NarLimits::default()
+        };
+        let temp_dir = TempDir::new()?;
+        let repo = Repository::init(temp_dir.path().join("repo"))?;
+        let decoder = NarGitDecoder::new(&repo).with_limits(limits);
+        decoder.parse(Cursor::new(nested_directories_nar(3)))?;
+
+        let limit = exceeded_limit(nested_directories_nar(4), limits);
+        assert_eq!(limit, Some(NarLimit::Depth));
+        // the default limit stops archives which would otherwise overflow the stack
+        let limit = exceeded_limit(nested_directories_nar(100_000), NarLimits::default());
+        assert_eq!(limit, Some(NarLimit::Depth));
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_limit() {
+        let limits = NarLimits {
+            max_entries: 2,
+            ..NarLimits::default()
+        };
+        let nar = directory_nar(&[b"a", b"b", b"c"]);
+        assert_eq!(exceeded_limit(nar, limits), Some(NarLimit::Entries));
+    }
+
+    #[test]
+    fn test_truncated_and_corrupted_nars_fail_cleanly() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = Repository::init(temp_dir.path().join("repo"))?;
+        let decoder = NarGitDecoder::new(&repo);
+        let (header, trailer) = regular_file_nar_parts(5);
+        let file_nar = [header, b"hello".to_vec(), trailer].concat();
+
+        for nar in [
+            nested_directories_nar(2),
+            directory_nar(&[b"a", b"b"]),
+            file_nar,
+        ] {
+            for len in 0..nar.len() {
+                assert!(decoder.parse(Cursor::new(&nar[..len])).is_err());
+            }
+            // every length field (and everything else) replaced by u64::MAX in turn
+            for offset in (0..nar.len()).step_by(PAD_LEN) {
+                let mut corrupted = nar.clone();
+                corrupted[offset..offset + PAD_LEN].copy_from_slice(&u64::MAX.to_le_bytes());
+                let _ = decoder.parse(Cursor::new(corrupted));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_case_hack_names_roundtrip() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = Repository::init(temp_dir.path().join("repo"))?;
+        let nar = directory_nar(&[
+            b"Makefile",
+            b"makefile",
+            b"makefile~nix~case~hack~1",
+            b"readme~nix~case~hack~",
+        ]);
+
+        let decoder = NarGitDecoder::new(&repo).with_case_collision_warnings(true);
+        let (oid, filemode) = decoder.parse(Cursor::new(&nar))?;
+        let tree = repo.find_tree(oid)?;
+        assert_eq!(tree.len(), 4);
+        assert!(tree.get_name("makefile~nix~case~hack~1").is_some());
+
+        let object = repo.find_object(oid, None)?;
+        let encoded = NarGitEncoder::new(&repo, &object, filemode).encode()?;
+        assert_eq!(encoded, nar);
+        Ok(())
+    }
+
+    #[test]
+    fn test_case_collisions() {
+        let names: [&[u8]; 6] = [
+            b"Makefile",
+            b"README",
+            b"makefile",
+            b"readme~nix~case~hack~2",
+            b"readme~nix~case~hack~",
+            b"\xc3\x84",
+        ];
+        let collisions = case_collisions(names.into_iter().chain([b"\xc3\xa4".as_slice()]));
+        assert_eq!(
+            collisions,
+            vec![
+                (b"Makefile".as_slice(), b"makefile".as_slice()),
+                (b"README".as_slice(), b"readme~nix~case~hack~2".as_slice()),
+                (b"\xc3\x84".as_slice(), b"\xc3\xa4".as_slice()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_non_utf8_entry_names_roundtrip() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = Repository::init(temp_dir.path().join("repo"))?;
+        let nar = directory_nar(&[b"Z", b"a", b"caf\xc3\xa9", b"caf\xe9", b"\xff\xfe"]);
+
+        let (oid, filemode) = NarGitDecoder::new(&repo).parse(Cursor::new(&nar))?;
+        let tree = repo.find_tree(oid)?;
+        assert!(tree.get_name_bytes(b"caf\xe9").is_some());
+
+        let object = repo.find_object(oid, None)?;
+        let encoded = NarGitEncoder::new(&repo, &object, filemode).encode()?;
+        assert_eq!(encoded, nar);
+        Ok(())
+    }
+
+    #[test]
+    fn test_non_utf8_file_name_with_contents() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        // Built by hand, since encoders which take names from the file system may reject them
+        let mut nar = padded(NIX_VERSION_MAGIC);
+        for token in [
+            b"(".as_slice(),
+            b"type",
+            b"directory",
+            b"entry",
+            b"(",
+            b"name",
+            b"invalid-\xff-name",
+            b"node",
+            b"(",
+            b"type",
+            b"regular",
+            b"contents",
+            b"content",
+            b")",
+            b")",
+            b")",
+        ] {
+            nar.extend(padded(token));
+        }
+
+        let repo = Repository::init(temp_dir.path().join("repo"))?;
+        let (oid, filemode) = NarGitDecoder::new(&repo).parse(Cursor::new(&nar))?;
+        let tree = repo.find_tree(oid)?;
+        let entry = tree.get_name_bytes(b"invalid-\xff-name").unwrap();
+        assert_eq!(repo.find_blob(entry.id())?.content(), b"content");
+
+        let object = repo.find_object(oid, None)?;
+        let encoded = NarGitEncoder::new(&repo, &object, filemode).encode()?;
+        assert_eq!(encoded, nar);
+        Ok(())
+    }
+
     #[test]
     fn test_decode_directory() -> Result<(), Box<dyn std::error::Error>> {
         let temp_dir = TempDir::new()?;
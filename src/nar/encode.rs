@@ -1,10 +1,11 @@
+use super::chunked::read_chunked_file;
+use super::entry::EntryKind;
 use super::{NIX_VERSION_MAGIC, PAD_LEN};
 use anyhow::Result;
-use anyhow::anyhow;
-use git2::{FileMode, Object, ObjectType, Repository};
+use git2::{Object, Oid, Repository};
 use std::io::{self, Write};
 
-#[allow(dead_code)]
+/// Serializes a git object as a NAR in one go
 pub struct NarGitEncoder<'a> {
     repo: &'a Repository,
     root_obj: &'a Object<'a>,
@@ -12,7 +13,6 @@ pub struct NarGitEncoder<'a> {
 }
 
 impl<'a> NarGitEncoder<'a> {
-    #[allow(dead_code)]
     pub fn new(repo: &'a Repository, root_obj: &'a Object, root_obj_filemode: i32) -> Self {
         NarGitEncoder {
             repo,
@@ -21,72 +21,75 @@ impl<'a> NarGitEncoder<'a> {
         }
     }
 
-    #[allow(dead_code)]
     pub fn encode(self) -> Result<Vec<u8>> {
         let mut buffer = Vec::new();
         self.encode_into(&mut buffer)?;
         Ok(buffer)
     }
 
-    #[allow(dead_code)]
     pub fn encode_into<W: Write>(&self, mut writer: W) -> Result<()> {
         write_padded(&mut writer, NIX_VERSION_MAGIC)?;
-        self._encode_into(&mut writer, self.root_obj, self.root_obj_filemode)?;
+        self._encode_into(&mut writer, self.root_obj.id(), self.root_obj_filemode)?;
         Ok(())
     }
 
-    fn _encode_into<W: Write>(&self, writer: &mut W, obj: &Object, filemode: i32) -> Result<()> {
+    fn _encode_into<W: Write>(&self, writer: &mut W, oid: Oid, filemode: i32) -> Result<()> {
+        let kind = EntryKind::from_filemode(filemode)?;
+        let obj = self.repo.find_object(oid, Some(kind.object_type()))?;
         write_padded(writer, b"(")?;
         write_padded(writer, b"type")?;
-        let kind = obj.kind();
 
         match kind {
-            Some(ObjectType::Tree) => {
-                write_padded(writer, b"directory")?;
-
+            EntryKind::Directory => {
                 let tree = obj.as_tree().unwrap();
+                if let Some((manifest, chunks)) = read_chunked_file(self.repo, tree)? {
+                    write_padded(writer, b"regular")?;
+                    if manifest.executable {
+                        write_padded(writer, b"executable")?;
+                        write_padded(writer, b"")?;
+                    }
+                    write_padded(writer, b"contents")?;
+                    writer.write_all(&manifest.size.to_le_bytes())?;
+                    for chunk in chunks {
+                        writer.write_all(self.repo.find_blob(chunk)?.content())?;
+                    }
+                    write_padding(writer, manifest.size)?;
+                    write_padded(writer, b")")?;
+                    return Ok(());
+                }
+
+                write_padded(writer, b"directory")?;
                 let mut entries: Vec<_> = tree.iter().collect();
-                // NAR requires directory entries to be sorted by name
-                entries.sort_by(|x, y| x.name().unwrap().cmp(&y.name().unwrap()));
+                // NAR requires directory entries to be sorted by name, which need not be UTF-8
+                entries.sort_by(|x, y| x.name_bytes().cmp(y.name_bytes()));
 
                 for entry in entries {
-                    let entry_obj = entry
-                        .to_object(self.repo)
-                        .expect("Couldn't transform entry to object");
                     write_padded(writer, b"entry")?;
                     write_padded(writer, b"(")?;
                     write_padded(writer, b"name")?;
-                    write_padded(writer, entry.name().unwrap().as_bytes())?;
+                    write_padded(writer, entry.name_bytes())?;
                     write_padded(writer, b"node")?;
 
-                    self._encode_into(writer, &entry_obj, entry.filemode())?;
+                    self._encode_into(writer, entry.id(), entry.filemode())?;
 
                     write_padded(writer, b")")?;
                 }
             }
-            Some(ObjectType::Blob) => {
+            EntryKind::Regular { executable } => {
                 let blob = obj.as_blob().unwrap();
-
-                if filemode == <FileMode as Into<i32>>::into(FileMode::BlobExecutable) {
-                    write_padded(writer, b"regular")?;
+                write_padded(writer, b"regular")?;
+                if executable {
                     write_padded(writer, b"executable")?;
                     write_padded(writer, b"")?;
-                    write_padded(writer, b"contents")?;
-                    write_padded(writer, blob.content())?;
-                } else if filemode == <FileMode as Into<i32>>::into(FileMode::Blob) {
-                    write_padded(writer, b"regular")?;
-                    write_padded(writer, b"contents")?;
-                    write_padded(writer, blob.content())?;
-                } else if filemode == <FileMode as Into<i32>>::into(FileMode::Link) {
-                    write_padded(writer, b"symlink")?;
-                    write_padded(writer, b"target")?;
-                    write_padded(writer, blob.content())?;
-                } else {
-                    return Err(anyhow!("Unsupported blob filemode: {}", filemode));
                 }
+                write_padded(writer, b"contents")?;
+                write_padded(writer, blob.content())?;
             }
-            _ => {
-                return Err(anyhow!("Unrecognized file type"));
+            EntryKind::Symlink => {
+                let blob = obj.as_blob().unwrap();
+                write_padded(writer, b"symlink")?;
+                write_padded(writer, b"target")?;
+                write_padded(writer, blob.content())?;
             }
         }
         write_padded(writer, b")")?;
@@ -98,8 +101,11 @@ fn write_padded<W: Write>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
     let len = bytes.len() as u64;
     writer.write_all(&len.to_le_bytes())?;
     writer.write_all(bytes)?;
+    write_padding(writer, len)
+}
 
-    let remainder = bytes.len() % PAD_LEN;
+fn write_padding<W: Write>(writer: &mut W, len: u64) -> io::Result<()> {
+    let remainder = (len % PAD_LEN as u64) as usize;
     if remainder > 0 {
         let padding = PAD_LEN - remainder;
         writer.write_all(&[0u8; PAD_LEN][..padding])?;
@@ -107,3 +113,93 @@ fn write_padded<W: Write>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nar::NarGitStream;
+    use futures::{StreamExt, executor::block_on};
+    use git2::FileMode;
+    use nix_nar::Encoder;
+    use std::fs;
+    use std::io::Read;
+    use tempfile::TempDir;
+
+    // Unsorted, and sorted differently by bytes than by case-insensitive or locale order
+    const NAMES: [&str; 3] = ["é", "a", "Z"];
+
+    fn padded(token: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_padded(&mut buf, token).unwrap();
+        buf
+    }
+
+    fn git_tree(repo: &Repository) -> Result<Oid> {
+        let mut builder = repo.treebuilder(None)?;
+        for name in NAMES {
+            builder.insert(name, repo.blob(name.as_bytes())?, FileMode::Blob.into())?;
+        }
+        Ok(builder.write()?)
+    }
+
+    /// Both encoders serialize the tree
+    fn encode_both(repo: &Repository, oid: Oid) -> Result<(Vec<u8>, Vec<u8>)> {
+        let object = repo.find_object(oid, None)?;
+        let encoded = NarGitEncoder::new(repo, &object, FileMode::Tree.into()).encode()?;
+        let stream = NarGitStream::new(Repository::open(repo.path())?, oid, FileMode::Tree.into());
+        let streamed = block_on(stream.collect::<Vec<_>>())
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?
+            .concat();
+        Ok((encoded, streamed))
+    }
+
+    #[test]
+    fn test_encoders_sort_entries_by_bytes() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = Repository::init(temp_dir.path().join("repo"))?;
+        let oid = git_tree(&repo)?;
+
+        let mut expected = padded(NIX_VERSION_MAGIC);
+        for token in [b"(".as_slice(), b"type", b"directory"] {
+            expected.extend(padded(token));
+        }
+        for name in ["Z", "a", "é"] {
+            for token in [b"entry".as_slice(), b"(", b"name", name.as_bytes(), b"node"] {
+                expected.extend(padded(token));
+            }
+            for token in [b"(".as_slice(), b"type", b"regular", b"contents"] {
+                expected.extend(padded(token));
+            }
+            expected.extend(padded(name.as_bytes()));
+            expected.extend(padded(b")"));
+            expected.extend(padded(b")"));
+        }
+        expected.extend(padded(b")"));
+
+        let (encoded, streamed) = encode_both(&repo, oid)?;
+        assert_eq!(encoded, expected);
+        assert_eq!(streamed, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_encoders_match_nix_dump() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = Repository::init(temp_dir.path().join("repo"))?;
+        let oid = git_tree(&repo)?;
+
+        let dir = temp_dir.path().join("package");
+        fs::create_dir(&dir)?;
+        for name in NAMES {
+            fs::write(dir.join(name), name)?;
+        }
+        let mut dumped = Vec::new();
+        Encoder::new(&dir)?.read_to_end(&mut dumped)?;
+
+        let (encoded, streamed) = encode_both(&repo, oid)?;
+        assert_eq!(encoded, dumped);
+        assert_eq!(streamed, dumped);
+        Ok(())
+    }
+}
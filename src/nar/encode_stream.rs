@@ -1,13 +1,19 @@
+use super::chunked::read_chunked_file;
+use super::entry::EntryKind;
 use super::{NIX_VERSION_MAGIC, PAD_LEN};
+use crate::git_store::repo_pool::RepoHandle;
 use anyhow::{Result, anyhow};
 use bytes::Bytes;
 use futures::Stream;
-use git2::{FileMode, ObjectType, Oid, Repository};
+use git2::{ObjectType, Oid, Repository};
 use std::collections::VecDeque;
+use std::future::Future;
+use std::io::{self, Read};
 use std::pin::Pin;
-use std::sync::{Arc, RwLock};
-use std::task::{Context, Poll};
+use std::task::{Context, Poll, ready};
+use std::thread;
 use std::vec::IntoIter;
+use tokio::sync::{mpsc, oneshot};
 
 #[derive(Debug)]
 struct OwnedTreeEntry {
@@ -39,21 +45,241 @@ fn write_padded_bytes(bytes: &[u8]) -> Bytes {
     Bytes::from(buf)
 }
 
+fn padding_bytes(len: u64) -> Bytes {
+    let remainder = (len % PAD_LEN as u64) as usize;
+    if remainder > 0 {
+        Bytes::from_static(&[0u8; PAD_LEN][..PAD_LEN - remainder])
+    } else {
+        Bytes::new()
+    }
+}
+
+/// File contents are emitted in pieces of at most this many bytes
+pub const DEFAULT_STREAM_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Blobs loaded ahead of the traversal at most, which bounds the memory held by a stream
+pub const DEFAULT_PREFETCH_DEPTH: usize = 2;
+
+/// Pieces of a streamed blob read ahead of the stream at most
+const BLOB_STREAM_BUFFER: usize = 1;
+
+/// A blob as loaded by `load_blob`
+enum LoadedBlob {
+    Content(Bytes),
+    // Larger than the limit, with the size of its contents
+    Large(u64),
+}
+
+/// Loads the contents of a blob of at most `max_len` bytes. Only the header of larger loose blobs
+/// is read, their contents are streamed with a `BlobStream` instead. Packed blobs are inflated
+/// whole by libgit2 in any case, so they are loaded whatever their size.
+fn load_blob(repo: &Repository, oid: Oid, max_len: usize) -> Result<LoadedBlob> {
+    let odb = repo.odb()?;
+    let not_found = || anyhow!("Could not find object with oid {}", oid);
+    match odb.read_header(oid) {
+        Ok((size, ObjectType::Blob)) if size > max_len && odb.reader(oid).is_ok() => {
+            Ok(LoadedBlob::Large(size as u64))
+        }
+        Ok((_, ObjectType::Blob)) => {
+            let object = odb.read(oid).map_err(|_| not_found())?;
+            Ok(LoadedBlob::Content(Bytes::copy_from_slice(object.data())))
+        }
+        _ => Err(not_found()),
+    }
+}
+
+/// Runs `work` on the blocking pool of the tokio runtime the stream is polled from, which bounds
+/// the threads of all streams together. Without a runtime, e.g. when the command line writes a NAR
+/// to a file, the work gets a thread of its own.
+fn spawn_blocking(work: impl FnOnce() + Send + 'static) {
+    match tokio::runtime::Handle::try_current() {
+        Ok(runtime) => drop(runtime.spawn_blocking(work)),
+        Err(_) => drop(thread::spawn(work)),
+    }
+}
+
+/// Reads a large loose blob piece by piece on the blocking pool, since the object database reader
+/// borrows the repository handle it was opened with. The pieces are produced directly from the
+/// object store, so only a bounded number of them is held in memory whatever the blob's size.
+struct BlobStream {
+    pieces: mpsc::Receiver<Result<Bytes>>,
+    oid: Oid,
+    remaining: u64,
+}
+
+impl BlobStream {
+    fn spawn(
+        repo: Result<RepoHandle, git2::Error>,
+        oid: Oid,
+        size: u64,
+        piece_size: usize,
+    ) -> Self {
+        let (sender, pieces) = mpsc::channel(BLOB_STREAM_BUFFER);
+        spawn_blocking(move || {
+            // Fails once the stream is dropped
+            let send = |piece: Result<Bytes>| sender.blocking_send(piece).is_ok();
+            let repo = match repo {
+                Ok(repo) => repo,
+                Err(err) => {
+                    send(Err(err.into()));
+                    return;
+                }
+            };
+            let odb = match repo.odb() {
+                Ok(odb) => odb,
+                Err(err) => {
+                    send(Err(err.into()));
+                    return;
+                }
+            };
+            let mut reader = match odb.reader(oid) {
+                Ok((reader, _, _)) => reader,
+                // E.g. packed since it was loaded
+                Err(_) => {
+                    send(Err(anyhow!("Could not stream object with oid {}", oid)));
+                    return;
+                }
+            };
+            loop {
+                let mut piece = Vec::with_capacity(piece_size);
+                match reader
+                    .by_ref()
+                    .take(piece_size as u64)
+                    .read_to_end(&mut piece)
+                {
+                    Ok(0) => return,
+                    Ok(_) => {
+                        if !send(Ok(Bytes::from(piece))) {
+                            return;
+                        }
+                    }
+                    Err(err) => {
+                        send(Err(err.into()));
+                        return;
+                    }
+                }
+            }
+        });
+        BlobStream {
+            pieces,
+            oid,
+            remaining: size,
+        }
+    }
+
+    /// The next piece of the contents, None once all were read
+    fn poll_piece(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes>>> {
+        if self.remaining == 0 {
+            return Poll::Ready(None);
+        }
+        let piece = match ready!(self.pieces.poll_recv(cx)) {
+            Some(Ok(piece)) => piece,
+            Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+            None => {
+                return Poll::Ready(Some(Err(anyhow!(
+                    "Blob {} ended {} bytes before its size",
+                    self.oid,
+                    self.remaining
+                ))));
+            }
+        };
+        Poll::Ready(match self.remaining.checked_sub(piece.len() as u64) {
+            Some(remaining) => {
+                self.remaining = remaining;
+                Some(Ok(piece))
+            }
+            None => Some(Err(anyhow!("Blob {} is larger than its size", self.oid))),
+        })
+    }
+}
+
+/// A blob loaded ahead of the traversal, together with the handle it was loaded with
+type Prefetched = oneshot::Receiver<(Result<LoadedBlob>, RepoHandle)>;
+
+/// Loads blobs on the blocking pool while the stream yields the entry before them,
+/// so sending a package with many small files does not wait for the object database on every file.
+struct Prefetcher {
+    max_len: usize,
+    // Blobs which are loaded or were loaded but not taken yet
+    requested: Vec<(Oid, Prefetched)>,
+    // Handles of finished loads, which are used for the next ones
+    idle: Vec<RepoHandle>,
+}
+
+impl Prefetcher {
+    /// Loads the blobs of at most `max_len` bytes
+    fn new(max_len: usize) -> Self {
+        Prefetcher {
+            max_len,
+            requested: Vec::new(),
+            idle: Vec::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.requested.len()
+    }
+
+    /// Starts loading a blob with a handle of its own, a sibling of `repo` if none is idle.
+    /// The blob is read by the stream itself if there is no handle.
+    fn request(&mut self, oid: Oid, repo: &RepoHandle) {
+        let Some(handle) = self.idle.pop().or_else(|| repo.sibling().ok()) else {
+            return;
+        };
+        let (sender, loaded) = oneshot::channel();
+        let max_len = self.max_len;
+        spawn_blocking(move || {
+            let content = load_blob(&handle, oid, max_len);
+            // The stream may be gone already
+            let _ = sender.send((content, handle));
+        });
+        self.requested.push((oid, loaded));
+    }
+
+    /// The contents of a requested blob once it is loaded, None if it was not requested
+    /// or the load did not finish
+    fn poll_take(&mut self, oid: Oid, cx: &mut Context<'_>) -> Poll<Option<Result<LoadedBlob>>> {
+        let Some(pos) = self.requested.iter().position(|(id, _)| *id == oid) else {
+            return Poll::Ready(None);
+        };
+        let loaded = ready!(Pin::new(&mut self.requested[pos].1).poll(cx));
+        self.requested.swap_remove(pos);
+        Poll::Ready(loaded.ok().map(|(content, handle)| {
+            self.idle.push(handle);
+            content
+        }))
+    }
+}
+
 enum TraversalState {
     StartNode(Oid, i32),
     ProcessTreeEntries(IntoIter<OwnedTreeEntry>),
+    // Chunks of a large file are read one at a time to bound memory usage
+    ProcessFileChunks { chunks: IntoIter<Oid>, size: u64 },
+    // The part of the file contents starting at `offset` which was not emitted yet
+    ProcessContent { content: Bytes, offset: usize },
+    // The contents of a blob larger than a piece, read from the object store while emitted
+    StreamContent(BlobStream),
+    Padding(u64),
     FinishTreeEntry,
     FinishNode,
 }
 
+/// Streams the NAR serialization of a git object.
+/// The stream owns its repository handle, so it can be polled from any thread.
+/// A pooled handle is returned to its pool once the stream is dropped.
 pub struct NarGitStream {
-    repo: Arc<RwLock<Repository>>,
+    repo: RepoHandle,
     stack: Vec<TraversalState>,
     pending_chunks: VecDeque<Result<Bytes>>,
+    chunk_size: usize,
+    prefetch_depth: usize,
+    // Started with the first directory, as a single file has nothing to load ahead
+    prefetcher: Option<Prefetcher>,
 }
 
 impl NarGitStream {
-    pub fn new(repo: Arc<RwLock<Repository>>, root_obj: Oid, root_obj_filemode: i32) -> Self {
+    pub fn new(repo: impl Into<RepoHandle>, root_obj: Oid, root_obj_filemode: i32) -> Self {
         let mut pending_chunks = VecDeque::new();
         pending_chunks.push_back(Ok(write_padded_bytes(NIX_VERSION_MAGIC)));
 
@@ -63,32 +289,102 @@ impl NarGitStream {
         ];
 
         NarGitStream {
-            repo,
+            repo: repo.into(),
             stack,
             pending_chunks,
+            chunk_size: DEFAULT_STREAM_CHUNK_SIZE,
+            prefetch_depth: DEFAULT_PREFETCH_DEPTH,
+            prefetcher: None,
         }
     }
-}
 
-impl Stream for NarGitStream {
-    type Item = Result<Bytes>;
+    #[allow(dead_code)]
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Sets how many blobs are loaded ahead of the traversal, 0 disables loading ahead
+    pub fn with_prefetch_depth(mut self, prefetch_depth: usize) -> Self {
+        self.prefetch_depth = prefetch_depth;
+        self
+    }
 
-    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    /// Starts loading the blob of a tree entry which will be visited soon
+    fn prefetch(&mut self, entry: &OwnedTreeEntry) {
+        if matches!(
+            EntryKind::from_filemode(entry.filemode),
+            Ok(EntryKind::Directory) | Err(_)
+        ) {
+            return;
+        }
+        if self.prefetch_depth == 0 {
+            return;
+        }
+        let prefetcher = self
+            .prefetcher
+            .get_or_insert_with(|| Prefetcher::new(self.chunk_size));
+        if prefetcher.len() < self.prefetch_depth {
+            prefetcher.request(entry.id, &self.repo);
+        }
+    }
+
+    /// The contents of a blob of at most one piece, or the size of a larger one
+    fn poll_blob_content(&mut self, oid: Oid, cx: &mut Context<'_>) -> Poll<Result<LoadedBlob>> {
+        let prefetched = match self.prefetcher.as_mut() {
+            Some(prefetcher) => ready!(prefetcher.poll_take(oid, cx)),
+            None => None,
+        };
+        Poll::Ready(match prefetched {
+            Some(content) => content,
+            None => load_blob(&self.repo, oid, self.chunk_size),
+        })
+    }
+
+    /// The whole contents of a blob, e.g. a symlink target
+    fn whole_blob(&self, loaded: LoadedBlob, oid: Oid) -> Result<Bytes> {
+        match loaded {
+            LoadedBlob::Content(content) => Ok(content),
+            LoadedBlob::Large(_) => match load_blob(&self.repo, oid, usize::MAX)? {
+                LoadedBlob::Content(content) => Ok(content),
+                LoadedBlob::Large(_) => unreachable!("no blob is larger than usize::MAX"),
+            },
+        }
+    }
+
+    /// Advances the traversal until the next piece of the NAR is available.
+    /// Both the async stream and the blocking reader are driven by this.
+    fn poll_next_chunk(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes>>> {
         loop {
             if let Some(chunk) = self.pending_chunks.pop_front() {
                 return Poll::Ready(Some(chunk));
             }
 
+            // the traversal is complete once the stack is empty
             let Some(current_state) = self.stack.pop() else {
                 return Poll::Ready(None);
             };
 
             match current_state {
                 TraversalState::StartNode(oid, filemode) => {
-                    let kind = if filemode == <FileMode as Into<i32>>::into(FileMode::Tree) {
-                        ObjectType::Tree
-                    } else {
-                        ObjectType::Blob
+                    let kind = match EntryKind::from_filemode(filemode) {
+                        Ok(kind) => kind,
+                        Err(err) => return Poll::Ready(Some(Err(err))),
+                    };
+                    // Loaded before anything of the node is emitted, so it is started again if the
+                    // blob is not loaded yet
+                    let loaded = match kind {
+                        EntryKind::Directory => None,
+                        EntryKind::Regular { .. } | EntryKind::Symlink => {
+                            match self.poll_blob_content(oid, cx) {
+                                Poll::Ready(Ok(loaded)) => Some(loaded),
+                                Poll::Ready(Err(err)) => return Poll::Ready(Some(Err(err))),
+                                Poll::Pending => {
+                                    self.stack.push(TraversalState::StartNode(oid, filemode));
+                                    return Poll::Pending;
+                                }
+                            }
+                        }
                     };
 
                     self.pending_chunks.push_back(Ok(write_padded_bytes(b"(")));
@@ -97,70 +393,66 @@ impl Stream for NarGitStream {
 
                     enum OwnedData {
                         TreeEntries(IntoIter<OwnedTreeEntry>),
-                        Blob { content: Vec<u8>, executable: bool },
-                        LinkTarget(Vec<u8>),
+                        ChunkedFile {
+                            chunks: IntoIter<Oid>,
+                            size: u64,
+                            executable: bool,
+                        },
+                        Blob {
+                            content: LoadedBlob,
+                            executable: bool,
+                        },
+                        LinkTarget(Bytes),
                     }
 
-                    let (node_type_str, owned_data) = {
-                        let repo = self.repo.read().unwrap();
-                        let Ok(obj) = repo.find_object(oid, Some(kind)) else {
-                            let err = anyhow!("Could not find object with oid {}", oid);
-                            return Poll::Ready(Some(Err(err)));
-                        };
-
-                        match kind {
-                            ObjectType::Tree => {
-                                let tree = obj.as_tree().unwrap();
-                                let mut entries: Vec<_> = tree
-                                    .iter()
-                                    .map(|entry| OwnedTreeEntry {
-                                        id: entry.id(),
-                                        filemode: entry.filemode(),
-                                        name: entry.name_bytes().to_vec(),
-                                    })
-                                    .collect();
-                                entries.sort_by(|x, y| x.name.cmp(&y.name));
-                                (
-                                    b"directory".as_slice(),
-                                    Some(OwnedData::TreeEntries(entries.into_iter())),
-                                )
-                            }
-                            ObjectType::Blob => {
-                                let blob = obj.as_blob().unwrap();
-                                let content = blob.content().to_vec();
-
-                                if filemode
-                                    == <FileMode as Into<i32>>::into(FileMode::BlobExecutable)
-                                {
-                                    (
-                                        b"regular".as_slice(),
-                                        Some(OwnedData::Blob {
-                                            content,
-                                            executable: true,
-                                        }),
-                                    )
-                                } else if filemode == <FileMode as Into<i32>>::into(FileMode::Blob)
-                                {
+                    let (node_type_str, owned_data) = match (kind, loaded) {
+                        (EntryKind::Directory, _) => {
+                            let repo = &self.repo;
+                            let Ok(tree) = repo.find_tree(oid) else {
+                                let err = anyhow!("Could not find object with oid {}", oid);
+                                return Poll::Ready(Some(Err(err)));
+                            };
+                            match read_chunked_file(repo, &tree) {
+                                Ok(Some((manifest, chunks))) => (
+                                    b"regular".as_slice(),
+                                    Some(OwnedData::ChunkedFile {
+                                        chunks: chunks.into_iter(),
+                                        size: manifest.size,
+                                        executable: manifest.executable,
+                                    }),
+                                ),
+                                Ok(None) => {
+                                    let mut entries: Vec<_> = tree
+                                        .iter()
+                                        .map(|entry| OwnedTreeEntry {
+                                            id: entry.id(),
+                                            filemode: entry.filemode(),
+                                            name: entry.name_bytes().to_vec(),
+                                        })
+                                        .collect();
+                                    entries.sort_by(|x, y| x.name.cmp(&y.name));
                                     (
-                                        b"regular".as_slice(),
-                                        Some(OwnedData::Blob {
-                                            content,
-                                            executable: false,
-                                        }),
+                                        b"directory".as_slice(),
+                                        Some(OwnedData::TreeEntries(entries.into_iter())),
                                     )
-                                } else if filemode == <FileMode as Into<i32>>::into(FileMode::Link)
-                                {
-                                    (b"symlink".as_slice(), Some(OwnedData::LinkTarget(content)))
-                                } else {
-                                    let err = anyhow!("Unsupported blob filemode: {}", filemode);
-                                    return Poll::Ready(Some(Err(err)));
                                 }
-                            }
-                            _ => {
-                                let err = anyhow!("Unrecognized file type");
-                                return Poll::Ready(Some(Err(err)));
+                                Err(err) => return Poll::Ready(Some(Err(err))),
                             }
                         }
+                        (EntryKind::Regular { executable }, Some(content)) => (
+                            b"regular".as_slice(),
+                            Some(OwnedData::Blob {
+                                content,
+                                executable,
+                            }),
+                        ),
+                        (EntryKind::Symlink, Some(loaded)) => match self.whole_blob(loaded, oid) {
+                            Ok(target) => {
+                                (b"symlink".as_slice(), Some(OwnedData::LinkTarget(target)))
+                            }
+                            Err(err) => return Poll::Ready(Some(Err(err))),
+                        },
+                        (_, None) => unreachable!("the blobs of files are loaded above"),
                     };
 
                     self.pending_chunks
@@ -169,12 +461,47 @@ impl Stream for NarGitStream {
                     if let Some(data) = owned_data {
                         match data {
                             OwnedData::TreeEntries(entries_iter) => {
+                                if let Some(first) = entries_iter.as_slice().first() {
+                                    self.prefetch(first);
+                                }
                                 self.stack
                                     .push(TraversalState::ProcessTreeEntries(entries_iter));
                             }
                             OwnedData::Blob {
                                 content,
                                 executable,
+                            } => {
+                                if executable {
+                                    self.pending_chunks
+                                        .push_back(Ok(write_padded_bytes(b"executable")));
+                                    self.pending_chunks.push_back(Ok(write_padded_bytes(b"")));
+                                }
+                                self.pending_chunks
+                                    .push_back(Ok(write_padded_bytes(b"contents")));
+                                let (size, contents) = match content {
+                                    LoadedBlob::Content(content) => (
+                                        content.len() as u64,
+                                        TraversalState::ProcessContent { content, offset: 0 },
+                                    ),
+                                    LoadedBlob::Large(size) => (
+                                        size,
+                                        TraversalState::StreamContent(BlobStream::spawn(
+                                            self.repo.sibling(),
+                                            oid,
+                                            size,
+                                            self.chunk_size,
+                                        )),
+                                    ),
+                                };
+                                self.pending_chunks
+                                    .push_back(Ok(Bytes::copy_from_slice(&size.to_le_bytes())));
+                                self.stack.push(TraversalState::Padding(size));
+                                self.stack.push(contents);
+                            }
+                            OwnedData::ChunkedFile {
+                                chunks,
+                                size,
+                                executable,
                             } => {
                                 if executable {
                                     self.pending_chunks
@@ -184,7 +511,9 @@ impl Stream for NarGitStream {
                                 self.pending_chunks
                                     .push_back(Ok(write_padded_bytes(b"contents")));
                                 self.pending_chunks
-                                    .push_back(Ok(write_padded_bytes(&content)));
+                                    .push_back(Ok(Bytes::copy_from_slice(&size.to_le_bytes())));
+                                self.stack
+                                    .push(TraversalState::ProcessFileChunks { chunks, size });
                             }
                             OwnedData::LinkTarget(target) => {
                                 self.pending_chunks
@@ -198,6 +527,10 @@ impl Stream for NarGitStream {
 
                 TraversalState::ProcessTreeEntries(mut entries_iter) => {
                     if let Some(entry) = entries_iter.next() {
+                        // Loaded while this entry is sent
+                        if let Some(next) = entries_iter.as_slice().first() {
+                            self.prefetch(next);
+                        }
                         self.stack
                             .push(TraversalState::ProcessTreeEntries(entries_iter));
                         let name_bytes = &entry.name;
@@ -219,6 +552,61 @@ impl Stream for NarGitStream {
                     }
                 }
 
+                TraversalState::ProcessFileChunks { mut chunks, size } => {
+                    if let Some(chunk) = chunks.next() {
+                        self.stack
+                            .push(TraversalState::ProcessFileChunks { chunks, size });
+                        let content = {
+                            let repo = &self.repo;
+                            match repo.find_blob(chunk) {
+                                Ok(blob) => Bytes::copy_from_slice(blob.content()),
+                                Err(_) => {
+                                    let err = anyhow!("Could not find chunk with oid {}", chunk);
+                                    return Poll::Ready(Some(Err(err)));
+                                }
+                            }
+                        };
+                        self.stack
+                            .push(TraversalState::ProcessContent { content, offset: 0 });
+                    } else {
+                        self.pending_chunks.push_back(Ok(padding_bytes(size)));
+                    }
+                }
+
+                // Only the next piece is produced, so the pieces are not all queued at once
+                TraversalState::ProcessContent { content, offset } => {
+                    let end = content.len().min(offset + self.chunk_size);
+                    if end < content.len() {
+                        self.stack.push(TraversalState::ProcessContent {
+                            content: content.clone(),
+                            offset: end,
+                        });
+                    }
+                    if end > offset {
+                        self.pending_chunks
+                            .push_back(Ok(content.slice(offset..end)));
+                    }
+                }
+
+                TraversalState::StreamContent(mut blob) => match blob.poll_piece(cx) {
+                    Poll::Ready(Some(piece)) => {
+                        let failed = piece.is_err();
+                        self.pending_chunks.push_back(piece);
+                        if !failed {
+                            self.stack.push(TraversalState::StreamContent(blob));
+                        }
+                    }
+                    Poll::Ready(None) => {}
+                    Poll::Pending => {
+                        self.stack.push(TraversalState::StreamContent(blob));
+                        return Poll::Pending;
+                    }
+                },
+
+                TraversalState::Padding(size) => {
+                    self.pending_chunks.push_back(Ok(padding_bytes(size)));
+                }
+
                 TraversalState::FinishTreeEntry => {
                     self.pending_chunks.push_back(Ok(write_padded_bytes(b")")));
                 }
@@ -229,17 +617,62 @@ impl Stream for NarGitStream {
             }
         }
     }
+
+    /// Waits for the next piece of the NAR, for reads without an async runtime
+    fn next_chunk(&mut self) -> Option<Result<Bytes>> {
+        futures::executor::block_on(futures::future::poll_fn(|cx| self.poll_next_chunk(cx)))
+    }
+
+    /// Turns the stream into a blocking reader producing the same bytes
+    pub fn into_sync_reader(self) -> NarGitReader {
+        NarGitReader {
+            stream: self,
+            current: Bytes::new(),
+        }
+    }
+}
+
+impl Stream for NarGitStream {
+    type Item = Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_next_chunk(cx)
+    }
+}
+
+/// Reads the NAR serialization of a git object without an async runtime,
+/// e.g. to write it to a file with `io::copy`
+pub struct NarGitReader {
+    stream: NarGitStream,
+    // The part of the last chunk which was not read yet
+    current: Bytes,
+}
+
+impl Read for NarGitReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() {
+            match self.stream.next_chunk() {
+                Some(Ok(chunk)) => self.current = chunk,
+                Some(Err(err)) => return Err(io::Error::other(err)),
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.current.len());
+        buf[..n].copy_from_slice(&self.current.split_to(n));
+        Ok(n)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use futures::{StreamExt, executor::block_on};
-    use git2::Repository;
+    use crate::nar::encode::NarGitEncoder;
+    use futures::StreamExt;
+    use futures::executor::{block_on, block_on_stream};
+    use git2::{FileMode, Repository};
     use nix_nar::Encoder;
     use std::fs::File;
     use std::io::{Read, Write};
-    use std::sync::{Arc, RwLock};
     use tempfile::TempDir;
 
     #[test]
@@ -258,7 +691,6 @@ mod tests {
         let mut encoder = Encoder::new(&file_name)?;
         encoder.read_to_end(&mut expected_nar)?;
 
-        let repo = Arc::new(RwLock::new(repo));
         let nar_stream = NarGitStream::new(repo, oid, FileMode::Blob.into());
         let results: Vec<Result<Bytes>> = block_on(nar_stream.collect());
         let mut actual_nar = Vec::new();
@@ -273,4 +705,203 @@ mod tests {
 
         Ok(())
     }
+    #[test]
+    fn test_encode_file_larger_than_chunk_size() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = Repository::init(temp_dir.path().join("repo"))?;
+        let content: Vec<u8> = (0..1001).map(|i| (i % 251) as u8).collect();
+        let oid = repo.blob(&content)?;
+        let object = repo.find_object(oid, None)?;
+        let expected_nar =
+            NarGitEncoder::new(&repo, &object, FileMode::BlobExecutable.into()).encode()?;
+        drop(object);
+
+        let nar_stream =
+            NarGitStream::new(repo, oid, FileMode::BlobExecutable.into()).with_chunk_size(100);
+        let chunks = block_on(nar_stream.collect::<Vec<_>>())
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 100));
+        assert_eq!(chunks.concat(), expected_nar);
+        Ok(())
+    }
+
+    /// A tree with nested directories, executables, symlinks and empty files
+    fn directory_heavy_tree(repo: &Repository) -> Result<Oid> {
+        let mut root = repo.treebuilder(None)?;
+        for dir in 0..5 {
+            let mut subdir = repo.treebuilder(None)?;
+            for file in 0..20 {
+                let content = vec![(dir * file) as u8; file * 37];
+                let mode = if file % 3 == 0 {
+                    FileMode::BlobExecutable
+                } else {
+                    FileMode::Blob
+                };
+                subdir.insert(format!("file-{file}"), repo.blob(&content)?, mode.into())?;
+            }
+            let target = repo.blob(format!("../dir-{}", (dir + 1) % 5).as_bytes())?;
+            subdir.insert("link", target, FileMode::Link.into())?;
+            let mut empty = repo.treebuilder(None)?;
+            empty.insert("empty", repo.blob(b"")?, FileMode::Blob.into())?;
+            subdir.insert("nested", empty.write()?, FileMode::Tree.into())?;
+            root.insert(format!("dir-{dir}"), subdir.write()?, FileMode::Tree.into())?;
+        }
+        Ok(root.write()?)
+    }
+
+    #[test]
+    fn test_sync_reader_matches_stream() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("repo");
+        let repo = Repository::init(&path)?;
+        let oid = directory_heavy_tree(&repo)?;
+        let object = repo.find_object(oid, None)?;
+        let expected_nar = NarGitEncoder::new(&repo, &object, FileMode::Tree.into()).encode()?;
+        drop(object);
+
+        let stream = NarGitStream::new(repo, oid, FileMode::Tree.into()).with_chunk_size(50);
+        let streamed = block_on(stream.collect::<Vec<_>>())
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?
+            .concat();
+        assert_eq!(streamed, expected_nar);
+
+        let repo = Repository::open(&path)?;
+        let mut reader = NarGitStream::new(repo, oid, FileMode::Tree.into())
+            .with_chunk_size(50)
+            .into_sync_reader();
+        // reads which do not line up with the chunks
+        let mut read = Vec::new();
+        let mut buf = [0; 7];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            read.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(read, expected_nar);
+
+        let repo = Repository::open(&path)?;
+        let mut copied = Vec::new();
+        std::io::copy(
+            &mut NarGitStream::new(repo, oid, FileMode::Tree.into()).into_sync_reader(),
+            &mut copied,
+        )?;
+        assert_eq!(copied, expected_nar);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefetching_stream() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("repo");
+        let repo = Repository::init(&path)?;
+        let oid = directory_heavy_tree(&repo)?;
+        let object = repo.find_object(oid, None)?;
+        let expected_nar = NarGitEncoder::new(&repo, &object, FileMode::Tree.into()).encode()?;
+        drop(object);
+
+        for depth in [0, 1, DEFAULT_PREFETCH_DEPTH, 10] {
+            let mut stream =
+                NarGitStream::new(Repository::open(&path)?, oid, FileMode::Tree.into())
+                    .with_prefetch_depth(depth)
+                    .with_chunk_size(50);
+            let mut streamed = Vec::new();
+            while let Some(chunk) = stream.next_chunk() {
+                streamed.extend_from_slice(&chunk?);
+                let prefetched = stream.prefetcher.as_ref().map_or(0, Prefetcher::len);
+                assert!(prefetched <= depth);
+            }
+            assert_eq!(streamed, expected_nar, "prefetch depth {depth}");
+            assert_eq!(stream.prefetcher.is_some(), depth > 0);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_on_runtime() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("repo");
+        let repo = Repository::init(&path)?;
+        let oid = directory_heavy_tree(&repo)?;
+        let object = repo.find_object(oid, None)?;
+        let expected_nar = NarGitEncoder::new(&repo, &object, FileMode::Tree.into()).encode()?;
+        drop(object);
+
+        // Most blobs are larger than a piece, so they are streamed from the blocking pool
+        // of the single thread runtime, which the stream must not block
+        let stream = NarGitStream::new(repo, oid, FileMode::Tree.into()).with_chunk_size(50);
+        let streamed = stream
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?
+            .concat();
+        assert_eq!(streamed, expected_nar);
+        Ok(())
+    }
+
+    fn nar_len(stream: NarGitStream) -> Result<u64> {
+        let mut len = 0;
+        for chunk in block_on_stream(stream) {
+            len += chunk?.len() as u64;
+        }
+        Ok(len)
+    }
+
+    #[test]
+    fn test_large_blob_is_streamed() -> Result<()> {
+        const SIZE: u64 = 16 << 20;
+        const PIECE: usize = 4096;
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("repo");
+        let repo = Repository::init(&path)?;
+        let odb = repo.odb()?;
+        let mut writer = odb.writer(SIZE as usize, ObjectType::Blob)?;
+        io::copy(&mut io::repeat(7).take(SIZE), &mut writer)?;
+        let oid = writer.finalize()?;
+        let empty = repo.blob(b"")?;
+        drop(writer);
+        drop(odb);
+
+        let mut stream = NarGitStream::new(repo, oid, FileMode::Blob.into()).with_chunk_size(PIECE);
+        let mut len = 0;
+        let mut contents = 0;
+        while let Some(chunk) = stream.next_chunk() {
+            let chunk = chunk?;
+            // the blob is never held in memory as a whole
+            assert!(chunk.len() <= PIECE);
+            assert!(
+                !stream
+                    .stack
+                    .iter()
+                    .any(|state| matches!(state, TraversalState::ProcessContent { .. }))
+            );
+            if chunk.len() == PIECE && chunk.iter().all(|byte| *byte == 7) {
+                contents += chunk.len() as u64;
+            }
+            len += chunk.len() as u64;
+        }
+        assert_eq!(contents, SIZE);
+        let empty_nar_len = nar_len(NarGitStream::new(
+            Repository::open(&path)?,
+            empty,
+            FileMode::Blob.into(),
+        ))?;
+        assert_eq!(len, empty_nar_len + SIZE);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_reader_reports_errors() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = Repository::init(temp_dir.path().join("repo"))?;
+        let missing = Oid::from_str(&"1".repeat(40))?;
+        let mut reader = NarGitStream::new(repo, missing, FileMode::Blob.into()).into_sync_reader();
+        let error = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert!(error.to_string().contains("Could not find object"));
+        Ok(())
+    }
 }
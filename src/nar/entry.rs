@@ -0,0 +1,135 @@
+use super::error::Error;
+use anyhow::{Result, anyhow};
+use git2::{FileMode, ObjectType, Oid, Repository};
+use std::fmt;
+
+/// How a git object with a given filemode is serialized in a NAR.
+/// Trees holding a chunked file are classified as `Directory` and told apart by their contents.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntryKind {
+    Directory,
+    Regular { executable: bool },
+    Symlink,
+}
+
+impl EntryKind {
+    pub fn from_filemode(filemode: i32) -> Result<Self> {
+        match filemode {
+            m if m == i32::from(FileMode::Tree) => Ok(Self::Directory),
+            m if m == i32::from(FileMode::Blob) => Ok(Self::Regular { executable: false }),
+            // obsolete mode which git still accepts, it can only come from foreign history
+            m if m == i32::from(FileMode::BlobGroupWritable) => {
+                Ok(Self::Regular { executable: false })
+            }
+            m if m == i32::from(FileMode::BlobExecutable) => Ok(Self::Regular { executable: true }),
+            m if m == i32::from(FileMode::Link) => Ok(Self::Symlink),
+            m => Err(anyhow!("Unsupported filemode {}", describe_filemode(m))),
+        }
+    }
+
+    pub fn object_type(&self) -> ObjectType {
+        match self {
+            Self::Directory => ObjectType::Tree,
+            Self::Regular { .. } | Self::Symlink => ObjectType::Blob,
+        }
+    }
+}
+
+fn describe_filemode(filemode: i32) -> String {
+    if filemode == i32::from(FileMode::Commit) {
+        format!("{filemode:06o} (a git submodule)")
+    } else {
+        format!("{filemode:06o}")
+    }
+}
+
+/// An entry of a package tree which has no NAR representation
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnsupportedEntry {
+    /// Location of the entry inside the package, empty for the root
+    pub path: Vec<u8>,
+    pub filemode: i32,
+}
+
+impl fmt::Display for UnsupportedEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = if self.path.is_empty() {
+            "/".to_string()
+        } else {
+            self.path.escape_ascii().to_string()
+        };
+        write!(
+            f,
+            "Package entry '{}' has the unsupported filemode {}",
+            path,
+            describe_filemode(self.filemode)
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedEntry {}
+
+/// Checks that every entry below the object can be serialized, so that errors surface
+/// before a response is started rather than in the middle of a NAR.
+/// Only trees are read, blob contents are not touched.
+pub fn validate_tree(repo: &Repository, oid: Oid, filemode: i32) -> Result<(), Error> {
+    validate_entry(repo, oid, filemode, b"")
+}
+
+fn validate_entry(repo: &Repository, oid: Oid, filemode: i32, path: &[u8]) -> Result<(), Error> {
+    let kind = EntryKind::from_filemode(filemode).map_err(|_| UnsupportedEntry {
+        path: path.to_vec(),
+        filemode,
+    })?;
+    if kind != EntryKind::Directory {
+        return Ok(());
+    }
+    let tree = repo.find_tree(oid)?;
+    for entry in tree.iter() {
+        let entry_path = [path, b"/", entry.name_bytes()].concat();
+        validate_entry(repo, entry.id(), entry.filemode(), &entry_path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nar::encode::NarGitEncoder;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_reject_submodule_entry() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = Repository::init(temp_dir.path().join("repo"))?;
+        let blob = repo.blob(b"content")?;
+        let mut inner = repo.treebuilder(None)?;
+        inner.insert("file", blob, FileMode::Blob.into())?;
+        // gitlinks point to commits of other repositories, which need not exist here
+        inner.insert(
+            "module",
+            Oid::from_str(&"1".repeat(40))?,
+            FileMode::Commit.into(),
+        )?;
+        let inner = inner.write()?;
+        let mut root = repo.treebuilder(None)?;
+        root.insert("lib", inner, FileMode::Tree.into())?;
+        let root = root.write()?;
+
+        let error = validate_tree(&repo, root, FileMode::Tree.into()).unwrap_err();
+        assert!(matches!(&error, Error::UnsupportedEntry(e) if e.path == b"/lib/module"));
+        assert!(error.to_string().contains("160000 (a git submodule)"));
+
+        validate_tree(&repo, inner, FileMode::Tree.into()).unwrap_err();
+        validate_tree(&repo, blob, FileMode::BlobExecutable.into())?;
+
+        // the encoders fail instead of panicking when they are used without validation
+        let object = repo.find_object(root, None)?;
+        assert!(
+            NarGitEncoder::new(&repo, &object, FileMode::Tree.into())
+                .encode()
+                .is_err()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,22 @@
+use super::decode::LimitExceeded;
+use super::entry::UnsupportedEntry;
+use std::io;
+use thiserror::Error;
+
+/// Errors of decoding NARs into the object database and of reading them back
+#[derive(Debug, Error)]
+pub enum Error {
+    /// The input is not a well-formed NAR, or one Nix would not produce
+    #[error("{0}")]
+    InvalidNar(String),
+    #[error(transparent)]
+    LimitExceeded(#[from] LimitExceeded),
+    #[error(transparent)]
+    UnsupportedEntry(#[from] UnsupportedEntry),
+    #[error(transparent)]
+    Git(#[from] git2::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
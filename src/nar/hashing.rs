@@ -0,0 +1,201 @@
+use anyhow::Result;
+use bytes::Bytes;
+use futures::Stream;
+use sha2::{Digest, Sha256};
+use std::io::{self, Read};
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+
+/// The sha256 hash and length of a serialized NAR
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NarDigest {
+    pub sha256: [u8; 32],
+    pub size: u64,
+}
+
+impl NarDigest {
+    /// The hash in the format used by narinfos, e.g. `sha256:1l29f8r5...`
+    pub fn nix_hash(&self) -> String {
+        format!("sha256:{}", nix_base32::to_nix_base32(&self.sha256))
+    }
+}
+
+/// Gives access to the digest of a `HashingNarStream` once it has completed
+#[derive(Debug, Clone)]
+pub struct NarDigestHandle(Arc<OnceLock<NarDigest>>);
+
+impl NarDigestHandle {
+    /// Returns `None` until the stream has yielded its last chunk, or if it failed
+    pub fn get(&self) -> Option<NarDigest> {
+        self.0.get().copied()
+    }
+}
+
+/// Passes the chunks of a NAR stream through unchanged while hashing them
+pub struct HashingNarStream<S> {
+    inner: S,
+    hasher: Sha256,
+    size: u64,
+    failed: bool,
+    digest: Arc<OnceLock<NarDigest>>,
+}
+
+impl<S> HashingNarStream<S>
+where
+    S: Stream<Item = Result<Bytes>> + Unpin,
+{
+    pub fn new(inner: S) -> (Self, NarDigestHandle) {
+        let digest = Arc::new(OnceLock::new());
+        let stream = HashingNarStream {
+            inner,
+            hasher: Sha256::new(),
+            size: 0,
+            failed: false,
+            digest: digest.clone(),
+        };
+        (stream, NarDigestHandle(digest))
+    }
+}
+
+impl<S> Stream for HashingNarStream<S>
+where
+    S: Stream<Item = Result<Bytes>> + Unpin,
+{
+    type Item = Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let item = std::task::ready!(Pin::new(&mut this.inner).poll_next(cx));
+        match &item {
+            Some(Ok(chunk)) => {
+                this.hasher.update(chunk);
+                this.size += chunk.len() as u64;
+            }
+            // The digest of a partial NAR is meaningless
+            Some(Err(_)) => this.failed = true,
+            None if !this.failed => {
+                let sha256 = std::mem::take(&mut this.hasher).finalize().into();
+                let _ = this.digest.set(NarDigest {
+                    sha256,
+                    size: this.size,
+                });
+            }
+            None => {}
+        }
+        Poll::Ready(item)
+    }
+}
+
+/// Drives `stream` to completion and returns the digest of everything it produced
+pub fn digest_nar_stream<S>(stream: S) -> Result<NarDigest>
+where
+    S: Stream<Item = Result<Bytes>> + Unpin,
+{
+    let (stream, handle) = HashingNarStream::new(stream);
+    for chunk in futures::executor::block_on_stream(stream) {
+        chunk?;
+    }
+    // the stream completed without errors, so the digest was recorded
+    Ok(handle.get().unwrap())
+}
+
+/// Passes reads through unchanged while hashing the bytes which were read
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        HashingReader {
+            inner,
+            hasher: Sha256::new(),
+            size: 0,
+        }
+    }
+
+    /// The digest of everything read so far
+    pub fn digest(&self) -> NarDigest {
+        NarDigest {
+            sha256: self.hasher.clone().finalize().into(),
+            size: self.size,
+        }
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nar::NarGitStream;
+    use crate::nar::encode::NarGitEncoder;
+    use anyhow::anyhow;
+    use futures::{StreamExt, executor::block_on, stream};
+    use git2::{FileMode, Repository};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_digest_matches_encoded_nar() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = Repository::init(temp_dir.path().join("repo"))?;
+        let content: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        let oid = repo.blob(&content)?;
+        let object = repo.find_object(oid, None)?;
+        let expected_nar = NarGitEncoder::new(&repo, &object, FileMode::Blob.into()).encode()?;
+        drop(object);
+
+        let stream = NarGitStream::new(repo, oid, FileMode::Blob.into()).with_chunk_size(1000);
+        let digest = digest_nar_stream(stream)?;
+        assert_eq!(digest.size, expected_nar.len() as u64);
+        assert_eq!(
+            digest.sha256,
+            <[u8; 32]>::from(Sha256::digest(&expected_nar))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_digest_after_error() {
+        let chunks = vec![Ok(Bytes::from_static(b"partial")), Err(anyhow!("broken"))];
+        let (stream, handle) = HashingNarStream::new(stream::iter(chunks));
+        let results: Vec<_> = block_on(stream.collect());
+        assert!(results[1].is_err());
+        assert_eq!(handle.get(), None);
+    }
+
+    #[test]
+    fn test_hashing_reader() -> Result<()> {
+        let content: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        let mut reader = HashingReader::new(content.as_slice());
+        let mut prefix = [0; 100];
+        reader.read_exact(&mut prefix)?;
+        assert_eq!(reader.digest().size, 100);
+        io::copy(&mut reader, &mut io::sink())?;
+        let digest = reader.digest();
+        assert_eq!(digest.size, content.len() as u64);
+        assert_eq!(digest.sha256, <[u8; 32]>::from(Sha256::digest(&content)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_nix_hash_format() {
+        let digest = NarDigest {
+            sha256: Sha256::digest(b"").into(),
+            size: 0,
+        };
+        assert_eq!(
+            digest.nix_hash(),
+            "sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use super::chunked::read_chunked_file;
+use super::entry::EntryKind;
+use super::{NIX_VERSION_MAGIC, PAD_LEN};
+use anyhow::Result;
+use git2::{Oid, Repository};
+use serde_json::{Map, Value, json};
+
+/// Builds the `.ls` listing of the NAR serialization of an object, in the format of
+/// `nix nar ls --json --recursive` with the `narOffset` of every regular file
+pub fn nar_listing(repo: &Repository, oid: Oid, filemode: i32) -> Result<String> {
+    let mut builder = ListingBuilder {
+        repo,
+        offset: padded_len(NIX_VERSION_MAGIC.len() as u64),
+    };
+    let root = builder.node(oid, filemode)?;
+    Ok(json!({ "version": 1, "root": root }).to_string())
+}
+
+/// Length of a string in a NAR, including its length prefix and padding
+fn padded_len(len: u64) -> u64 {
+    8 + len.next_multiple_of(PAD_LEN as u64)
+}
+
+/// Walks a tree in NAR order while keeping track of the position in the NAR
+struct ListingBuilder<'a> {
+    repo: &'a Repository,
+    offset: u64,
+}
+
+impl ListingBuilder<'_> {
+    fn token(&mut self, token: &[u8]) {
+        self.offset += padded_len(token.len() as u64);
+    }
+
+    fn node(&mut self, oid: Oid, filemode: i32) -> Result<Value> {
+        self.token(b"(");
+        self.token(b"type");
+        let value = match EntryKind::from_filemode(filemode)? {
+            EntryKind::Directory => {
+                let tree = self.repo.find_tree(oid)?;
+                if let Some((manifest, _)) = read_chunked_file(self.repo, &tree)? {
+                    self.regular(manifest.size, manifest.executable)
+                } else {
+                    self.token(b"directory");
+                    let mut entries: Vec<_> = tree.iter().collect();
+                    entries.sort_by(|x, y| x.name_bytes().cmp(y.name_bytes()));
+                    let mut listing = Map::new();
+                    for entry in entries {
+                        for token in [b"entry".as_slice(), b"(", b"name", entry.name_bytes()] {
+                            self.token(token);
+                        }
+                        self.token(b"node");
+                        let node = self.node(entry.id(), entry.filemode())?;
+                        self.token(b")");
+                        let name = String::from_utf8_lossy(entry.name_bytes()).into_owned();
+                        listing.insert(name, node);
+                    }
+                    json!({ "type": "directory", "entries": listing })
+                }
+            }
+            EntryKind::Regular { executable } => {
+                // only the header is read, the contents stay on disk
+                let (size, _) = self.repo.odb()?.read_header(oid)?;
+                self.regular(size as u64, executable)
+            }
+            EntryKind::Symlink => {
+                let blob = self.repo.find_blob(oid)?;
+                for token in [b"symlink".as_slice(), b"target", blob.content()] {
+                    self.token(token);
+                }
+                let target = String::from_utf8_lossy(blob.content());
+                json!({ "type": "symlink", "target": target })
+            }
+        };
+        self.token(b")");
+        Ok(value)
+    }
+
+    fn regular(&mut self, size: u64, executable: bool) -> Value {
+        self.token(b"regular");
+        if executable {
+            self.token(b"executable");
+            self.token(b"");
+        }
+        self.token(b"contents");
+        let nar_offset = self.offset + 8;
+        self.offset += padded_len(size);
+        let mut value = json!({ "type": "regular", "size": size, "narOffset": nar_offset });
+        if executable {
+            value["executable"] = json!(true);
+        }
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nar::encode::NarGitEncoder;
+    use git2::FileMode;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_listing_offsets_point_at_contents() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = Repository::init(temp_dir.path().join("repo"))?;
+        let script = repo.blob(b"#!/bin/sh\necho hi\n")?;
+        let data = repo.blob(b"0123456789")?;
+        let target = repo.blob(b"../bin/hello")?;
+        let mut bin = repo.treebuilder(None)?;
+        bin.insert("hello", script, FileMode::BlobExecutable.into())?;
+        let bin = bin.write()?;
+        let mut root = repo.treebuilder(None)?;
+        root.insert("bin", bin, FileMode::Tree.into())?;
+        root.insert("data", data, FileMode::Blob.into())?;
+        root.insert("link", target, FileMode::Link.into())?;
+        let root = root.write()?;
+
+        let listing: Value =
+            serde_json::from_str(&nar_listing(&repo, root, FileMode::Tree.into())?)?;
+        assert_eq!(listing["version"], 1);
+        let entries = &listing["root"]["entries"];
+        assert_eq!(entries["bin"]["entries"]["hello"]["executable"], true);
+        assert_eq!(entries["data"]["size"], 10);
+        assert_eq!(entries["data"].get("executable"), None);
+        assert_eq!(entries["link"]["target"], "../bin/hello");
+
+        let object = repo.find_object(root, None)?;
+        let nar = NarGitEncoder::new(&repo, &object, FileMode::Tree.into()).encode()?;
+        for (file, content) in [
+            (
+                &entries["bin"]["entries"]["hello"],
+                b"#!/bin/sh\necho hi\n".as_slice(),
+            ),
+            (&entries["data"], b"0123456789"),
+        ] {
+            let offset = file["narOffset"].as_u64().unwrap() as usize;
+            assert_eq!(&nar[offset..offset + content.len()], content);
+        }
+        Ok(())
+    }
+}
@@ -1,8 +1,19 @@
 use crate::nar;
+pub mod chunked;
+pub mod compression;
 pub mod decode;
-pub mod encode;
+// Only the reference the streaming encoder is tested against, gachix serves NARs with `NarGitStream`
+#[cfg(test)]
+pub(crate) mod encode;
 pub mod encode_stream;
+pub mod entry;
+pub mod error;
+pub mod hashing;
+pub mod listing;
+pub mod pipeline;
+pub use nar::decode::NarGitDecoder;
 pub use nar::encode_stream::NarGitStream;
+pub use nar::error::Error;
 
 const NIX_VERSION_MAGIC: &[u8] = b"nix-archive-1";
 const PAD_LEN: usize = 8;
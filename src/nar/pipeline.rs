@@ -0,0 +1,174 @@
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use std::io::{self, Read};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::mpsc;
+
+// Size of the chunks passed from the network to the decoder
+const CHUNK_LEN: usize = 64 * 1024;
+// Chunks buffered before reading waits for the decoder, so at most 1 MiB is held in memory
+const CHANNEL_CAPACITY: usize = 16;
+
+/// Reads `reader` on the current task while `parse` consumes its bytes on a blocking thread,
+/// so receiving a NAR overlaps with hashing and writing its git objects.
+/// Reading stops as soon as `parse` returns, which is also how an error of the decoder
+/// ends the transfer. A read error is passed on to `parse` and ends it.
+pub async fn parse_pipelined<A, F, T>(mut reader: A, parse: F) -> Result<T>
+where
+    A: AsyncRead + Unpin,
+    F: FnOnce(&mut dyn Read) -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+    let mut parser = tokio::task::spawn_blocking(move || {
+        parse(&mut ChannelReader {
+            receiver,
+            current: Bytes::new(),
+        })
+    });
+
+    let forward = async {
+        // A chunk is only read once the decoder has room for it, so a slow decoder throttles the reader
+        while let Ok(permit) = sender.reserve().await {
+            let mut chunk = BytesMut::with_capacity(CHUNK_LEN);
+            match reader.read_buf(&mut chunk).await {
+                Ok(0) => break,
+                Ok(_) => permit.send(Ok(chunk.freeze())),
+                Err(e) => {
+                    permit.send(Err(e));
+                    break;
+                }
+            }
+        }
+    };
+    tokio::select! {
+        biased;
+        // The NAR may end before the reader does, e.g. on a daemon connection
+        result = &mut parser => return result?,
+        () = forward => {}
+    }
+    // Lets the decoder see the end of the input
+    drop(sender);
+    parser.await?
+}
+
+/// The receiving end of `parse_pipelined`, blocking until the next chunk arrives
+struct ChannelReader {
+    receiver: mpsc::Receiver<io::Result<Bytes>>,
+    current: Bytes,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() {
+            match self.receiver.blocking_recv() {
+                Some(chunk) => self.current = chunk?,
+                None => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.current.len());
+        buf[..len].copy_from_slice(&self.current.split_to(len));
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Context, Poll};
+    use tokio::io::ReadBuf;
+
+    /// Yields `chunks` chunks of 1 KiB and then either ends, fails or never returns
+    struct ChunkReader {
+        chunks: usize,
+        reads: Arc<AtomicUsize>,
+        end: End,
+    }
+
+    enum End {
+        Eof,
+        Error,
+        Pending,
+    }
+
+    impl AsyncRead for ChunkReader {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if self.reads.load(Ordering::SeqCst) < self.chunks {
+                self.reads.fetch_add(1, Ordering::SeqCst);
+                buf.put_slice(&[7; 1024]);
+                return Poll::Ready(Ok(()));
+            }
+            match self.end {
+                End::Eof => Poll::Ready(Ok(())),
+                End::Error => Poll::Ready(Err(io::Error::other("connection reset"))),
+                End::Pending => Poll::Pending,
+            }
+        }
+    }
+
+    fn chunk_reader(chunks: usize, end: End) -> (ChunkReader, Arc<AtomicUsize>) {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let reader = ChunkReader {
+            chunks,
+            reads: reads.clone(),
+            end,
+        };
+        (reader, reads)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_parse_pipelined() -> Result<()> {
+        let (reader, _) = chunk_reader(100, End::Eof);
+        let content = parse_pipelined(reader, |r| {
+            let mut content = Vec::new();
+            r.read_to_end(&mut content)?;
+            Ok(content)
+        })
+        .await?;
+        assert_eq!(content, vec![7; 100 * 1024]);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_parser_end_stops_reading() -> Result<()> {
+        // the input never ends, like a connection which stays open after the NAR
+        let (reader, _) = chunk_reader(4, End::Pending);
+        let len = parse_pipelined(reader, |r| {
+            let mut content = [0; 4 * 1024];
+            r.read_exact(&mut content)?;
+            Ok(content.len())
+        })
+        .await?;
+        assert_eq!(len, 4 * 1024);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_errors_end_the_other_side() -> Result<()> {
+        let (reader, _) = chunk_reader(3, End::Error);
+        let error = parse_pipelined(reader, |r| Ok(r.read_to_end(&mut Vec::new())?))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("connection reset"), "{error}");
+
+        // a failing decoder stops the reader long before the input ends
+        let (reader, reads) = chunk_reader(100_000, End::Eof);
+        let error = parse_pipelined(reader, |r| -> Result<()> {
+            r.read_exact(&mut [0; 1024])?;
+            bail!("invalid NAR")
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(error.to_string(), "invalid NAR");
+        assert!(reads.load(Ordering::SeqCst) <= CHANNEL_CAPACITY + 2);
+        Ok(())
+    }
+}
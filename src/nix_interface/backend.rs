@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::Read;
+
+use anyhow::Result;
+use nix_daemon::{BuildResult, PathInfo};
+
+use crate::nix_interface::path::NixPath;
+
+/// The operations the store needs from a Nix daemon.
+/// Implemented by `DynNixDaemon` and, for tests without a Nix installation, by `MockNixBackend`.
+pub trait NixBackend: Send + 'static {
+    fn connect(&mut self) -> impl Future<Output = Result<()>> + Send;
+
+    fn get_pathinfo(
+        &mut self,
+        path: &NixPath,
+    ) -> impl Future<Output = Result<Option<PathInfo>>> + Send;
+
+    fn path_exists(&mut self, store_path: &NixPath) -> impl Future<Output = Result<bool>> + Send;
+
+    /// Returns the subset of `store_paths` which are valid in the store
+    fn query_valid_paths(
+        &mut self,
+        store_paths: &[NixPath],
+    ) -> impl Future<Output = Result<Vec<NixPath>>> + Send;
+
+    fn query_path_from_hash_part(
+        &mut self,
+        hash: &str,
+    ) -> impl Future<Output = Result<Option<NixPath>>> + Send;
+
+    /// Returns the output paths of the derivation `drv_path` by output name
+    fn derivation_outputs(
+        &mut self,
+        drv_path: &NixPath,
+    ) -> impl Future<Output = Result<HashMap<String, NixPath>>> + Send;
+
+    fn add_signatures(
+        &mut self,
+        store_path: &NixPath,
+        signatures: &[String],
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Runs `parser` on the NAR of `store_path`. The parser may be run more than once
+    /// if the transfer has to be restarted.
+    fn fetch<F, R>(
+        &mut self,
+        store_path: &NixPath,
+        parser: F,
+    ) -> impl Future<Output = Result<R>> + Send
+    where
+        R: Send + Sync + 'static,
+        F: for<'a> Fn(&'a mut dyn Read) -> Result<R> + Clone + Send + Sync + 'static;
+
+    /// Makes the store fetch `store_path` from its substituters
+    fn substitute(&mut self, store_path: &NixPath) -> impl Future<Output = Result<()>> + Send;
+
+    fn build(
+        &mut self,
+        drv_paths: &[&NixPath],
+    ) -> impl Future<Output = Result<HashMap<String, BuildResult>>> + Send;
+
+    /// Checks that the connection still works with a cheap request
+    fn ping(&mut self) -> impl Future<Output = Result<()>> + Send {
+        async move { self.query_valid_paths(&[]).await.map(|_| ()) }
+    }
+
+    fn get_address(&self) -> String;
+
+    /// Whether this is the Nix store of the machine gachix runs on
+    fn is_local(&self) -> bool;
+}
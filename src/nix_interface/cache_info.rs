@@ -1,9 +1,61 @@
 use std::fmt::Display;
 
+use super::error::{Error, Result};
+use super::path::store_dir;
+
+/// The `nix-cache-info` file at the root of a binary cache, served by gachix and read from
+/// upstream caches
+#[derive(Debug, Clone, PartialEq)]
 pub struct CacheInfo {
-    store_dir: String,
-    want_mass_query: bool,
-    priority: usize,
+    pub store_dir: String,
+    pub want_mass_query: bool,
+    pub priority: usize,
+}
+
+impl CacheInfo {
+    /// The cache info of a cache for the store directory gachix runs with
+    pub fn new(want_mass_query: bool, priority: usize) -> Self {
+        Self {
+            store_dir: store_dir().to_string(),
+            want_mass_query,
+            priority,
+        }
+    }
+
+    /// Parses the `key: value` lines of a `nix-cache-info` file.
+    /// Keys which are unknown or missing are ignored, as Nix does.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut cache_info = Self::default();
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let (key, value) = line.split_once(':').ok_or_else(|| {
+                Error::InvalidCacheInfo(format!("line '{line}' is not of the form 'key: value'"))
+            })?;
+            let value = value.trim();
+            match key {
+                "StoreDir" => cache_info.store_dir = value.to_string(),
+                "WantMassQuery" => cache_info.want_mass_query = value == "1",
+                "Priority" => {
+                    cache_info.priority = value.parse().map_err(|e| {
+                        Error::InvalidCacheInfo(format!("Priority is not a number: {e}"))
+                    })?
+                }
+                _ => {}
+            }
+        }
+        Ok(cache_info)
+    }
+
+    /// Fails unless the paths of the cache are in `store_dir`, as paths can't be moved
+    /// between store directories
+    pub fn check_store_dir(&self, store_dir: &str) -> Result<()> {
+        if self.store_dir != store_dir {
+            return Err(Error::InvalidCacheInfo(format!(
+                "the cache is for the store directory {} instead of {}",
+                self.store_dir, store_dir
+            )));
+        }
+        Ok(())
+    }
 }
 
 impl Display for CacheInfo {
@@ -11,24 +63,54 @@ impl Display for CacheInfo {
         let keys = ["StoreDir", "WantMassQuery", "Priority"];
         let mass_query = if self.want_mass_query { "1" } else { "0" };
         let values = [&self.store_dir, mass_query, &self.priority.to_string()];
-        let output = keys
-            .iter()
-            .zip(values)
-            .map(|(k, v)| format!("{}: {}", k, v))
-            .collect::<Vec<String>>()
-            .join("\n");
-
-        f.write_str(&output)?;
+        for (key, value) in keys.iter().zip(values) {
+            write!(f, "{}: {}\n", key, value)?;
+        }
         Ok(())
     }
 }
 
-impl CacheInfo {
-    pub fn default() -> Self {
+impl Default for CacheInfo {
+    fn default() -> Self {
         Self {
-            store_dir: "/nix/store".to_string(),
+            store_dir: store_dir().to_string(),
             want_mass_query: false,
             priority: 50,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // As served by https://cache.nixos.org/nix-cache-info
+    const CACHE_NIXOS_ORG: &str = "StoreDir: /nix/store\nWantMassQuery: 1\nPriority: 40\n";
+
+    #[test]
+    fn test_parse_cache_info() -> Result<()> {
+        let cache_info = CacheInfo::default();
+        assert_eq!(CacheInfo::parse(&cache_info.to_string())?, cache_info);
+
+        let upstream = CacheInfo::parse(CACHE_NIXOS_ORG)?;
+        assert!(upstream.want_mass_query);
+        assert_eq!(upstream.priority, 40);
+        assert_eq!(upstream.to_string(), CACHE_NIXOS_ORG);
+        assert_eq!(CacheInfo::new(true, 40), upstream);
+        assert!(CacheInfo::parse("Priority: high").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_store_dir() -> Result<()> {
+        let upstream = CacheInfo::parse(CACHE_NIXOS_ORG)?;
+        upstream.check_store_dir("/nix/store")?;
+        let error = upstream.check_store_dir("/gnu/store").unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("/nix/store instead of /gnu/store")
+        );
+        Ok(())
+    }
+}
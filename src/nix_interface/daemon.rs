@@ -1,69 +1,149 @@
 use std::collections::HashMap;
-use std::io::{BufReader, Read};
-use std::path::PathBuf;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 
-use anyhow::{Result, anyhow, bail};
-use async_ssh2_lite::{AsyncChannel, AsyncSession, TokioTcpStream};
+use anyhow::{Context as _, Result, anyhow, bail};
+use async_ssh2_lite::ssh2::{CheckResult, KnownHostFileKind};
+use async_ssh2_lite::{AsyncChannel, AsyncSession, AsyncSessionStream, TokioTcpStream};
+use base64::{Engine, engine::general_purpose::STANDARD_NO_PAD};
 use futures::io;
 use nix_daemon::{BuildMode, ClientSettings, Progress, Store, nix::DaemonStore};
-use nix_daemon::{BuildResult, PathInfo};
+use nix_daemon::{
+    BuildResult, PathInfo, Stderr, StderrActivityType, StderrField, StderrResultType,
+};
+use sha2::{Digest, Sha256};
+use std::future::Future;
 use std::net::ToSocketAddrs;
-use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::pin::Pin;
+use std::process::Stdio;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 use tokio::net::UnixStream;
-use tokio_util::io::SyncIoBridge;
+use tokio::process::{Child, ChildStdin, ChildStdout};
+use tokio::time::{Instant, Sleep};
 
+use crate::nar::pipeline::parse_pipelined;
+use crate::nix_interface::backend::NixBackend;
+use crate::nix_interface::nar_info::NarInfo;
 use crate::nix_interface::path::NixPath;
+use crate::settings::DaemonTimeouts;
+use tracing::{debug, info, trace, warn};
 
 pub trait AsyncStream: AsyncWriteExt + AsyncReadExt + Unpin + Unpin + Send {}
 impl<T> AsyncStream for T where T: AsyncWriteExt + AsyncReadExt + AsyncWrite + Unpin + Send {}
 
+pub const DEFAULT_DAEMON_SOCKET: &str = "/nix/var/nix/daemon-socket/socket";
+
 pub struct NixDaemon<C: AsyncStream> {
     daemon: Option<DaemonStore<C>>,
     address: String,
+    // Socket locations considered for the local daemon, with where they came from
+    socket_candidates: Vec<(PathBuf, &'static str)>,
+    timeouts: DaemonTimeouts,
     // TODO: this is only used by the ssh Nix daemon. find a better place to store this
-    ssh_private_key_path: Option<PathBuf>,
+    ssh: Option<SshOptions>,
+    // Options passed to the ssh command when connecting through OpenSSH
+    ssh_args: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SshOptions {
+    pub port: u16,
+    pub user: String,
+    pub private_key_path: PathBuf,
+    pub host_key: HostKeyPolicy,
+}
+
+/// How the host key offered by an SSH server is verified
+#[derive(Debug, Clone, Default)]
+pub struct HostKeyPolicy {
+    /// known_hosts files to look the key up in. New keys are added to the first one
+    pub known_hosts_files: Vec<PathBuf>,
+    /// Trust and remember the key of hosts which are not in any of the known_hosts files
+    pub accept_new: bool,
+    /// Accept only the key with this fingerprint, ignoring the known_hosts files
+    pub pinned_fingerprint: Option<String>,
 }
 
 impl NixDaemon<UnixStream> {
-    pub fn local() -> Self {
+    /// Connects to the first existing socket out of `configured_socket`, `NIX_REMOTE` and the default location
+    pub fn local(configured_socket: Option<&Path>) -> Self {
+        let socket_candidates = local_socket_candidates(
+            configured_socket,
+            std::env::var("NIX_REMOTE").ok().as_deref(),
+        );
+        let address = socket_candidates
+            .iter()
+            .find(|(path, _)| path.exists())
+            .unwrap_or(&socket_candidates[0])
+            .0
+            .to_string_lossy()
+            .to_string();
         Self {
             daemon: None,
-            address: "/nix/var/nix/daemon-socket/socket".to_string(),
-            ssh_private_key_path: None,
+            address,
+            socket_candidates,
+            timeouts: DaemonTimeouts::default(),
+            ssh: None,
+            ssh_args: Vec::new(),
         }
     }
     pub async fn connect(&mut self) -> Result<()> {
-        let store = DaemonStore::builder().connect_unix(&self.address).await?;
+        if !Path::new(&self.address).exists() {
+            let tried = self
+                .socket_candidates
+                .iter()
+                .map(|(path, source)| format!("{} ({})", path.display(), source))
+                .collect::<Vec<_>>()
+                .join(", ");
+            bail!(
+                "Could not find the Nix daemon socket, tried: {}. Set store.daemon_socket or NIX_REMOTE to its location",
+                tried
+            );
+        }
+        let store = with_timeout(
+            "connect",
+            &self.get_address(),
+            self.timeouts.connect(),
+            DaemonStore::builder().connect_unix(&self.address),
+        )
+        .await??;
         self.daemon = Some(store);
         Ok(())
     }
 }
 impl NixDaemon<AsyncChannel<TokioTcpStream>> {
-    pub fn remote(address: &str, ssh_private_key_path: PathBuf) -> Self {
+    pub fn remote(address: &str, ssh: SshOptions) -> Self {
         Self {
             daemon: None,
             address: address.to_string(),
-            ssh_private_key_path: Some(ssh_private_key_path),
+            socket_candidates: Vec::new(),
+            timeouts: DaemonTimeouts::default(),
+            ssh: Some(ssh),
+            ssh_args: Vec::new(),
         }
     }
 
     pub async fn connect(&mut self) -> Result<()> {
-        let addr = (self.address.as_str(), 22)
+        let address = self.get_address();
+        with_timeout("connect", &address, self.timeouts.connect(), self.open()).await?
+    }
+
+    async fn open(&mut self) -> Result<()> {
+        // we can safely unwrap because all ssh Nix daemons are provided with ssh options
+        let ssh = self.ssh.as_ref().unwrap();
+        let addr = (self.address.as_str(), ssh.port)
             .to_socket_addrs()?
             .next()
             .ok_or(anyhow!("Failed to resolve address"))?;
         let stream = TokioTcpStream::connect(addr).await?;
         let mut session = AsyncSession::new(stream, None)?;
         session.handshake().await?;
-
-        // we can safely unwrap because all ssh Nix daemons are provided with a private key
-        let key_path = self.ssh_private_key_path.as_ref().unwrap();
-        // the default user name for accessing remote ssh stores
-        // as specified in https://nix.dev/manual/nix/2.22/package-management/ssh-substituter
-        let user = "nix-ssh";
+        verify_host_key(&session, &self.address, ssh.port, &ssh.host_key)?;
 
         session
-            .userauth_pubkey_file(&user, None, &key_path, None)
+            .userauth_pubkey_file(&ssh.user, None, &ssh.private_key_path, None)
             .await?;
         if !session.authenticated() {
             return Err(anyhow!("Could not authenticate to remote",));
@@ -76,127 +156,804 @@ impl NixDaemon<AsyncChannel<TokioTcpStream>> {
     }
 }
 
+impl NixDaemon<SshCommandStream> {
+    /// Speaks the daemon protocol over `ssh <host> nix-daemon --stdio`, so OpenSSH resolves
+    /// the host alias and options such as Port, User, IdentityFile and ProxyJump from ssh_config.
+    /// `ssh_args` are passed to ssh before the host, e.g. `-p 2222`.
+    pub fn ssh_command(host: &str, ssh_args: Vec<String>) -> Self {
+        Self {
+            daemon: None,
+            address: host.to_string(),
+            socket_candidates: Vec::new(),
+            timeouts: DaemonTimeouts::default(),
+            ssh: None,
+            ssh_args,
+        }
+    }
+
+    pub async fn connect(&mut self) -> Result<()> {
+        let address = self.get_address();
+        let mut command = tokio::process::Command::new("ssh");
+        command
+            // never wait for a password or passphrase prompt
+            .args(["-o", "BatchMode=yes"])
+            .args(&self.ssh_args)
+            .arg(&self.address)
+            .args(["nix-daemon", "--stdio"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true);
+        debug!("Connecting to Nix daemon via {:?}", command.as_std());
+        let mut child = command
+            .spawn()
+            .with_context(|| format!("Failed to run ssh to connect to {}", address))?;
+        let stream = SshCommandStream {
+            stdout: child.stdout.take().unwrap(),
+            stdin: child.stdin.take().unwrap(),
+            _child: child,
+        };
+        let store = with_timeout(
+            "connect",
+            &address,
+            self.timeouts.connect(),
+            DaemonStore::builder().init(stream),
+        )
+        .await?
+        .with_context(|| {
+            format!(
+                "Failed to start a Nix daemon session via ssh at {}",
+                address
+            )
+        })?;
+        self.daemon = Some(store);
+        Ok(())
+    }
+}
+
+/// The stdio of an ssh process running `nix-daemon --stdio` on the remote host.
+/// The process is killed when the stream is dropped.
+pub struct SshCommandStream {
+    stdout: ChildStdout,
+    stdin: ChildStdin,
+    _child: Child,
+}
+
+impl AsyncRead for SshCommandStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SshCommandStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stdin).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdin).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdin).poll_shutdown(cx)
+    }
+}
+
 impl<C: AsyncStream> NixDaemon<C> {
+    pub fn with_timeouts(mut self, timeouts: DaemonTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
     pub async fn get_pathinfo(&mut self, path: &NixPath) -> Result<Option<PathInfo>> {
+        let address = self.get_address();
         let Some(daemon) = &mut self.daemon else {
             bail!("Not connected to Nix Daemon")
         };
-        let path_info = daemon.query_pathinfo(path).result().await?;
+        let path_info = with_timeout(
+            "query path info",
+            &address,
+            self.timeouts.query(),
+            drain_progress(daemon.query_pathinfo(path), &address),
+        )
+        .await??;
         Ok(path_info)
     }
 
-    #[allow(dead_code)]
-    // This function could be used to trigger builds
     pub async fn build(&mut self, drv_paths: &[&NixPath]) -> Result<HashMap<String, BuildResult>> {
+        let address = self.get_address();
         let Some(daemon) = &mut self.daemon else {
             bail!("Not connected to Nix Daemon")
         };
-        daemon.set_options(ClientSettings {
-            try_fallback: true,
-            use_substitutes: false,
-            ..ClientSettings::default()
+        drain_progress(
+            daemon.set_options(ClientSettings {
+                try_fallback: true,
+                use_substitutes: false,
+                ..ClientSettings::default()
+            }),
+            &address,
+        )
+        .await?;
+        // Paths without a selected output build `out`
+        let out_drv_paths = drv_paths.iter().map(|p| {
+            format!(
+                "{}!{}",
+                p.without_output(),
+                p.output_name().unwrap_or("out")
+            )
         });
-        let out_drv_paths = drv_paths.iter().map(|p| format!("{}!out", p));
-        let result = daemon
-            .build_paths_with_results(out_drv_paths, BuildMode::Normal)
-            .result()
-            .await?;
+        let result = with_timeout(
+            "build",
+            &address,
+            self.timeouts.build(),
+            drain_progress(
+                daemon.build_paths_with_results(out_drv_paths, BuildMode::Normal),
+                &address,
+            ),
+        )
+        .await?
+        .map_err(|e| explain_untrusted(e.into(), &address, &self.client_user()))?;
         Ok(result)
     }
 
+    /// Makes the daemon fetch `store_path` from its substituters
+    pub async fn substitute(&mut self, store_path: &NixPath) -> Result<()> {
+        let address = self.get_address();
+        let Some(daemon) = &mut self.daemon else {
+            bail!("Not connected to Nix Daemon")
+        };
+        drain_progress(
+            daemon.set_options(ClientSettings {
+                use_substitutes: true,
+                ..ClientSettings::default()
+            }),
+            &address,
+        )
+        .await?;
+        with_timeout(
+            "substitute",
+            &address,
+            self.timeouts.build(),
+            drain_progress(daemon.ensure_path(store_path), &address),
+        )
+        .await?
+        .with_context(|| format!("Failed to substitute {}", store_path))?;
+        Ok(())
+    }
+
     pub async fn path_exists(&mut self, store_path: &NixPath) -> Result<bool> {
+        let address = self.get_address();
         let Some(daemon) = &mut self.daemon else {
             bail!("Not connected to Nix Daemon")
         };
-        let exists = daemon.is_valid_path(store_path).result().await?;
+        let exists = with_timeout(
+            "query path validity",
+            &address,
+            self.timeouts.query(),
+            drain_progress(daemon.is_valid_path(store_path), &address),
+        )
+        .await??;
         Ok(exists)
     }
 
+    /// Returns the subset of `store_paths` which are valid in the daemon's store in one round trip
+    pub async fn query_valid_paths(&mut self, store_paths: &[NixPath]) -> Result<Vec<NixPath>> {
+        let address = self.get_address();
+        let Some(daemon) = &mut self.daemon else {
+            bail!("Not connected to Nix Daemon")
+        };
+        // QueryValidPaths was introduced in protocol version 1.12
+        if (daemon.proto.major, daemon.proto.minor) < (1, 12) {
+            let mut valid_paths = Vec::new();
+            for store_path in store_paths {
+                if self.path_exists(store_path).await? {
+                    valid_paths.push(store_path.clone());
+                }
+            }
+            return Ok(valid_paths);
+        }
+        with_timeout(
+            "query valid paths",
+            &address,
+            self.timeouts.query(),
+            drain_progress(
+                daemon.query_valid_paths(store_paths.iter().map(|p| p.get_path()), false),
+                &address,
+            ),
+        )
+        .await??
+        .iter()
+        .map(|path| Ok(NixPath::new(path)?))
+        .collect()
+    }
+
+    /// Returns the store path whose hash part is `hash`, if the daemon's store has one
+    pub async fn query_path_from_hash_part(&mut self, hash: &str) -> Result<Option<NixPath>> {
+        if !NixPath::is_hash_part(hash) {
+            bail!("'{}' is not a valid store path hash", hash);
+        }
+        let address = self.get_address();
+        let Some(daemon) = &mut self.daemon else {
+            bail!("Not connected to Nix Daemon")
+        };
+        let path = with_timeout(
+            "query path from hash part",
+            &address,
+            self.timeouts.query(),
+            drain_progress(daemon.query_path_from_hash_part(hash), &address),
+        )
+        .await??;
+        // Older daemons signal an unknown hash with an empty path instead of no path
+        match path.as_deref() {
+            None | Some("") => Ok(None),
+            Some(path) => Ok(Some(NixPath::new(path)?)),
+        }
+    }
+
+    /// Returns the output paths of the derivation `drv_path` by output name
+    pub async fn derivation_outputs(
+        &mut self,
+        drv_path: &NixPath,
+    ) -> Result<HashMap<String, NixPath>> {
+        let address = self.get_address();
+        let Some(daemon) = &mut self.daemon else {
+            bail!("Not connected to Nix Daemon")
+        };
+        let outputs = with_timeout(
+            "query derivation outputs",
+            &address,
+            self.timeouts.query(),
+            drain_progress(daemon.query_derivation_output_map(drv_path), &address),
+        )
+        .await??;
+        outputs
+            .into_iter()
+            .map(|(name, path)| Ok((name, NixPath::new(&path)?)))
+            .collect()
+    }
+
+    pub async fn add_signatures(
+        &mut self,
+        store_path: &NixPath,
+        signatures: &[String],
+    ) -> Result<()> {
+        let address = self.get_address();
+        let client_user = self.client_user();
+        let Some(daemon) = &mut self.daemon else {
+            bail!("Not connected to Nix Daemon")
+        };
+        with_timeout(
+            "add signatures",
+            &address,
+            self.timeouts.query(),
+            drain_progress(daemon.add_signatures(store_path, signatures), &address),
+        )
+        .await?
+        .map_err(|e| explain_untrusted(e.into(), &address, &client_user))?;
+        Ok(())
+    }
+
+    /// Imports the NAR of the package described by `narinfo` into the daemon's store.
+    /// All references of the package must already be valid in the store.
+    pub async fn add_to_store_nar(
+        &mut self,
+        narinfo: &NarInfo,
+        nar: impl AsyncRead + Send + Unpin,
+        repair: bool,
+        check_sigs: bool,
+    ) -> Result<()> {
+        let dependencies: Vec<NixPath> = narinfo.get_dependencies().into_iter().cloned().collect();
+        let valid_dependencies = self.query_valid_paths(&dependencies).await?;
+        let missing: Vec<&str> = dependencies
+            .iter()
+            .filter(|d| !valid_dependencies.contains(d))
+            .map(|d| d.get_path())
+            .collect();
+        if !missing.is_empty() {
+            bail!(
+                "Cannot import {} because its references are not valid in the Nix store: {}",
+                narinfo.store_path,
+                missing.join(", ")
+            );
+        }
+
+        let nar_hash = narinfo
+            .nar_hash
+            .strip_prefix("sha256:")
+            .and_then(nix_base32::from_nix_base32)
+            .ok_or_else(|| anyhow!("Invalid NAR hash '{}'", narinfo.nar_hash))?;
+        // Fields which are not known from the narinfo (e.g. the registration time) keep their defaults
+        #[allow(clippy::needless_update)]
+        let path_info = PathInfo {
+            deriver: narinfo.deriver.as_ref().map(|d| d.get_path().to_string()),
+            references: narinfo
+                .references
+                .iter()
+                .map(|r| r.get_path().to_string())
+                .collect(),
+            nar_hash: hex::encode(nar_hash),
+            nar_size: narinfo.nar_size,
+            ultimate: false,
+            signatures: narinfo.signatures.clone(),
+            // lets the daemon accept content-addressed paths without a trusted signature
+            ca: narinfo.ca.clone(),
+            ..PathInfo::default()
+        };
+
+        let address = self.get_address();
+        let client_user = self.client_user();
+        let Some(daemon) = &mut self.daemon else {
+            bail!("Not connected to Nix Daemon")
+        };
+        // The daemon hashes and writes the NAR while receiving it, which takes about as long as
+        // building a large package
+        with_timeout(
+            "import",
+            &address,
+            self.timeouts.build(),
+            drain_progress(
+                daemon.add_to_store_nar(&path_info, &narinfo.store_path, nar, repair, !check_sigs),
+                &address,
+            ),
+        )
+        .await?
+        .map_err(|e| explain_untrusted(e.into(), &address, &client_user))
+        .with_context(|| {
+            format!(
+                "Failed to import {} into Nix daemon at {}",
+                narinfo.store_path, address
+            )
+        })?;
+        Ok(())
+    }
+
     pub async fn fetch<F, R>(&mut self, store_path: &NixPath, parser: F) -> Result<R>
     where
         R: Send + Sync + 'static,
         F: for<'a> FnOnce(&'a mut dyn Read) -> Result<R> + Send + Sync + 'static,
     {
+        let address = self.get_address();
+        let idle_timeout = self.timeouts.fetch_idle();
         let Some(daemon) = &mut self.daemon else {
             bail!("Not connected to Nix Daemon")
         };
 
-        let progress = daemon.nar_from_path(store_path, |reader| {
+        // Large paths may take arbitrarily long, so only a stalled transfer is considered a timeout.
+        // The NAR is received while the parser decodes what arrived before.
+        let progress = daemon.nar_from_path(store_path, move |reader| {
             Box::pin(async move {
-                tokio::task::block_in_place(|| {
-                    let sync_reader = SyncIoBridge::new(reader);
-                    let mut buf_reader = BufReader::new(sync_reader);
-                    let val = parser(&mut buf_reader)
-                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-                    Ok(val)
-                })
+                let reader = IdleTimeoutReader::new(reader, idle_timeout);
+                parse_pipelined(reader, parser)
+                    .await
+                    .map_err(io::Error::other)
             })
         });
 
-        let val = progress.result().await?;
+        let val = drain_progress(progress, &address).await.with_context(|| {
+            format!(
+                "Failed to fetch {} from Nix daemon at {}",
+                store_path, address
+            )
+        })?;
 
         Ok(val)
     }
     pub fn get_address(&self) -> String {
-        self.address.clone()
+        match &self.ssh {
+            Some(ssh) if self.address.contains(':') => {
+                format!("{}@[{}]:{}", ssh.user, self.address, ssh.port)
+            }
+            Some(ssh) => format!("{}@{}:{}", ssh.user, self.address, ssh.port),
+            None => self.address.clone(),
+        }
     }
 
-    pub fn disconnect(mut self) {
-        self.daemon = None;
+    /// The user the daemon sees us as
+    fn client_user(&self) -> String {
+        match &self.ssh {
+            Some(ssh) => ssh.user.clone(),
+            // only the local daemon has socket candidates, the others are reached through OpenSSH
+            None if self.socket_candidates.is_empty() => "the ssh user".to_string(),
+            None => std::env::var("USER").unwrap_or_else(|_| "the current user".to_string()),
+        }
     }
 }
 
+/// Orders the possible daemon socket locations by precedence.
+/// `NIX_REMOTE` is honored if it is `daemon`, empty or a `unix://` URI; other stores cannot be used as a daemon.
+fn local_socket_candidates(
+    configured_socket: Option<&Path>,
+    nix_remote: Option<&str>,
+) -> Vec<(PathBuf, &'static str)> {
+    let mut candidates = Vec::new();
+    if let Some(socket) = configured_socket {
+        candidates.push((socket.to_path_buf(), "store.daemon_socket"));
+    }
+    match nix_remote {
+        Some(uri) if uri.starts_with("unix://") => {
+            candidates.push((PathBuf::from(&uri["unix://".len()..]), "NIX_REMOTE"));
+        }
+        Some("" | "daemon") | None => {}
+        Some(other) => warn!(
+            "Ignoring NIX_REMOTE={}, which is not a daemon socket",
+            other
+        ),
+    }
+    let default = PathBuf::from(DEFAULT_DAEMON_SOCKET);
+    if !candidates.iter().any(|(path, _)| *path == default) {
+        candidates.push((default, "default"));
+    }
+    candidates
+}
+
+/// Fails unless the host key offered in `session` is pinned or listed in a known_hosts file
+fn verify_host_key<S>(
+    session: &AsyncSession<S>,
+    host: &str,
+    port: u16,
+    policy: &HostKeyPolicy,
+) -> Result<()>
+where
+    S: AsyncSessionStream + Send + Sync + 'static,
+{
+    let (key, key_type) = session
+        .host_key()
+        .ok_or_else(|| anyhow!("{} did not offer a host key", host))?;
+    let fingerprint = host_key_fingerprint(key);
+    if let Some(pinned) = &policy.pinned_fingerprint {
+        if *pinned != fingerprint {
+            bail!(
+                "Host key mismatch for {}: expected {}, but the server offered {}",
+                host,
+                pinned,
+                fingerprint
+            );
+        }
+        return Ok(());
+    }
+
+    let mut known_hosts = session.known_hosts()?;
+    for file in policy.known_hosts_files.iter().filter(|f| f.exists()) {
+        known_hosts
+            .read_file(file, KnownHostFileKind::OpenSSH)
+            .with_context(|| format!("Failed to read known hosts from {}", file.display()))?;
+    }
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => bail!(
+            "Host key mismatch for {}:{}: the server offered {}, which differs from the key in known_hosts. Someone could be intercepting the connection",
+            host,
+            port,
+            fingerprint
+        ),
+        CheckResult::NotFound if policy.accept_new => {
+            let Some(file) = policy.known_hosts_files.first() else {
+                bail!("No known_hosts file to add the host key of {} to", host);
+            };
+            // Only the target file is loaded, so entries of the other files are not copied into it
+            let mut target = session.known_hosts()?;
+            if file.exists() {
+                target.read_file(file, KnownHostFileKind::OpenSSH)?;
+            }
+            let name = if port == 22 {
+                host.to_string()
+            } else {
+                format!("[{host}]:{port}")
+            };
+            target.add(&name, key, "added by gachix", key_type.into())?;
+            target
+                .write_file(file, KnownHostFileKind::OpenSSH)
+                .with_context(|| format!("Failed to write known hosts to {}", file.display()))?;
+            warn!(
+                "Added host key {} of {} to {}",
+                fingerprint,
+                name,
+                file.display()
+            );
+            Ok(())
+        }
+        CheckResult::NotFound => bail!(
+            "Unknown host key for {}:{} with fingerprint {}. Add it to known_hosts, pin it with host_key_fingerprint or pass --accept-new-host-keys",
+            host,
+            port,
+            fingerprint
+        ),
+        CheckResult::Failure => bail!("Failed to check the host key of {}", host),
+    }
+}
+
+/// The fingerprint of a host key in the format of `ssh-keygen -l`
+fn host_key_fingerprint(key: &[u8]) -> String {
+    format!("SHA256:{}", STANDARD_NO_PAD.encode(Sha256::digest(key)))
+}
+
+/// Logs the messages the daemon sends while processing an operation and returns its result
+async fn drain_progress<P: Progress>(mut progress: P, address: &str) -> Result<P::T, P::Error> {
+    while let Some(message) = progress.next().await? {
+        log_daemon_message(address, message);
+    }
+    progress.result().await
+}
+
+fn log_daemon_message(address: &str, message: Stderr) {
+    match message {
+        Stderr::Next(line) => info!(daemon = address, "{}", line.trim_end()),
+        Stderr::Error(error) => warn!(daemon = address, "{}", error.msg),
+        Stderr::StartActivity(activity) if !activity.s.is_empty() => match activity.kind {
+            StderrActivityType::Build | StderrActivityType::Substitute => {
+                info!(daemon = address, "{}", activity.s)
+            }
+            _ => debug!(daemon = address, "{}", activity.s),
+        },
+        Stderr::Result(result) => match (result.kind, result.fields.as_slice()) {
+            (
+                StderrResultType::BuildLogLine | StderrResultType::PostBuildLogLine,
+                [StderrField::String(line), ..],
+            ) => info!(daemon = address, "{}", line),
+            (StderrResultType::SetPhase, [StderrField::String(phase), ..]) => {
+                info!(daemon = address, "Entering phase {}", phase)
+            }
+            (
+                StderrResultType::Progress,
+                [StderrField::Int(done), StderrField::Int(expected), ..],
+            ) => {
+                debug!(daemon = address, "Progress: {}/{}", done, expected)
+            }
+            _ => trace!(daemon = address, "{:?}", result),
+        },
+        other => trace!(daemon = address, "{:?}", other),
+    }
+}
+
+async fn with_timeout<T>(
+    operation: &str,
+    address: &str,
+    limit: Duration,
+    future: impl Future<Output = T>,
+) -> Result<T> {
+    tokio::time::timeout(limit, future).await.map_err(|_| {
+        anyhow!(
+            "Nix daemon operation '{}' at {} timed out after {}s",
+            operation,
+            address,
+            limit.as_secs()
+        )
+    })
+}
+
+/// Fails reads once the inner reader has not produced any bytes for `idle_timeout`.
+/// The number of bytes read so far is logged periodically.
+struct IdleTimeoutReader<R> {
+    inner: R,
+    idle_timeout: Duration,
+    deadline: Pin<Box<Sleep>>,
+    bytes_read: u64,
+}
+
+const LOG_PROGRESS_EVERY_BYTES: u64 = 64 * 1024 * 1024;
+
+impl<R> IdleTimeoutReader<R> {
+    fn new(inner: R, idle_timeout: Duration) -> Self {
+        Self {
+            inner,
+            idle_timeout,
+            deadline: Box::pin(tokio::time::sleep(idle_timeout)),
+            bytes_read: 0,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for IdleTimeoutReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                if buf.filled().len() > filled {
+                    let deadline = Instant::now() + self.idle_timeout;
+                    self.deadline.as_mut().reset(deadline);
+                    let previous = self.bytes_read;
+                    self.bytes_read += (buf.filled().len() - filled) as u64;
+                    if previous / LOG_PROGRESS_EVERY_BYTES
+                        != self.bytes_read / LOG_PROGRESS_EVERY_BYTES
+                    {
+                        debug!("Received {} bytes of NAR", self.bytes_read);
+                    }
+                }
+                Poll::Ready(result)
+            }
+            Poll::Pending => match self.deadline.as_mut().poll(cx) {
+                Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no data received for {}s", self.idle_timeout.as_secs()),
+                ))),
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+}
+
+/// Runs an operation on the inner daemon of a `DynNixDaemon` and, if the connection
+/// to the daemon dropped meanwhile, reconnects and retries it once
+macro_rules! retry_on_disconnect {
+    ($nix:expr, |$daemon:ident| $operation:expr) => {{
+        let result = match &mut *$nix {
+            DynNixDaemon::Local($daemon) => $operation.await,
+            DynNixDaemon::Remote($daemon) => $operation.await,
+            DynNixDaemon::OpenSsh($daemon) => $operation.await,
+        };
+        match result {
+            Err(e) if is_connection_error(&e) => {
+                warn!(
+                    "Lost connection to Nix daemon at {}, reconnecting: {}",
+                    $nix.get_address(),
+                    e
+                );
+                $nix.connect().await?;
+                match &mut *$nix {
+                    DynNixDaemon::Local($daemon) => $operation.await,
+                    DynNixDaemon::Remote($daemon) => $operation.await,
+                    DynNixDaemon::OpenSsh($daemon) => $operation.await,
+                }
+            }
+            result => result,
+        }
+    }};
+}
+
+/// Messages with which the Nix daemon rejects operations reserved to users in `trusted-users`
+const UNTRUSTED_MESSAGES: &[&str] = &[
+    "lacks a valid signature",
+    "you are not privileged",
+    "only allowed to trusted users",
+    "not a trusted user",
+];
+
+/// Adds an explanation of how to fix the failure if the daemon rejected `error`'s operation
+/// because `user` is not trusted
+fn explain_untrusted(error: anyhow::Error, address: &str, user: &str) -> anyhow::Error {
+    let message = format!("{error:#}");
+    let Some(reason) = UNTRUSTED_MESSAGES.iter().find(|m| message.contains(*m)) else {
+        return error;
+    };
+    let mut hint = format!(
+        "The Nix daemon at {} does not trust {} ({}). Add `trusted-users = {}` to nix.conf on that host and restart the daemon, or run gachix as a trusted user such as root",
+        address, user, reason, user
+    );
+    if *reason == "lacks a valid signature" {
+        hint.push_str(
+            ", or sign the packages with a key listed in `trusted-public-keys` (store.sign_key_paths)",
+        );
+    }
+    error.context(hint)
+}
+
+/// Whether `error` was caused by the connection to the daemon breaking down
+fn is_connection_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause.downcast_ref::<io::Error>().is_some_and(|e| {
+            matches!(
+                e.kind(),
+                io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::UnexpectedEof
+            )
+        })
+    })
+}
+
 pub enum DynNixDaemon {
     Local(NixDaemon<UnixStream>),
     Remote(NixDaemon<AsyncChannel<TokioTcpStream>>),
+    OpenSsh(NixDaemon<SshCommandStream>),
 }
 
 impl DynNixDaemon {
-    pub async fn connect(&mut self) -> Result<()> {
+    pub async fn add_to_store_nar(
+        &mut self,
+        narinfo: &NarInfo,
+        nar: impl AsyncRead + Send + Unpin,
+        repair: bool,
+        check_sigs: bool,
+    ) -> Result<()> {
         match self {
-            DynNixDaemon::Local(daemon) => daemon.connect().await,
-            DynNixDaemon::Remote(daemon) => daemon.connect().await,
+            DynNixDaemon::Local(daemon) => {
+                daemon
+                    .add_to_store_nar(narinfo, nar, repair, check_sigs)
+                    .await
+            }
+            DynNixDaemon::Remote(daemon) => {
+                daemon
+                    .add_to_store_nar(narinfo, nar, repair, check_sigs)
+                    .await
+            }
+            DynNixDaemon::OpenSsh(daemon) => {
+                daemon
+                    .add_to_store_nar(narinfo, nar, repair, check_sigs)
+                    .await
+            }
         }
     }
+}
 
-    pub async fn get_pathinfo(&mut self, path: &NixPath) -> Result<Option<PathInfo>> {
+impl NixBackend for DynNixDaemon {
+    async fn connect(&mut self) -> Result<()> {
         match self {
-            DynNixDaemon::Local(daemon) => daemon.get_pathinfo(path).await,
-            DynNixDaemon::Remote(daemon) => daemon.get_pathinfo(path).await,
+            DynNixDaemon::Local(daemon) => daemon.connect().await,
+            DynNixDaemon::Remote(daemon) => daemon.connect().await,
+            DynNixDaemon::OpenSsh(daemon) => daemon.connect().await,
         }
     }
 
-    pub async fn path_exists(&mut self, store_path: &NixPath) -> Result<bool> {
-        match self {
-            DynNixDaemon::Local(daemon) => daemon.path_exists(store_path).await,
-            DynNixDaemon::Remote(daemon) => daemon.path_exists(store_path).await,
-        }
+    async fn get_pathinfo(&mut self, path: &NixPath) -> Result<Option<PathInfo>> {
+        retry_on_disconnect!(self, |daemon| daemon.get_pathinfo(path))
     }
 
-    pub async fn fetch<F, R>(&mut self, store_path: &NixPath, parser: F) -> Result<R>
+    async fn path_exists(&mut self, store_path: &NixPath) -> Result<bool> {
+        retry_on_disconnect!(self, |daemon| daemon.path_exists(store_path))
+    }
+
+    async fn query_valid_paths(&mut self, store_paths: &[NixPath]) -> Result<Vec<NixPath>> {
+        retry_on_disconnect!(self, |daemon| daemon.query_valid_paths(store_paths))
+    }
+
+    async fn query_path_from_hash_part(&mut self, hash: &str) -> Result<Option<NixPath>> {
+        retry_on_disconnect!(self, |daemon| daemon.query_path_from_hash_part(hash))
+    }
+
+    async fn derivation_outputs(&mut self, drv_path: &NixPath) -> Result<HashMap<String, NixPath>> {
+        retry_on_disconnect!(self, |daemon| daemon.derivation_outputs(drv_path))
+    }
+
+    async fn add_signatures(&mut self, store_path: &NixPath, signatures: &[String]) -> Result<()> {
+        retry_on_disconnect!(self, |daemon| daemon.add_signatures(store_path, signatures))
+    }
+
+    /// A fetch whose connection dropped is retried from the beginning of the NAR.
+    /// The parser must therefore tolerate being run again, e.g. by only writing git objects and no refs.
+    async fn fetch<F, R>(&mut self, store_path: &NixPath, parser: F) -> Result<R>
     where
         R: Send + Sync + 'static,
-        F: for<'a> FnOnce(&'a mut dyn Read) -> Result<R> + Send + Sync + 'static,
+        F: for<'a> Fn(&'a mut dyn Read) -> Result<R> + Clone + Send + Sync + 'static,
     {
-        match self {
-            DynNixDaemon::Local(daemon) => daemon.fetch(store_path, parser).await,
-            DynNixDaemon::Remote(daemon) => daemon.fetch(store_path, parser).await,
-        }
+        retry_on_disconnect!(self, |daemon| daemon.fetch(store_path, parser.clone()))
     }
 
-    pub fn disconnect(self) {
+    async fn substitute(&mut self, store_path: &NixPath) -> Result<()> {
+        retry_on_disconnect!(self, |daemon| daemon.substitute(store_path))
+    }
+
+    async fn build(&mut self, drv_paths: &[&NixPath]) -> Result<HashMap<String, BuildResult>> {
         match self {
-            DynNixDaemon::Local(daemon) => daemon.disconnect(),
-            DynNixDaemon::Remote(daemon) => daemon.disconnect(),
+            DynNixDaemon::Local(daemon) => daemon.build(drv_paths).await,
+            DynNixDaemon::Remote(daemon) => daemon.build(drv_paths).await,
+            DynNixDaemon::OpenSsh(daemon) => daemon.build(drv_paths).await,
         }
     }
 
-    pub fn get_address(&self) -> String {
+    fn get_address(&self) -> String {
         match self {
             DynNixDaemon::Local(daemon) => daemon.get_address(),
             DynNixDaemon::Remote(daemon) => daemon.get_address(),
+            DynNixDaemon::OpenSsh(daemon) => daemon.get_address(),
         }
     }
+
+    fn is_local(&self) -> bool {
+        matches!(self, DynNixDaemon::Local(_))
+    }
 }
 
 #[cfg(test)]
@@ -206,11 +963,10 @@ mod tests {
     use nix_daemon::BuildResultStatus;
     use rand;
     use std::io::Write;
-    use std::process::Stdio;
 
     #[tokio::test]
     async fn test_local_build_package() -> Result<()> {
-        let mut nix = NixDaemon::local();
+        let mut nix = NixDaemon::local(None);
         nix.connect().await?;
         let drv_path = create_random_derivation().await?;
         let drv_path = NixPath::new(&drv_path)?;
@@ -226,6 +982,89 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_query_valid_paths_matches_individual_queries() -> Result<()> {
+        let mut nix = NixDaemon::local(None);
+        nix.connect().await?;
+        let drv_path = NixPath::new(&create_random_derivation().await?)?;
+        let missing_path =
+            NixPath::new("/nix/store/00000000000000000000000000000000-gachix-missing")?;
+        let paths = vec![drv_path, missing_path];
+
+        let valid_paths = nix.query_valid_paths(&paths).await?;
+        for path in &paths {
+            assert_eq!(
+                valid_paths.iter().any(|p| p.get_path() == path.get_path()),
+                nix.path_exists(path).await?,
+                "Batch result differs for {}",
+                path
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_local_socket_candidates() {
+        let configured = Path::new("/run/nix/socket");
+        let candidates = local_socket_candidates(Some(configured), Some("unix:///tmp/nix.sock"));
+        let paths: Vec<_> = candidates
+            .iter()
+            .map(|(p, _)| p.to_str().unwrap())
+            .collect();
+        assert_eq!(
+            paths,
+            ["/run/nix/socket", "/tmp/nix.sock", DEFAULT_DAEMON_SOCKET]
+        );
+
+        let candidates = local_socket_candidates(None, Some("daemon"));
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].1, "default");
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout_on_stalled_reader() -> Result<()> {
+        let (client, mut server) = tokio::io::duplex(64);
+        server.write_all(b"some bytes").await?;
+        let mut reader = IdleTimeoutReader::new(client, Duration::from_millis(50));
+
+        let mut buf = [0u8; 10];
+        reader.read_exact(&mut buf).await?;
+        let err = reader.read_u8().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        drop(server);
+        Ok(())
+    }
+
+    #[test]
+    fn test_host_key_fingerprint() {
+        assert_eq!(
+            host_key_fingerprint(b""),
+            "SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU"
+        );
+    }
+
+    #[test]
+    fn test_explain_untrusted() {
+        let error = anyhow!(
+            "path '/nix/store/5vnba43n1w87cs2i2dd242zy88k4dwf9-zlib-1.3.1' lacks a valid signature"
+        );
+        let explained = explain_untrusted(error, "/nix/var/nix/daemon-socket/socket", "alice");
+        assert!(explained.to_string().contains("trusted-users = alice"));
+        assert!(explained.to_string().contains("trusted-public-keys"));
+
+        let error = anyhow!("path is not valid");
+        let explained = explain_untrusted(error, "/nix/var/nix/daemon-socket/socket", "alice");
+        assert_eq!(explained.to_string(), "path is not valid");
+    }
+
+    #[test]
+    fn test_is_connection_error() {
+        let broken = anyhow::Error::new(io::Error::from(io::ErrorKind::BrokenPipe))
+            .context("Failed to fetch");
+        assert!(is_connection_error(&broken));
+        assert!(!is_connection_error(&anyhow!("Invalid NAR hash")));
+    }
+
     async fn create_random_derivation() -> Result<String> {
         let cookie = {
             use rand::distributions::{Alphanumeric, DistString};
@@ -0,0 +1,209 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::{Result, anyhow};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::debug;
+
+use crate::nix_interface::backend::NixBackend;
+use crate::nix_interface::daemon::DynNixDaemon;
+use crate::nix_interface::error::Error;
+use crate::settings::DaemonPoolSettings;
+
+type DaemonFactory<D> = dyn Fn() -> Result<D> + Send + Sync;
+
+/// A bounded set of connections to one Nix daemon, shared by all users of the store
+pub struct DaemonPool<D = DynNixDaemon> {
+    inner: Arc<PoolInner<D>>,
+}
+
+struct PoolInner<D> {
+    address: String,
+    factory: Box<DaemonFactory<D>>,
+    settings: DaemonPoolSettings,
+    permits: Arc<Semaphore>,
+    // Connections which are not in use, with the time they were returned
+    idle: Mutex<Vec<(D, Instant)>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PoolStats {
+    pub in_use: usize,
+    pub idle: usize,
+    pub max_connections: usize,
+}
+
+// Not derived, as that would require the daemon to be `Clone`
+impl<D> Clone for DaemonPool<D> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<D: NixBackend> DaemonPool<D> {
+    /// `factory` creates an unconnected daemon whenever the pool needs a new connection
+    pub fn new(
+        address: String,
+        settings: DaemonPoolSettings,
+        factory: impl Fn() -> Result<D> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            inner: Arc::new(PoolInner {
+                address,
+                factory: Box::new(factory),
+                permits: Arc::new(Semaphore::new(settings.max_connections)),
+                settings,
+                idle: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    pub fn get_address(&self) -> &str {
+        &self.inner.address
+    }
+
+    /// Waits for a free connection if all of them are in use.
+    /// Idle connections are reused if they still work, otherwise a new one is opened.
+    pub async fn get(&self) -> Result<PooledDaemon<D>, Error> {
+        let permit = tokio::time::timeout(
+            self.inner.settings.acquire_timeout(),
+            self.inner.permits.clone().acquire_owned(),
+        )
+        .await
+        .map_err(|_| {
+            Error::DaemonUnavailable(anyhow!(
+                "Timed out after {}s waiting for a free connection to the Nix daemon at {}",
+                self.inner.settings.acquire_timeout,
+                self.inner.address
+            ))
+        })?
+        .map_err(|e| Error::DaemonUnavailable(e.into()))?;
+        let stats = self.stats();
+        debug!(
+            "Nix daemon pool {}: {} of {} connections in use, {} idle",
+            self.inner.address, stats.in_use, stats.max_connections, stats.idle
+        );
+
+        while let Some(mut daemon) = self.inner.take_idle() {
+            match daemon.ping().await {
+                Ok(()) => return Ok(self.guard(daemon, permit)),
+                Err(e) => debug!(
+                    "Dropping broken connection to Nix daemon at {}: {}",
+                    self.inner.address, e
+                ),
+            }
+        }
+        let mut daemon = (self.inner.factory)().map_err(Error::DaemonUnavailable)?;
+        daemon.connect().await.map_err(Error::DaemonUnavailable)?;
+        Ok(self.guard(daemon, permit))
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let max_connections = self.inner.settings.max_connections;
+        PoolStats {
+            in_use: max_connections - self.inner.permits.available_permits(),
+            idle: self.inner.idle.lock().unwrap().len(),
+            max_connections,
+        }
+    }
+
+    fn guard(&self, daemon: D, permit: OwnedSemaphorePermit) -> PooledDaemon<D> {
+        PooledDaemon {
+            daemon: Some(daemon),
+            pool: self.inner.clone(),
+            _permit: permit,
+        }
+    }
+}
+
+impl<D> PoolInner<D> {
+    fn take_idle(&self) -> Option<D> {
+        let mut idle = self.idle.lock().unwrap();
+        self.reap(&mut idle);
+        idle.pop().map(|(daemon, _)| daemon)
+    }
+
+    /// Closes the connections which have been idle for longer than the idle timeout
+    fn reap(&self, idle: &mut Vec<(D, Instant)>) {
+        let idle_timeout = self.settings.idle_timeout();
+        idle.retain(|(_, since)| since.elapsed() < idle_timeout);
+    }
+}
+
+/// A connection borrowed from a `DaemonPool`, which is returned to the pool when dropped
+pub struct PooledDaemon<D = DynNixDaemon> {
+    daemon: Option<D>,
+    pool: Arc<PoolInner<D>>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl<D> Deref for PooledDaemon<D> {
+    type Target = D;
+
+    fn deref(&self) -> &D {
+        // only taken on drop
+        self.daemon.as_ref().unwrap()
+    }
+}
+
+impl<D> DerefMut for PooledDaemon<D> {
+    fn deref_mut(&mut self) -> &mut D {
+        self.daemon.as_mut().unwrap()
+    }
+}
+
+impl<D> Drop for PooledDaemon<D> {
+    fn drop(&mut self) {
+        if let Some(daemon) = self.daemon.take() {
+            let mut idle = self.pool.idle.lock().unwrap();
+            self.pool.reap(&mut idle);
+            idle.push((daemon, Instant::now()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nix_interface::daemon::NixDaemon;
+    use std::time::Duration;
+
+    fn unreachable_pool(settings: DaemonPoolSettings) -> DaemonPool {
+        DaemonPool::new("/nonexistent/socket".to_string(), settings, || {
+            Ok(DynNixDaemon::Local(NixDaemon::local(Some(
+                std::path::Path::new("/nonexistent/socket"),
+            ))))
+        })
+    }
+
+    #[tokio::test]
+    async fn test_failed_connect_releases_permit() {
+        let pool = unreachable_pool(DaemonPoolSettings {
+            max_connections: 1,
+            ..DaemonPoolSettings::default()
+        });
+        assert!(matches!(pool.get().await, Err(Error::DaemonUnavailable(_))));
+        assert!(pool.get().await.is_err());
+        assert_eq!(pool.stats().in_use, 0);
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_pool_times_out() {
+        let pool = unreachable_pool(DaemonPoolSettings {
+            max_connections: 1,
+            acquire_timeout: 0,
+            ..DaemonPoolSettings::default()
+        });
+        let _permit = pool.inner.permits.clone().acquire_owned().await.unwrap();
+        let err = tokio::time::timeout(Duration::from_secs(5), pool.get())
+            .await
+            .unwrap()
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("waiting for a free connection"));
+        assert_eq!(pool.stats().in_use, 1);
+    }
+}
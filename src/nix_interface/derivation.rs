@@ -0,0 +1,157 @@
+use anyhow::{Result, anyhow, bail};
+use std::collections::BTreeMap;
+use std::fs;
+
+use crate::nix_interface::path::NixPath;
+
+/// The parts of a store derivation (`.drv` file) needed to dispatch and collect builds
+#[derive(Debug, Clone, PartialEq)]
+pub struct Derivation {
+    /// Output name (e.g. `out`) to output path. The path is empty for content-addressed outputs.
+    pub outputs: BTreeMap<String, String>,
+    pub system: String,
+}
+
+impl Derivation {
+    pub fn from_store(drv_path: &NixPath) -> Result<Self> {
+        if !drv_path.is_derivation() {
+            bail!("{} is not a derivation", drv_path);
+        }
+        let content = fs::read_to_string(drv_path.get_path())
+            .map_err(|e| anyhow!("Could not read derivation {}: {}", drv_path, e))?;
+        Self::parse(&content)
+    }
+
+    /// Parses the ATerm format: `Derive([outputs],[inputDrvs],[inputSrcs],"system","builder",[args],[env])`
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut parser = ATermParser {
+            input: content.trim().as_bytes(),
+            pos: 0,
+        };
+        parser.expect(b"Derive")?;
+        let ATerm::Tuple(fields) = parser.parse_term()? else {
+            bail!("Derivation is not a tuple");
+        };
+        let [ATerm::List(outputs), _, _, ATerm::Str(system), ..] = fields.as_slice() else {
+            bail!("Derivation has unexpected fields");
+        };
+
+        let mut output_paths = BTreeMap::new();
+        for output in outputs {
+            let ATerm::Tuple(output) = output else {
+                bail!("Derivation output is not a tuple");
+            };
+            let [ATerm::Str(name), ATerm::Str(path), ..] = output.as_slice() else {
+                bail!("Derivation output has unexpected fields");
+            };
+            output_paths.insert(name.clone(), path.clone());
+        }
+        Ok(Self {
+            outputs: output_paths,
+            system: system.clone(),
+        })
+    }
+}
+
+enum ATerm {
+    Str(String),
+    List(Vec<ATerm>),
+    Tuple(Vec<ATerm>),
+}
+
+struct ATermParser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl ATermParser<'_> {
+    fn expect(&mut self, expected: &[u8]) -> Result<()> {
+        if !self.input[self.pos..].starts_with(expected) {
+            bail!(
+                "Expected '{}' at offset {} of derivation",
+                String::from_utf8_lossy(expected),
+                self.pos
+            );
+        }
+        self.pos += expected.len();
+        Ok(())
+    }
+
+    fn parse_term(&mut self) -> Result<ATerm> {
+        match self.input.get(self.pos) {
+            Some(b'"') => self.parse_string().map(ATerm::Str),
+            Some(b'[') => self.parse_sequence(b'[', b']').map(ATerm::List),
+            Some(b'(') => self.parse_sequence(b'(', b')').map(ATerm::Tuple),
+            _ => bail!("Unexpected token at offset {} of derivation", self.pos),
+        }
+    }
+
+    fn parse_sequence(&mut self, open: u8, close: u8) -> Result<Vec<ATerm>> {
+        self.expect(&[open])?;
+        let mut terms = Vec::new();
+        if self.input.get(self.pos) == Some(&close) {
+            self.pos += 1;
+            return Ok(terms);
+        }
+        loop {
+            terms.push(self.parse_term()?);
+            match self.input.get(self.pos) {
+                Some(b',') => self.pos += 1,
+                Some(c) if *c == close => {
+                    self.pos += 1;
+                    return Ok(terms);
+                }
+                _ => bail!("Unterminated sequence at offset {} of derivation", self.pos),
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String> {
+        self.expect(b"\"")?;
+        let mut bytes = Vec::new();
+        loop {
+            match self.input.get(self.pos) {
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(String::from_utf8(bytes)?);
+                }
+                Some(b'\\') => {
+                    let escaped = self
+                        .input
+                        .get(self.pos + 1)
+                        .ok_or_else(|| anyhow!("Unterminated escape in derivation"))?;
+                    bytes.push(match escaped {
+                        b'n' => b'\n',
+                        b'r' => b'\r',
+                        b't' => b'\t',
+                        c => *c,
+                    });
+                    self.pos += 2;
+                }
+                Some(c) => {
+                    bytes.push(*c);
+                    self.pos += 1;
+                }
+                None => bail!("Unterminated string in derivation"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_derivation() -> Result<()> {
+        let drv = r#"Derive([("dev","/nix/store/3bfrgv7ni6ai5q1xbkbi3d1v1hfz2zsz-zlib-1.3.1-dev","",""),("out","/nix/store/5vnba43n1w87cs2i2dd242zy88k4dwf9-zlib-1.3.1","","")],[("/nix/store/0hkbfkcz8y2n6ylhwl4jnnlgsd5z3y8x-bash-5.2p37.drv",["out"])],["/nix/store/v6x3cs394jgqfbi0a42pam708flxaphh-default-builder.sh"],"x86_64-linux","/nix/store/0irlcqx2n3qm6b1pc9rsd2i8qpvcccaj-bash-5.2p37/bin/bash",["-e","/nix/store/v6x3cs394jgqfbi0a42pam708flxaphh-default-builder.sh"],[("name","zlib-1.3.1"),("script","echo \"a\\b\"\n")])"#;
+        let derivation = Derivation::parse(drv)?;
+        assert_eq!(derivation.system, "x86_64-linux");
+        assert_eq!(derivation.outputs.len(), 2);
+        assert_eq!(
+            derivation.outputs["out"],
+            "/nix/store/5vnba43n1w87cs2i2dd242zy88k4dwf9-zlib-1.3.1"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,23 @@
+use thiserror::Error;
+
+/// Errors of the types which describe Nix store objects and of the connections to Nix daemons
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{0}")]
+    InvalidStorePath(String),
+    #[error("Invalid narinfo: {0}")]
+    InvalidNarInfo(String),
+    #[error("Invalid nix-cache-info: {0}")]
+    InvalidCacheInfo(String),
+    #[error("Invalid private key: {0}")]
+    InvalidPrivateKey(String),
+    #[error("Invalid public key: {0}")]
+    InvalidPublicKey(String),
+    #[error("Signing failed: {0}")]
+    SignerFailed(String),
+    /// No connection to the Nix daemon could be opened, the cause is part of the message
+    #[error("{0:#}")]
+    DaemonUnavailable(anyhow::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
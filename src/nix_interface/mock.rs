@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Result, anyhow, bail};
+use nix_daemon::{BuildResult, PathInfo};
+use sha2::{Digest, Sha256};
+
+use crate::nar::pipeline::parse_pipelined;
+use crate::nix_interface::backend::NixBackend;
+use crate::nix_interface::path::NixPath;
+
+/// An in-memory Nix store serving canned paths, to test the store without a Nix installation.
+/// Clones share their paths, so the same backend can be handed to a `DaemonPool` as factory.
+#[derive(Debug, Clone, Default)]
+pub struct MockNixBackend {
+    paths: Arc<Mutex<HashMap<String, MockPath>>>,
+}
+
+#[derive(Debug, Clone)]
+struct MockPath {
+    path: NixPath,
+    nar: Arc<Vec<u8>>,
+    references: Vec<NixPath>,
+    deriver: Option<NixPath>,
+    signatures: Vec<String>,
+    ca: Option<String>,
+}
+
+impl MockPath {
+    fn path_info(&self) -> PathInfo {
+        #[allow(clippy::needless_update)]
+        PathInfo {
+            deriver: self.deriver.as_ref().map(|d| d.get_path().to_string()),
+            references: self
+                .references
+                .iter()
+                .map(|r| r.get_path().to_string())
+                .collect(),
+            nar_hash: hex::encode(Sha256::digest(self.nar.as_slice())),
+            nar_size: self.nar.len() as u64,
+            ultimate: false,
+            signatures: self.signatures.clone(),
+            ca: self.ca.clone(),
+            ..PathInfo::default()
+        }
+    }
+
+    /// The name of the output `self` is of `drv_path`, `out` unless the store path has a suffix
+    fn output_name(&self, drv_path: &NixPath) -> String {
+        let drv_name = drv_path.get_name().trim_end_matches(".drv");
+        match self.path.get_name().strip_prefix(drv_name) {
+            Some(suffix) if suffix.starts_with('-') => suffix[1..].to_string(),
+            _ => "out".to_string(),
+        }
+    }
+}
+
+impl MockNixBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes `path` valid with the serialized `nar` as content
+    pub fn add_path(
+        &self,
+        path: &NixPath,
+        nar: Vec<u8>,
+        references: &[NixPath],
+        deriver: Option<&NixPath>,
+    ) {
+        self.paths.lock().unwrap().insert(
+            path.get_path().to_string(),
+            MockPath {
+                path: path.clone(),
+                nar: Arc::new(nar),
+                references: references.to_vec(),
+                deriver: deriver.cloned(),
+                signatures: Vec::new(),
+                ca: None,
+            },
+        );
+    }
+
+    /// Makes the valid `path` content-addressed by `ca`, e.g. `fixed:sha256:...`
+    pub fn set_ca(&self, path: &NixPath, ca: &str) {
+        if let Some(path) = self.paths.lock().unwrap().get_mut(path.get_path()) {
+            path.ca = Some(ca.to_string());
+        }
+    }
+
+    /// The signatures added to `path` through the backend
+    pub fn signatures(&self, path: &NixPath) -> Vec<String> {
+        self.get(path).map(|p| p.signatures).unwrap_or_default()
+    }
+
+    fn get(&self, path: &NixPath) -> Option<MockPath> {
+        self.paths.lock().unwrap().get(path.get_path()).cloned()
+    }
+}
+
+impl NixBackend for MockNixBackend {
+    async fn connect(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn get_pathinfo(&mut self, path: &NixPath) -> Result<Option<PathInfo>> {
+        Ok(self.get(path).map(|p| p.path_info()))
+    }
+
+    async fn path_exists(&mut self, store_path: &NixPath) -> Result<bool> {
+        Ok(self.get(store_path).is_some())
+    }
+
+    async fn query_valid_paths(&mut self, store_paths: &[NixPath]) -> Result<Vec<NixPath>> {
+        Ok(store_paths
+            .iter()
+            .filter(|path| self.get(path).is_some())
+            .cloned()
+            .collect())
+    }
+
+    async fn query_path_from_hash_part(&mut self, hash: &str) -> Result<Option<NixPath>> {
+        let paths = self.paths.lock().unwrap();
+        Ok(paths
+            .values()
+            .find(|p| p.path.get_base_32_hash() == hash)
+            .map(|p| p.path.clone()))
+    }
+
+    async fn derivation_outputs(&mut self, drv_path: &NixPath) -> Result<HashMap<String, NixPath>> {
+        let paths = self.paths.lock().unwrap();
+        Ok(paths
+            .values()
+            .filter(|p| p.deriver.as_ref() == Some(drv_path))
+            .map(|p| (p.output_name(drv_path), p.path.clone()))
+            .collect())
+    }
+
+    async fn add_signatures(&mut self, store_path: &NixPath, signatures: &[String]) -> Result<()> {
+        let mut paths = self.paths.lock().unwrap();
+        let path = paths
+            .get_mut(store_path.get_path())
+            .ok_or_else(|| anyhow!("{} is not valid", store_path))?;
+        path.signatures.extend(signatures.iter().cloned());
+        Ok(())
+    }
+
+    async fn fetch<F, R>(&mut self, store_path: &NixPath, parser: F) -> Result<R>
+    where
+        R: Send + Sync + 'static,
+        F: for<'a> Fn(&'a mut dyn Read) -> Result<R> + Clone + Send + Sync + 'static,
+    {
+        let Some(path) = self.get(store_path) else {
+            bail!("{} is not valid", store_path);
+        };
+        parse_pipelined(path.nar.as_slice(), parser).await
+    }
+
+    async fn substitute(&mut self, store_path: &NixPath) -> Result<()> {
+        bail!(
+            "The mock Nix backend has no substituters for {}",
+            store_path
+        )
+    }
+
+    async fn build(&mut self, drv_paths: &[&NixPath]) -> Result<HashMap<String, BuildResult>> {
+        bail!("The mock Nix backend cannot build {:?}", drv_paths)
+    }
+
+    fn get_address(&self) -> String {
+        "mock".to_string()
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+}
@@ -1,5 +1,19 @@
+pub mod backend;
 pub mod cache_info;
 pub mod daemon;
+pub mod daemon_pool;
+pub mod derivation;
+pub mod error;
+pub mod mock;
 pub mod nar_info;
 pub mod path;
 pub mod signature;
+pub mod signer;
+
+pub use backend::NixBackend;
+pub use cache_info::CacheInfo;
+pub use error::Error;
+pub use nar_info::NarInfo;
+pub use path::NixPath;
+pub use signature::{PrivateKey, PublicKey, SigStatus};
+pub use signer::NarSigner;
@@ -1,9 +1,16 @@
-use anyhow::Result;
 use std::{collections::HashMap, fmt::Display};
 
-use crate::nix_interface::path::NixPath;
+use base64::{Engine, prelude::BASE64_STANDARD};
 
-const KEYS: [&str; 10] = [
+use super::error::{Error, Result};
+use crate::nar::compression::Compression;
+use crate::nix_interface::path::{NIX_BASE32_CHARS, NixPath, StoreConfig};
+use crate::nix_interface::signature::{
+    self, PublicKey, SigStatus, fingerprint_store_object, fingerprint_unsorted,
+};
+
+// Keys with a field of their own, all others are kept in `NarInfo::extra`
+const KNOWN_KEYS: [&str; 12] = [
     "StorePath",
     "URL",
     "Compression",
@@ -13,162 +20,418 @@ const KEYS: [&str; 10] = [
     "NarSize",
     "References",
     "Deriver",
+    "System",
     "Sig",
+    "CA",
 ];
 
+// Of a SHA-256 hash in Nix base32
+const NAR_HASH_LEN: usize = 52;
+// Of an Ed25519 signature
+const SIGNATURE_LEN: usize = 64;
+
+/// A structural problem of a narinfo found by `NarInfo::validate`
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ValidationIssue {
+    #[error("NarHash '{0}' is not a base32 encoded sha256 hash")]
+    InvalidNarHash(String),
+    #[error("{0} is zero")]
+    ZeroSize(&'static str),
+    #[error("URL {url} does not match the compression {compression}")]
+    UrlCompressionMismatch { url: String, compression: String },
+    #[error("{0} is not in the store directory {1}")]
+    OutsideStoreDir(String, String),
+    #[error("Deriver {0} is not a derivation")]
+    InvalidDeriver(String),
+    #[error("Signature '{0}' is not a key name followed by a base64 encoded signature")]
+    InvalidSignature(String),
+}
+
 #[derive(Debug, Clone)]
 pub struct NarInfo {
     pub store_path: NixPath,
     pub key: String,
     pub url: Option<String>,
-    pub compression_type: Option<String>,
-    pub file_hash: String,
-    pub file_size: u64,
+    pub compression: Compression,
+    pub file_hash: Option<String>,
+    pub file_size: Option<u64>,
     pub nar_hash: String,
     pub nar_size: u64,
     pub references: Vec<NixPath>,
     pub deriver: Option<NixPath>,
-    pub signature: Option<String>,
+    /// The platform the package was built for, e.g. `x86_64-linux`
+    pub system: Option<String>,
+    pub signatures: Vec<String>,
+    /// The content address of the store path, e.g. `fixed:r:sha256:...`
+    pub ca: Option<String>,
+    /// Lines with keys gachix doesn't interpret, e.g. from newer Nix versions, in their order
+    pub extra: Vec<(String, String)>,
 }
 
 impl NarInfo {
-    pub fn new(
-        store_path: NixPath,
-        key: String,
-        file_hash: String,
-        file_size: u64,
-        compression_type: Option<String>,
-        nar_hash: String,
-        nar_size: u64,
-        deriver: Option<NixPath>,
-        references: Vec<NixPath>,
-        signature: Option<String>,
-    ) -> Self {
-        Self {
-            store_path: store_path,
-            key: key,
-            url: None,
-            compression_type: compression_type,
-            file_hash: file_hash,
-            file_size: file_size,
-            nar_hash: nar_hash,
-            nar_size: nar_size,
-            references: references,
-            deriver: deriver,
-            signature: signature,
-        }
+    pub fn builder() -> NarInfoBuilder {
+        NarInfoBuilder::default()
     }
 
+    /// Parses a narinfo as served by a binary cache. Only `StorePath`, `URL`, `NarHash` and
+    /// `NarSize` are required, keys which gachix doesn't know are kept in `extra`.
     pub fn parse(content: &str) -> Result<Self> {
-        let hashmap: HashMap<&str, &str> = content
-            .trim()
-            .lines()
-            .enumerate()
-            .map(|(line_num, line)| {
-                line.split_once(": ")
-                    .map(|(k, v)| Ok((k.trim(), v.trim())))
-                    .unwrap_or_else(|| {
-                        Err(anyhow::anyhow!(
-                            "invalid narinfo: line {line_num} does not contain 'key: value'. Instead found: '{line}'"
-                        ))
-                    })
-            })
-            .collect::<Result<_>>()?;
+        let mut fields: HashMap<&str, &str> = HashMap::new();
+        let mut signatures = Vec::new();
+        let mut extra = Vec::new();
+        // `lines` also strips the '\r' of CRLF line endings
+        for (line_num, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (k, v) = line
+                .split_once(':')
+                .map(|(k, v)| (k.trim(), v.trim()))
+                .ok_or_else(|| {
+                    Error::InvalidNarInfo(format!(
+                        "line {} does not contain 'key: value'. Instead found: '{line}'",
+                        line_num + 1
+                    ))
+                })?;
+            match k {
+                // the only key which may appear more than once
+                "Sig" if !v.is_empty() => signatures.push(v.to_string()),
+                "Sig" => {}
+                _ if KNOWN_KEYS.contains(&k) => {
+                    fields.insert(k, v);
+                }
+                _ => extra.push((k.to_string(), v.to_string())),
+            }
+        }
 
         let get = |k| {
-            hashmap
+            fields
                 .get(k)
                 .copied()
-                .ok_or_else(|| anyhow::anyhow!("Missing Narinfo key: {k}"))
+                .ok_or_else(|| Error::InvalidNarInfo(format!("missing key {k}")))
+        };
+        let get_optional = |k| fields.get(k).copied().filter(|v| !v.is_empty());
+        let parse_size = |k: &str, v: &str| {
+            v.parse::<u64>()
+                .map_err(|e| Error::InvalidNarInfo(format!("{k} is not a size: {e}")))
         };
 
-        let store_path_str = get("StorePath")?;
-        let deriver_str = get("Deriver")?;
-        let references_str = get("References")?;
         let url_str = get("URL")?;
-
+        let invalid_url = || Error::InvalidNarInfo("URL is not valid".to_string());
         let key = url_str
             .split("nar/")
             .last()
-            .ok_or_else(|| anyhow::anyhow!("Narinfo does not contain a valid URL"))?
+            .ok_or_else(invalid_url)?
             .split(".")
             .next()
-            .ok_or_else(|| anyhow::anyhow!("Narinfo does not contain a valid URL"))?
+            .ok_or_else(invalid_url)?
             .to_string();
 
-        let compression_type = match get("Compression")? {
-            "" => None,
-            s => Some(s.to_string()),
-        };
+        let deriver = get_optional("Deriver")
+            .map(NixPath::from_base_name)
+            .transpose()?;
 
-        let deriver = match deriver_str {
-            "" => None,
-            s => Some(NixPath::new(s)?),
-        };
-
-        let references = match references_str {
-            "" => Vec::new(),
-            r => r
-                .split(' ')
-                .map(NixPath::new)
-                .collect::<Result<Vec<NixPath>>>()?,
-        };
+        let references = get_optional("References")
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(NixPath::from_base_name)
+            .collect::<Result<Vec<NixPath>>>()?;
 
         Ok(Self {
-            store_path: NixPath::new(store_path_str)?,
+            store_path: NixPath::new(get("StorePath")?)?,
             key,
             url: Some(url_str.to_string()),
-            compression_type,
-            file_hash: get("FileHash")?.to_string(),
-            file_size: get("FileSize")?.parse::<u64>()?,
+            compression: Compression::from_name(get_optional("Compression").unwrap_or_default()),
+            file_hash: get_optional("FileHash").map(str::to_string),
+            file_size: get_optional("FileSize")
+                .map(|v| parse_size("FileSize", v))
+                .transpose()?,
             nar_hash: get("NarHash")?.to_string(),
-            nar_size: get("NarSize")?.parse::<u64>()?,
+            nar_size: parse_size("NarSize", get("NarSize")?)?,
             references,
             deriver,
-            signature: Some(get("Sig")?.to_string()),
+            system: get_optional("System").map(str::to_string),
+            signatures,
+            ca: get_optional("CA").map(str::to_string),
+            extra,
         })
     }
 
-    pub fn get_dependencies(&self) -> Vec<&NixPath> {
-        self.references
+    /// The data covered by the signatures
+    pub fn fingerprint(&self) -> String {
+        fingerprint_store_object(
+            &self.store_path,
+            &self.nar_hash,
+            self.nar_size,
+            &self.references,
+        )
+    }
+
+    /// Checks every signature against the trusted keys, in the order of `signatures`
+    pub fn verify_signatures(&self, trusted_keys: &[PublicKey]) -> Vec<SigStatus> {
+        let fingerprint = self.fingerprint();
+        let unsorted = fingerprint_unsorted(
+            &self.store_path,
+            &self.nar_hash,
+            self.nar_size,
+            &self.references,
+        );
+        self.signatures
             .iter()
+            .map(
+                |sig| match signature::verify(&fingerprint, sig, trusted_keys) {
+                    // narinfos signed before the references were sorted
+                    SigStatus::Invalid if unsorted != fingerprint => {
+                        signature::verify(&unsorted, sig, trusted_keys)
+                    }
+                    status => status,
+                },
+            )
+            .collect()
+    }
+
+    /// Whether one of the signatures is by a trusted key
+    pub fn has_valid_signature(&self, trusted_keys: &[PublicKey]) -> bool {
+        self.verify_signatures(trusted_keys)
+            .contains(&SigStatus::Valid)
+    }
+
+    /// Whether a client accepts the path. Like Nix, content-addressed paths are trusted
+    /// without a signature, since their store path is computed from the content.
+    pub fn is_trusted(&self, trusted_keys: &[PublicKey]) -> bool {
+        self.ca.is_some() || self.has_valid_signature(trusted_keys)
+    }
+
+    /// Checks the fields for problems which parsing doesn't catch, all problems are returned
+    pub fn validate(&self, config: &StoreConfig) -> Result<(), Vec<ValidationIssue>> {
+        let mut issues = Vec::new();
+        let hash_is_valid = self.nar_hash.strip_prefix("sha256:").is_some_and(|hash| {
+            hash.len() == NAR_HASH_LEN && hash.chars().all(|c| NIX_BASE32_CHARS.contains(c))
+        });
+        if !hash_is_valid {
+            issues.push(ValidationIssue::InvalidNarHash(self.nar_hash.clone()));
+        }
+        if self.nar_size == 0 {
+            issues.push(ValidationIssue::ZeroSize("NarSize"));
+        }
+        if self.file_size == Some(0) {
+            issues.push(ValidationIssue::ZeroSize("FileSize"));
+        }
+
+        let url = self.effective_url();
+        // there is no convention for unknown formats
+        if let Some(extension) = self.compression.extension()
+            && !url.ends_with(&format!(".nar{extension}"))
+        {
+            issues.push(ValidationIssue::UrlCompressionMismatch {
+                url,
+                compression: self.compression.to_string(),
+            });
+        }
+
+        let store_dir_prefix = format!("{}/", config.store_dir);
+        let paths = std::iter::once(&self.store_path)
+            .chain(&self.references)
+            .chain(&self.deriver);
+        for path in paths {
+            if !path.get_path().starts_with(&store_dir_prefix) {
+                issues.push(ValidationIssue::OutsideStoreDir(
+                    path.to_string(),
+                    config.store_dir.clone(),
+                ));
+            }
+        }
+        if let Some(deriver) = &self.deriver
+            && !deriver.is_derivation()
+        {
+            issues.push(ValidationIssue::InvalidDeriver(deriver.to_string()));
+        }
+
+        for sig in &self.signatures {
+            let is_plausible = sig.split_once(':').is_some_and(|(name, signature)| {
+                !name.is_empty()
+                    && BASE64_STANDARD
+                        .decode(signature)
+                        .is_ok_and(|s| s.len() == SIGNATURE_LEN)
+            });
+            if !is_plausible {
+                issues.push(ValidationIssue::InvalidSignature(sig.clone()));
+            }
+        }
+
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(issues)
+        }
+    }
+
+    /// The URL of the NAR, which is derived from the key and the compression unless it is set
+    fn effective_url(&self) -> String {
+        let extension = self.compression.extension().unwrap_or_default();
+        self.url
+            .clone()
+            .unwrap_or(format!("nar/{}.nar{}", self.key, extension))
+    }
+
+    /// The references sorted by path without repeats, in which order they are serialized
+    pub fn sorted_references(&self) -> Vec<&NixPath> {
+        let mut references: Vec<&NixPath> = self.references.iter().collect();
+        references.sort_unstable_by(|a, b| a.get_path().cmp(b.get_path()));
+        references.dedup();
+        references
+    }
+
+    /// The references other than the package itself, sorted by path. Their commits are the
+    /// parents of the package's commit in this order, so it is the same on every machine.
+    pub fn get_dependencies(&self) -> Vec<&NixPath> {
+        self.sorted_references()
+            .into_iter()
             .filter(|r| **r != self.store_path)
             .collect()
     }
 }
 
+/// Builds a `NarInfo`, of which the store path, key, NAR hash and NAR size are required
+#[derive(Debug, Default)]
+pub struct NarInfoBuilder {
+    store_path: Option<NixPath>,
+    key: Option<String>,
+    url: Option<String>,
+    compression: Compression,
+    file_hash: Option<String>,
+    file_size: Option<u64>,
+    nar_hash: Option<String>,
+    nar_size: Option<u64>,
+    references: Vec<NixPath>,
+    deriver: Option<NixPath>,
+    system: Option<String>,
+    signatures: Vec<String>,
+    ca: Option<String>,
+}
+
+impl NarInfoBuilder {
+    pub fn store_path(mut self, store_path: NixPath) -> Self {
+        self.store_path = Some(store_path);
+        self
+    }
+
+    /// The name of the NAR, the URL is `nar/<key>.nar` with the extension of the compression
+    /// unless it is set
+    pub fn key(mut self, key: impl Into<String>) -> Self {
+        self.key = Some(key.into());
+        self
+    }
+
+    pub fn url(mut self, url: impl Into<String>) -> Self {
+        self.url = Some(url.into());
+        self
+    }
+
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// The hash and size of the file at the URL, which differ from the NAR's if it is compressed
+    pub fn file(mut self, file_hash: impl Into<String>, file_size: u64) -> Self {
+        self.file_hash = Some(file_hash.into());
+        self.file_size = Some(file_size);
+        self
+    }
+
+    /// The hash in the form `sha256:<nix base32>` and the size of the NAR
+    pub fn nar(mut self, nar_hash: impl Into<String>, nar_size: u64) -> Self {
+        self.nar_hash = Some(nar_hash.into());
+        self.nar_size = Some(nar_size);
+        self
+    }
+
+    pub fn references(mut self, references: Vec<NixPath>) -> Self {
+        self.references = references;
+        self
+    }
+
+    pub fn deriver(mut self, deriver: Option<NixPath>) -> Self {
+        self.deriver = deriver;
+        self
+    }
+
+    pub fn system(mut self, system: impl Into<String>) -> Self {
+        self.system = Some(system.into());
+        self
+    }
+
+    pub fn signatures(mut self, signatures: Vec<String>) -> Self {
+        self.signatures = signatures;
+        self
+    }
+
+    pub fn ca(mut self, ca: Option<String>) -> Self {
+        self.ca = ca;
+        self
+    }
+
+    pub fn build(self) -> Result<NarInfo> {
+        let missing = |field: &str| Error::InvalidNarInfo(format!("missing {field}"));
+        Ok(NarInfo {
+            store_path: self.store_path.ok_or_else(|| missing("store path"))?,
+            key: self.key.ok_or_else(|| missing("key"))?,
+            url: self.url,
+            compression: self.compression,
+            file_hash: self.file_hash,
+            file_size: self.file_size,
+            nar_hash: self.nar_hash.ok_or_else(|| missing("NAR hash"))?,
+            nar_size: self.nar_size.ok_or_else(|| missing("NAR size"))?,
+            references: self.references,
+            deriver: self.deriver,
+            system: self.system,
+            signatures: self.signatures,
+            ca: self.ca,
+            extra: Vec::new(),
+        })
+    }
+}
+
 impl Display for NarInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let file_size_str = self.file_size.to_string();
-        let nar_size_str = self.nar_size.to_string();
-
         let references_str = self
-            .references
-            .iter()
-            .map(|s| format!("{}-{}", s.get_base_32_hash(), s.get_name()))
+            .sorted_references()
+            .into_iter()
+            .map(NixPath::get_base_name)
             .collect::<Vec<_>>()
             .join(" ");
 
-        let deriver = match &self.deriver {
-            Some(d) => format!("{}-{}", d.get_base_32_hash(), d.get_name()),
-            None => "".to_string(),
-        };
-
-        let url = self.url.clone().unwrap_or(format!("nar/{}.nar", self.key));
-        let values = [
-            self.store_path.get_path(),
-            url.as_str(),
-            self.compression_type.as_deref().unwrap_or("none"),
-            self.file_hash.as_str(),
-            file_size_str.as_str(),
-            self.nar_hash.as_str(),
-            nar_size_str.as_str(),
-            references_str.as_str(),
-            &deriver,
-            self.signature.as_deref().unwrap_or(""),
+        let url = self.effective_url();
+        // absent optional fields are left out instead of written with an empty value
+        let lines = [
+            ("StorePath", Some(self.store_path.get_path().to_string())),
+            ("URL", Some(url)),
+            ("Compression", Some(self.compression.to_string())),
+            ("FileHash", self.file_hash.clone()),
+            ("FileSize", self.file_size.map(|s| s.to_string())),
+            ("NarHash", Some(self.nar_hash.clone())),
+            ("NarSize", Some(self.nar_size.to_string())),
+            ("References", Some(references_str)),
+            (
+                "Deriver",
+                self.deriver.as_ref().map(|d| d.get_base_name().to_string()),
+            ),
+            ("System", self.system.clone()),
         ];
 
-        for (key, value) in KEYS.iter().zip(values) {
+        for (key, value) in lines {
+            if let Some(value) = value {
+                write!(f, "{}: {}\n", key, value)?;
+            }
+        }
+        for signature in &self.signatures {
+            write!(f, "Sig: {}\n", signature)?;
+        }
+        if let Some(ca) = &self.ca {
+            write!(f, "CA: {}\n", ca)?;
+        }
+        for (key, value) in &self.extra {
             write!(f, "{}: {}\n", key, value)?;
         }
         Ok(())
@@ -179,10 +442,14 @@ impl Display for NarInfo {
 mod tests {
 
     use super::*;
+    use crate::nix_interface::signature::PrivateKey;
+    use std::str::FromStr;
 
-    #[test]
-    fn test_parse_narinfo() -> Result<()> {
-        let content = r#"
+    const CACHE_NIXOS_ORG_KEY: &str =
+        "cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=";
+
+    // Signed by cache.nixos.org
+    const KITTY_NARINFO: &str = r#"
 StorePath: /nix/store/iylhaki6573cpsvspivjfsim700n46r3-kitty-0.43.1
 URL: nar/0lfjpl49j2na01l1zdmyszxz5wr957kl5qxn278alyv0fvxh2lab.nar.xz
 Compression: xz
@@ -193,9 +460,399 @@ NarSize: 63152768
 References: 3m5cgk18mw6lrlbdawc71rlx0sqw6z8i-imagemagick-7.1.2-5 49c4bxmqq5y53y38v7amdcs05d061wvr-tzdata-2025b 5vnba43n1w87cs2i2dd242zy88k4dwf9-zlib-1.3.1 8v0n10sz2rlh6iz2vc95haryx2dvgs1y-harfbuzz-11.2.1 b9crq3qpr3wnma88kwlx4jp1kly45v91-iana-etc-20250505 bsnylm1xz0d3350lzij8yw26wr0qywg0-ncurses-6.5-dev hxmkygn2zl0f2w9kbixmm50lsy60zya0-openssl-3.5.2 iylhaki6573cpsvspivjfsim700n46r3-kitty-0.43.1 j4ik7djz2f6pwavxp2j91615fy9p93j9-lcms2-2.17 k5c8pi2sfycps2ig2z4flh0yr95f79s6-kitty-0.43.1-terminfo n50rq66j0a9dfmw38yd6nwkca9fhb55p-mailcap-2.1.54 ncn2lbkihg40bnihakgxancwsrs39xch-libpng-apng-1.6.50 nrz6nhv4vpa1j0dlyydjxf23gawfl9xy-xxHash-0.8.3 xjpv7j44jn7mifw8r69p7shrsh1aqmnf-python3-3.13.7
 Deriver: sm4iyczmq406d83inf5s1ynr5h5h4sym-kitty-0.43.1.drv
 Sig: cache.nixos.org-1:NqjenY5yhRXNsUTUHwR9Io9xoD8B2XIUJQJFt6gBl9ik55Rcnj7wdHV1L8YTk4MtO4PEabpfdckXRpVgPh4jDg==
-        "#;
+"#;
+
+    #[test]
+    fn test_parse_narinfo() -> Result<()> {
+        let content = KITTY_NARINFO;
         let narinfo = NarInfo::parse(content)?;
         assert_eq!(content.trim(), narinfo.to_string().trim());
+        assert_eq!(
+            narinfo.references[2].get_path(),
+            "/nix/store/5vnba43n1w87cs2i2dd242zy88k4dwf9-zlib-1.3.1"
+        );
+        assert_eq!(
+            narinfo.get_dependencies().len(),
+            narinfo.references.len() - 1
+        );
+        Ok(())
+    }
+
+    // Without the optional Deriver, System and Sig lines
+    const UNSIGNED_NARINFO: &str = r#"
+StorePath: /nix/store/5vnba43n1w87cs2i2dd242zy88k4dwf9-zlib-1.3.1
+URL: nar/1l29f8r5q2739wnq4i7m2v545qx77b3wrdsw9xz2ajiy3hv1al8b.nar
+Compression: none
+FileHash: sha256:1l29f8r5q2739wnq4i7m2v545qx77b3wrdsw9xz2ajiy3hv1al8b
+FileSize: 42
+NarHash: sha256:1l29f8r5q2739wnq4i7m2v545qx77b3wrdsw9xz2ajiy3hv1al8b
+NarSize: 42
+References: 
+"#;
+
+    #[test]
+    fn test_round_trip_optional_fields() -> Result<()> {
+        let narinfo = NarInfo::parse(UNSIGNED_NARINFO)?;
+        assert!(narinfo.deriver.is_none() && narinfo.system.is_none());
+        assert!(narinfo.signatures.is_empty());
+        assert_eq!(UNSIGNED_NARINFO.trim_start(), narinfo.to_string());
+
+        let with_system = format!(
+            "{}Deriver: sm4iyczmq406d83inf5s1ynr5h5h4sym-zlib-1.3.1.drv\nSystem: x86_64-linux\n",
+            UNSIGNED_NARINFO.trim_start()
+        );
+        let narinfo = NarInfo::parse(&with_system)?;
+        assert_eq!(narinfo.system.as_deref(), Some("x86_64-linux"));
+        assert_eq!(with_system, narinfo.to_string());
+        Ok(())
+    }
+
+    // A fixed-output path as served by newer caches, with a CA line and without a deriver
+    const FIXED_OUTPUT_NARINFO: &str = r#"
+StorePath: /nix/store/0rbiz7h5rppx3sv3bisk1ypx2hrx0w58-source.tar.gz
+URL: nar/1r6j6nj7x8pnqrvlnggqcg64z0vjvwpwncx2wcpfm0vw9gvqsqx3.nar.zst
+Compression: zstd
+FileHash: sha256:1r6j6nj7x8pnqrvlnggqcg64z0vjvwpwncx2wcpfm0vw9gvqsqx3
+FileSize: 1822
+NarHash: sha256:0xcqhcsfvjcbhiq2vjbx8yhz29mhqrjbqv2yj4fg1b1lxkp5cw9n
+NarSize: 2032
+References: 
+CA: fixed:sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73
+IndexedAt: 2025-09-14
+"#;
+
+    #[test]
+    fn test_parse_tolerates_variants() -> Result<()> {
+        let crlf = FIXED_OUTPUT_NARINFO.replace('\n', "\r\n\r\n");
+        let narinfo = NarInfo::parse(&crlf)?;
+        assert!(narinfo.deriver.is_none() && narinfo.signatures.is_empty());
+        assert_eq!(
+            narinfo.extra,
+            vec![("IndexedAt".to_string(), "2025-09-14".to_string())]
+        );
+        assert_eq!(FIXED_OUTPUT_NARINFO.trim_start(), narinfo.to_string());
+
+        // only StorePath, URL, NarHash and NarSize are required
+        let minimal = "StorePath: /nix/store/0rbiz7h5rppx3sv3bisk1ypx2hrx0w58-source.tar.gz\n\
+                       URL: nar/1r6j6nj7x8pnqrvlnggqcg64z0vjvwpwncx2wcpfm0vw9gvqsqx3.nar\n\
+                       NarHash: sha256:0xcqhcsfvjcbhiq2vjbx8yhz29mhqrjbqv2yj4fg1b1lxkp5cw9n\n\
+                       NarSize: 2032\n";
+        let narinfo = NarInfo::parse(minimal)?;
+        assert!(narinfo.file_hash.is_none() && narinfo.file_size.is_none());
+        assert!(narinfo.references.is_empty());
+        assert!(!narinfo.to_string().contains("FileHash"));
+        for required in ["StorePath", "URL", "NarHash", "NarSize"] {
+            let missing: String = minimal
+                .lines()
+                .filter(|l| !l.starts_with(required))
+                .map(|l| format!("{l}\n"))
+                .collect();
+            assert!(matches!(
+                NarInfo::parse(&missing),
+                Err(Error::InvalidNarInfo(message)) if message == format!("missing key {required}")
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_compression_spellings() -> Result<()> {
+        // written by older versions of gachix, or left out by other caches
+        let empty = UNSIGNED_NARINFO.replace("Compression: none", "Compression: ");
+        let absent = UNSIGNED_NARINFO.replace("Compression: none\n", "");
+        for content in [UNSIGNED_NARINFO, empty.as_str(), absent.as_str()] {
+            let narinfo = NarInfo::parse(content)?;
+            assert_eq!(narinfo.compression, Compression::None);
+            assert_eq!(narinfo.to_string(), UNSIGNED_NARINFO.trim_start());
+        }
+
+        let narinfo = NarInfo::parse(&UNSIGNED_NARINFO.replace("none", "br"))?;
+        assert_eq!(narinfo.compression, Compression::Unknown("br".to_string()));
+        assert!(narinfo.to_string().contains("Compression: br\n"));
+
+        let narinfo = NarInfo::builder()
+            .store_path(narinfo.store_path)
+            .key("somekey")
+            .compression(Compression::Xz)
+            .nar(narinfo.nar_hash, narinfo.nar_size)
+            .build()?;
+        assert!(narinfo.to_string().contains("URL: nar/somekey.nar.xz\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_content_addressed() -> Result<()> {
+        let narinfo = NarInfo::parse(FIXED_OUTPUT_NARINFO)?;
+        assert_eq!(
+            narinfo.ca.as_deref(),
+            Some("fixed:sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73")
+        );
+        assert!(narinfo.is_trusted(&[]));
+
+        // the CA line follows the signatures, as written by Nix
+        let mut signed = narinfo.clone();
+        signed
+            .signatures
+            .push(format!("cache.example.org-1:{}", "A".repeat(86) + "=="));
+        let serialized = signed.to_string();
+        assert!(serialized.find("Sig: ").unwrap() < serialized.find("CA: ").unwrap());
+        assert_eq!(NarInfo::parse(&serialized)?.ca, narinfo.ca);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sorted_references() -> anyhow::Result<()> {
+        let trusted_keys = [PublicKey::from_str(CACHE_NIXOS_ORG_KEY)?];
+        let mut narinfo = NarInfo::parse(KITTY_NARINFO)?;
+        let fingerprint = narinfo.fingerprint();
+        narinfo.references.reverse();
+        narinfo.references.push(narinfo.references[3].clone());
+
+        assert_eq!(KITTY_NARINFO.trim_start(), narinfo.to_string());
+        assert_eq!(narinfo.fingerprint(), fingerprint);
+        assert!(narinfo.has_valid_signature(&trusted_keys));
+
+        // stored by older versions of gachix, which signed the references in the stored order
+        let key = PrivateKey::generate("cache.example.org-1")?;
+        let unsorted = fingerprint_unsorted(
+            &narinfo.store_path,
+            &narinfo.nar_hash,
+            narinfo.nar_size,
+            &narinfo.references,
+        );
+        let references: Vec<_> = narinfo
+            .references
+            .iter()
+            .map(NixPath::get_base_name)
+            .collect();
+        let stored: String = KITTY_NARINFO
+            .lines()
+            .map(|line| match line.split_once(": ") {
+                Some(("References", _)) => format!("References: {}\n", references.join(" ")),
+                Some(("Sig", _)) => format!(
+                    "Sig: cache.example.org-1:{}\n",
+                    BASE64_STANDARD.encode(key.sign(&unsorted))
+                ),
+                _ => format!("{line}\n"),
+            })
+            .collect();
+        assert_eq!(
+            NarInfo::parse(&stored)?.verify_signatures(&[key.public_key()]),
+            vec![SigStatus::Valid]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_fingerprint_matches_nix() -> anyhow::Result<()> {
+        let narinfo = NarInfo::parse(KITTY_NARINFO)?;
+        assert!(
+            narinfo
+                .fingerprint()
+                .ends_with(",/nix/store/xjpv7j44jn7mifw8r69p7shrsh1aqmnf-python3-3.13.7")
+        );
+        let trusted_keys = [PublicKey::from_str(CACHE_NIXOS_ORG_KEY)?];
+        assert_eq!(
+            narinfo.verify_signatures(&trusted_keys),
+            vec![SigStatus::Valid]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reject_signatures() -> anyhow::Result<()> {
+        let trusted_keys = [PublicKey::from_str(CACHE_NIXOS_ORG_KEY)?];
+        let narinfo = NarInfo::parse(KITTY_NARINFO)?;
+
+        let mut altered = narinfo.clone();
+        altered.nar_hash = altered.file_hash.clone().unwrap();
+        assert_eq!(
+            altered.verify_signatures(&trusted_keys),
+            vec![SigStatus::Invalid]
+        );
+
+        let mut altered = narinfo.clone();
+        altered.references.pop();
+        assert_eq!(
+            altered.verify_signatures(&trusted_keys),
+            vec![SigStatus::Invalid]
+        );
+
+        // a valid signature by a key which is not trusted
+        let untrusted_key = PrivateKey::generate("cache.example.org-1")?;
+        let mut resigned = narinfo.clone();
+        resigned.signatures.push(format!(
+            "cache.example.org-1:{}",
+            BASE64_STANDARD.encode(untrusted_key.sign(narinfo.fingerprint()))
+        ));
+        assert_eq!(
+            resigned.verify_signatures(&trusted_keys),
+            vec![SigStatus::Valid, SigStatus::UnknownKey]
+        );
+        assert_eq!(
+            NarInfo::parse(&resigned.to_string())?.signatures,
+            resigned.signatures
+        );
+        assert!(resigned.has_valid_signature(&[untrusted_key.public_key()]));
+
+        // a key which is trusted by name only
+        let impostor = PrivateKey::generate("cache.nixos.org-1")?;
+        assert_eq!(
+            narinfo.verify_signatures(&[impostor.public_key()]),
+            vec![SigStatus::Invalid]
+        );
+        assert!(!narinfo.has_valid_signature(&[]));
+        assert!(!narinfo.is_trusted(&[]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate() -> Result<()> {
+        let config = StoreConfig {
+            store_dir: "/nix/store".to_string(),
+        };
+        for content in [KITTY_NARINFO, UNSIGNED_NARINFO, FIXED_OUTPUT_NARINFO] {
+            assert_eq!(NarInfo::parse(content)?.validate(&config), Ok(()));
+        }
+
+        let narinfo = NarInfo::parse(KITTY_NARINFO)?;
+        let issues = |change: fn(&mut NarInfo)| {
+            let mut narinfo = narinfo.clone();
+            change(&mut narinfo);
+            narinfo.validate(&config).unwrap_err()
+        };
+        assert_eq!(
+            issues(|n| n.nar_hash = "sha256:163xjwsv9c433ivkycx26g7y".to_string()),
+            vec![ValidationIssue::InvalidNarHash(
+                "sha256:163xjwsv9c433ivkycx26g7y".to_string()
+            )]
+        );
+        assert_eq!(
+            issues(|n| n.nar_hash = n.nar_hash.replace("sha256:", "sha512:")),
+            vec![ValidationIssue::InvalidNarHash(
+                "sha512:163xjwsv9c433ivkycx26g7yb7ig2zq6h1vnmk9faah7qiqb4app".to_string()
+            )]
+        );
+        assert_eq!(
+            issues(|n| n.nar_size = 0),
+            vec![ValidationIssue::ZeroSize("NarSize")]
+        );
+        assert_eq!(
+            issues(|n| n.file_size = Some(0)),
+            vec![ValidationIssue::ZeroSize("FileSize")]
+        );
+        assert_eq!(
+            issues(|n| n.compression = Compression::Zstd),
+            vec![ValidationIssue::UrlCompressionMismatch {
+                url: "nar/0lfjpl49j2na01l1zdmyszxz5wr957kl5qxn278alyv0fvxh2lab.nar.xz".to_string(),
+                compression: "zstd".to_string(),
+            }]
+        );
+        assert_eq!(
+            issues(|n| n.deriver = Some(n.store_path.clone())),
+            vec![ValidationIssue::InvalidDeriver(
+                "/nix/store/iylhaki6573cpsvspivjfsim700n46r3-kitty-0.43.1".to_string()
+            )]
+        );
+        assert_eq!(
+            issues(|n| n.signatures = vec!["cache.nixos.org-1:c2hvcnQ=".to_string()]),
+            vec![ValidationIssue::InvalidSignature(
+                "cache.nixos.org-1:c2hvcnQ=".to_string()
+            )]
+        );
+        assert_eq!(
+            issues(|n| n.signatures[0] = n.signatures[0].replace("cache.nixos.org-1:", "")),
+            vec![ValidationIssue::InvalidSignature(
+                narinfo.signatures[0].replace("cache.nixos.org-1:", "")
+            )]
+        );
+
+        // the store path, all references and the deriver
+        let other_store = StoreConfig {
+            store_dir: "/gnu/store".to_string(),
+        };
+        let issues = narinfo.validate(&other_store).unwrap_err();
+        assert_eq!(issues.len(), 1 + narinfo.references.len() + 1);
+        assert_eq!(
+            issues[0],
+            ValidationIssue::OutsideStoreDir(
+                narinfo.store_path.to_string(),
+                "/gnu/store".to_string()
+            )
+        );
         Ok(())
     }
+
+    #[test]
+    fn test_builder() -> Result<()> {
+        let parsed = NarInfo::parse(KITTY_NARINFO)?;
+        let built = NarInfo::builder()
+            .store_path(parsed.store_path.clone())
+            .key(parsed.key.clone())
+            .url(parsed.url.clone().unwrap())
+            .compression(Compression::Xz)
+            .file(parsed.file_hash.clone().unwrap(), 18391180)
+            .nar(parsed.nar_hash.clone(), parsed.nar_size)
+            .references(parsed.references.clone())
+            .deriver(parsed.deriver.clone())
+            .signatures(parsed.signatures.clone())
+            .build()?;
+        assert_eq!(built.to_string(), parsed.to_string());
+
+        // without a URL, the uncompressed NAR is named by the key
+        let built = NarInfo::builder()
+            .store_path(parsed.store_path.clone())
+            .key("somekey")
+            .nar(parsed.nar_hash.clone(), parsed.nar_size)
+            .system("x86_64-linux")
+            .build()?;
+        assert!(built.to_string().contains("URL: nar/somekey.nar\n"));
+        assert_eq!(built.system.as_deref(), Some("x86_64-linux"));
+        assert!(built.file_hash.is_none() && built.references.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_builder_requires_fields() {
+        let store_path =
+            NixPath::new("/nix/store/iylhaki6573cpsvspivjfsim700n46r3-kitty-0.43.1").unwrap();
+        let error = NarInfo::builder()
+            .store_path(store_path.clone())
+            .key("somekey")
+            .build()
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Invalid narinfo: missing NAR hash");
+        let error = NarInfo::builder()
+            .key("somekey")
+            .nar(
+                "sha256:163xjwsv9c433ivkycx26g7yb7ig2zq6h1vnmk9faah7qiqb4app",
+                1,
+            )
+            .build()
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Invalid narinfo: missing store path");
+        assert!(
+            NarInfo::builder()
+                .store_path(store_path)
+                .nar(
+                    "sha256:163xjwsv9c433ivkycx26g7yb7ig2zq6h1vnmk9faah7qiqb4app",
+                    1
+                )
+                .build()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_reject_invalid_narinfos() {
+        let content = "StorePath: /nix/store/iylhaki6573cpsvspivjfsim700n46r3-kitty-0.43.1\nURL: nar/0lfjpl49.nar";
+        assert!(matches!(
+            NarInfo::parse(content),
+            Err(Error::InvalidNarInfo(message)) if message.contains("missing key")
+        ));
+        assert!(matches!(
+            NarInfo::parse("StorePath /nix/store/iylhaki6573cpsvspivjfsim700n46r3-kitty-0.43.1"),
+            Err(Error::InvalidNarInfo(_))
+        ));
+    }
 }
@@ -1,46 +1,164 @@
-use anyhow::{Result, anyhow};
+use super::error::{Error, Result};
+use std::sync::OnceLock;
 use std::{fmt::Display, path::Path};
 
+/// A top-level path in the Nix store, e.g. `/nix/store/<hash>-hello-2.12.2`
 #[derive(Debug, Clone)]
 pub struct NixPath {
     path: String,
     hash: String,
     name: String,
+    // The part of the path it was created from below the store path, e.g. `bin/hello`
+    subpath: Option<String>,
+    // The output selected from a derivation as in `<drv path>!out`
+    output: Option<String>,
+}
+
+// Nix uses its own base32 alphabet, which omits the letters e, o, u and t
+pub(crate) const NIX_BASE32_CHARS: &str = "0123456789abcdfghijklmnpqrsvwxyz";
+const HASH_PART_LEN: usize = 32;
+pub const DEFAULT_STORE_DIR: &str = "/nix/store";
+const DRV_EXTENSION: &str = ".drv";
+
+static STORE_DIR: OnceLock<String> = OnceLock::new();
+
+/// The Nix store directory, which is `NIX_STORE_DIR` if set like for Nix itself
+pub fn store_dir() -> &'static str {
+    STORE_DIR.get_or_init(|| {
+        std::env::var("NIX_STORE_DIR")
+            .map(|dir| dir.trim_end_matches('/').to_string())
+            .unwrap_or_else(|_| DEFAULT_STORE_DIR.to_string())
+    })
+}
+
+/// Sets the store directory before any path is parsed, e.g. to the one of the settings.
+/// Fails if a different one is in use already.
+pub fn init_store_dir(dir: &str) -> Result<()> {
+    let dir = dir.trim_end_matches('/');
+    let current = STORE_DIR.get_or_init(|| dir.to_string());
+    if current != dir {
+        return Err(Error::InvalidStorePath(format!(
+            "The store directory is {current} already and can't be changed to {dir}"
+        )));
+    }
+    Ok(())
+}
+
+/// The properties of the Nix store whose paths are cached
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoreConfig {
+    pub store_dir: String,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        Self {
+            store_dir: store_dir().to_string(),
+        }
+    }
 }
 
 impl NixPath {
+    /// Whether `hash` is the hash part of a store path, e.g. `2bcv91i8fahqghn8dmyr791iaycbsjdd`
+    pub fn is_hash_part(hash: &str) -> bool {
+        Self::validate_hash(hash).is_ok()
+    }
+
+    /// Checks that `hash` has the length of a hash part and only contains Nix base32 characters
+    pub fn validate_hash(hash: &str) -> Result<()> {
+        if let Some(c) = hash.chars().find(|c| !NIX_BASE32_CHARS.contains(*c)) {
+            return Err(Error::InvalidStorePath(format!(
+                "Invalid character '{}' in nix hash {}, which must be in the Nix base32 alphabet",
+                c, hash
+            )));
+        }
+        if hash.len() != HASH_PART_LEN {
+            return Err(Error::InvalidStorePath(format!(
+                "Nix hash {} has {} characters instead of {}",
+                hash,
+                hash.len(),
+                HASH_PART_LEN
+            )));
+        }
+        Ok(())
+    }
+
+    /// Parses a path in the store directory. Paths to files inside a store path, such as
+    /// `/nix/store/<hash>-hello-2.12.2/bin/hello`, are truncated to the store path.
     pub fn new<T: AsRef<Path> + ?Sized>(path_like: &T) -> Result<Self> {
         let path_ref = path_like.as_ref();
-        let full_path = path_ref
-            .to_str()
-            .ok_or_else(|| anyhow!("Nix path is not valid UTF-8: {}", path_ref.display()))?;
+        let full_path = path_ref.to_str().ok_or_else(|| {
+            Error::InvalidStorePath(format!(
+                "Nix path is not valid UTF-8: {}",
+                path_ref.display()
+            ))
+        })?;
         let full_path = full_path.trim();
 
-        let stem = path_ref
-            .file_name()
-            .ok_or_else(|| anyhow!("Nix path has no file name component: {}", full_path))?;
-        let stem_str = stem
-            .to_str()
-            .ok_or_else(|| anyhow!("Nix path component is not valid UTF-8: {}", full_path))?;
+        let store_dir = store_dir();
+        let relative = full_path
+            .strip_prefix(store_dir)
+            .and_then(|relative| relative.strip_prefix('/'))
+            .ok_or_else(|| {
+                Error::InvalidStorePath(format!(
+                    "Nix path is not in the Nix store {}: {}",
+                    store_dir, full_path
+                ))
+            })?;
+        let (stem_str, subpath) = match relative.split_once('/') {
+            Some((stem, subpath)) => (stem, Some(subpath.trim_end_matches('/'))),
+            None => (relative, None),
+        };
+        let (stem_str, output) = match stem_str.split_once('!') {
+            Some((stem, output)) if stem.ends_with(DRV_EXTENSION) && !output.is_empty() => {
+                (stem, Some(output))
+            }
+            Some(_) => {
+                return Err(Error::InvalidStorePath(format!(
+                    "Only an output of a derivation can be selected with '!': {}",
+                    full_path
+                )));
+            }
+            None => (stem_str, None),
+        };
+        if stem_str.is_empty() {
+            return Err(Error::InvalidStorePath(format!(
+                "Nix path has no file name component: {}",
+                full_path
+            )));
+        }
 
         let (hash, name) = stem_str.split_once('-').ok_or_else(|| {
-            anyhow!(
+            Error::InvalidStorePath(format!(
                 "Invalid nix path format (missing 'hash-name' separator): {}",
                 stem_str
-            )
+            ))
         })?;
 
-        if hash.len() != 32 {
-            return Err(anyhow!("Invalid nix hash in nix path: {}", full_path));
-        }
+        Self::validate_hash(hash).map_err(|e| {
+            Error::InvalidStorePath(format!("Invalid nix hash in nix path {}: {}", full_path, e))
+        })?;
 
         Ok(Self {
-            path: full_path.to_string(),
+            path: format!("{}/{}", store_dir, stem_str),
             hash: hash.to_string(),
             name: name.to_string(),
+            subpath: subpath.filter(|s| !s.is_empty()).map(str::to_string),
+            output: output.map(str::to_string),
         })
     }
 
+    /// Parses a store path given without the store directory, as in the references of narinfos
+    pub fn from_base_name(base_name: &str) -> Result<Self> {
+        if base_name.contains('/') {
+            return Err(Error::InvalidStorePath(format!(
+                "Nix path base name contains a '/': {}",
+                base_name
+            )));
+        }
+        Self::new(&format!("{}/{}", store_dir(), base_name))
+    }
+
     pub fn get_base_32_hash(&self) -> &str {
         &self.hash
     }
@@ -49,9 +167,69 @@ impl NixPath {
         &self.name
     }
 
+    /// The absolute store path, which the daemon and signature fingerprints require
     pub fn get_path(&self) -> &str {
         &self.path
     }
+
+    /// The store path without the store directory (`hash-name`), as in the references of narinfos
+    pub fn get_base_name(&self) -> &str {
+        let start = self.path.len() - self.name.len() - self.hash.len() - 1;
+        &self.path[start..]
+    }
+
+    /// The file inside the store path the `NixPath` was created from, e.g. `bin/hello`
+    pub fn subpath(&self) -> Option<&str> {
+        self.subpath.as_deref()
+    }
+
+    /// Whether this is the path of a store derivation (`.drv` file)
+    pub fn is_derivation(&self) -> bool {
+        self.name.ends_with(DRV_EXTENSION)
+    }
+
+    /// The output selected from the derivation, e.g. `out` for `<drv path>!out`
+    pub fn output_name(&self) -> Option<&str> {
+        self.output.as_deref()
+    }
+
+    /// The path without a selected output
+    pub fn without_output(&self) -> Self {
+        Self {
+            output: None,
+            ..self.clone()
+        }
+    }
+
+    /// The name without the version, e.g. `hello` for `hello-2.12.1`
+    pub fn name_without_version(&self) -> &str {
+        self.split_version().0
+    }
+
+    /// The version in the name, e.g. `2.12.1` for `hello-2.12.1` and `6.5-dev` for `ncurses-6.5-dev`
+    pub fn version(&self) -> Option<&str> {
+        self.split_version().1
+    }
+
+    /// Splits the name like Nix does, at the first dash which is not followed by a letter.
+    /// The `.drv` extension of derivations is not part of the version.
+    fn split_version(&self) -> (&str, Option<&str>) {
+        let name = self.name.strip_suffix(DRV_EXTENSION).unwrap_or(&self.name);
+        let version_start = name
+            .char_indices()
+            .find(|&(i, c)| {
+                c == '-'
+                    && name[i + 1..]
+                        .chars()
+                        .next()
+                        .is_some_and(|next| !next.is_ascii_alphabetic())
+            })
+            .map(|(i, _)| i);
+        match version_start {
+            Some(i) => (&name[..i], Some(&name[i + 1..])),
+            None => (name, None),
+        }
+    }
 }
 
 impl AsRef<str> for NixPath {
@@ -68,11 +246,166 @@ impl AsRef<Path> for NixPath {
 
 impl Display for NixPath {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&self.path)
+        f.write_str(&self.path)?;
+        if let Some(output) = &self.output {
+            write!(f, "!{}", output)?;
+        }
+        Ok(())
     }
 }
 impl PartialEq for NixPath {
     fn eq(&self, other: &Self) -> bool {
-        self.path == other.path
+        self.path == other.path && self.output == other.output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_hash_part() {
+        assert!(NixPath::is_hash_part("2bcv91i8fahqghn8dmyr791iaycbsjdd"));
+        assert!(!NixPath::is_hash_part("2bcv91i8fahqghn8dmyr791iaycbsjd"));
+        assert!(!NixPath::is_hash_part("ebcv91i8fahqghn8dmyr791iaycbsjdd"));
+        assert!(!NixPath::is_hash_part("/nix/store/2bcv91i8fahqghn8dmyr7"));
+    }
+
+    #[test]
+    fn test_validate_hash() {
+        let error = NixPath::validate_hash("2bcv91i8fahqghn8dmyr791iaycbsjde").unwrap_err();
+        assert!(error.to_string().contains("'e'"));
+        let error = NixPath::new("/nix/store/2bcv91i8fahqghn8dmyr791iaycbsjdt-hello").unwrap_err();
+        assert!(error.to_string().contains("'t'"));
+        assert!(NixPath::validate_hash("2bcv91i8").is_err());
+    }
+
+    /// Every accepted hash part decodes to the 20 byte hash it encodes
+    #[test]
+    fn test_accepted_hashes_roundtrip() {
+        use rand::Rng;
+        let mut rng = rand::thread_rng();
+        let alphabet = b"0123456789abcdefghijklmnopqrstuvwxyz";
+        for _ in 0..10_000 {
+            let hash: String = (0..HASH_PART_LEN)
+                .map(|_| alphabet[rng.gen_range(0..alphabet.len())] as char)
+                .collect();
+            let in_alphabet = !hash.contains(['e', 'o', 'u', 't']);
+            assert_eq!(NixPath::is_hash_part(&hash), in_alphabet, "{hash}");
+            if in_alphabet {
+                let decoded = nix_base32::from_nix_base32(&hash).unwrap();
+                assert_eq!(decoded.len(), 20);
+                assert_eq!(nix_base32::to_nix_base32(&decoded), hash);
+            }
+        }
+    }
+
+    #[test]
+    fn test_paths_inside_store_paths() -> Result<()> {
+        let store_path = "/nix/store/2bcv91i8fahqghn8dmyr791iaycbsjdd-hello-2.12.2";
+        let path = NixPath::new(store_path)?;
+        assert_eq!(path.get_path(), store_path);
+        assert_eq!(path.subpath(), None);
+        assert_eq!(NixPath::new(&format!("{store_path}/"))?.subpath(), None);
+
+        let binary = NixPath::new(&format!("{store_path}/bin/hello"))?;
+        assert_eq!(binary.get_path(), store_path);
+        assert_eq!(binary.get_name(), "hello-2.12.2");
+        assert_eq!(
+            binary.get_base_32_hash(),
+            "2bcv91i8fahqghn8dmyr791iaycbsjdd"
+        );
+        assert_eq!(binary.subpath(), Some("bin/hello"));
+        assert_eq!(binary, path);
+        Ok(())
+    }
+
+    #[test]
+    fn test_derivation_outputs() -> Result<()> {
+        let drv = "/nix/store/2bcv91i8fahqghn8dmyr791iaycbsjdd-hello-2.12.1.drv";
+        let path = NixPath::new(drv)?;
+        assert!(path.is_derivation());
+        assert_eq!(path.output_name(), None);
+
+        let out = NixPath::new(&format!("{drv}!dev"))?;
+        assert!(out.is_derivation());
+        assert_eq!(out.get_path(), drv);
+        assert_eq!(out.get_name(), "hello-2.12.1.drv");
+        assert_eq!(out.output_name(), Some("dev"));
+        assert_eq!(out.to_string(), format!("{drv}!dev"));
+        assert_ne!(out, path);
+        assert_eq!(out.without_output(), path);
+
+        let hello = "/nix/store/2bcv91i8fahqghn8dmyr791iaycbsjdd-hello-2.12.1";
+        assert!(!NixPath::new(hello)?.is_derivation());
+        for invalid in [format!("{hello}!out"), format!("{drv}!")] {
+            assert!(NixPath::new(&invalid).is_err(), "{invalid}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_name_without_version() -> Result<()> {
+        for (name, expected_name, expected_version) in [
+            ("hello-2.12.1", "hello", Some("2.12.1")),
+            ("hello-2.12.1.drv", "hello", Some("2.12.1")),
+            ("ncurses-6.5-dev", "ncurses", Some("6.5-dev")),
+            ("kitty-0.43.1-terminfo", "kitty", Some("0.43.1-terminfo")),
+            ("iana-etc-20250505", "iana-etc", Some("20250505")),
+            ("python3-3.13.7", "python3", Some("3.13.7")),
+            ("libpng-apng-1.6.50", "libpng-apng", Some("1.6.50")),
+            ("source", "source", None),
+            ("hello-world", "hello-world", None),
+            ("builder.sh", "builder.sh", None),
+            ("bash-", "bash-", None),
+        ] {
+            let path = NixPath::new(&format!(
+                "/nix/store/2bcv91i8fahqghn8dmyr791iaycbsjdd-{name}"
+            ))?;
+            assert_eq!(path.name_without_version(), expected_name, "{name}");
+            assert_eq!(path.version(), expected_version, "{name}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_base_name() -> Result<()> {
+        let base_name = "5vnba43n1w87cs2i2dd242zy88k4dwf9-zlib-1.3.1";
+        let path = NixPath::from_base_name(base_name)?;
+        assert_eq!(path.get_path(), format!("{}/{}", store_dir(), base_name));
+        assert_eq!(path.get_base_name(), base_name);
+        let binary = NixPath::new(&format!("{}/{}/lib/libz.so", store_dir(), base_name))?;
+        assert_eq!(binary.get_base_name(), base_name);
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_base_name() -> Result<()> {
+        let path = NixPath::from_base_name("2bcv91i8fahqghn8dmyr791iaycbsjdd-hello-2.12.2")?;
+        assert_eq!(
+            path.get_path(),
+            "/nix/store/2bcv91i8fahqghn8dmyr791iaycbsjdd-hello-2.12.2"
+        );
+        assert!(NixPath::from_base_name("2bcv91i8fahqghn8dmyr791iaycbsjdd-hello/bin").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_paths() {
+        for path in [
+            "/nix/store/hello",
+            "/nix/store/2bcv91i8-hello",
+            "/nix/store/2bcv91i8fahqghn8dmyr791iaycbsjdo-hello",
+            "/nix/store/",
+            "/",
+            "/usr/bin/2bcv91i8fahqghn8dmyr791iaycbsjdd-hello",
+            "/nix/store2/2bcv91i8fahqghn8dmyr791iaycbsjdd-hello",
+            "2bcv91i8fahqghn8dmyr791iaycbsjdd-hello",
+        ] {
+            assert!(matches!(
+                NixPath::new(path),
+                Err(Error::InvalidStorePath(_))
+            ));
+        }
     }
 }
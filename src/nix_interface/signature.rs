@@ -1,8 +1,13 @@
+use super::error::Error;
 use crate::nix_interface::path::NixPath;
-use anyhow::{Result, anyhow};
 use base64::{Engine, prelude::BASE64_STANDARD};
-use ring::signature::Ed25519KeyPair;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{ED25519, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
 use std::str::FromStr;
+use zeroize::{Zeroize, Zeroizing};
 
 pub const NUM_SEED_BYTES: usize = 32;
 pub const NUM_PUBLIC_KEY_BYTES: usize = 32;
@@ -16,6 +21,61 @@ pub struct PrivateKey {
 }
 
 impl PrivateKey {
+    /// Creates a new random key, written to files like by `nix-store --generate-binary-cache-key`
+    pub fn generate(name: &str) -> Result<Self, Error> {
+        validate_name(name)?;
+        let mut seed = [0; NUM_SEED_BYTES];
+        SystemRandom::new()
+            .fill(&mut seed)
+            .expect("the system provides random numbers");
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&seed).expect("any seed is valid");
+        let public_key = key_pair.public_key().as_ref().try_into().unwrap();
+        let key = Self {
+            name: name.to_string(),
+            seed,
+            public_key,
+        };
+        seed.zeroize();
+        Ok(key)
+    }
+
+    /// Reads a secret key file, e.g. one created by `nix-store --generate-binary-cache-key`
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let content = Zeroizing::new(fs::read_to_string(path).map_err(|e| {
+            Error::InvalidPrivateKey(format!("could not read {}: {e}", path.display()))
+        })?);
+        Self::from_str(&content).map_err(|e| match e {
+            Error::InvalidPrivateKey(message) => {
+                Error::InvalidPrivateKey(format!("{}: {message}", path.display()))
+            }
+            e => e,
+        })
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey {
+            name: self.name.clone(),
+            key: self.public_key,
+        }
+    }
+
+    /// The public key in the `name:base64` form of `trusted-public-keys`
+    pub fn public_key_string(&self) -> String {
+        self.public_key().to_string()
+    }
+
+    /// The key in the `name:base64` form of secret key files
+    pub fn secret_key_string(&self) -> Zeroizing<String> {
+        let mut key_bytes = Zeroizing::new([0; NUM_SECRET_KEY_BYTES]);
+        key_bytes[..NUM_SEED_BYTES].copy_from_slice(&self.seed);
+        key_bytes[NUM_SEED_BYTES..].copy_from_slice(&self.public_key);
+        Zeroizing::new(format!(
+            "{}:{}",
+            self.name,
+            BASE64_STANDARD.encode(key_bytes.as_slice())
+        ))
+    }
+
     pub fn sign<M: AsRef<[u8]>>(&self, data: M) -> Vec<u8> {
         let key_pair = Ed25519KeyPair::from_seed_and_public_key(&self.seed, &self.public_key)
             .expect("Valid keys stored in struct");
@@ -23,43 +83,174 @@ impl PrivateKey {
         let sig = key_pair.sign(data.as_ref());
         sig.as_ref().to_vec()
     }
+
+    /// Whether `signature` was made for `data` with this key
+    pub fn verify<M: AsRef<[u8]>>(&self, data: M, signature: &[u8]) -> bool {
+        self.public_key().verify(data, signature)
+    }
+}
+
+/// A key as listed in `trusted-public-keys`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublicKey {
+    pub name: String,
+    key: [u8; NUM_PUBLIC_KEY_BYTES],
+}
+
+impl PublicKey {
+    /// Whether `signature` was made for `data` with this key
+    pub fn verify<M: AsRef<[u8]>>(&self, data: M, signature: &[u8]) -> bool {
+        UnparsedPublicKey::new(&ED25519, self.key)
+            .verify(data.as_ref(), signature)
+            .is_ok()
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = Error;
+    /// Parses `name:base64`, ignoring surrounding whitespace
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |message: String| Error::InvalidPublicKey(format!("{message}: {s}"));
+        let (name, key_base64) = s
+            .trim()
+            .split_once(':')
+            .ok_or_else(|| invalid("expected the format 'name:key'".to_string()))?;
+        if name.is_empty() {
+            return Err(invalid("key name is empty".to_string()));
+        }
+        let key_bytes = BASE64_STANDARD
+            .decode(key_base64)
+            .map_err(|e| invalid(format!("key is not base64 ({e})")))?;
+        let key = key_bytes.as_slice().try_into().map_err(|_| {
+            invalid(format!(
+                "key has {} bytes instead of {}",
+                key_bytes.len(),
+                NUM_PUBLIC_KEY_BYTES
+            ))
+        })?;
+        Ok(Self {
+            name: name.to_string(),
+            key,
+        })
+    }
+}
+
+impl Display for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.name, BASE64_STANDARD.encode(self.key))
+    }
+}
+
+/// The result of checking one signature of a store object
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SigStatus {
+    Valid,
+    /// The signature is malformed or does not match the fingerprint
+    Invalid,
+    /// None of the trusted keys has the name of the signature's key
+    UnknownKey,
+}
+
+/// Checks a `name:base64` signature of `fingerprint` with the trusted key of that name
+pub fn verify(fingerprint: &str, signature: &str, trusted_keys: &[PublicKey]) -> SigStatus {
+    let Some((name, signature_base64)) = signature.split_once(':') else {
+        return SigStatus::Invalid;
+    };
+    let Some(key) = trusted_keys.iter().find(|key| key.name == name) else {
+        return SigStatus::UnknownKey;
+    };
+    match BASE64_STANDARD.decode(signature_base64) {
+        Ok(signature) if key.verify(fingerprint, &signature) => SigStatus::Valid,
+        _ => SigStatus::Invalid,
+    }
+}
+
+impl Drop for PrivateKey {
+    fn drop(&mut self) {
+        self.seed.zeroize();
+    }
 }
 
 impl FromStr for PrivateKey {
-    type Err = anyhow::Error;
+    type Err = Error;
+    /// Parses `name:base64`, ignoring surrounding whitespace such as a trailing newline
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut split = s.splitn(2, ':');
-        let name = split
-            .next()
-            .ok_or_else(|| anyhow!("Could not retrieve name from private key"))?;
-        let key_base64 = split
-            .next()
-            .ok_or_else(|| anyhow!("Could not retrieve key from private key"))?;
-        let key_bytes = BASE64_STANDARD.decode(key_base64)?;
-        let seed = key_bytes[0..NUM_SEED_BYTES].try_into()?;
-        let public_key = key_bytes[NUM_SEED_BYTES..NUM_SECRET_KEY_BYTES].try_into()?;
+        let (name, key_base64) = s.trim().split_once(':').ok_or_else(|| {
+            Error::InvalidPrivateKey("expected the format 'name:key', found no ':'".to_string())
+        })?;
+        validate_name(name)?;
+        let key_bytes = Zeroizing::new(
+            BASE64_STANDARD
+                .decode(key_base64)
+                .map_err(|e| Error::InvalidPrivateKey(format!("key is not base64: {e}")))?,
+        );
+        if key_bytes.len() != NUM_SECRET_KEY_BYTES {
+            return Err(Error::InvalidPrivateKey(format!(
+                "key has {} bytes instead of {}",
+                key_bytes.len(),
+                NUM_SECRET_KEY_BYTES
+            )));
+        }
+        let (seed, public_key) = key_bytes.split_at(NUM_SEED_BYTES);
+        // `sign` relies on the key pair being consistent
+        Ed25519KeyPair::from_seed_and_public_key(seed, public_key).map_err(|_| {
+            Error::InvalidPrivateKey("public key does not belong to the secret key".to_string())
+        })?;
         Ok(Self {
             name: name.to_string(),
-            seed: seed,
-            public_key: public_key,
+            seed: seed.try_into().unwrap(),
+            public_key: public_key.try_into().unwrap(),
         })
     }
 }
 
+fn validate_name(name: &str) -> Result<(), Error> {
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(Error::InvalidPrivateKey(format!(
+            "key name '{name}' is empty or contains whitespace"
+        )));
+    }
+    Ok(())
+}
+
+/// The data Nix signs for a store object. The references are sorted and deduplicated
+/// like Nix does, so the fingerprint doesn't depend on the order they were queried in.
 pub fn fingerprint_store_object(
     store_path: &NixPath,
     nar_hash: &str,
     nar_size: u64,
     references: &[NixPath],
 ) -> String {
-    let references_str = references
-        .iter()
-        .map(|p| p.to_string())
-        .collect::<Vec<_>>()
-        .join(",");
+    let mut reference_paths: Vec<&str> = references.iter().map(NixPath::get_path).collect();
+    reference_paths.sort_unstable();
+    reference_paths.dedup();
+    format_fingerprint(store_path, nar_hash, nar_size, &reference_paths)
+}
+
+/// The fingerprint with the references in the order of `references`. Older versions of gachix
+/// signed this instead of the sorted references.
+pub(crate) fn fingerprint_unsorted(
+    store_path: &NixPath,
+    nar_hash: &str,
+    nar_size: u64,
+    references: &[NixPath],
+) -> String {
+    let reference_paths: Vec<&str> = references.iter().map(NixPath::get_path).collect();
+    format_fingerprint(store_path, nar_hash, nar_size, &reference_paths)
+}
+
+fn format_fingerprint(
+    store_path: &NixPath,
+    nar_hash: &str,
+    nar_size: u64,
+    reference_paths: &[&str],
+) -> String {
     format!(
         "1;{};{};{};{}",
-        store_path, nar_hash, nar_size, references_str
+        store_path.get_path(),
+        nar_hash,
+        nar_size,
+        reference_paths.join(",")
     )
 }
 
@@ -69,11 +260,158 @@ mod tests {
     use super::*;
     use ring::signature::{self, UnparsedPublicKey};
 
+    const TEST_SECRET_KEY: &str = "cache.example.org-1:ZJui+kG6vPCSRD4+p1P4DyUVlASmp/zsaeN84PTFW28tj2/PtQWvFWK6Mw+ay8kGif8AZkR5KosHLvuwlzDlgg==";
+    const LIBSSH2_FINGERPRINT: &str = "1;/nix/store/02bfycjg1607gpcnsg8l13lc45qa8qj3-libssh2-1.10.0;sha256:1l29f8r5q2739wnq4i7m2v545qx77b3wrdsw9xz2ajiy3hv1al8b;294664;/nix/store/02bfycjg1607gpcnsg8l13lc45qa8qj3-libssh2-1.10.0,/nix/store/1l4r0r4ab3v3a3ppir4jwiah3icalk9d-zlib-1.2.11,/nix/store/gf6j3k1flnhayvpnwnhikkg0s5dxrn1i-openssl-1.1.1l,/nix/store/z56jcx3j1gfyk4sv7g8iaan0ssbdkhz1-glibc-2.33-56";
+
     #[test]
-    fn test_signature() -> Result<()> {
-        let data = "1;/nix/store/02bfycjg1607gpcnsg8l13lc45qa8qj3-libssh2-1.10.0;sha256:1l29f8r5q2739wnq4i7m2v545qx77b3wrdsw9xz2ajiy3hv1al8b;294664;/nix/store/02bfycjg1607gpcnsg8l13lc45qa8qj3-libssh2-1.10.0,/nix/store/1l4r0r4ab3v3a3ppir4jwiah3icalk9d-zlib-1.2.11,/nix/store/gf6j3k1flnhayvpnwnhikkg0s5dxrn1i-openssl-1.1.1l,/nix/store/z56jcx3j1gfyk4sv7g8iaan0ssbdkhz1-glibc-2.33-56";
-        let secret_key_str = "cache.example.org-1:ZJui+kG6vPCSRD4+p1P4DyUVlASmp/zsaeN84PTFW28tj2/PtQWvFWK6Mw+ay8kGif8AZkR5KosHLvuwlzDlgg==";
-        let secret_key = PrivateKey::from_str(secret_key_str)?;
+    fn test_reject_invalid_private_keys() {
+        // the secret key with its public half replaced by the one of another key
+        let mismatched = format!(
+            "name:{}",
+            BASE64_STANDARD.encode([[1u8; NUM_SEED_BYTES], [2; NUM_PUBLIC_KEY_BYTES]].concat())
+        );
+        for (key, reason) in [
+            ("no-colon", "found no ':'"),
+            (":AAAA", "empty"),
+            ("name:not base64", "not base64"),
+            ("name:AAAA", "3 bytes instead of 64"),
+            (mismatched.as_str(), "does not belong"),
+        ] {
+            assert!(
+                matches!(
+                    PrivateKey::from_str(key),
+                    Err(Error::InvalidPrivateKey(message)) if message.contains(reason)
+                ),
+                "{key}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_public_key() -> anyhow::Result<()> {
+        let key = PublicKey::from_str(
+            " cache.example.org-1:LY9vz7UFrxViujMPmsvJBon/AGZEeSqLBy77sJcw5YI=\n",
+        )?;
+        assert_eq!(key, PrivateKey::from_str(TEST_SECRET_KEY)?.public_key());
+        for invalid in [
+            "LY9vz7UFrxViujMPmsvJBon/AGZEeSqLBy77sJcw5YI=",
+            ":LY9vz7UFrxViujMPmsvJBon/AGZEeSqLBy77sJcw5YI=",
+            "name:not base64",
+            "name:AAAA",
+        ] {
+            assert!(
+                matches!(
+                    PublicKey::from_str(invalid),
+                    Err(Error::InvalidPublicKey(_))
+                ),
+                "{invalid}"
+            );
+        }
+
+        let signature = format!(
+            "cache.example.org-1:{}",
+            BASE64_STANDARD
+                .encode(PrivateKey::from_str(TEST_SECRET_KEY)?.sign(LIBSSH2_FINGERPRINT))
+        );
+        let trusted_keys = [key];
+        assert_eq!(
+            verify(LIBSSH2_FINGERPRINT, &signature, &trusted_keys),
+            SigStatus::Valid
+        );
+        assert_eq!(
+            verify(
+                &LIBSSH2_FINGERPRINT.replace("294664", "1"),
+                &signature,
+                &trusted_keys
+            ),
+            SigStatus::Invalid
+        );
+        assert_eq!(
+            verify(
+                LIBSSH2_FINGERPRINT,
+                "cache.example.org-1:AAAA",
+                &trusted_keys
+            ),
+            SigStatus::Invalid
+        );
+        assert_eq!(
+            verify(LIBSSH2_FINGERPRINT, &signature, &[]),
+            SigStatus::UnknownKey
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_file() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("secret-key");
+        // as written by `nix-store --generate-binary-cache-key`, with the newline of an editor
+        for content in [TEST_SECRET_KEY.to_string(), format!("{TEST_SECRET_KEY}\n")] {
+            fs::write(&path, content)?;
+            let key = PrivateKey::from_file(&path)?;
+            assert_eq!(key.name, "cache.example.org-1");
+            assert_eq!(
+                key.public_key_string(),
+                "cache.example.org-1:LY9vz7UFrxViujMPmsvJBon/AGZEeSqLBy77sJcw5YI="
+            );
+            assert_eq!(key.secret_key_string().as_str(), TEST_SECRET_KEY);
+        }
+
+        fs::write(&path, "cache.example.org-1:AAAA\n")?;
+        let error = PrivateKey::from_file(&path).err().unwrap().to_string();
+        assert!(error.contains(&path.display().to_string()), "{error}");
+        assert!(PrivateKey::from_file(&dir.path().join("missing")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate() -> anyhow::Result<()> {
+        let key = PrivateKey::generate("cache.example.org-2")?;
+        let parsed = PrivateKey::from_str(&key.secret_key_string())?;
+        assert_eq!(parsed.public_key_string(), key.public_key_string());
+        assert!(parsed.verify("data", &key.sign("data")));
+        assert_ne!(
+            PrivateKey::generate("cache.example.org-2")?.public_key_string(),
+            key.public_key_string()
+        );
+        assert!(PrivateKey::generate("with space").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sign_like_nix() -> anyhow::Result<()> {
+        let path = |base_name| NixPath::from_base_name(base_name);
+        let store_path = path("02bfycjg1607gpcnsg8l13lc45qa8qj3-libssh2-1.10.0")?;
+        let references = [
+            store_path.clone(),
+            path("1l4r0r4ab3v3a3ppir4jwiah3icalk9d-zlib-1.2.11")?,
+            path("gf6j3k1flnhayvpnwnhikkg0s5dxrn1i-openssl-1.1.1l")?,
+            path("z56jcx3j1gfyk4sv7g8iaan0ssbdkhz1-glibc-2.33-56")?,
+        ];
+        let fingerprint = fingerprint_store_object(
+            &store_path,
+            "sha256:1l29f8r5q2739wnq4i7m2v545qx77b3wrdsw9xz2ajiy3hv1al8b",
+            294664,
+            &references,
+        );
+        assert_eq!(fingerprint, LIBSSH2_FINGERPRINT);
+
+        // Ed25519 signatures are deterministic, `nix store sign --key-file` writes the same one
+        let secret_key = PrivateKey::from_str(TEST_SECRET_KEY)?;
+        let signature = secret_key.sign(&fingerprint);
+        assert_eq!(
+            BASE64_STANDARD.encode(&signature),
+            "BnssDBhJMOpHzm5e49iwwysDuiatlh6dubgkp5it1Rn/b5P6wweEgPIn3lp5/W7Kkap1K9L1m/8xH4IrJ+VDAQ=="
+        );
+        assert!(secret_key.verify(&fingerprint, &signature));
+        assert!(!secret_key.verify(fingerprint.replace("294664", "294665"), &signature));
+        Ok(())
+    }
+
+    #[test]
+    fn test_signature() -> anyhow::Result<()> {
+        let data = LIBSSH2_FINGERPRINT;
+        let secret_key = PrivateKey::from_str(TEST_SECRET_KEY)?;
         let private_key_bytes =
             BASE64_STANDARD.decode("LY9vz7UFrxViujMPmsvJBon/AGZEeSqLBy77sJcw5YI=")?;
         let public_key = UnparsedPublicKey::new(&signature::ED25519, private_key_bytes);
@@ -82,19 +420,4 @@ mod tests {
         assert!(public_key.verify(data.as_bytes(), &signature).is_ok());
         Ok(())
     }
-
-    // #[test]
-    // fn test_fingerprint() {
-    //     let store_path = "/nix/store/2bcv91i8fahqghn8dmyr791iaycbsjdd-hello-2.12.2";
-    //     let nar_hash = "sha256:0rmalafq2v3k7a83jcmh8hnh5kilbbzqf1rkzsdz0fv1nayscp04";
-    //     let nar_size = 274568;
-    //     let references = vec![
-    //         "2bcv91i8fahqghn8dmyr791iaycbsjdd-hello-2.12.2",
-    //         "xx7cm72qy2c0643cm1ipngd87aqwkcdp-glibc-2.40-66",
-    //     ];
-    //
-    //     let fingerprint = fingerprint_store_object(store_path, nar_hash, nar_size, references);
-    //     let expected = b"1;/nix/store/syd87l2rxw8cbsxmxl853h0r6pdwhwjr-curl-7.82.0-bin;sha256:1b4sb93wp679q4zx9k1ignby1yna3z7c4c2ri3wphylbc2dwsys0;196040;/nix/store/0jqd0rlxzra1rs38rdxl43yh6rxchgc6-curl-7.82.0";
-    //     assert_eq!(fingerprint, expected);
-    // }
 }
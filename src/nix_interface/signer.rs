@@ -0,0 +1,290 @@
+use std::io::{Read, Write};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+
+use super::error::{Error, Result};
+use crate::nix_interface::signature::{PrivateKey, PublicKey};
+
+// of Ed25519 signatures
+const SIGNATURE_LEN: usize = 64;
+
+/// Creates the Ed25519 signatures of store object fingerprints
+pub trait NarSigner: Send + Sync {
+    /// The name of the key, which prefixes the signatures in narinfos
+    fn key_name(&self) -> &str;
+
+    fn public_key(&self) -> PublicKey;
+
+    fn sign(&self, fingerprint: &[u8]) -> Result<Vec<u8>>;
+
+    /// Signs all fingerprints, at once where the signer supports it
+    fn sign_batch(&self, fingerprints: &[&[u8]]) -> Result<Vec<Vec<u8>>> {
+        fingerprints
+            .iter()
+            .map(|fingerprint| self.sign(fingerprint))
+            .collect()
+    }
+}
+
+impl NarSigner for PrivateKey {
+    fn key_name(&self) -> &str {
+        &self.name
+    }
+
+    fn public_key(&self) -> PublicKey {
+        PrivateKey::public_key(self)
+    }
+
+    fn sign(&self, fingerprint: &[u8]) -> Result<Vec<u8>> {
+        Ok(PrivateKey::sign(self, fingerprint))
+    }
+}
+
+/// Signs with an external program, e.g. a wrapper around an HSM or a KMS, so the secret key
+/// never has to be on disk. The program reads one fingerprint per line from stdin and writes
+/// the base64 encoded signature of each, in the same order, as a line to stdout.
+pub struct CommandSigner {
+    command: Vec<String>,
+    public_key: PublicKey,
+    timeout: Duration,
+}
+
+impl CommandSigner {
+    /// `command` is the program followed by its arguments, `public_key` the key it signs for
+    pub fn new(command: Vec<String>, public_key: PublicKey, timeout: Duration) -> Result<Self> {
+        if command.is_empty() {
+            return Err(Error::SignerFailed(
+                "the signer command is empty".to_string(),
+            ));
+        }
+        Ok(Self {
+            command,
+            public_key,
+            timeout,
+        })
+    }
+
+    fn command_line(&self) -> String {
+        self.command.join(" ")
+    }
+
+    fn fail(&self, reason: impl std::fmt::Display) -> Error {
+        Error::SignerFailed(format!("`{}` {}", self.command_line(), reason))
+    }
+
+    /// Runs the program once for all fingerprints and returns its stdout
+    fn run(&self, fingerprints: &[&[u8]]) -> Result<String> {
+        let mut child = Command::new(&self.command[0])
+            .args(&self.command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| self.fail(format_args!("could not be started: {e}")))?;
+
+        let mut input = Vec::new();
+        for fingerprint in fingerprints {
+            input.extend_from_slice(fingerprint);
+            input.push(b'\n');
+        }
+        // Written and read in threads, so a program which doesn't read all input can't block us
+        let mut stdin = child.stdin.take().unwrap();
+        let writer = thread::spawn(move || stdin.write_all(&input));
+        let stdout = read_to_end(child.stdout.take().unwrap());
+        let stderr = read_to_end(child.stderr.take().unwrap());
+
+        let status = match wait_timeout(&mut child, self.timeout) {
+            Ok(Some(status)) => status,
+            Ok(None) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(self.fail(format_args!(
+                    "did not finish within {}s",
+                    self.timeout.as_secs_f32()
+                )));
+            }
+            Err(e) => return Err(self.fail(e)),
+        };
+        let _ = writer.join();
+        let stdout = stdout.join().unwrap_or_default();
+        let stderr = stderr.join().unwrap_or_default();
+        if !status.success() {
+            return Err(self.fail(format_args!(
+                "failed with {}: {}",
+                status,
+                String::from_utf8_lossy(&stderr).trim()
+            )));
+        }
+        String::from_utf8(stdout).map_err(|_| self.fail("wrote output which is not UTF-8"))
+    }
+}
+
+impl NarSigner for CommandSigner {
+    fn key_name(&self) -> &str {
+        &self.public_key.name
+    }
+
+    fn public_key(&self) -> PublicKey {
+        self.public_key.clone()
+    }
+
+    fn sign(&self, fingerprint: &[u8]) -> Result<Vec<u8>> {
+        Ok(self.sign_batch(&[fingerprint])?.remove(0))
+    }
+
+    fn sign_batch(&self, fingerprints: &[&[u8]]) -> Result<Vec<Vec<u8>>> {
+        if fingerprints.is_empty() {
+            return Ok(Vec::new());
+        }
+        if let Some(i) = fingerprints.iter().position(|f| f.contains(&b'\n')) {
+            return Err(Error::SignerFailed(format!(
+                "fingerprint {} contains a newline",
+                String::from_utf8_lossy(fingerprints[i])
+            )));
+        }
+        let output = self.run(fingerprints)?;
+        let lines: Vec<&str> = output.lines().filter(|l| !l.trim().is_empty()).collect();
+        if lines.len() != fingerprints.len() {
+            return Err(self.fail(format_args!(
+                "returned {} signatures for {} fingerprints",
+                lines.len(),
+                fingerprints.len()
+            )));
+        }
+        lines
+            .iter()
+            .zip(fingerprints)
+            .enumerate()
+            .map(|(i, (line, fingerprint))| {
+                let signature = BASE64_STANDARD
+                    .decode(line.trim())
+                    .ok()
+                    .filter(|s| s.len() == SIGNATURE_LEN)
+                    .ok_or_else(|| {
+                        self.fail(format_args!(
+                            "returned a malformed signature on line {}: '{}'",
+                            i + 1,
+                            line
+                        ))
+                    })?;
+                if !self.public_key.verify(fingerprint, &signature) {
+                    return Err(self.fail(format_args!(
+                        "returned a signature on line {} which does not verify against {}",
+                        i + 1,
+                        self.public_key
+                    )));
+                }
+                Ok(signature)
+            })
+            .collect()
+    }
+}
+
+fn read_to_end(mut reader: impl Read + Send + 'static) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = reader.read_to_end(&mut buf);
+        buf
+    })
+}
+
+/// Waits for the process to exit, returns `None` if it is still running after `timeout`
+fn wait_timeout(
+    child: &mut Child,
+    timeout: Duration,
+) -> std::io::Result<Option<std::process::ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    const PUBLIC_KEY: &str = "cache.example.org-1:LY9vz7UFrxViujMPmsvJBon/AGZEeSqLBy77sJcw5YI=";
+    const FINGERPRINT: &[u8] = b"1;/nix/store/5vnba43n1w87cs2i2dd242zy88k4dwf9-zlib-1.3.1;sha256:1l29f8r5q2739wnq4i7m2v545qx77b3wrdsw9xz2ajiy3hv1al8b;42;";
+    // by the key of PUBLIC_KEY, of FINGERPRINT and of "other"
+    const SIGNATURE: &str =
+        "fsR7NdbF4/oegmc3V0YJyt/OptaoR0Jk0swIfJSlU1+9jn9WW7uXVWvwz5VWXNYB2iLJsC8Oxy5pfWvB2oWrBg==";
+    const OTHER_SIGNATURE: &str =
+        "/yGCW8yu1konMfe0nuFPS/GRTAwkn6CG19bKQx1uQqvEtQseSaZdF6KP3WxJxVuRDqICGhlHLODF1YC1lBoMAw==";
+
+    fn signer(script: &str) -> Result<CommandSigner> {
+        CommandSigner::new(
+            vec!["sh".to_string(), "-c".to_string(), script.to_string()],
+            PublicKey::from_str(PUBLIC_KEY)?,
+            Duration::from_secs(5),
+        )
+    }
+
+    fn sign_error(signer: &CommandSigner, fingerprints: &[&[u8]]) -> String {
+        signer.sign_batch(fingerprints).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_command_signer() -> Result<()> {
+        let signer = signer(&format!("while read f; do echo {SIGNATURE}; done"))?;
+        assert_eq!(signer.key_name(), "cache.example.org-1");
+        let signatures = signer.sign_batch(&[FINGERPRINT, FINGERPRINT])?;
+        assert_eq!(signatures.len(), 2);
+        assert_eq!(BASE64_STANDARD.encode(&signatures[1]), SIGNATURE);
+        assert!(signer.sign_batch(&[])?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_signer_errors() -> Result<()> {
+        let error = sign_error(&signer("echo key unavailable >&2; exit 3")?, &[FINGERPRINT]);
+        assert!(error.contains("exit status: 3"), "{error}");
+        assert!(error.contains("key unavailable"), "{error}");
+
+        let error = sign_error(&signer("cat > /dev/null; echo abc")?, &[FINGERPRINT]);
+        assert!(error.contains("malformed signature on line 1"), "{error}");
+
+        let error = sign_error(
+            &signer(&format!("cat > /dev/null; echo {SIGNATURE}"))?,
+            &[FINGERPRINT, FINGERPRINT],
+        );
+        assert!(error.contains("1 signatures for 2 fingerprints"), "{error}");
+
+        let error = sign_error(
+            &signer(&format!("cat > /dev/null; echo {OTHER_SIGNATURE}"))?,
+            &[FINGERPRINT],
+        );
+        assert!(error.contains("does not verify"), "{error}");
+
+        let slow = CommandSigner::new(
+            vec!["sleep".to_string(), "10".to_string()],
+            PublicKey::from_str(PUBLIC_KEY)?,
+            Duration::from_millis(100),
+        )?;
+        let start = Instant::now();
+        let error = sign_error(&slow, &[FINGERPRINT]);
+        assert!(error.contains("did not finish within 0.1s"), "{error}");
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let missing = CommandSigner::new(
+            vec!["/nonexistent/signer".to_string()],
+            PublicKey::from_str(PUBLIC_KEY)?,
+            Duration::from_secs(1),
+        )?;
+        assert!(sign_error(&missing, &[FINGERPRINT]).contains("could not be started"));
+        assert!(
+            CommandSigner::new(Vec::new(), PublicKey::from_str(PUBLIC_KEY)?, Duration::ZERO)
+                .is_err()
+        );
+        Ok(())
+    }
+}
@@ -1,56 +1,2323 @@
-use std::path::PathBuf;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 
-use config::{Config, ConfigError, Environment, File};
-use serde::Deserialize;
-use url::Url;
+use anyhow::{Context, anyhow};
+use config::{Config, ConfigError, Environment, File, Map, Source, Value, ValueKind};
+use serde::{Deserialize, Serialize, Serializer};
+use url::{Host, Url};
 
-#[derive(Debug, Deserialize, Clone)]
+use crate::config_template;
+use crate::nar::compression::Compression;
+use crate::nix_interface::PublicKey;
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Server {
     pub port: u16,
     pub host: String,
+    /// Announced in `/nix-cache-info`, Nix prefers caches with a lower priority
+    #[serde(default = "Server::default_priority")]
+    pub priority: usize,
+    /// Announced in `/nix-cache-info`, lets Nix query the narinfos of many paths at once
+    #[serde(default)]
+    pub want_mass_query: bool,
+    /// Number of worker threads; unset starts one per CPU core
+    #[serde(default)]
+    pub workers: Option<usize>,
+    /// Unset serves everyone
+    pub auth: Option<Auth>,
+    #[serde(default)]
+    pub limits: ServerLimits,
+    #[serde(default)]
+    pub compression: ServerCompression,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self {
+            port: 8080,
+            host: "localhost".to_string(),
+            priority: Self::default_priority(),
+            want_mass_query: false,
+            workers: None,
+            auth: None,
+            limits: ServerLimits::default(),
+            compression: ServerCompression::default(),
+        }
+    }
+}
+
+impl Server {
+    fn default_priority() -> usize {
+        50
+    }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// HTTP basic authentication, which Nix does with the credentials of its netrc file
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Auth {
+    pub user: String,
+    #[serde(default, skip_serializing_if = "Secret::is_empty")]
+    pub password: Secret,
+    /// Read when the settings are loaded, takes precedence over `password`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_file: Option<PathBuf>,
+    /// The content of `password_file`
+    #[serde(skip)]
+    file_password: Option<Secret>,
+}
+
+impl Auth {
+    pub fn new(user: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            user: user.into(),
+            password: Secret::new(password),
+            password_file: None,
+            file_password: None,
+        }
+    }
+
+    /// The content of `password_file` if it is set, otherwise `password`
+    pub fn password(&self) -> &Secret {
+        self.file_password.as_ref().unwrap_or(&self.password)
+    }
+}
+
+/// A password or token, which `Debug` does not print
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(secret: impl Into<String>) -> Self {
+        Self(secret.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Reads the secret of the setting `key` from `path`, without trailing newlines
+    fn from_file(key: &str, path: &Path) -> Result<Self, ConfigError> {
+        let secret = std::fs::read_to_string(path).map_err(|e| {
+            ConfigError::Message(format!("{key}: could not read {}: {e}", path.display()))
+        })?;
+        Ok(Self::new(secret.trim_end_matches(['\n', '\r'])))
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// Limits of the connections and requests the server accepts. Durations are in seconds
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct ServerLimits {
+    /// Per worker, further connections wait until one is closed
+    pub max_connections: usize,
+    /// How long an idle connection is kept open
+    pub keep_alive: u64,
+    /// How long a client may take to send the request headers
+    pub client_request_timeout: u64,
+    /// Maximum size of a request body in bytes
+    pub max_request_body_size: usize,
+}
+
+impl Default for ServerLimits {
+    fn default() -> Self {
+        // the defaults of actix
+        Self {
+            max_connections: 25_000,
+            keep_alive: 5,
+            client_request_timeout: 5,
+            max_request_body_size: 256 * 1024,
+        }
+    }
+}
+
+impl ServerLimits {
+    pub fn keep_alive(&self) -> Duration {
+        Duration::from_secs(self.keep_alive)
+    }
+
+    pub fn client_request_timeout(&self) -> Duration {
+        Duration::from_secs(self.client_request_timeout)
+    }
+}
+
+/// Compression of responses for clients sending an `Accept-Encoding` header
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(default)]
+pub struct ServerCompression {
+    /// Compress NARs and narinfos with gzip, brotli or zstd, whichever the client prefers
+    pub enabled: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Store {
     pub path: PathBuf,
-    pub builders: Vec<Url>,
-    pub remotes: Vec<Url>,
+    #[serde(default)]
+    pub builders: Vec<Builder>,
+    /// Tried in the order of their priority
+    #[serde(default)]
+    pub remotes: Vec<RemoteConfig>,
     pub use_local_nix_daemon: bool,
+    /// Socket of the local Nix daemon, takes precedence over NIX_REMOTE
+    pub daemon_socket: Option<PathBuf>,
+    #[serde(default)]
+    pub daemon_timeouts: DaemonTimeouts,
+    #[serde(default)]
+    pub daemon_pool: DaemonPoolSettings,
+    /// Deprecated, added to `sign_key_paths`
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub sign_private_key_path: Option<PathBuf>,
+    /// Each package is signed with all of these keys, e.g. with the old and the new one while rotating
+    #[serde(default)]
+    pub sign_key_paths: Vec<PathBuf>,
+    /// Signs with an external program instead of the keys at `sign_key_paths`
+    pub signer: Option<Signer>,
+    /// Public keys `name:base64` of which a package from an upstream cache needs a signature.
+    /// The keys gachix signs with are trusted as well; empty accepts all packages
+    #[serde(default)]
+    pub trusted_public_keys: Vec<String>,
+    /// The Nix store directory of the cached paths; unset uses NIX_STORE_DIR or `/nix/store`
+    pub store_dir: Option<String>,
+    /// The references of a package are `<ref_namespace>/<hash>/...`, peers must use the same
+    #[serde(default = "Store::default_ref_namespace")]
+    pub ref_namespace: String,
+    /// Packages whose dependency chain is longer are not added
+    #[serde(default = "Store::default_max_closure_depth")]
+    pub max_closure_depth: usize,
+    /// Compression of the `.ls` listings stored with each package, `zstd` or `none`
+    #[serde(default = "Store::default_compression", with = "listing_compression")]
+    pub compression: Compression,
     pub ssh_private_key_path: Option<PathBuf>,
+    /// Checked for builder host keys in addition to ~/.ssh/known_hosts
+    pub known_hosts_file: Option<PathBuf>,
+    /// Trust builders whose host key is not known yet and remember their key
+    #[serde(default)]
+    pub accept_new_host_keys: bool,
+    /// Let the Nix daemons substitute packages none of them has, e.g. from cache.nixos.org
+    #[serde(default)]
+    pub allow_substitute: bool,
+    /// Also add the other outputs (e.g. `dev`, `man`) of a package's derivation
+    #[serde(default)]
+    pub all_outputs: bool,
+    /// Add the signatures of packages fetched from the local Nix daemon to its store as well
+    #[serde(default)]
+    pub sign_local_store: bool,
+    /// Files larger than this many bytes are split into chunks; unset disables chunking
+    pub chunk_threshold: Option<u64>,
+    #[serde(default)]
+    pub nar_limits: NarLimits,
+    /// Warn about packages with files whose names differ only by case
+    #[serde(default)]
+    pub warn_case_collisions: bool,
+    #[serde(default)]
+    pub git: GitSettings,
+    /// Record who added or signed which package on `refs/gachix/audit`
+    pub audit_log: bool,
+    #[serde(default)]
+    pub narinfo_cache: NarinfoCacheSettings,
+}
+
+impl Store {
+    /// Settings for a repository at `path` with the defaults of the config file
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            builders: vec![],
+            remotes: vec![],
+            use_local_nix_daemon: true,
+            daemon_socket: None,
+            daemon_timeouts: DaemonTimeouts::default(),
+            daemon_pool: DaemonPoolSettings::default(),
+            sign_private_key_path: None,
+            sign_key_paths: vec![],
+            signer: None,
+            trusted_public_keys: vec![],
+            store_dir: None,
+            ref_namespace: Self::default_ref_namespace(),
+            max_closure_depth: Self::default_max_closure_depth(),
+            compression: Self::default_compression(),
+            ssh_private_key_path: None,
+            known_hosts_file: None,
+            accept_new_host_keys: false,
+            allow_substitute: false,
+            all_outputs: false,
+            sign_local_store: false,
+            chunk_threshold: None,
+            nar_limits: NarLimits::default(),
+            warn_case_collisions: false,
+            git: GitSettings::default(),
+            audit_log: true,
+            narinfo_cache: NarinfoCacheSettings::default(),
+        }
+    }
+
+    fn default_ref_namespace() -> String {
+        "refs".to_string()
+    }
+
+    fn default_max_closure_depth() -> usize {
+        100
+    }
+
+    fn default_compression() -> Compression {
+        Compression::Zstd
+    }
+
+    /// `sign_key_paths` and the deprecated `sign_private_key_path`
+    pub fn all_sign_key_paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.sign_private_key_path
+            .iter()
+            .chain(&self.sign_key_paths)
+    }
+
+    pub fn trusted_keys(&self) -> Result<Vec<PublicKey>, SettingsIssue> {
+        self.trusted_public_keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| {
+                PublicKey::from_str(key).map_err(|e| {
+                    SettingsIssue::new(format!("store.trusted_public_keys[{i}]"), e.to_string())
+                })
+            })
+            .collect()
+    }
+
+    /// The namespace must be a valid reference prefix apart from the bookkeeping refs of gachix
+    pub fn check_ref_namespace(&self) -> Result<(), SettingsIssue> {
+        check_ref_namespace("store.ref_namespace", &self.ref_namespace)
+    }
+
+    /// The remotes in the order they are tried
+    pub fn remotes_by_priority(&self) -> Vec<RemoteConfig> {
+        let mut remotes = self.remotes.clone();
+        remotes.sort_by_key(|remote| remote.priority);
+        remotes
+    }
+}
+
+/// Whether `namespace`, the setting at `key`, is a valid reference prefix apart from the
+/// bookkeeping refs of gachix
+fn check_ref_namespace(key: &str, namespace: &str) -> Result<(), SettingsIssue> {
+    let valid = (namespace == "refs" || namespace.starts_with("refs/"))
+        && !namespace.ends_with('/')
+        && !namespace.contains(['*', ' ', ':', '?', '[', '\\', '^', '~'])
+        && !namespace.contains("..")
+        && !namespace.contains("//");
+    if !valid {
+        return Err(SettingsIssue::new(
+            key,
+            format!("'{namespace}' is not a reference prefix like refs or refs/packages"),
+        ));
+    }
+    if namespace == "refs/gachix" || namespace.starts_with("refs/gachix/") {
+        return Err(SettingsIssue::new(
+            key,
+            "refs/gachix is reserved for the bookkeeping of gachix",
+        ));
+    }
+    Ok(())
+}
+
+/// An external program creating the signatures, e.g. a wrapper around an HSM.
+/// It reads one fingerprint per line from stdin and writes one base64 signature per line to stdout.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Signer {
+    /// The program followed by its arguments
+    pub command: Vec<String>,
+    /// The public key `name:base64` of the key the program signs with
+    pub public_key: String,
+    /// Seconds the program may run for one batch of fingerprints
+    #[serde(default = "Signer::default_timeout")]
+    pub timeout: u64,
+}
+
+impl Signer {
+    fn default_timeout() -> u64 {
+        30
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+/// How the git object database stores objects
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct GitSettings {
+    /// zlib level (0-9) of objects rolled into packs; unset uses the git default
+    pub compression_level: Option<u32>,
+    /// Loose objects are rolled into a pack by `gachix maintenance repack` once there are more than this many
+    pub pack_threshold: usize,
+    /// The objects of an added package are written as one pack, unless they take up more than this
+    /// many bytes in memory; the rest is written loose. 0 writes all objects loose
+    pub ingest_pack_budget: u64,
+}
+
+impl Default for GitSettings {
+    fn default() -> Self {
+        Self {
+            compression_level: None,
+            // the default of git's gc.auto
+            pack_threshold: 6700,
+            ingest_pack_budget: 64 << 20,
+        }
+    }
+}
+
+/// The narinfos kept in memory after they were served, the least recently used are dropped first
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct NarinfoCacheSettings {
+    /// 0 disables the cache
+    pub max_entries: usize,
+    /// The narinfos in the cache take up at most this many bytes
+    pub max_bytes: usize,
+}
+
+impl Default for NarinfoCacheSettings {
+    fn default() -> Self {
+        Self {
+            max_entries: 10_000,
+            max_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// Limits on the structure of decoded NARs, which protect against malicious archives
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct NarLimits {
+    /// Maximum size of a single file in bytes
+    pub max_file_size: u64,
+    /// Maximum size of all files of an archive in bytes
+    pub max_total_size: u64,
+    /// Maximum nesting of directories
+    pub max_depth: usize,
+    /// Maximum number of directory entries of an archive
+    pub max_entries: u64,
+}
+
+impl Default for NarLimits {
+    fn default() -> Self {
+        Self {
+            max_file_size: 64 * 1024 * 1024 * 1024,
+            max_total_size: 256 * 1024 * 1024 * 1024,
+            max_depth: 256,
+            max_entries: 10_000_000,
+        }
+    }
+}
+
+/// Timeouts in seconds for operations on Nix daemons
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+#[serde(default)]
+pub struct DaemonTimeouts {
+    pub connect: u64,
+    /// Metadata queries, e.g. path info and validity
+    pub query: u64,
+    pub build: u64,
+    /// Maximum time without receiving any bytes while fetching a NAR
+    pub fetch_idle: u64,
+}
+
+impl Default for DaemonTimeouts {
+    fn default() -> Self {
+        Self {
+            connect: 10,
+            query: 30,
+            build: 6 * 60 * 60,
+            fetch_idle: 60,
+        }
+    }
+}
+
+impl DaemonTimeouts {
+    pub fn connect(&self) -> Duration {
+        Duration::from_secs(self.connect)
+    }
+
+    pub fn query(&self) -> Duration {
+        Duration::from_secs(self.query)
+    }
+
+    pub fn build(&self) -> Duration {
+        Duration::from_secs(self.build)
+    }
+
+    pub fn fetch_idle(&self) -> Duration {
+        Duration::from_secs(self.fetch_idle)
+    }
+}
+
+/// Limits of the connections kept open to each Nix daemon. Durations are in seconds
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+#[serde(default)]
+pub struct DaemonPoolSettings {
+    pub max_connections: usize,
+    /// Connections unused for this long are closed
+    pub idle_timeout: u64,
+    /// Maximum time to wait for a free connection when all are in use
+    pub acquire_timeout: u64,
+}
+
+impl Default for DaemonPoolSettings {
+    fn default() -> Self {
+        Self {
+            max_connections: 4,
+            idle_timeout: 300,
+            acquire_timeout: 30,
+        }
+    }
+}
+
+impl DaemonPoolSettings {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout)
+    }
+
+    pub fn acquire_timeout(&self) -> Duration {
+        Duration::from_secs(self.acquire_timeout)
+    }
+}
+
+/// A remote Nix daemon reachable over SSH, given as `[ssh://][user@]host[:port]`
+/// or as a structured entry with an optional `ssh_key_path`
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(try_from = "BuilderEntry")]
+pub struct Builder {
+    pub host: String,
+    /// Unset means the default of the transport, for OpenSSH the one from ssh_config
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    pub ssh_key_path: Option<PathBuf>,
+    /// Platforms (e.g. `x86_64-linux`) the builder can build for; empty means any platform
+    pub systems: Vec<String>,
+    pub transport: SshTransport,
+    /// Only accept this host key, given as `SHA256:<base64>` as printed by `ssh-keygen -l`
+    pub host_key_fingerprint: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SshTransport {
+    /// Built-in SSH client, ignores ssh_config
+    #[default]
+    Libssh2,
+    /// Runs `ssh <host> nix-daemon --stdio`, which honours ssh_config (aliases, ProxyJump, IdentityFile, ...)
+    Openssh,
+}
+
+impl Builder {
+    pub const DEFAULT_PORT: u16 = 22;
+    // the default user name for accessing remote ssh stores
+    // as specified in https://nix.dev/manual/nix/2.22/package-management/ssh-substituter
+    pub const DEFAULT_USER: &str = "nix-ssh";
+
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(Self::DEFAULT_PORT)
+    }
+
+    pub fn user(&self) -> &str {
+        self.user.as_deref().unwrap_or(Self::DEFAULT_USER)
+    }
+
+    pub fn supports_system(&self, system: &str) -> bool {
+        self.systems.is_empty() || self.systems.iter().any(|s| s == system)
+    }
+}
+
+impl FromStr for Builder {
+    type Err = anyhow::Error;
+
+    fn from_str(entry: &str) -> Result<Self, Self::Err> {
+        let parse = || -> anyhow::Result<Self> {
+            let address = if entry.contains("://") {
+                entry.to_string()
+            } else {
+                format!("ssh://{entry}")
+            };
+            let url = Url::parse(&address)?;
+            if url.scheme() != "ssh" {
+                return Err(anyhow!("unsupported scheme '{}'", url.scheme()));
+            }
+            // IPv6 literals are stored without their brackets, so they can be resolved as is
+            let host = match url.host() {
+                Some(Host::Ipv6(address)) => address.to_string(),
+                Some(host) => host.to_string(),
+                None => String::new(),
+            };
+            if host.is_empty() {
+                return Err(anyhow!("missing host"));
+            }
+            Ok(Self {
+                host,
+                port: url.port(),
+                user: match url.username() {
+                    "" => None,
+                    user => Some(user.to_string()),
+                },
+                ssh_key_path: None,
+                systems: Vec::new(),
+                transport: SshTransport::default(),
+                host_key_fingerprint: None,
+            })
+        };
+        parse().with_context(|| format!("Invalid builder entry '{entry}'"))
+    }
+}
+
+impl Display for Builder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.transport {
+            SshTransport::Libssh2 if self.host.contains(':') => {
+                write!(f, "{}@[{}]:{}", self.user(), self.host, self.port())
+            }
+            SshTransport::Libssh2 => write!(f, "{}@{}:{}", self.user(), self.host, self.port()),
+            SshTransport::Openssh => write!(f, "{}", self.host),
+        }
+    }
+}
+
+/// Written as a structured entry, which is read back as the same builder
+impl Serialize for Builder {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Entry<'a> {
+            host: String,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            port: Option<u16>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            user: Option<&'a str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            ssh_key_path: Option<&'a Path>,
+            #[serde(skip_serializing_if = "<[String]>::is_empty")]
+            systems: &'a [String],
+            transport: SshTransport,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            host_key_fingerprint: Option<&'a str>,
+        }
+        Entry {
+            // IPv6 literals need their brackets again to be parsed
+            host: if self.host.contains(':') {
+                format!("[{}]", self.host)
+            } else {
+                self.host.clone()
+            },
+            port: self.port,
+            user: self.user.as_deref(),
+            ssh_key_path: self.ssh_key_path.as_deref(),
+            systems: &self.systems,
+            transport: self.transport,
+            host_key_fingerprint: self.host_key_fingerprint.as_deref(),
+        }
+        .serialize(serializer)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BuilderEntry {
+    Address(String),
+    Structured {
+        host: String,
+        port: Option<u16>,
+        user: Option<String>,
+        ssh_key_path: Option<PathBuf>,
+        #[serde(default)]
+        systems: Vec<String>,
+        #[serde(default)]
+        transport: SshTransport,
+        host_key_fingerprint: Option<String>,
+    },
+}
+
+impl TryFrom<BuilderEntry> for Builder {
+    type Error = anyhow::Error;
+
+    fn try_from(entry: BuilderEntry) -> Result<Self, Self::Error> {
+        match entry {
+            BuilderEntry::Address(address) => address.parse(),
+            BuilderEntry::Structured {
+                host,
+                port,
+                user,
+                ssh_key_path,
+                systems,
+                transport,
+                host_key_fingerprint,
+            } => {
+                if host_key_fingerprint.is_some() && transport == SshTransport::Openssh {
+                    return Err(anyhow!(
+                        "Builder {}: host_key_fingerprint is not supported with the openssh transport, add the key to known_hosts instead",
+                        host
+                    ));
+                }
+                let mut builder: Builder = host.parse()?;
+                builder.port = port.or(builder.port);
+                builder.user = user.or(builder.user);
+                builder.ssh_key_path = ssh_key_path;
+                builder.systems = systems;
+                builder.transport = transport;
+                builder.host_key_fingerprint = host_key_fingerprint;
+                Ok(builder)
+            }
+        }
+    }
+}
+
+/// A peer packages are fetched from, given as its URL or as a structured entry
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(try_from = "RemoteEntry")]
+pub struct RemoteConfig {
+    /// Identifies the remote in the log, the host of the URL by default
+    pub name: String,
+    pub url: Url,
+    /// Remotes with a lower priority are tried first
+    pub priority: u32,
+    pub kind: RemoteKind,
+    /// The SSH key for git over SSH, `~/.ssh/id_ed25519` by default
+    pub ssh_key_path: Option<PathBuf>,
+    /// The environment variable holding the token for git over HTTP(S)
+    pub token_env: Option<String>,
+    /// Read when the settings are loaded, takes precedence over `token_env`
+    pub token_file: Option<PathBuf>,
+    /// The content of `token_file`
+    file_token: Option<Secret>,
+    /// Reserved for pushing packages to the remote, which is not supported yet
+    pub push: bool,
+    /// Where the remote keeps its packages, `store.ref_namespace` by default
+    pub ref_namespace: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RemoteKind {
+    /// The git repository of another gachix
+    #[default]
+    Git,
+    /// A Nix binary cache, which is only checked by `gachix doctor` for now
+    Http,
+}
+
+impl RemoteConfig {
+    pub const DEFAULT_PRIORITY: u32 = 50;
+
+    /// A git remote at `url`
+    pub fn new(url: Url) -> Self {
+        Self {
+            name: Self::default_name(&url),
+            url,
+            priority: Self::DEFAULT_PRIORITY,
+            kind: RemoteKind::default(),
+            ssh_key_path: None,
+            token_env: None,
+            token_file: None,
+            file_token: None,
+            push: false,
+            ref_namespace: None,
+        }
+    }
+
+    fn default_name(url: &Url) -> String {
+        match url.host_str() {
+            Some(host) if !host.is_empty() => host.to_string(),
+            _ => url.to_string(),
+        }
+    }
+
+    /// The content of `token_file`, otherwise the token of `token_env` if the variable is set
+    pub fn token(&self) -> Option<Secret> {
+        self.file_token.clone().or_else(|| {
+            self.token_env
+                .as_ref()
+                .and_then(|variable| std::env::var(variable).ok())
+                .map(Secret::new)
+        })
+    }
+}
+
+impl FromStr for RemoteConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(url).with_context(|| format!("Invalid remote URL '{url}'"))?;
+        Ok(Self::new(url))
+    }
+}
+
+impl Display for RemoteConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.name == Self::default_name(&self.url) {
+            write!(f, "{}", self.url)
+        } else {
+            write!(f, "{} ({})", self.name, self.url)
+        }
+    }
+}
+
+/// Written as the bare URL unless a setting differs from its default
+impl Serialize for RemoteConfig {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if *self == Self::new(self.url.clone()) {
+            return serializer.serialize_str(self.url.as_str());
+        }
+        #[derive(Serialize)]
+        struct Entry<'a> {
+            name: &'a str,
+            url: &'a str,
+            priority: u32,
+            kind: RemoteKind,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            ssh_key_path: Option<&'a Path>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            token_env: Option<&'a str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            token_file: Option<&'a Path>,
+            push: bool,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            ref_namespace: Option<&'a str>,
+        }
+        Entry {
+            name: &self.name,
+            url: self.url.as_str(),
+            priority: self.priority,
+            kind: self.kind,
+            ssh_key_path: self.ssh_key_path.as_deref(),
+            token_env: self.token_env.as_deref(),
+            token_file: self.token_file.as_deref(),
+            push: self.push,
+            ref_namespace: self.ref_namespace.as_deref(),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// The formats the `.ls` listings can be stored in, as named in narinfo files
+mod listing_compression {
+    use super::Compression;
+    use serde::{Deserialize, Deserializer, Serializer, de};
+
+    pub fn serialize<S: Serializer>(
+        compression: &Compression,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(compression.name())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Compression, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        match name.as_str() {
+            "zstd" => Ok(Compression::Zstd),
+            "none" => Ok(Compression::None),
+            _ => Err(de::Error::unknown_variant(&name, &["zstd", "none"])),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RemoteEntry {
+    Url(String),
+    Structured {
+        name: Option<String>,
+        url: String,
+        priority: Option<u32>,
+        #[serde(default)]
+        kind: RemoteKind,
+        #[serde(alias = "ssh_key")]
+        ssh_key_path: Option<PathBuf>,
+        token_env: Option<String>,
+        token_file: Option<PathBuf>,
+        #[serde(default)]
+        push: bool,
+        ref_namespace: Option<String>,
+    },
+}
+
+impl TryFrom<RemoteEntry> for RemoteConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(entry: RemoteEntry) -> Result<Self, Self::Error> {
+        match entry {
+            RemoteEntry::Url(url) => url.trim().parse(),
+            RemoteEntry::Structured {
+                name,
+                url,
+                priority,
+                kind,
+                ssh_key_path,
+                token_env,
+                token_file,
+                push,
+                ref_namespace,
+            } => {
+                let mut remote: RemoteConfig = url.trim().parse()?;
+                if let Some(name) = name {
+                    if name.trim().is_empty() {
+                        return Err(anyhow!("Remote {}: the name must not be empty", url));
+                    }
+                    remote.name = name.trim().to_string();
+                }
+                remote.priority = priority.unwrap_or(remote.priority);
+                remote.kind = kind;
+                remote.ssh_key_path = ssh_key_path;
+                remote.token_env = token_env;
+                remote.token_file = token_file;
+                remote.push = push;
+                remote.ref_namespace = ref_namespace;
+                Ok(remote)
+            }
+        }
+    }
+}
+
+/// Where traces are exported to, in addition to the log output
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Telemetry {
+    /// OTLP/HTTP endpoint receiving spans, e.g. `http://localhost:4318/v1/traces`.
+    /// Only used by builds with the `otlp` feature
+    pub otlp_endpoint: Option<String>,
+}
+
+/// How log events are formatted
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for log pipelines
+    Json,
+}
+
+/// The least severe events which are logged
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    #[default]
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    /// The directive of an `EnvFilter` logging this level
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Trace => "trace",
+            Self::Debug => "debug",
+            Self::Info => "info",
+            Self::Warn => "warn",
+            Self::Error => "error",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Log {
+    /// `RUST_LOG` takes precedence, e.g. to log a single module in more detail
+    pub level: LogLevel,
+    pub format: LogFormat,
+    /// Prefix each event with the time, which can be left out if e.g. journald adds it
+    pub timestamps: bool,
+    /// The server also writes its log to this file
+    pub file: Option<PathBuf>,
+    pub rotate: LogRotation,
+    /// Write the log to stdout as well
+    pub console: bool,
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Self {
+            level: LogLevel::default(),
+            format: LogFormat::default(),
+            timestamps: true,
+            file: None,
+            rotate: LogRotation::default(),
+            console: true,
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// When `log.file` is rolled over and how many of the old files are kept
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct LogRotation {
+    pub max_size_mb: u64,
+    pub max_files: usize,
+}
+
+impl Default for LogRotation {
+    fn default() -> Self {
+        Self {
+            max_size_mb: 100,
+            max_files: 5,
+        }
+    }
+}
+
+impl LogRotation {
+    pub fn max_size(&self) -> u64 {
+        self.max_size_mb * 1024 * 1024
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Settings {
     pub store: Store,
     pub server: Server,
-    pub log_level: String,
-}
-
-pub fn load_config(config_file: &str) -> Result<Settings, ConfigError> {
-    let defaults = r#"
-log_level: info
-store:
-    path: ./cache
-    builders: []
-    remotes: []
-    use_local_nix_daemon: true
-
-server:
-    host: localhost
-    port: 8080
-    "#;
-    let settings = Config::builder()
-        .add_source(File::from_str(defaults, config::FileFormat::Yaml).required(true))
-        .add_source(File::with_name(config_file).required(false))
-        .add_source(
-            Environment::with_prefix("GACHIX")
-                .separator("__")
-                .list_separator(",")
-                .with_list_parse_key("store.remotes")
-                .with_list_parse_key("store.builders")
-                .try_parsing(true),
-        )
+    /// Deprecated, replaced by `log.level`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<LogLevel>,
+    #[serde(default)]
+    pub log: Log,
+    #[serde(default)]
+    pub telemetry: Telemetry,
+    /// The files the settings were read from, later ones override earlier ones
+    #[serde(skip)]
+    pub config_files: Vec<PathBuf>,
+    /// The keys of the settings given on the command line
+    #[serde(skip)]
+    pub overrides: Vec<String>,
+}
+
+impl Default for Settings {
+    /// The settings if neither a config file nor the environment sets any
+    fn default() -> Self {
+        Self {
+            store: Store::new(PathBuf::from("./cache")),
+            server: Server::default(),
+            log_level: None,
+            log: Log::default(),
+            telemetry: Telemetry::default(),
+            config_files: Vec::new(),
+            overrides: Vec::new(),
+        }
+    }
+}
+
+/// The formats of config files
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+}
+
+impl ConfigFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Yaml => "yaml",
+            Self::Toml => "toml",
+        }
+    }
+
+    /// The format of a config file named `path`, if its extension is known
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "yaml" | "yml" => Some(Self::Yaml),
+            "toml" => Some(Self::Toml),
+            _ => None,
+        }
+    }
+}
+
+impl Settings {
+    /// A config file with every setting at its default value, explained by a comment
+    pub fn default_config_file(format: ConfigFormat) -> anyhow::Result<String> {
+        let settings = serde_json::to_value(Settings::default())?;
+        let mut config_file = String::from(
+            "# The configuration of Gachix, with every setting at its default value.\n\
+             # Unset settings are commented out.\n\n",
+        );
+        if let serde_json::Value::Object(settings) = settings {
+            config_file.push_str(&config_template::render(&settings, format));
+        }
+        Ok(config_file)
+    }
+
+    /// A config file with only the settings which differ from their default value
+    pub fn minimal_config_file(&self, format: ConfigFormat) -> anyhow::Result<String> {
+        let changed = config_template::changed(
+            &serde_json::to_value(self)?,
+            &serde_json::to_value(Settings::default())?,
+        );
+        let mut config_file =
+            String::from("# The settings of Gachix which differ from their default value.\n\n");
+        if let Some(serde_json::Value::Object(changed)) = changed {
+            config_file.push_str(&config_template::render(&changed, format));
+        }
+        Ok(config_file)
+    }
+}
+
+impl Settings {
+    /// Reads the secrets given as `*_file`, which take precedence over those given inline
+    fn read_secret_files(&mut self) -> Result<(), ConfigError> {
+        if let Some(auth) = &mut self.server.auth {
+            if let Some(path) = &auth.password_file {
+                auth.file_password = Some(Secret::from_file("server.auth.password_file", path)?);
+            } else if auth.password.is_empty() {
+                return Err(ConfigError::Message(
+                    "server.auth: either password or password_file must be set".to_string(),
+                ));
+            }
+        }
+        for (i, remote) in self.store.remotes.iter_mut().enumerate() {
+            if let Some(path) = &remote.token_file {
+                remote.file_token = Some(Secret::from_file(
+                    &format!("store.remotes[{i}].token_file"),
+                    path,
+                )?);
+            }
+        }
+        Ok(())
+    }
+
+    /// `new` with the settings which can only change on a restart kept as in `self`, and the
+    /// keys of those settings which `new` changes nevertheless. Reloaded are `log.level`,
+    /// `store.remotes`, the signing and the trusted keys, and `store.max_closure_depth`.
+    pub fn reloadable(&self, new: &Settings) -> anyhow::Result<(Settings, Vec<String>)> {
+        let mut reloaded = self.clone();
+        reloaded.config_files = new.config_files.clone();
+        reloaded.overrides = new.overrides.clone();
+        reloaded.log.level = new.log.level;
+        let store = &mut reloaded.store;
+        store.remotes = new.store.remotes.clone();
+        store.sign_private_key_path = new.store.sign_private_key_path.clone();
+        store.sign_key_paths = new.store.sign_key_paths.clone();
+        store.signer = new.store.signer.clone();
+        store.trusted_public_keys = new.store.trusted_public_keys.clone();
+        store.max_closure_depth = new.store.max_closure_depth;
+        let ignored = config_template::changed_keys(
+            &serde_json::to_value(new)?,
+            &serde_json::to_value(&reloaded)?,
+        );
+        Ok((reloaded, ignored))
+    }
+}
+
+/// A setting which can't work, found by `Settings::validate`
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("{key}: {message}")]
+pub struct SettingsIssue {
+    /// The key of the setting, e.g. `store.path`
+    pub key: String,
+    pub message: String,
+}
+
+impl SettingsIssue {
+    fn new(key: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            message: message.into(),
+        }
+    }
+}
+
+/// The schemes of git remotes which gachix can fetch from
+const REMOTE_SCHEMES: [&str; 5] = ["file", "git", "http", "https", "ssh"];
+
+impl Settings {
+    /// Checks the settings which would otherwise only fail once a command uses them
+    pub fn validate(&self) -> Result<(), Vec<SettingsIssue>> {
+        let mut issues = Vec::new();
+        let store = &self.store;
+
+        if let Err(message) = check_creatable_dir(&store.path) {
+            issues.push(SettingsIssue::new("store.path", message));
+        }
+        if self.server.port == 0 {
+            issues.push(SettingsIssue::new("server.port", "must not be 0"));
+        }
+        if self.server.workers == Some(0) {
+            issues.push(SettingsIssue::new("server.workers", "must not be 0"));
+        }
+        if self.server.limits.max_connections == 0 {
+            issues.push(SettingsIssue::new(
+                "server.limits.max_connections",
+                "must not be 0",
+            ));
+        }
+        if let Some(auth) = &self.server.auth
+            && (auth.user.is_empty() || auth.user.contains(':'))
+        {
+            issues.push(SettingsIssue::new(
+                "server.auth.user",
+                "must be non-empty and must not contain ':'",
+            ));
+        }
+
+        for (i, builder) in store.builders.iter().enumerate() {
+            if let Some(fingerprint) = &builder.host_key_fingerprint
+                && !fingerprint.starts_with("SHA256:")
+            {
+                issues.push(SettingsIssue::new(
+                    format!("store.builders[{i}].host_key_fingerprint"),
+                    format!("'{fingerprint}' is not of the form SHA256:<base64>"),
+                ));
+            }
+            if let Some(key_path) = &builder.ssh_key_path
+                && let Err(message) = check_readable_file(key_path)
+            {
+                issues.push(SettingsIssue::new(
+                    format!("store.builders[{i}].ssh_key_path"),
+                    message,
+                ));
+            }
+        }
+
+        for (i, remote_config) in store.remotes.iter().enumerate() {
+            let key = format!("store.remotes[{i}]");
+            if store.remotes[..i]
+                .iter()
+                .any(|other| other.name == remote_config.name)
+            {
+                issues.push(SettingsIssue::new(
+                    format!("{key}.name"),
+                    format!("'{}' is the name of another remote", remote_config.name),
+                ));
+            }
+            if remote_config.push {
+                issues.push(SettingsIssue::new(
+                    format!("{key}.push"),
+                    "pushing to remotes is not supported yet",
+                ));
+            }
+            if let Some(key_path) = &remote_config.ssh_key_path
+                && let Err(message) = check_readable_file(key_path)
+            {
+                issues.push(SettingsIssue::new(format!("{key}.ssh_key_path"), message));
+            }
+            if let Some(namespace) = &remote_config.ref_namespace
+                && let Err(issue) = check_ref_namespace(&format!("{key}.ref_namespace"), namespace)
+            {
+                issues.push(issue);
+            }
+            let remote = &remote_config.url;
+            if remote_config.kind == RemoteKind::Http {
+                if !matches!(remote.scheme(), "http" | "https") {
+                    issues.push(SettingsIssue::new(
+                        key,
+                        format!("'{remote}' is a binary cache, which needs an http or https URL"),
+                    ));
+                }
+            } else if !REMOTE_SCHEMES.contains(&remote.scheme()) {
+                issues.push(SettingsIssue::new(
+                    key,
+                    format!(
+                        "'{}' has the unsupported scheme '{}', use one of {}",
+                        remote,
+                        remote.scheme(),
+                        REMOTE_SCHEMES.join(", ")
+                    ),
+                ));
+            } else if remote.scheme() == "file" {
+                match remote.to_file_path() {
+                    Ok(path) if path.is_dir() => {}
+                    _ => issues.push(SettingsIssue::new(
+                        key,
+                        format!("'{remote}' is not an existing directory"),
+                    )),
+                }
+            } else if remote.host_str().is_none_or(str::is_empty) {
+                issues.push(SettingsIssue::new(key, format!("'{remote}' has no host")));
+            }
+        }
+
+        if store.all_sign_key_paths().next().is_some() && store.signer.is_some() {
+            issues.push(SettingsIssue::new(
+                "store.signer",
+                "only one of store.sign_key_paths and store.signer can be set",
+            ));
+        }
+        if let Some(key_path) = &store.sign_private_key_path
+            && let Err(message) = check_readable_file(key_path)
+        {
+            issues.push(SettingsIssue::new("store.sign_private_key_path", message));
+        }
+        for (i, key_path) in store.sign_key_paths.iter().enumerate() {
+            if let Err(message) = check_readable_file(key_path) {
+                issues.push(SettingsIssue::new(
+                    format!("store.sign_key_paths[{i}]"),
+                    message,
+                ));
+            }
+        }
+        if let Err(issue) = store.trusted_keys() {
+            issues.push(issue);
+        }
+        if let Some(store_dir) = &store.store_dir
+            && !store_dir.starts_with('/')
+        {
+            issues.push(SettingsIssue::new(
+                "store.store_dir",
+                format!("'{store_dir}' is not an absolute path"),
+            ));
+        }
+        if let Err(issue) = store.check_ref_namespace() {
+            issues.push(issue);
+        }
+        if store.max_closure_depth == 0 {
+            issues.push(SettingsIssue::new(
+                "store.max_closure_depth",
+                "must not be 0",
+            ));
+        }
+        if let Some(key_path) = &store.ssh_private_key_path
+            && let Err(message) = check_readable_file(key_path)
+        {
+            issues.push(SettingsIssue::new("store.ssh_private_key_path", message));
+        }
+        if let Some(signer) = &store.signer {
+            if signer.command.is_empty() {
+                issues.push(SettingsIssue::new(
+                    "store.signer.command",
+                    "must not be empty",
+                ));
+            }
+            if let Err(e) = PublicKey::from_str(&signer.public_key) {
+                issues.push(SettingsIssue::new("store.signer.public_key", e.to_string()));
+            }
+        }
+        if store.sign_local_store && !store.use_local_nix_daemon {
+            issues.push(SettingsIssue::new(
+                "store.sign_local_store",
+                "requires store.use_local_nix_daemon",
+            ));
+        }
+
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(issues)
+        }
+    }
+}
+
+/// Whether a directory exists at `path` or can be created there
+fn check_creatable_dir(path: &Path) -> Result<(), String> {
+    let absolute = std::path::absolute(path).map_err(|e| e.to_string())?;
+    // A relative path is resolved against the working directory, which may be surprising
+    let shown = absolute.display();
+    let Some(existing) = absolute.ancestors().find(|a| a.exists()) else {
+        return Err(format!("no parent of {shown} exists"));
+    };
+    if !existing.is_dir() {
+        return Err(format!("{} is not a directory", existing.display()));
+    }
+    if existing != absolute
+        && existing
+            .metadata()
+            .is_ok_and(|metadata| metadata.permissions().readonly())
+    {
+        return Err(format!(
+            "{shown} can't be created, {} is read-only",
+            existing.display()
+        ));
+    }
+    Ok(())
+}
+
+fn check_readable_file(path: &Path) -> Result<(), String> {
+    std::fs::File::open(path)
+        .map(|_| ())
+        .map_err(|e| format!("{} can't be read: {}", path.display(), e))
+}
+
+/// Reads the settings from `config_file`, or if it is not given from the config files found
+/// in the default locations. Environment variables override the files and `overrides` of the
+/// command line override both.
+pub fn load_config(
+    config_file: Option<&Path>,
+    overrides: &[Override],
+) -> Result<Settings, ConfigError> {
+    let config_files = match config_file {
+        Some(config_file) => vec![explicit_config_file(config_file)?],
+        None => find_config_files(&config_dirs()),
+    };
+    load(&config_files, environment(None), overrides).map_err(|e| match (e, config_file) {
+        // The config crate names the file relative to the working directory
+        (ConfigError::FileParse { cause, .. }, Some(config_file)) => ConfigError::Message(format!(
+            "Config file {} can't be parsed: {cause}",
+            config_file.display()
+        )),
+        (e, _) => e,
+    })
+}
+
+/// A config file the user asked for must exist, unlike those in the default locations
+fn explicit_config_file(config_file: &Path) -> Result<PathBuf, ConfigError> {
+    match std::fs::metadata(config_file) {
+        Ok(metadata) if metadata.is_file() => Ok(config_file.to_path_buf()),
+        Ok(_) => Err(ConfigError::Message(format!(
+            "Config file {} is not a file",
+            config_file.display()
+        ))),
+        Err(e) => Err(ConfigError::Message(format!(
+            "Config file {} can't be read: {e}",
+            config_file.display()
+        ))),
+    }
+}
+
+/// The directories searched for a config file, in increasing precedence:
+/// `/etc/gachix` and `$XDG_CONFIG_HOME/gachix` (`~/.config/gachix` by default)
+fn config_dirs() -> Vec<PathBuf> {
+    std::iter::once(PathBuf::from("/etc"))
+        .chain(user_config_dir())
+        .map(|dir| dir.join("gachix"))
+        .collect()
+}
+
+fn user_config_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| dirs::home_dir().map(|home| home.join(".config")))
+}
+
+/// The config file of `format` which the user's settings are read from,
+/// `$XDG_CONFIG_HOME/gachix/config.<format>`
+pub fn user_config_file(format: ConfigFormat) -> Option<PathBuf> {
+    user_config_dir().map(|dir| dir.join("gachix/config").with_extension(format.extension()))
+}
+
+/// The `config.yaml` and `config.toml` files in `dirs`, in the order they are applied
+fn find_config_files(dirs: &[PathBuf]) -> Vec<PathBuf> {
+    dirs.iter()
+        .flat_map(|dir| {
+            ["yaml", "toml"].map(|extension| dir.join("config").with_extension(extension))
+        })
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+/// The settings which are lists, given as comma separated values in the environment
+const LIST_KEYS: [&str; 5] = [
+    "store.builders",
+    "store.remotes",
+    "store.sign_key_paths",
+    "store.trusted_public_keys",
+    "store.signer.command",
+];
+
+/// The variables overriding the config, e.g. `GACHIX__LOG__LEVEL` for `log.level`.
+/// `variables` replaces the variables of the process, e.g. in tests.
+fn environment(variables: Option<Map<String, String>>) -> ListEnvironment {
+    ListEnvironment(
+        Environment::with_prefix("GACHIX")
+            .separator("__")
+            .try_parsing(true)
+            .source(variables),
+    )
+}
+
+/// The environment with the values of `LIST_KEYS` split into lists. Unlike with the list
+/// separator of `Environment`, elements can contain commas escaped as `\,`
+#[derive(Debug, Clone)]
+struct ListEnvironment(Environment);
+
+impl Source for ListEnvironment {
+    fn clone_into_box(&self) -> Box<dyn Source + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn collect(&self) -> Result<Map<String, Value>, ConfigError> {
+        let mut values = self.0.collect()?;
+        for key in LIST_KEYS {
+            if let Some(value) = values.remove(key) {
+                let elements = split_list(&value.into_string()?);
+                values.insert(key.to_string(), Value::from(elements));
+            }
+        }
+        Ok(values)
+    }
+}
+
+/// Splits at commas which are not escaped as `\,`, `\\` is a backslash. Elements are trimmed
+fn split_list(value: &str) -> Vec<String> {
+    if value.trim().is_empty() {
+        return Vec::new();
+    }
+    let mut elements = vec![String::new()];
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        let element = elements.last_mut().unwrap();
+        match c {
+            '\\' => match chars.next() {
+                Some(escaped @ (',' | '\\')) => element.push(escaped),
+                Some(other) => {
+                    element.push('\\');
+                    element.push(other);
+                }
+                None => element.push('\\'),
+            },
+            ',' => elements.push(String::new()),
+            c => element.push(c),
+        }
+    }
+    elements
+        .into_iter()
+        .map(|element| element.trim().to_string())
+        .collect()
+}
+
+/// A setting given on the command line, as with `--set store.compression=zstd`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Override {
+    /// The dotted path of the setting
+    pub key: String,
+    pub value: String,
+}
+
+impl Override {
+    pub fn new(key: impl Into<String>, value: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            value: value.into(),
+        }
+    }
+}
+
+impl FromStr for Override {
+    type Err = anyhow::Error;
+
+    fn from_str(setting: &str) -> Result<Self, Self::Err> {
+        let (key, value) = setting
+            .split_once('=')
+            .ok_or_else(|| anyhow!("'{setting}' is not of the form key=value"))?;
+        let key = key.trim().to_lowercase();
+        if !is_setting(&key) {
+            return Err(anyhow!("'{key}' is not a setting"));
+        }
+        Ok(Self::new(key, value.trim()))
+    }
+}
+
+/// Whether `key` is the dotted path of a setting or of a value inside an unset section
+fn is_setting(key: &str) -> bool {
+    let Ok(mut value) = serde_json::to_value(Settings::default()) else {
+        return false;
+    };
+    for part in key.split('.') {
+        value = match value {
+            serde_json::Value::Object(mut table) => match table.remove(part) {
+                Some(value) => value,
+                None => return false,
+            },
+            // e.g. `server.auth.user`, the section is null by default
+            serde_json::Value::Null => return true,
+            _ => return false,
+        };
+    }
+    true
+}
+
+/// The overrides of the command line, with the values of `LIST_KEYS` split like in the
+/// environment. They take precedence over all other sources
+#[derive(Debug, Clone, Default)]
+struct CommandLine(Vec<Override>);
+
+impl Source for CommandLine {
+    fn clone_into_box(&self) -> Box<dyn Source + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn collect(&self) -> Result<Map<String, Value>, ConfigError> {
+        let origin = "cli".to_string();
+        Ok(self
+            .0
+            .iter()
+            .map(|Override { key, value }| {
+                let value = if LIST_KEYS.contains(&key.as_str()) {
+                    ValueKind::from(split_list(value))
+                } else {
+                    ValueKind::from(value.as_str())
+                };
+                (key.clone(), Value::new(Some(&origin), value))
+            })
+            .collect())
+    }
+}
+
+fn load(
+    config_files: &[PathBuf],
+    environment: ListEnvironment,
+    overrides: &[Override],
+) -> Result<Settings, ConfigError> {
+    // The defaults are those of the structs, so they are the same as in `config init`
+    let defaults = Config::try_from(&Settings::default())?;
+    let mut builder = Config::builder().add_source(defaults);
+    // The format is detected from the extension. The files were found or checked to exist,
+    // so one which disappeared since or doesn't parse is an error
+    for config_file in config_files {
+        builder = builder.add_source(File::from(config_file.as_path()).required(true));
+    }
+    let settings = builder
+        .add_source(environment)
+        .add_source(CommandLine(overrides.to_vec()))
         .build()?;
-    settings.try_deserialize()
+    let mut settings: Settings = settings.try_deserialize()?;
+    settings.read_secret_files()?;
+    settings.config_files = config_files.to_vec();
+    for Override { key, .. } in overrides {
+        if !settings.overrides.contains(key) {
+            settings.overrides.push(key.clone());
+        }
+    }
+    if let Some(level) = settings.log_level.take() {
+        settings.log.level = level;
+    }
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables(variables: &[(&str, &str)]) -> ListEnvironment {
+        let variables: Map<String, String> = variables
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        environment(Some(variables))
+    }
+
+    #[test]
+    fn test_log_settings() -> anyhow::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let config_file = temp_dir.path().join("gachix.yaml");
+
+        let settings = load(&[], variables(&[]), &[])?;
+        assert_eq!(settings.log.level, LogLevel::Info);
+        assert!(settings.log.timestamps);
+
+        std::fs::write(&config_file, "log:\n  level: debug\n  timestamps: false\n")?;
+        let config_file = [config_file];
+        let settings = load(&config_file, variables(&[]), &[])?;
+        assert_eq!(settings.log.level, LogLevel::Debug);
+        assert!(!settings.log.timestamps);
+        assert_eq!(settings.log.format, LogFormat::Text);
+
+        let settings = load(
+            &config_file,
+            variables(&[("GACHIX__LOG__LEVEL", "error")]),
+            &[],
+        )?;
+        assert_eq!(settings.log.level, LogLevel::Error);
+
+        // the deprecated top-level key still works
+        let settings = load(&[], variables(&[("GACHIX__LOG_LEVEL", "warn")]), &[])?;
+        assert_eq!(settings.log.level, LogLevel::Warn);
+
+        let error = load(&[], variables(&[("GACHIX__LOG__LEVEL", "verbose")]), &[]).unwrap_err();
+        assert!(error.to_string().contains("verbose"), "{error}");
+        assert_eq!(error.to_string().lines().count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_config_files() -> anyhow::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let system_dir = temp_dir.path().join("etc/gachix");
+        let user_dir = temp_dir.path().join("home/.config/gachix");
+        std::fs::create_dir_all(&system_dir)?;
+        std::fs::create_dir_all(&user_dir)?;
+        let dirs = [system_dir.clone(), user_dir.clone()];
+        assert!(find_config_files(&dirs).is_empty());
+
+        std::fs::write(
+            system_dir.join("config.yaml"),
+            "server:\n  port: 9000\nlog:\n  level: debug\n",
+        )?;
+        std::fs::write(user_dir.join("config.toml"), "[log]\nlevel = \"warn\"\n")?;
+        let config_files = find_config_files(&dirs);
+        assert_eq!(
+            config_files,
+            [system_dir.join("config.yaml"), user_dir.join("config.toml")]
+        );
+
+        // the user's config overrides the system's, the environment overrides both
+        let settings = load(&config_files, variables(&[]), &[])?;
+        assert_eq!(settings.server.port, 9000);
+        assert_eq!(settings.log.level, LogLevel::Warn);
+        assert_eq!(settings.config_files, config_files);
+        let settings = load(
+            &config_files,
+            variables(&[("GACHIX__SERVER__PORT", "9001")]),
+            &[],
+        )?;
+        assert_eq!(settings.server.port, 9001);
+        Ok(())
+    }
+
+    #[test]
+    fn test_explicit_config_file() -> anyhow::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let typo = temp_dir.path().join("prdo.yaml");
+        let error = load_config(Some(&typo), &[]).unwrap_err().to_string();
+        assert!(error.contains(&typo.display().to_string()), "{error}");
+        assert!(error.contains("No such file"), "{error}");
+        let error = load_config(Some(temp_dir.path()), &[]).unwrap_err();
+        assert!(error.to_string().contains("is not a file"), "{error}");
+
+        let invalid = temp_dir.path().join("invalid.yaml");
+        std::fs::write(&invalid, "server: [port\n")?;
+        let error = load_config(Some(&invalid), &[]).unwrap_err();
+        assert!(
+            error.to_string().contains(&invalid.display().to_string()),
+            "{error}"
+        );
+
+        let config_file = temp_dir.path().join("prod.yaml");
+        std::fs::write(&config_file, "server:\n  port: 9000\n")?;
+        let settings = load_config(Some(&config_file), &[])?;
+        assert_eq!(settings.server.port, 9000);
+        assert_eq!(settings.config_files, [config_file]);
+
+        // without any config file the defaults apply
+        assert!(find_config_files(&[temp_dir.path().join("gachix")]).is_empty());
+        let settings = load(&[], variables(&[]), &[])?;
+        assert!(settings.config_files.is_empty());
+        assert_eq!(settings.server.port, Server::default().port);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_file() -> anyhow::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let defaults = serde_json::to_value(Settings::default())?;
+        let mut changed = Settings::default();
+        changed.server.port = 9000;
+        changed.store.remotes = vec![
+            "https://cache.example.org".parse()?,
+            RemoteConfig {
+                priority: 10,
+                token_env: Some("GACHIX_TOKEN".to_string()),
+                ..RemoteConfig::new(Url::parse("https://other.example.org/cache.git")?)
+            },
+        ];
+        changed.store.builders = vec!["[::1]:2222".parse()?];
+        changed.log.file = Some(PathBuf::from("/var/log/gachix.log"));
+
+        for format in [ConfigFormat::Yaml, ConfigFormat::Toml] {
+            let config_file = [temp_dir
+                .path()
+                .join("config")
+                .with_extension(format.extension())];
+            let contents = Settings::default_config_file(format)?;
+            assert!(contents.contains("# The port under which Gachix listens"));
+            std::fs::write(&config_file[0], contents)?;
+            let settings = load(&config_file, variables(&[]), &[])?;
+            assert_eq!(serde_json::to_value(&settings)?, defaults);
+
+            let contents = changed.minimal_config_file(format)?;
+            assert!(contents.contains("9000"), "{contents}");
+            // the default priority is left out, the comments mention the setting
+            assert!(
+                !contents.contains("priority: 50") && !contents.contains("priority = 50"),
+                "{contents}"
+            );
+            std::fs::write(&config_file[0], contents)?;
+            let settings = load(&config_file, variables(&[]), &[])?;
+            assert_eq!(
+                serde_json::to_value(&settings)?,
+                serde_json::to_value(&changed)?
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_reloadable() -> anyhow::Result<()> {
+        let current = Settings::default();
+        let mut new = Settings::default();
+        new.log.level = LogLevel::Debug;
+        new.store.sign_key_paths = vec![PathBuf::from("/etc/gachix/key")];
+        new.store.remotes = vec!["https://cache.example.org".parse()?];
+        let (reloaded, ignored) = current.reloadable(&new)?;
+        assert!(ignored.is_empty(), "{ignored:?}");
+        assert_eq!(
+            serde_json::to_value(&reloaded)?,
+            serde_json::to_value(&new)?
+        );
+
+        new.store.path = PathBuf::from("/srv/gachix");
+        new.server.port = 9000;
+        new.server.limits.keep_alive = 10;
+        let (reloaded, ignored) = current.reloadable(&new)?;
+        assert_eq!(
+            ignored,
+            ["server.limits.keep_alive", "server.port", "store.path"]
+        );
+        assert_eq!(reloaded.store.path, current.store.path);
+        assert_eq!(reloaded.server.port, 8080);
+        assert_eq!(reloaded.log.level, LogLevel::Debug);
+        Ok(())
+    }
+
+    /// The keys of the issues `validate` finds in the settings of `config`, in which `$DIR` is
+    /// replaced by a temporary directory
+    fn issue_keys(config: &str) -> anyhow::Result<Vec<String>> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let config_file = temp_dir.path().join("gachix.yaml");
+        // a regular file, e.g. for keys
+        std::fs::write(temp_dir.path().join("file"), "")?;
+        std::fs::write(
+            &config_file,
+            config.replace("$DIR", &temp_dir.path().to_string_lossy()),
+        )?;
+        let settings = load(&[config_file], variables(&[]), &[])?;
+        Ok(settings
+            .validate()
+            .err()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|issue| issue.key)
+            .collect())
+    }
+
+    #[test]
+    fn test_validate() -> anyhow::Result<()> {
+        let valid = "store:\n  path: $DIR/new/cache\n  remotes: [https://example.org/cache.git, file://$DIR]\n";
+        assert!(issue_keys(valid)?.is_empty());
+
+        assert_eq!(
+            issue_keys("store:\n  path: $DIR/file/cache\n")?,
+            ["store.path"]
+        );
+        assert_eq!(issue_keys("server:\n  port: 0\n")?, ["server.port"]);
+        assert_eq!(
+            issue_keys("server:\n  workers: 0\n  limits:\n    max_connections: 0\n")?,
+            ["server.workers", "server.limits.max_connections"]
+        );
+        assert_eq!(
+            issue_keys("server:\n  auth:\n    user: 'a:b'\n    password: secret\n")?,
+            ["server.auth.user"]
+        );
+        assert_eq!(
+            issue_keys(
+                "store:\n  builders:\n    - host: build.example.org\n      host_key_fingerprint: MD5:ab\n      ssh_key_path: $DIR/missing\n"
+            )?,
+            [
+                "store.builders[0].host_key_fingerprint",
+                "store.builders[0].ssh_key_path"
+            ]
+        );
+        assert_eq!(
+            issue_keys("store:\n  remotes: [ftp://example.org/cache, file://$DIR/missing]\n")?,
+            ["store.remotes[0]", "store.remotes[1]"]
+        );
+        assert_eq!(
+            issue_keys(
+                "store:\n  remotes:\n    - https://a.example.org/cache.git\n    - url: https://a.example.org/other.git\n      push: true\n      ref_namespace: refs/gachix\n    - url: ssh://b.example.org/cache.git\n      kind: http\n      ssh_key_path: $DIR/missing\n"
+            )?,
+            [
+                "store.remotes[1].name",
+                "store.remotes[1].push",
+                "store.remotes[1].ref_namespace",
+                "store.remotes[2].ssh_key_path",
+                "store.remotes[2]"
+            ]
+        );
+        assert_eq!(
+            issue_keys("store:\n  sign_private_key_path: $DIR/missing\n")?,
+            ["store.sign_private_key_path"]
+        );
+        assert_eq!(
+            issue_keys(
+                "store:\n  sign_private_key_path: $DIR/file\n  signer:\n    command: []\n    public_key: invalid\n"
+            )?,
+            [
+                "store.signer",
+                "store.signer.command",
+                "store.signer.public_key"
+            ]
+        );
+        assert_eq!(
+            issue_keys("store:\n  use_local_nix_daemon: false\n  sign_local_store: true\n")?,
+            ["store.sign_local_store"]
+        );
+        assert_eq!(
+            issue_keys(
+                "store:\n  sign_key_paths: [$DIR/file, $DIR/missing]\n  trusted_public_keys: [invalid]\n  store_dir: nix/store\n  ref_namespace: packages\n  max_closure_depth: 0\n"
+            )?,
+            [
+                "store.sign_key_paths[1]",
+                "store.trusted_public_keys[0]",
+                "store.store_dir",
+                "store.ref_namespace",
+                "store.max_closure_depth"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_server_settings() -> anyhow::Result<()> {
+        // configs with only the original keys keep working
+        let settings = load(&[], variables(&[]), &[])?;
+        let server = settings.server;
+        assert_eq!((server.host.as_str(), server.port), ("localhost", 8080));
+        assert_eq!(server.priority, 50);
+        assert!(!server.want_mass_query);
+        assert_eq!(server.workers, None);
+        assert_eq!(server.auth, None);
+        assert_eq!(server.limits, ServerLimits::default());
+        assert_eq!(server.compression, ServerCompression::default());
+
+        let temp_dir = tempfile::TempDir::new()?;
+        let config_file = temp_dir.path().join("gachix.yaml");
+        std::fs::write(
+            &config_file,
+            "server:
+  host: 0.0.0.0
+  port: 80
+  workers: 2
+  auth:
+    user: nix
+    password: secret
+  limits:
+    max_connections: 100
+    keep_alive: 30
+  compression:
+    enabled: true
+",
+        )?;
+        let config_file = [config_file];
+        let server = load(&config_file, variables(&[]), &[])?.server;
+        assert_eq!(server.workers, Some(2));
+        assert_eq!(server.auth, Some(Auth::new("nix", "secret")));
+        assert_eq!(server.limits.max_connections, 100);
+        assert_eq!(server.limits.keep_alive(), Duration::from_secs(30));
+        // unset keys of a section keep their default
+        assert_eq!(server.limits.client_request_timeout, 5);
+        assert_eq!(server.limits.max_request_body_size, 256 * 1024);
+        assert!(server.compression.enabled);
+
+        // nested keys are separated by `__` in the environment
+        let server = load(
+            &config_file,
+            variables(&[
+                ("GACHIX__SERVER__WORKERS", "8"),
+                ("GACHIX__SERVER__AUTH__PASSWORD", "other"),
+                ("GACHIX__SERVER__LIMITS__MAX_REQUEST_BODY_SIZE", "1024"),
+                ("GACHIX__SERVER__COMPRESSION__ENABLED", "false"),
+            ]),
+            &[],
+        )?
+        .server;
+        assert_eq!(server.workers, Some(8));
+        assert_eq!(server.auth.unwrap().password().expose(), "other");
+        assert_eq!(server.limits.max_request_body_size, 1024);
+        assert_eq!(server.limits.max_connections, 100);
+        assert!(!server.compression.enabled);
+
+        // a section missing a required key is an error
+        let error = load(
+            &[],
+            variables(&[("GACHIX__SERVER__AUTH__USER", "nix")]),
+            &[],
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("password"), "{error}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_overrides() -> anyhow::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let config_file = [temp_dir.path().join("config.yaml")];
+        std::fs::write(
+            &config_file[0],
+            "server:\n  port: 9000\n  priority: 30\nstore:\n  compression: none\n",
+        )?;
+        let overrides: Vec<Override> = [
+            "server.port=9002",
+            "store.remotes=https://a.example.org, https://b.example.org",
+            "STORE.Compression = zstd",
+            "server.port=9003",
+            "server.auth.user=nix",
+            "server.auth.password=secret",
+        ]
+        .iter()
+        .map(|setting| setting.parse())
+        .collect::<anyhow::Result<_>>()?;
+
+        // the command line wins over the environment, which wins over the file
+        let settings = load(
+            &config_file,
+            variables(&[
+                ("GACHIX__SERVER__PORT", "9001"),
+                ("GACHIX__SERVER__PRIORITY", "20"),
+            ]),
+            &overrides,
+        )?;
+        assert_eq!(settings.server.port, 9003);
+        assert_eq!(settings.server.priority, 20);
+        assert_eq!(settings.store.compression, Compression::Zstd);
+        assert_eq!(settings.store.remotes.len(), 2);
+        assert_eq!(settings.server.auth.unwrap().user, "nix");
+        assert_eq!(
+            settings.overrides,
+            [
+                "server.port",
+                "store.remotes",
+                "store.compression",
+                "server.auth.user",
+                "server.auth.password"
+            ]
+        );
+
+        for invalid in ["server.port", "server.prot=1", "server.port.number=1", "=1"] {
+            assert!(invalid.parse::<Override>().is_err(), "{invalid}");
+        }
+        // the value must still have the type of the setting
+        let overrides = ["server.port=many".parse()?];
+        assert!(load(&[], variables(&[]), &overrides).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_secret_files() -> anyhow::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let password_file = temp_dir.path().join("password");
+        let token_file = temp_dir.path().join("token");
+        std::fs::write(&password_file, "from-file\n")?;
+        std::fs::write(&token_file, "token-from-file\r\n")?;
+        let config_file = [temp_dir.path().join("config.yaml")];
+        std::fs::write(
+            &config_file[0],
+            format!(
+                "server:\n  auth:\n    user: nix\n    password: inline\n    password_file: {}\nstore:\n  remotes:\n    - url: https://a.example.org/cache.git\n      token_env: GACHIX_TEST_SECRET_FILES_TOKEN\n      token_file: {}\n",
+                password_file.display(),
+                token_file.display()
+            ),
+        )?;
+
+        // the file wins over the inline password and the environment variable
+        let settings = load(&config_file, variables(&[]), &[])?;
+        let auth = settings.server.auth.as_ref().unwrap();
+        assert_eq!(auth.password().expose(), "from-file");
+        assert_eq!(
+            settings.store.remotes[0].token().unwrap().expose(),
+            "token-from-file"
+        );
+
+        // neither `config show` nor the serialized settings contain the read secrets
+        let shown = format!("{settings:#?}");
+        assert!(
+            !shown.contains("inline") && !shown.contains("from-file"),
+            "{shown}"
+        );
+        let serialized = serde_json::to_string(&settings)?;
+        assert!(!serialized.contains("from-file"), "{serialized}");
+
+        // without the file the inline password is used
+        let settings = load(
+            &[],
+            variables(&[
+                ("GACHIX__SERVER__AUTH__USER", "nix"),
+                ("GACHIX__SERVER__AUTH__PASSWORD", "inline"),
+            ]),
+            &[],
+        )?;
+        assert_eq!(settings.server.auth.unwrap().password().expose(), "inline");
+
+        std::fs::remove_file(&password_file)?;
+        let error = load(&config_file, variables(&[]), &[]).unwrap_err();
+        assert!(
+            error.to_string().contains("server.auth.password_file"),
+            "{error}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_settings() -> anyhow::Result<()> {
+        let store = load(&[], variables(&[]), &[])?.store;
+        assert_eq!(store.all_sign_key_paths().count(), 0);
+        assert!(store.trusted_keys()?.is_empty());
+        assert_eq!(store.store_dir, None);
+        assert_eq!(store.ref_namespace, "refs");
+        assert_eq!(store.max_closure_depth, 100);
+        assert_eq!(store.compression, Compression::Zstd);
+        assert_eq!(store.nar_limits, NarLimits::default());
+
+        let temp_dir = tempfile::TempDir::new()?;
+        let config_file = temp_dir.path().join("gachix.yaml");
+        std::fs::write(
+            &config_file,
+            "store:
+  sign_private_key_path: /keys/old
+  sign_key_paths: [/keys/new]
+  trusted_public_keys:
+    - cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=
+  store_dir: /gnu/store
+  ref_namespace: refs/packages
+  max_closure_depth: 500
+  nar_limits:
+    max_depth: 64
+  compression: none
+",
+        )?;
+        let config_file = [config_file];
+        let store = load(&config_file, variables(&[]), &[])?.store;
+        assert_eq!(
+            store.all_sign_key_paths().collect::<Vec<_>>(),
+            [&PathBuf::from("/keys/old"), &PathBuf::from("/keys/new")]
+        );
+        assert_eq!(store.trusted_keys()?[0].name, "cache.nixos.org-1");
+        assert_eq!(store.store_dir.as_deref(), Some("/gnu/store"));
+        assert_eq!(store.ref_namespace, "refs/packages");
+        assert_eq!(store.max_closure_depth, 500);
+        assert_eq!(store.nar_limits.max_depth, 64);
+        assert_eq!(
+            store.nar_limits.max_entries,
+            NarLimits::default().max_entries
+        );
+        assert_eq!(store.compression, Compression::None);
+        store.check_ref_namespace()?;
+
+        let store = load(
+            &config_file,
+            variables(&[
+                ("GACHIX__STORE__SIGN_KEY_PATHS", "/keys/a,/keys/b"),
+                ("GACHIX__STORE__TRUSTED_PUBLIC_KEYS", "a-1:LY9vz7UFrxViujMPmsvJBon/AGZEeSqLBy77sJcw5YI=,b-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY="),
+                ("GACHIX__STORE__MAX_CLOSURE_DEPTH", "20"),
+                ("GACHIX__STORE__NAR_LIMITS__MAX_ENTRIES", "1000"),
+                ("GACHIX__STORE__COMPRESSION", "zstd"),
+            ]),
+&[],
+        )?
+        .store;
+        assert_eq!(
+            store.sign_key_paths,
+            [PathBuf::from("/keys/a"), PathBuf::from("/keys/b")]
+        );
+        let names: Vec<String> = store.trusted_keys()?.into_iter().map(|k| k.name).collect();
+        assert_eq!(names, ["a-1", "b-1"]);
+        assert_eq!(store.max_closure_depth, 20);
+        assert_eq!(store.nar_limits.max_entries, 1000);
+        assert_eq!(store.compression, Compression::Zstd);
+
+        // only the formats listings can be stored in
+        let error = load(&[], variables(&[("GACHIX__STORE__COMPRESSION", "xz")]), &[]).unwrap_err();
+        assert!(error.to_string().contains("store.compression"), "{error}");
+        assert!(error.to_string().contains("xz"), "{error}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_ref_namespace() {
+        let mut store = Store::new(PathBuf::from("cache"));
+        for valid in ["refs", "refs/packages", "refs/gachix-packages"] {
+            store.ref_namespace = valid.to_string();
+            assert!(store.check_ref_namespace().is_ok(), "{valid}");
+        }
+        for invalid in [
+            "",
+            "packages",
+            "refs/",
+            "refs/a b",
+            "refs/*",
+            "refs/gachix",
+            "refs/gachix/x",
+        ] {
+            store.ref_namespace = invalid.to_string();
+            assert!(store.check_ref_namespace().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_split_list() {
+        assert_eq!(split_list(""), Vec::<String>::new());
+        assert_eq!(split_list("a"), ["a"]);
+        assert_eq!(split_list("a, b ,c"), ["a", "b", "c"]);
+        assert_eq!(split_list("a\\,b,c"), ["a,b", "c"]);
+        assert_eq!(split_list("a\\\\,b\\n"), ["a\\", "b\\n"]);
+        assert_eq!(split_list("a,,b"), ["a", "", "b"]);
+    }
+
+    #[test]
+    fn test_list_variables() -> anyhow::Result<()> {
+        let settings = load(
+            &[],
+            variables(&[
+                ("GACHIX__STORE__BUILDERS", "host1,alice@host2:2222"),
+                (
+                    "GACHIX__STORE__REMOTES",
+                    "https://example.org/cache.git,ssh://git@example.org/cache.git",
+                ),
+                ("GACHIX__STORE__SIGN_KEY_PATHS", "/keys/a"),
+                (
+                    "GACHIX__STORE__TRUSTED_PUBLIC_KEYS",
+                    "a-1:LY9vz7UFrxViujMPmsvJBon/AGZEeSqLBy77sJcw5YI=, b-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=",
+                ),
+                ("GACHIX__STORE__SIGNER__COMMAND", "sh,-c,echo a\\,b"),
+                (
+                    "GACHIX__STORE__SIGNER__PUBLIC_KEY",
+                    "a-1:LY9vz7UFrxViujMPmsvJBon/AGZEeSqLBy77sJcw5YI=",
+                ),
+            ]),
+            &[],
+        )?;
+        let store = settings.store;
+        let builders: Vec<String> = store.builders.iter().map(|b| b.to_string()).collect();
+        assert_eq!(builders, ["nix-ssh@host1:22", "alice@host2:2222"]);
+        assert_eq!(store.remotes.len(), 2);
+        assert_eq!(store.remotes[1].url.scheme(), "ssh");
+        assert_eq!(store.sign_key_paths, [PathBuf::from("/keys/a")]);
+        assert_eq!(store.trusted_keys()?.len(), 2);
+        assert_eq!(store.signer.unwrap().command, ["sh", "-c", "echo a,b"]);
+
+        // an empty variable clears the list of the config
+        let temp_dir = tempfile::TempDir::new()?;
+        let config_file = temp_dir.path().join("gachix.yaml");
+        std::fs::write(&config_file, "store:\n  builders: [host1]\n")?;
+        let settings = load(
+            &[config_file],
+            variables(&[("GACHIX__STORE__BUILDERS", "")]),
+            &[],
+        )?;
+        assert!(settings.store.builders.is_empty());
+
+        // keys which are not lists are not split
+        let settings = load(&[], variables(&[("GACHIX__SERVER__HOST", "a,b")]), &[])?;
+        assert_eq!(settings.server.host, "a,b");
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_builder_entries() -> anyhow::Result<()> {
+        let builder: Builder = "alice@build.example.org:2222".parse()?;
+        assert_eq!(builder.user(), "alice");
+        assert_eq!(builder.host, "build.example.org");
+        assert_eq!(builder.port(), 2222);
+
+        let builder: Builder = "ssh://build.example.org".parse()?;
+        assert_eq!(builder.user(), Builder::DEFAULT_USER);
+        assert_eq!(builder.port(), Builder::DEFAULT_PORT);
+
+        let builder: Builder = "build.example.org:2222".parse()?;
+        assert_eq!(builder.user, None);
+        assert_eq!(builder.user(), Builder::DEFAULT_USER);
+        assert_eq!(builder.port(), 2222);
+
+        let builder: Builder = "alice@[2001:db8::1]:2222".parse()?;
+        assert_eq!(builder.host, "2001:db8::1");
+        assert_eq!(builder.port(), 2222);
+        assert_eq!(builder.to_string(), "alice@[2001:db8::1]:2222");
+
+        let builder: Builder = "[::1]".parse()?;
+        assert_eq!(builder.host, "::1");
+        assert_eq!(builder.port(), Builder::DEFAULT_PORT);
+
+        assert!("alice@".parse::<Builder>().is_err());
+        let err = "http://build.example.org".parse::<Builder>().unwrap_err();
+        assert!(format!("{err:#}").contains("'http://build.example.org'"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_deserialize_remotes() -> anyhow::Result<()> {
+        let remotes = |yaml: &str| -> anyhow::Result<Vec<RemoteConfig>> {
+            let config = Config::builder()
+                .add_source(File::from_str(yaml, config::FileFormat::Yaml))
+                .build()?;
+            Ok(config.get("remotes")?)
+        };
+        let parsed = remotes(
+            "remotes:\n  - https://cache.example.org/cache.git\n  - name: backup\n    url: ssh://git@backup.example.org/cache.git\n    priority: 10\n    ssh_key: /etc/gachix/backup-key\n    ref_namespace: refs/packages\n  - url: https://binary.example.org\n    kind: http\n    token_env: GACHIX_TOKEN\n",
+        )?;
+        // bare URLs are normalized into entries with the defaults
+        assert_eq!(
+            parsed[0],
+            RemoteConfig::new(Url::parse("https://cache.example.org/cache.git")?)
+        );
+        assert_eq!(parsed[0].name, "cache.example.org");
+        assert_eq!(parsed[0].kind, RemoteKind::Git);
+        assert_eq!(parsed[1].name, "backup");
+        assert_eq!(parsed[1].priority, 10);
+        assert_eq!(
+            parsed[1].ssh_key_path,
+            Some(PathBuf::from("/etc/gachix/backup-key"))
+        );
+        assert_eq!(parsed[1].ref_namespace.as_deref(), Some("refs/packages"));
+        assert_eq!(parsed[2].name, "binary.example.org");
+        assert_eq!(parsed[2].kind, RemoteKind::Http);
+        assert_eq!(parsed[2].token_env.as_deref(), Some("GACHIX_TOKEN"));
+        assert!(!parsed[2].push);
+
+        let mut store = Store::new(PathBuf::from("cache"));
+        store.remotes = parsed.clone();
+        let names: Vec<String> = store
+            .remotes_by_priority()
+            .into_iter()
+            .map(|remote| remote.name)
+            .collect();
+        assert_eq!(names, ["backup", "cache.example.org", "binary.example.org"]);
+
+        // written back as they were given
+        let serialized = serde_json::to_value(&parsed)?;
+        assert_eq!(serialized[0], "https://cache.example.org/cache.git");
+        assert_eq!(serialized[1]["name"], "backup");
+        let reparsed: Vec<RemoteConfig> = serde_json::from_value(serialized)?;
+        assert_eq!(reparsed, parsed);
+
+        for invalid in [
+            "remotes: [not a url]",
+            "remotes:\n  - name: x\n",
+            "remotes:\n  - url: https://a.example.org\n    name: ' '\n",
+            "remotes:\n  - url: https://a.example.org\n    kind: svn\n",
+        ] {
+            assert!(remotes(invalid).is_err(), "{invalid}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_deserialize_structured_builder() -> anyhow::Result<()> {
+        let config = Config::builder()
+            .add_source(File::from_str(
+                "builders:\n  - host: build.example.org\n    port: 2222\n    ssh_key_path: /etc/gachix/key\n    systems: [aarch64-linux]\n  - bob@other.example.org\n  - host: my-builder-alias\n    transport: openssh",
+                config::FileFormat::Yaml,
+            ))
+            .build()?;
+        let builders: Vec<Builder> = config.get("builders")?;
+        assert_eq!(builders[0].port(), 2222);
+        assert_eq!(builders[0].user(), Builder::DEFAULT_USER);
+        assert_eq!(builders[0].transport, SshTransport::Libssh2);
+        assert_eq!(
+            builders[0].ssh_key_path,
+            Some(PathBuf::from("/etc/gachix/key"))
+        );
+        assert!(builders[0].supports_system("aarch64-linux"));
+        assert!(!builders[0].supports_system("x86_64-linux"));
+        assert_eq!(builders[1].user(), "bob");
+        assert!(builders[1].supports_system("x86_64-linux"));
+        assert_eq!(builders[2].user, None);
+        assert_eq!(builders[2].transport, SshTransport::Openssh);
+        Ok(())
+    }
 }
@@ -0,0 +1,441 @@
+use crate::log_file::RotatingFile;
+use crate::settings::{self, LogFormat, LogLevel, Settings};
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::path::Path;
+use tracing::{Subscriber, warn};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_chrome::{ChromeLayer, ChromeLayerBuilder, FlushGuard};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::{LookupSpan, Registry};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, reload};
+
+// The trace covers gachix in more detail than the log, e.g. waiting for locks
+const TRACE_DIRECTIVES: &str = "gachix=trace";
+
+/// Flushes the log file, the trace file and the exported spans when dropped,
+/// so `main` keeps it until it returns
+#[must_use]
+pub struct TelemetryGuard {
+    log_level: LogLevelHandle,
+    _log_file: Option<WorkerGuard>,
+    _trace_file: Option<FlushGuard>,
+    #[cfg(feature = "otlp")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl TelemetryGuard {
+    pub fn log_level(&self) -> LogLevelHandle {
+        self.log_level.clone()
+    }
+}
+
+/// Changes the level of the log while gachix runs, e.g. when the server reloads its settings
+#[derive(Clone, Default)]
+pub struct LogLevelHandle {
+    filters: Vec<reload::Handle<EnvFilter, Registry>>,
+}
+
+impl LogLevelHandle {
+    /// Logs the events of `level` from now on, unless `RUST_LOG` is set which keeps precedence
+    pub fn set(&self, level: LogLevel) -> Result<()> {
+        for filter in &self.filters {
+            filter.reload(log_filter(level))?;
+        }
+        Ok(())
+    }
+}
+
+/// `RUST_LOG`, or `level` if it is not set
+fn log_filter(level: LogLevel) -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level.as_str()))
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        if let Some(provider) = self.provider.take()
+            && let Err(e) = provider.shutdown()
+        {
+            warn!("Could not flush the remaining spans: {}", e);
+        }
+    }
+}
+
+/// Installs the global subscriber, which logs events filtered by `RUST_LOG` or `log.level`,
+/// writes a Chrome trace to `trace_out` and exports spans if an OTLP endpoint is configured
+pub fn init(settings: &Settings, trace_out: Option<&Path>) -> Result<TelemetryGuard> {
+    let telemetry = &settings.telemetry;
+    // Each output has its own filter, so the trace file does not make the log more verbose
+    let mut log_level = LogLevelHandle::default();
+    let mut reloadable_filter = || {
+        let (filter, handle) = reload::Layer::new(log_filter(settings.log.level));
+        log_level.filters.push(handle);
+        filter
+    };
+    let mut layers: Vec<Box<dyn Layer<Registry> + Send + Sync>> = Vec::new();
+    if settings.log.console {
+        let layer = fmt_layer(&settings.log, std::io::stdout, true);
+        layers.push(layer.with_filter(reloadable_filter()).boxed());
+    }
+    let log_file = match &settings.log.file {
+        Some(path) => {
+            let (layer, guard) = file_layer(path, &settings.log)?;
+            layers.push(layer.with_filter(reloadable_filter()).boxed());
+            Some(guard)
+        }
+        None => None,
+    };
+    let trace_file = match trace_out {
+        Some(path) => {
+            let (layer, guard) = trace_layer(path)?;
+            layers.push(layer.with_filter(EnvFilter::new(TRACE_DIRECTIVES)).boxed());
+            Some(guard)
+        }
+        None => None,
+    };
+
+    #[cfg(feature = "otlp")]
+    {
+        use opentelemetry::trace::TracerProvider;
+
+        let provider = telemetry
+            .otlp_endpoint
+            .as_deref()
+            .map(otlp::provider)
+            .transpose();
+        if let Ok(Some(provider)) = &provider {
+            let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("gachix"));
+            layers.push(layer.with_filter(reloadable_filter()).boxed());
+        }
+        tracing_subscriber::registry().with(layers).init();
+        // An unusable endpoint does not stop gachix, just like a collector which is down
+        let provider = provider.unwrap_or_else(|e| {
+            warn!("Spans are not exported: {:#}", e);
+            None
+        });
+        Ok(TelemetryGuard {
+            log_level,
+            _log_file: log_file,
+            _trace_file: trace_file,
+            provider,
+        })
+    }
+    #[cfg(not(feature = "otlp"))]
+    {
+        tracing_subscriber::registry().with(layers).init();
+        if telemetry.otlp_endpoint.is_some() {
+            warn!("telemetry.otlp_endpoint is ignored, gachix was built without the otlp feature");
+        }
+        Ok(TelemetryGuard {
+            log_level,
+            _log_file: log_file,
+            _trace_file: trace_file,
+        })
+    }
+}
+
+/// Records spans in the Chrome trace event format, which Perfetto and chrome://tracing load.
+/// The file is completed once the guard is dropped.
+fn trace_layer<S>(path: &Path) -> Result<(ChromeLayer<S>, FlushGuard)>
+where
+    S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+{
+    let file = File::create(path)
+        .with_context(|| format!("Could not create trace file {}", path.display()))?;
+    Ok(ChromeLayerBuilder::new()
+        .writer(file)
+        .include_args(true)
+        .build())
+}
+
+/// Writes the log to a rotated file. The file is written by a background thread,
+/// so logging does not wait for the disk.
+fn file_layer<S>(
+    path: &Path,
+    log: &settings::Log,
+) -> Result<(Box<dyn Layer<S> + Send + Sync>, WorkerGuard)>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let file = RotatingFile::new(path, log.rotate.max_size(), log.rotate.max_files)
+        .with_context(|| format!("Could not open log file {}", path.display()))?;
+    let (writer, guard) = tracing_appender::non_blocking(file);
+    Ok((fmt_layer(log, writer, false), guard))
+}
+
+/// Formats the log events written to `writer`
+fn fmt_layer<S, W>(log: &settings::Log, writer: W, ansi: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+    match (log.format, log.timestamps) {
+        (LogFormat::Text, true) => layer.boxed(),
+        (LogFormat::Text, false) => layer.without_time().boxed(),
+        // The fields of all enclosing spans are kept, so an event within a package span of a
+        // request still carries the request id
+        (LogFormat::Json, timestamps) => {
+            let layer = layer
+                .json()
+                .flatten_event(true)
+                .with_current_span(false)
+                .with_span_list(true);
+            if timestamps {
+                layer.boxed()
+            } else {
+                layer.without_time().boxed()
+            }
+        }
+    }
+}
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use anyhow::Result;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::Resource;
+    use opentelemetry_sdk::error::OTelSdkResult;
+    use opentelemetry_sdk::trace::{SdkTracerProvider, SpanData, SpanExporter};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+    use tracing::warn;
+
+    // Also bounds how long exiting waits for a collector which does not respond
+    const EXPORT_TIMEOUT: Duration = Duration::from_secs(3);
+
+    pub fn provider(endpoint: &str) -> Result<SdkTracerProvider> {
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .with_timeout(EXPORT_TIMEOUT)
+            .build()?;
+        Ok(SdkTracerProvider::builder()
+            .with_batch_exporter(FailOpenExporter {
+                inner: exporter,
+                warned: AtomicBool::new(false),
+            })
+            .with_resource(Resource::builder().with_service_name("gachix").build())
+            .build())
+    }
+
+    /// Drops the spans which can't be exported, warning only about the first failure
+    /// so an unreachable collector does not flood the log
+    #[derive(Debug)]
+    struct FailOpenExporter {
+        inner: opentelemetry_otlp::SpanExporter,
+        warned: AtomicBool,
+    }
+
+    impl SpanExporter for FailOpenExporter {
+        async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+            let result = self.inner.export(batch).await;
+            if let Err(e) = &result
+                && !self.warned.swap(true, Ordering::Relaxed)
+            {
+                warn!(
+                    "Could not export spans, they are dropped until the collector is reachable: {}",
+                    e
+                );
+            }
+            result
+        }
+
+        fn shutdown_with_timeout(&mut self, timeout: Duration) -> OTelSdkResult {
+            self.inner.shutdown_with_timeout(timeout)
+        }
+
+        fn force_flush(&mut self) -> OTelSdkResult {
+            self.inner.force_flush()
+        }
+
+        fn set_resource(&mut self, resource: &Resource) {
+            self.inner.set_resource(resource);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use tracing::{info, info_span};
+
+    /// Collects the formatted log output
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_log_lines() -> Result<()> {
+        let logs = CapturedLogs::default();
+        let writer = {
+            let logs = logs.clone();
+            move || logs.clone()
+        };
+        let log = settings::Log {
+            format: LogFormat::Json,
+            ..Default::default()
+        };
+        let subscriber = tracing_subscriber::registry().with(fmt_layer(&log, writer, false));
+        tracing::subscriber::with_default(subscriber, || {
+            let _request = info_span!("request", request_id = "client-id-1").entered();
+            let _package = info_span!("add_closure", package_hash = "abc").entered();
+            info!(bytes = 42, "Ingested package");
+        });
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone())?;
+        let lines: Vec<_> = logs.lines().collect();
+        assert_eq!(lines.len(), 1, "{logs}");
+        let line: serde_json::Value = serde_json::from_str(lines[0])?;
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["message"], "Ingested package");
+        assert_eq!(line["bytes"], 42);
+        // RFC 3339, the ISO 8601 profile
+        let timestamp = line["timestamp"].as_str().unwrap();
+        assert!(
+            timestamp.contains('T') && timestamp.ends_with('Z'),
+            "{timestamp}"
+        );
+        assert_eq!(line["spans"][0]["request_id"], "client-id-1");
+        assert_eq!(line["spans"][1]["package_hash"], "abc");
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_without_timestamps() -> Result<()> {
+        let logs = CapturedLogs::default();
+        let writer = {
+            let logs = logs.clone();
+            move || logs.clone()
+        };
+        let log = settings::Log {
+            format: LogFormat::Json,
+            timestamps: false,
+            ..Default::default()
+        };
+        let subscriber = tracing_subscriber::registry().with(fmt_layer(&log, writer, false));
+        tracing::subscriber::with_default(subscriber, || info!("Ingested package"));
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone())?;
+        let line: serde_json::Value = serde_json::from_str(logs.trim())?;
+        assert_eq!(line["message"], "Ingested package");
+        assert!(line.get("timestamp").is_none(), "{line}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_file() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let path = temp_dir.path().join("gachix.log");
+        let log = settings::Log {
+            file: Some(path.clone()),
+            ..Default::default()
+        };
+        let (layer, guard) = file_layer(&path, &log)?;
+        let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+        let handles: Vec<_> = (0..4)
+            .map(|thread| {
+                let dispatch = dispatch.clone();
+                std::thread::spawn(move || {
+                    tracing::dispatcher::with_default(&dispatch, || {
+                        for i in 0..50 {
+                            info!(thread, i, "Request completed");
+                        }
+                    })
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        // flushes the background writer
+        drop(guard);
+
+        let content = std::fs::read_to_string(&path)?;
+        assert_eq!(content.lines().count(), 200);
+        assert!(
+            content
+                .lines()
+                .all(|line| line.contains("Request completed"))
+        );
+        assert!(!content.contains('\x1b'), "no colors in the file");
+        Ok(())
+    }
+
+    #[test]
+    fn test_chrome_trace() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let path = temp_dir.path().join("trace.json");
+        let (layer, guard) = trace_layer(&path)?;
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let _closure = info_span!("add_closure", package_hash = "abc").entered();
+            let _fetch = tracing::debug_span!("fetch_nar").entered();
+            info!("Ingested package");
+        });
+        drop(guard);
+
+        let trace: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+        let events = trace.as_array().unwrap();
+        for event in events {
+            assert!(event["ph"].is_string(), "{event}");
+            // only metadata, e.g. thread names, has no timestamp
+            assert!(event["ph"] == "M" || event["ts"].is_number(), "{event}");
+            assert!(
+                event["pid"].is_number() && event["tid"].is_number(),
+                "{event}"
+            );
+        }
+        let phases = |name: &str| -> Vec<String> {
+            events
+                .iter()
+                .filter(|e| e["name"] == name)
+                .map(|e| e["ph"].as_str().unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(phases("add_closure"), ["B", "E"]);
+        assert_eq!(phases("fetch_nar"), ["B", "E"]);
+        let begin = events.iter().find(|e| e["name"] == "add_closure").unwrap();
+        // the fields are recorded in their debug representation
+        assert_eq!(begin["args"]["package_hash"], "\"abc\"");
+        Ok(())
+    }
+
+    #[cfg(feature = "otlp")]
+    #[test]
+    fn test_unreachable_collector_fails_open() {
+        use opentelemetry::trace::TracerProvider;
+        use std::time::{Duration, Instant};
+
+        assert!(otlp::provider("not a url").is_err());
+
+        // nothing listens on port 1
+        let provider = otlp::provider("http://127.0.0.1:1/v1/traces").unwrap();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("gachix")));
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = info_span!("add_closure", package_hash = "abc").entered();
+        });
+
+        let start = Instant::now();
+        assert!(provider.force_flush().is_err());
+        provider.shutdown().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,130 @@
+pub mod common;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tempfile::TempDir;
+
+use crate::common::fixtures;
+
+const STORE_PATH: &str = "/nix/store/0c8mr05lz0kpk3ir0r7zk36lrz5vl6vz-hello";
+
+/// Writes a config whose Nix daemon, builder and remote can't be reached
+fn write_config(dir: &Path, peer_host: &str) -> Result<String> {
+    let config_path = dir.join("config.yaml");
+    fs::write(
+        &config_path,
+        format!(
+            "store:\n  path: {}\n  daemon_socket: {}\n  builders: [\"nix-ssh@{peer_host}\"]\n  remotes: [\"ssh://git@{peer_host}/cache.git\"]\n",
+            dir.join("cache").display(),
+            dir.join("missing.sock").display(),
+        ),
+    )?;
+    Ok(config_path.to_string_lossy().to_string())
+}
+
+fn gachix(config: &str, args: &[&str]) -> Result<Output> {
+    Ok(Command::new(assert_cmd::cargo::cargo_bin!())
+        .arg("--config")
+        .arg(config)
+        .args(args)
+        .output()?)
+}
+
+#[test]
+fn test_list_with_unreachable_peers() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    // connecting to an address of TEST-NET-1 would only end with a timeout
+    let config = write_config(temp_dir.path(), "192.0.2.1")?;
+    let nar_path = temp_dir.path().join("hello.nar");
+    fs::write(&nar_path, fixtures::many_small_files_nar(3, 10))?;
+
+    let output = gachix(
+        &config,
+        &[
+            "import",
+            &nar_path.to_string_lossy(),
+            "--store-path",
+            STORE_PATH,
+        ],
+    )?;
+    assert!(output.status.success(), "{output:?}");
+
+    let start = Instant::now();
+    for args in [&["list"][..], &["list", "--filter", "hello"]] {
+        let output = gachix(&config, args)?;
+        assert!(output.status.success(), "{output:?}");
+        let stdout = String::from_utf8(output.stdout)?;
+        assert!(
+            stdout.contains("0c8mr05lz0kpk3ir0r7zk36lrz5vl6vz"),
+            "{stdout}"
+        );
+    }
+    assert!(start.elapsed() < Duration::from_secs(10));
+    Ok(())
+}
+
+#[test]
+fn test_doctor_reports_unreachable_peers() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    // nothing listens on port 1, so connections are refused right away
+    let config = write_config(temp_dir.path(), "127.0.0.1:1")?;
+    let output = gachix(&config, &["doctor"])?;
+    assert!(!output.status.success(), "{output:?}");
+    assert!(String::from_utf8(output.stderr)?.contains("Some peers are not reachable"));
+    Ok(())
+}
+
+#[test]
+fn test_trace_out_is_written_when_the_command_fails() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let config = write_config(temp_dir.path(), "127.0.0.1:1")?;
+    let nar_path = temp_dir.path().join("hello.nar");
+    fs::write(&nar_path, fixtures::many_small_files_nar(3, 10))?;
+
+    let trace_path = temp_dir.path().join("import.json");
+    let output = gachix(
+        &config,
+        &[
+            "--trace-out",
+            &trace_path.to_string_lossy(),
+            "import",
+            &nar_path.to_string_lossy(),
+            "--store-path",
+            STORE_PATH,
+        ],
+    )?;
+    assert!(output.status.success(), "{output:?}");
+    let trace: serde_json::Value = serde_json::from_str(&fs::read_to_string(&trace_path)?)?;
+    let names: Vec<_> = trace
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter_map(|event| event["name"].as_str())
+        .collect();
+    assert!(names.contains(&"import_nar"), "{names:?}");
+    assert!(names.contains(&"add_nar"), "{names:?}");
+
+    let trace_path = temp_dir.path().join("doctor.json");
+    let output = gachix(
+        &config,
+        &["doctor", "--trace-out", &trace_path.to_string_lossy()],
+    )?;
+    assert!(!output.status.success(), "{output:?}");
+    let trace: serde_json::Value = serde_json::from_str(&fs::read_to_string(&trace_path)?)?;
+    assert!(trace.is_array());
+    Ok(())
+}
+
+#[test]
+fn test_missing_config_file_is_an_error() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let typo = temp_dir.path().join("prdo.yaml");
+    let output = gachix(&typo.to_string_lossy(), &["doctor"])?;
+    assert!(!output.status.success(), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(&*typo.to_string_lossy()), "{stderr}");
+    Ok(())
+}
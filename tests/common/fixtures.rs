@@ -0,0 +1,147 @@
+//! Synthetic NARs and narinfos which don't need a Nix installation.
+//! They are shared by the integration tests, the unit tests of the store and the benchmarks in `benches/`.
+
+/// Writes the tokens of a NAR, which are length prefixed and padded to 8 bytes
+#[derive(Default)]
+pub struct NarBuilder {
+    nar: Vec<u8>,
+}
+
+impl NarBuilder {
+    pub fn new() -> Self {
+        let mut builder = Self::default();
+        builder.token(b"nix-archive-1");
+        builder
+    }
+
+    pub fn token(&mut self, token: &[u8]) -> &mut Self {
+        self.nar.extend((token.len() as u64).to_le_bytes());
+        self.nar.extend(token);
+        self.nar.resize(self.nar.len().next_multiple_of(8), 0);
+        self
+    }
+
+    pub fn tokens(&mut self, tokens: &[&[u8]]) -> &mut Self {
+        for token in tokens {
+            self.token(token);
+        }
+        self
+    }
+
+    pub fn regular(&mut self, content: &[u8], executable: bool) -> &mut Self {
+        self.tokens(&[b"(", b"type", b"regular"]);
+        if executable {
+            self.tokens(&[b"executable", b""]);
+        }
+        self.tokens(&[b"contents", content, b")"])
+    }
+
+    pub fn symlink(&mut self, target: &[u8]) -> &mut Self {
+        self.tokens(&[b"(", b"type", b"symlink", b"target", target, b")"])
+    }
+
+    pub fn start_directory(&mut self) -> &mut Self {
+        self.tokens(&[b"(", b"type", b"directory"])
+    }
+
+    /// Entries have to be added in the byte order of their names
+    pub fn start_entry(&mut self, name: &[u8]) -> &mut Self {
+        self.tokens(&[b"entry", b"(", b"name", name, b"node"])
+    }
+
+    /// Ends an entry or a directory
+    pub fn end(&mut self) -> &mut Self {
+        self.token(b")")
+    }
+
+    pub fn build(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.nar)
+    }
+}
+
+/// Reproducible, poorly compressible file contents
+pub fn file_content(seed: u64, size: usize) -> Vec<u8> {
+    let mut state = seed
+        .wrapping_mul(6364136223846793005)
+        .wrapping_add(1442695040888963407);
+    (0..size)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 56) as u8
+        })
+        .collect()
+}
+
+/// A package like a python site-packages directory: `files` small files spread over
+/// directories of 100 files each, every tenth one executable
+pub fn many_small_files_nar(files: usize, file_size: usize) -> Vec<u8> {
+    let mut nar = NarBuilder::new();
+    nar.start_directory();
+    for dir in 0..files.div_ceil(100) {
+        nar.start_entry(format!("dir-{dir:05}").as_bytes())
+            .start_directory();
+        for file in (dir * 100)..files.min((dir + 1) * 100) {
+            nar.start_entry(format!("file-{file:07}").as_bytes())
+                .regular(&file_content(file as u64, file_size), file % 10 == 0)
+                .end();
+        }
+        nar.end().end();
+    }
+    nar.end().build()
+}
+
+/// A package consisting of a single file of `size` bytes
+pub fn single_huge_file_nar(size: usize) -> Vec<u8> {
+    NarBuilder::new()
+        .regular(&file_content(0, size), false)
+        .build()
+}
+
+/// Directories nested `depth` levels deep, each holding `files_per_level` files and a symlink
+pub fn deep_tree_nar(depth: usize, files_per_level: usize) -> Vec<u8> {
+    fn level(nar: &mut NarBuilder, remaining: usize, files_per_level: usize) {
+        nar.start_directory();
+        for file in 0..files_per_level {
+            nar.start_entry(format!("file-{file:03}").as_bytes())
+                .regular(&file_content((remaining * 1000 + file) as u64, 512), false)
+                .end();
+        }
+        nar.start_entry(b"link").symlink(b"file-000").end();
+        if remaining > 0 {
+            nar.start_entry(b"sub");
+            level(nar, remaining - 1, files_per_level);
+            nar.end();
+        }
+        nar.end();
+    }
+    let mut nar = NarBuilder::new();
+    level(&mut nar, depth, files_per_level);
+    nar.build()
+}
+
+/// A narinfo with `references` references, in the format served by the cache
+pub fn narinfo_text(references: usize) -> String {
+    let hash = |i: usize| format!("{:0>32}", format!("{i}").replace('0', "a"));
+    let refs: Vec<_> = (0..references)
+        .map(|i| format!("{}-dependency-{i}", hash(i + 1)))
+        .collect();
+    format!(
+        "StorePath: /nix/store/{}-package-1.0\n\
+         URL: nar/{}.nar\n\
+         Compression: none\n\
+         FileHash: sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73\n\
+         FileSize: 4096\n\
+         NarHash: sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73\n\
+         NarSize: 4096\n\
+         References: {}\n\
+         Deriver: {}-package-1.0.drv\n\
+         Sig: cache.example.org-1:{}\n",
+        hash(0),
+        hash(0),
+        refs.join(" "),
+        hash(references + 1),
+        "A".repeat(86) + "==",
+    )
+}
@@ -1,20 +1,23 @@
+pub mod fixtures;
+
 use anyhow::{Result, anyhow, bail};
 use assert_cmd;
+use gachix::client::BinaryCacheClient;
 use regex::Regex;
-use reqwest;
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::process::{Child, Command, Stdio};
-use std::thread::sleep;
 use std::time::Duration;
+use url::Url;
 
 pub const NIXPGKS_VERSION: &str = "github:NixOS/nixpkgs/21.05";
 
 pub struct CacheServer {
     child: Child,
+    pub client: BinaryCacheClient,
 }
 impl CacheServer {
-    pub fn start(port: u16, cache_path: &Path) -> Result<Self> {
+    pub async fn start(port: u16, cache_path: &Path) -> Result<Self> {
         let mut command = Command::new(assert_cmd::cargo::cargo_bin!());
         let mut child = command
             .env("GACHIX__STORE__PATH", cache_path)
@@ -24,8 +27,8 @@ impl CacheServer {
             .spawn()
             .map_err(|e| anyhow!("Failed to start server: {}", e))?;
 
-        let base_url = format!("http://localhost:{}", port);
-        let health_check_url = format!("{base_url}/nix-cache-info");
+        let client = BinaryCacheClient::new(Url::parse(&format!("http://localhost:{}", port))?)
+            .with_retries(0);
 
         let max_attempts = 10;
         let delay = Duration::from_millis(500);
@@ -35,17 +38,12 @@ impl CacheServer {
                 bail!("Server stopped shortly after being spawned");
             }
 
-            match reqwest::blocking::get(&health_check_url) {
-                Ok(response) => {
-                    if response.status().is_success() {
-                        println!("Server ready after {} attempts.", attempt + 1);
-                        return Ok(CacheServer { child });
-                    }
-                }
-                Err(_) => {}
+            if client.get_cache_info().await.is_ok() {
+                println!("Server ready after {} attempts.", attempt + 1);
+                return Ok(CacheServer { child, client });
             }
 
-            sleep(delay);
+            tokio::time::sleep(delay).await;
         }
 
         child.kill().unwrap_or_default(); // Clean up the server process
@@ -100,6 +98,12 @@ pub fn add_to_cache(
         .arg("add")
         .arg(store_path)
         .stdout(Stdio::null());
+    // The environment is cleared, so pass on where the local Nix daemon can be found
+    for var in ["NIX_REMOTE", "GACHIX__STORE__DAEMON_SOCKET"] {
+        if let Ok(value) = std::env::var(var) {
+            process.env(var, value);
+        }
+    }
     if let Some(config) = config {
         process.envs(config);
     }
@@ -111,18 +115,8 @@ pub fn add_to_cache(
     Ok(())
 }
 
-pub fn request(url: &str) -> Result<reqwest::blocking::Response> {
-    let response = reqwest::blocking::get(url)?;
-    assert!(
-        response.status().is_success(),
-        "Request failed for: {}. Server responded with status code {}",
-        url,
-        response.status()
-    );
-    return Ok(response);
-}
 pub fn get_hash(store_path: &Path) -> Result<String> {
-    Ok(Regex::new(r"([0-9a-z]{32})")?
+    Ok(Regex::new(r"([0-9a-df-np-sv-z]{32})")?
         .find(store_path.to_str().unwrap())
         .unwrap()
         .as_str()
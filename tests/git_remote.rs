@@ -19,6 +19,32 @@ fn test_no_peers_leads_to_error() -> Result<()> {
     Ok(())
 }
 
+// Requires a Nix daemon reachable over SSH which has `hello` in its store, e.g.
+// GACHIX_TEST_SSH_BUILDER=nix-ssh@localhost:22 GACHIX_TEST_SSH_KEY=~/.ssh/id_ed25519
+#[test]
+fn test_fetch_closure_from_ssh_daemon() -> Result<()> {
+    let (Ok(builder), Ok(key)) = (
+        std::env::var("GACHIX_TEST_SSH_BUILDER"),
+        std::env::var("GACHIX_TEST_SSH_KEY"),
+    ) else {
+        println!("Skipping: GACHIX_TEST_SSH_BUILDER or GACHIX_TEST_SSH_KEY not set");
+        return Ok(());
+    };
+    let temp_dir = TempDir::new()?;
+    let requester_path = temp_dir.path().join("requester");
+
+    let package_path = common::build_nix_package("hello")?;
+    let config = HashMap::from([
+        ("GACHIX__STORE__BUILDERS", builder.as_str()),
+        ("GACHIX__STORE__SSH_PRIVATE_KEY_PATH", key.as_str()),
+        ("GACHIX__STORE__USE_LOCAL_NIX_DAEMON", "0"),
+    ]);
+
+    common::add_to_cache(&package_path, &requester_path, Some(config))?;
+
+    Ok(())
+}
+
 // #[test]
 // fn test_fetch_entire_closure_from_git_remote() -> Result<()> {
 //     let temp_dir = TempDir::new()?;
@@ -0,0 +1,69 @@
+pub mod common;
+use std::io::Read;
+
+use anyhow::Result;
+use gachix::git_store::GitRepo;
+use gachix::nix_interface::nar_info::NarInfo;
+use gachix::settings::GitSettings;
+use tempfile::TempDir;
+
+use crate::common::fixtures;
+
+#[test]
+fn test_synthetic_nars_roundtrip() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let repo = GitRepo::new(&temp_dir.path().join("repo"))?;
+    for nar in [
+        fixtures::many_small_files_nar(1000, 100),
+        fixtures::single_huge_file_nar(4 << 20),
+        fixtures::deep_tree_nar(50, 3),
+    ] {
+        let (oid, _) = repo.add_nar(nar.as_slice())?;
+        let mut served = Vec::new();
+        repo.get_entry_as_nar(oid)?
+            .unwrap()
+            .into_sync_reader()
+            .read_to_end(&mut served)?;
+        assert_eq!(served, nar);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_ingestion_is_deterministic() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    for (i, nar) in [
+        fixtures::many_small_files_nar(1000, 100),
+        fixtures::single_huge_file_nar(4 << 20),
+        fixtures::deep_tree_nar(50, 3),
+    ]
+    .iter()
+    .enumerate()
+    {
+        // Peers store the same NAR as the same tree, however its objects were written
+        let packed = GitRepo::new(&temp_dir.path().join(format!("packed-{i}")))?;
+        let loose = GitRepo::new(&temp_dir.path().join(format!("loose-{i}")))?.with_git_settings(
+            GitSettings {
+                ingest_pack_budget: 0,
+                ..GitSettings::default()
+            },
+        )?;
+        let (packed_oid, _) = packed.add_nar(nar.as_slice())?;
+        let (loose_oid, _) = loose.add_nar(nar.as_slice())?;
+        assert_eq!(packed_oid, loose_oid);
+        assert_eq!(packed.add_nar(nar.as_slice())?.0, packed_oid);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_synthetic_narinfo_roundtrip() -> Result<()> {
+    let text = fixtures::narinfo_text(20);
+    let narinfo = NarInfo::parse(&text)?;
+    assert_eq!(narinfo.references.len(), 20);
+    assert_eq!(
+        NarInfo::parse(&narinfo.to_string())?.to_string(),
+        narinfo.to_string()
+    );
+    Ok(())
+}
@@ -1,130 +1,101 @@
 pub mod common;
 use std::{collections::HashMap, fs, path::PathBuf, process::Command};
 
-use anyhow::{Result, bail};
-use bytes::Buf;
+use anyhow::Result;
+use futures::TryStreamExt;
+use gachix::client::BinaryCacheClient;
+use gachix::nix::cache_info::CacheInfo;
 use nix_nar::Decoder;
-use regex::Regex;
-use reqwest::StatusCode;
 use tempfile::TempDir;
 
 use crate::common::NIXPGKS_VERSION;
 
-#[test]
-fn test_cache_info_request() -> Result<()> {
+/// Downloads the NAR of the package with `nix_hash` through its narinfo
+async fn fetch_nar(client: &BinaryCacheClient, nix_hash: &str) -> Result<Vec<u8>> {
+    let url = client.get_narinfo(nix_hash).await?.unwrap().url.unwrap();
+    assert!(url.starts_with("nar/"), "Unexpected URL {}", url);
+    let nar = client.get_nar(&url).await?.try_collect::<Vec<_>>().await?;
+    Ok(nar.concat())
+}
+
+#[tokio::test]
+async fn test_cache_info_request() -> Result<()> {
     let tempdir = TempDir::new()?;
     let temp_path = tempdir.path();
     let port = 9234;
-    let base_url = format!("http://localhost:{}", port);
 
-    let _server = common::CacheServer::start(port, &temp_path.join("gachix"))?;
+    let server = common::CacheServer::start(port, &temp_path.join("gachix")).await?;
 
-    let response = common::request(&format!("{base_url}/nix-cache-info"))?;
-
-    assert!(
-        response.status().is_success(),
-        "Server responded with unexpected status code {}",
-        response.status()
-    );
-
-    let body = response.text()?;
-
-    let pattern = Regex::new(r"^StoreDir: (/\w*)*\nWantMassQuery: (0|1)\nPriority: \d*$")?;
-    assert!(
-        pattern.is_match(&body),
-        "Response body did not match the required regex pattern.\nBody received:\n{}",
-        body
-    );
+    let cache_info = server.client.get_cache_info().await?;
+    assert_eq!(cache_info, CacheInfo::default());
 
     Ok(())
 }
 
-#[test]
-fn test_head_request() -> Result<()> {
+#[tokio::test]
+async fn test_head_request() -> Result<()> {
     let tempdir = TempDir::new()?;
     let temp_path = tempdir.path();
     let port = 9231;
-    let base_url = format!("http://localhost:{}", port);
     let repo_path = &temp_path.join("gachix");
 
     let store_path = common::build_nix_package("hello")?;
     common::add_to_cache(&store_path, &repo_path, None)?;
     let nix_hash = common::get_hash(&store_path)?;
 
-    let _server = common::CacheServer::start(port, &repo_path)?;
-    let url = format!("{base_url}/{nix_hash}.narinfo");
+    let server = common::CacheServer::start(port, &repo_path).await?;
 
-    let client = reqwest::blocking::Client::new();
-    let response = client.head(&url).send()?;
     assert!(
-        response.status() == StatusCode::OK,
-        "Expected successful HEAD request, but it failed with status code: {}",
-        response.status()
+        server.client.head_narinfo(&nix_hash).await?,
+        "Expected successful HEAD request for {nix_hash}"
     );
-
-    let url = format!("{base_url}/h0b3pxg56bh5lnh4bqrb2gsrbkdzmpsh.narinfo");
-    let response = client.head(&url).send()?;
     assert!(
-        response.status() == StatusCode::NOT_FOUND,
-        "Expected status code NOT FOUND, but got: {}",
-        response.status()
+        !server
+            .client
+            .head_narinfo("h0b3pxg56bh5lnh4bqrb2gsrbkdzmpsh")
+            .await?,
+        "Expected status code NOT FOUND for a package which is not cached"
     );
 
     Ok(())
 }
 
-#[test]
-fn test_narinfo_request() -> Result<()> {
+#[tokio::test]
+async fn test_narinfo_request() -> Result<()> {
     let tempdir = TempDir::new()?;
     let temp_path = tempdir.path();
     let port = 9238;
-    let base_url = format!("http://localhost:{}", port);
     let repo_path = &temp_path.join("gachix");
 
     let store_path = common::build_nix_package("hello")?;
     common::add_to_cache(&store_path, &repo_path, None)?;
     let nix_hash = common::get_hash(&store_path)?;
 
-    let _server = common::CacheServer::start(port, &temp_path.join("gachix"))?;
-
-    let narinfo_response = common::request(&format!("{base_url}/{nix_hash}.narinfo"))?;
-    let body = narinfo_response.text()?;
-
-    // TODO: make more strict narinfo patterns
-    let patterns = [
-        r"^StorePath: [/\w]+[-\w\.]+",
-        r"^URL: [/\w\.]+",
-        r"^Compression:[ \w]*",
-        r"^FileHash:[ \w:]",
-        r"^FileSize: [\d]+",
-        r"^NarHash:[ \w:]+",
-        r"^NarSize: [\d]+",
-        r"^References:[ /\w]+[-\w\. ]*",
-        r"^Deriver: [ /\w]+[-\w\. ]*.drv",
-        r"^Sig: [\w\.\-:=]*",
-    ];
-
-    let lines = body.lines();
-
-    for (pattern, line) in patterns.iter().zip(lines) {
-        let regex = Regex::new(pattern)?;
-        assert!(
-            regex.is_match(line),
-            "NarInfo line did not match regex.\nExpected pattern: {}\nInstead got: {}",
-            pattern,
-            line
-        );
-    }
+    let server = common::CacheServer::start(port, &temp_path.join("gachix")).await?;
+
+    let narinfo = server.client.get_narinfo(&nix_hash).await?.unwrap();
+    assert_eq!(
+        narinfo.store_path.get_path(),
+        store_path.to_string_lossy().trim()
+    );
+    assert!(narinfo.url.is_some_and(|url| url.starts_with("nar/")));
+    assert!(narinfo.nar_hash.starts_with("sha256:"));
+    assert!(narinfo.nar_size > 0);
+    assert!(!narinfo.references.is_empty());
+    assert!(
+        narinfo
+            .deriver
+            .is_some_and(|deriver| deriver.get_name().ends_with(".drv"))
+    );
 
     Ok(())
 }
 
-#[test]
-fn test_package_retrieval() -> Result<()> {
+#[tokio::test]
+async fn test_package_retrieval() -> Result<()> {
     let tempdir = TempDir::new()?;
     let temp_path = tempdir.path();
     let port = 9239;
-    let base_url = format!("http://localhost:{}", port);
     let repo_path = &temp_path.join("gachix");
 
     // Add some package to the cache
@@ -132,39 +103,50 @@ fn test_package_retrieval() -> Result<()> {
     common::add_to_cache(&store_path, &repo_path, None)?;
 
     // start the server
-    let _server = common::CacheServer::start(port, &repo_path)?;
+    let server = common::CacheServer::start(port, &repo_path).await?;
 
     // retrieve nix hash from the nix path
     let nix_hash = common::get_hash(&store_path)?;
 
-    let narinfo_response = common::request(&format!("{base_url}/{nix_hash}.narinfo"))?;
-    let narinfo_body = narinfo_response.text()?;
-
-    // retrieve URL value from narinfo
-    let re = Regex::new(r"URL: (nar\/.*)\n")?;
-    let Some(caps) = re.captures(&narinfo_body) else {
-        bail!("Could not find URL in narinfo");
-    };
-    let url = &caps[1];
-
     // fetch the package
-    let package_response = common::request(&format!("{base_url}/{url}"))?;
+    let package_nar = fetch_nar(&server.client, &nix_hash).await?;
 
     // check whether the returned nar can be unpacked
-    let package_nar = package_response.bytes()?;
-    let decoder = Decoder::new(package_nar.reader())?;
+    let decoder = Decoder::new(package_nar.as_slice())?;
     let package_path = temp_path.join("my_package");
     decoder.unpack(package_path)?;
 
     Ok(())
 }
 
-#[test]
-fn test_single_file_retrieval() -> Result<()> {
+#[tokio::test]
+async fn test_listing_request() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let temp_path = tempdir.path();
+    let port = 9240;
+    let repo_path = &temp_path.join("gachix");
+
+    let store_path = common::build_nix_package("hello")?;
+    common::add_to_cache(&store_path, &repo_path, None)?;
+    let server = common::CacheServer::start(port, &repo_path).await?;
+    let nix_hash = common::get_hash(&store_path)?;
+
+    // the listing is requested with zstd as Content-Encoding
+    let listing = server.client.get_listing(&nix_hash).await?.unwrap();
+    assert_eq!(listing["version"], 1);
+    assert_eq!(listing["root"]["type"], "directory");
+    let hello = &listing["root"]["entries"]["bin"]["entries"]["hello"];
+    assert_eq!(hello["type"], "regular");
+    assert_eq!(hello["executable"], true);
+    assert!(hello["narOffset"].is_u64());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_single_file_retrieval() -> Result<()> {
     let tempdir = TempDir::new()?;
     let temp_path = tempdir.path();
     let port = 9239;
-    let base_url = format!("http://localhost:{}", port);
     let repo_path = &temp_path.join("gachix");
 
     // Add some package to the cache
@@ -180,27 +162,16 @@ fn test_single_file_retrieval() -> Result<()> {
     common::add_to_cache(&pkg_store_path, &repo_path, None)?;
 
     // start the server
-    let _server = common::CacheServer::start(port, &repo_path)?;
+    let server = common::CacheServer::start(port, &repo_path).await?;
 
     // retrieve nix hash from the nix path
     let nix_hash = common::get_hash(&pkg_store_path)?;
 
-    let narinfo_response = common::request(&format!("{base_url}/{nix_hash}.narinfo"))?;
-    let narinfo_body = narinfo_response.text()?;
-
-    // retrieve URL value from narinfo
-    let re = Regex::new(r"URL: (nar\/.*)\n")?;
-    let Some(caps) = re.captures(&narinfo_body) else {
-        bail!("Could not find URL in narinfo");
-    };
-    let url = &caps[1];
-
     // fetch the package
-    let package_response = common::request(&format!("{base_url}/{url}"))?;
+    let package_nar = fetch_nar(&server.client, &nix_hash).await?;
 
     // check whether the returned nar can be unpacked
-    let package_nar = package_response.bytes()?;
-    let decoder = Decoder::new(package_nar.reader())?;
+    let decoder = Decoder::new(package_nar.as_slice())?;
     let package_path = temp_path.join("my_package");
     decoder.unpack(&package_path)?;
 
@@ -211,8 +182,8 @@ fn test_single_file_retrieval() -> Result<()> {
     Ok(())
 }
 
-#[test]
-fn test_nix_substituter() -> Result<()> {
+#[tokio::test]
+async fn test_nix_substituter() -> Result<()> {
     let tempdir = TempDir::new()?;
     let temp_path = tempdir.path();
     let port = 9239;
@@ -247,7 +218,7 @@ fn test_nix_substituter() -> Result<()> {
     common::delete_nix_package(package_name)?;
 
     // Start the server
-    let _server = common::CacheServer::start(port, &repo_path)?;
+    let _server = common::CacheServer::start(port, &repo_path).await?;
 
     let public_key = fs::read_to_string(public_key_path)?;
     let _output = Command::new("nix")